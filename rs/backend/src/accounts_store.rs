@@ -1,20 +1,27 @@
 //! User accounts and transactions.
-use crate::constants::MEMO_CREATE_CANISTER;
-use crate::multi_part_transactions_processor::{MultiPartTransactionToBeProcessed, MultiPartTransactionsProcessor};
+use crate::constants::{MEMO_CREATE_CANISTER, MEMO_TOP_UP_CANISTER};
+use crate::multi_part_transactions_processor::{
+    EncodedMultiPartTransactions, MultiPartOperation, MultiPartTransactionToBeProcessed,
+    MultiPartTransactionsProcessor, OperationStatus, QueuedTransaction, RetryOutcome,
+};
 use crate::state::StableState;
 use crate::stats::Stats;
+use crate::time::time;
 use candid::CandidType;
 use dfn_candid::Candid;
 use histogram::AccountsStoreHistogram;
 use ic_base_types::{CanisterId, PrincipalId};
+use ic_cdk::println;
+use ic_nervous_system_common::ledger::compute_neuron_staking_subaccount;
 use ic_nns_common::types::NeuronId;
-use ic_nns_constants::CYCLES_MINTING_CANISTER_ID;
+use ic_nns_constants::{CYCLES_MINTING_CANISTER_ID, GOVERNANCE_CANISTER_ID};
 use ic_stable_structures::{storable::Bound, Storable};
 use icp_ledger::Operation::{self, Approve, Burn, Mint, Transfer};
 use icp_ledger::{AccountIdentifier, BlockIndex, Memo, Subaccount};
 use itertools::Itertools;
 use on_wire::{FromWire, IntoWire};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
@@ -23,12 +30,21 @@ use std::ops::RangeBounds;
 use std::time::{Duration, SystemTime};
 
 pub mod constructors;
+pub mod heap_or_stable_cell;
+pub mod heap_or_stable_map;
 pub mod histogram;
+pub mod operation_log;
 pub mod schema;
+pub mod transaction_index;
+pub mod verification;
+use heap_or_stable_map::HeapOrStableMap;
+use operation_log::OperationLog;
 use schema::{
-    proxy::{AccountsDb, AccountsDbAsProxy},
-    AccountsDbTrait,
+    proxy::{AccountsDb, AccountsDbAsProxy, MigrationError, MigrationProgress, SavedMigrations},
+    AccountsDbTrait, SchemaLabel,
 };
+use transaction_index::TransactionIndex;
+use verification::{Verification, VerificationStatus};
 
 // This limit is for DoS protection but should be increased if we get close to
 // the limit.
@@ -38,6 +54,51 @@ const ACCOUNT_LIMIT: u64 = 300_000;
 // Can be revisited if users find this too restrictive.
 const MAX_IMPORTED_TOKENS: i32 = 20;
 
+// Conservatively limit the number of address book entries to prevent using too much memory.
+// Can be revisited if users find this too restrictive.
+const MAX_ADDRESS_BOOK_ENTRIES: i32 = 30;
+
+// Conservatively limit the number of tags per canister to prevent using too much memory.
+const MAX_CANISTER_TAGS: i32 = 10;
+
+// Conservatively limit the number of canister groups to prevent using too much memory.
+const MAX_CANISTER_GROUPS: i32 = 20;
+
+// Preferences are meant for a handful of UI settings, so a few kilobytes should be plenty.
+const MAX_PREFERENCES_SIZE_BYTES: i32 = 4096;
+
+// Limits the size of responses from `get_indexed_transactions`.
+const MAX_INDEXED_TRANSACTIONS_PAGE_SIZE: u32 = 100;
+
+// Limits the number of neurons whose governance accounts are watched for top-ups.  Each costs
+// roughly 150 bytes of stable memory.  Neurons staked beyond the limit are still claimed.
+const MAX_NEURON_ACCOUNTS: u64 = 1_000_000;
+
+// Transfers to a neuron from anyone but its controller are refreshed only from this amount, 1 ICP,
+// so that sending dust cannot make the dapp call governance over and over.
+const MIN_NEURON_TOP_UP_E8S: u64 = 100_000_000;
+
+// Limits the number of undecodable blocks remembered for replay.  When exceeded, the oldest are dropped.
+const MAX_BLOCKS_TO_REPLAY: u64 = 1_000;
+
+// How far behind the next block to sync ledger sync can be rewound.  Processed blocks below this
+// window are pruned from `processed_blocks`.
+const RESYNC_WINDOW_BLOCKS: u64 = 1_000_000;
+
+// Limits the number of processed blocks pruned in one call, so that a large backlog is pruned over
+// several ledger syncs.
+const MAX_PROCESSED_BLOCKS_PRUNED_PER_CALL: usize = 1_000;
+
+/// The version of the format produced by `export_account`.
+const ACCOUNT_EXPORT_VERSION: u32 = 1;
+
+// Limits the size of responses from `export_account`.  Canisters, with their notes, make up most of
+// an account, so they are exported a page at a time.
+const MAX_EXPORTED_CANISTERS_PAGE_SIZE: u32 = 50;
+
+/// How long a proposed account transfer can be accepted for: one day.
+const ACCOUNT_TRANSFER_OFFER_TIMEOUT_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
 /// Accounts, transactions and related data.
 ///
 /// Note: Some monitoring fields are not included in the `Eq` and `PartialEq` implementations.  Additionally, please note
@@ -47,17 +108,66 @@ const MAX_IMPORTED_TOKENS: i32 = 20;
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub struct AccountsStore {
     // TODO(NNS1-720): Use AccountIdentifier directly as the key for this HashMap
+    ///
+    /// Note: The accounts database also indexes the sub-accounts and hardware wallets of each
+    /// account, so that they can be traced to the accounts they belong to.
     accounts_db: schema::proxy::AccountsDbAsProxy,
-    hardware_wallets_and_sub_accounts: HashMap<AccountIdentifier, AccountWrapper>,
+    /// The links from sub-accounts and hardware wallets to their accounts, as kept by releases that
+    /// predate the index in the accounts database, keyed by the sub-account or hardware wallet.
+    ///
+    /// These are consulted while the index is being built and discarded once it is complete.
+    legacy_links: HeapOrStableMap<AccountWrapper>,
     // pending_transactions: HashMap<(from, to), (TransactionType, timestamp_ms_since_epoch)>
     pending_transactions: HashMap<(AccountIdentifier, AccountIdentifier), (TransactionType, u64)>,
 
     block_height_synced_up_to: Option<BlockIndex>,
+    /// Set when ledger sync has been rewound to the first block of the ledger, until that block
+    /// has been processed.  `block_height_synced_up_to` cannot express this on its own.
+    resyncing_from_first_block: bool,
+    /// Note: This is kept in its own stable memory partition.
     multi_part_transactions_processor: MultiPartTransactionsProcessor,
     accounts_db_stats: AccountsDbStats,
     accounts_db_stats_recomputed_on_upgrade: IgnoreEq<Option<bool>>,
     last_ledger_sync_timestamp_nanos: u64,
     neurons_topped_up_count: u64,
+    /// Neurons staked by users of the dapp, keyed by the neuron's account in the governance canister.
+    ///
+    /// Used to recognize transfers that top up a neuron.
+    ///
+    /// Note: This is kept in its own stable memory partition.
+    neuron_accounts: HeapOrStableMap<NeuronDetails>,
+    /// Pending offers to move an account to a new principal, keyed by the current principal.
+    ///
+    /// Note: This is kept in its own stable memory partition.
+    account_transfer_offers: HeapOrStableMap<AccountTransferOffer>,
+    /// Blocks touching the accounts of users who have opted in to transaction indexing.
+    ///
+    /// Note: Like `accounts_db`, this is kept in its own stable memory partition rather than serialized with the heap.
+    transaction_index: TransactionIndex,
+    /// The outcomes of recently processed multi-part transactions, so that users can see why one failed.
+    ///
+    /// Note: This is kept in its own stable memory partition.
+    operation_log: OperationLog,
+    /// The heights of the blocks that have been queued as multi-part transactions, so that a block
+    /// that is synced or replayed again is never acted on twice.  Unlike the operation log, this
+    /// is not limited per principal.
+    ///
+    /// Note: This is kept in its own stable memory partition.
+    processed_blocks: HeapOrStableMap<()>,
+    /// Blocks below this height are treated as processed, and ledger sync cannot be rewound
+    /// below it.  Their entries in `processed_blocks` are pruned.
+    processed_blocks_floor: BlockIndex,
+    /// Blocks that could not be decoded during ledger sync and are to be fetched and processed
+    /// again, keyed by a sequence number so that they are replayed in the order they were scheduled.
+    ///
+    /// Note: This is kept in its own stable memory partition.
+    blocks_to_replay: HeapOrStableMap<BlockIndex>,
+    /// The tip of the ledger the last time it was checked.  Not persisted across upgrades.
+    tip_of_chain: IgnoreEq<Option<BlockIndex>>,
+    /// The verification in progress or, if there is none, the latest verification.
+    ///
+    /// Note: This is saved in its own stable memory partition on upgrade.
+    verification: IgnoreEq<Option<Verification>>,
 }
 
 /// A wrapper around a value that returns true for `PartialEq` and `Eq` equality checks, regardless of the value.
@@ -80,21 +190,33 @@ impl fmt::Debug for AccountsStore {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "AccountsStore{{accounts_db: {:?}, hardware_wallets_and_sub_accounts: HashMap[{:?}], pending_transactions: HashMap[{:?}], block_height_synced_up_to: {:?}, multi_part_transactions_processor: {:?}, accounts_db_stats: {:?}, last_ledger_sync_timestamp_nanos: {:?}, neurons_topped_up_count: {:?}}}",
+            "AccountsStore{{accounts_db: {:?}, legacy_links: {:?}, pending_transactions: HashMap[{:?}], block_height_synced_up_to: {:?}, resyncing_from_first_block: {:?}, multi_part_transactions_processor: {:?}, accounts_db_stats: {:?}, last_ledger_sync_timestamp_nanos: {:?}, neurons_topped_up_count: {:?}, neuron_accounts: {:?}, account_transfer_offers: {:?}, transaction_index: {:?}, operation_log: {:?}, processed_blocks: {:?}, processed_blocks_floor: {:?}, blocks_to_replay: {:?}, tip_of_chain: {:?}, verification: {:?}}}",
             self.accounts_db,
-            self.hardware_wallets_and_sub_accounts.len(),
+            self.legacy_links,
             self.pending_transactions.len(),
             self.block_height_synced_up_to,
+            self.resyncing_from_first_block,
             self.multi_part_transactions_processor,
             self.accounts_db_stats,
             self.last_ledger_sync_timestamp_nanos,
             self.neurons_topped_up_count,
+            self.neuron_accounts,
+            self.account_transfer_offers,
+            self.transaction_index,
+            self.operation_log,
+            self.processed_blocks,
+            self.processed_blocks_floor,
+            self.blocks_to_replay,
+            self.tip_of_chain.0,
+            self.verification.0,
         )
     }
 }
 
+/// Account changes are counted by the verification in progress, if any, before they are saved.
 impl AccountsDbTrait for AccountsStore {
     fn db_insert_account(&mut self, account_key: &[u8], account: Account) {
+        self.count_account_change_for_verification(account_key, Some(&account));
         self.accounts_db.db_insert_account(account_key, account);
     }
     fn db_contains_account(&self, account_key: &[u8]) -> bool {
@@ -104,8 +226,18 @@ impl AccountsDbTrait for AccountsStore {
         self.accounts_db.db_get_account(account_key)
     }
     fn db_remove_account(&mut self, account_key: &[u8]) {
+        self.count_account_change_for_verification(account_key, None);
         self.accounts_db.db_remove_account(account_key);
     }
+    fn db_get_linked_account_keys(&self, linked_key: &[u8]) -> Vec<Vec<u8>> {
+        self.accounts_db.db_get_linked_account_keys(linked_key)
+    }
+    fn db_link_entries(&self, start: Vec<u8>) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+        self.accounts_db.db_link_entries(start)
+    }
+    fn db_remove_link_entry(&mut self, entry: &[u8]) {
+        self.accounts_db.db_remove_link_entry(entry);
+    }
     fn db_accounts_len(&self) -> u64 {
         self.accounts_db.db_accounts_len()
     }
@@ -133,14 +265,41 @@ pub struct AccountsDbStats {
 }
 
 /// An abstraction over sub-accounts and hardware wallets.
-#[derive(CandidType, Deserialize, Debug, Eq, PartialEq)]
+///
+/// Note: Releases that predate the index in the accounts database kept these links on the heap or
+/// in their own stable memory partition.  See `AccountsStore::legacy_links`.
+#[derive(CandidType, Clone, Deserialize, Debug, Eq, PartialEq)]
 enum AccountWrapper {
     SubAccount(AccountIdentifier, u8),      // Account Identifier + Sub Account Identifier
     HardwareWallet(Vec<AccountIdentifier>), // Vec of Account Identifiers since a hardware wallet could theoretically be shared between multiple accounts
 }
 
+impl AccountWrapper {
+    /// The keys of the accounts that the sub-account or hardware wallet belongs to.
+    fn account_keys(&self) -> Vec<Vec<u8>> {
+        match self {
+            AccountWrapper::SubAccount(account_identifier, _) => vec![account_identifier.to_vec()],
+            AccountWrapper::HardwareWallet(account_identifiers) => {
+                account_identifiers.iter().map(AccountIdentifier::to_vec).collect()
+            }
+        }
+    }
+}
+
+impl Storable for AccountWrapper {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        candid::encode_one(self)
+            .expect("Failed to serialize sub-account or hardware wallet link")
+            .into()
+    }
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to parse sub-account or hardware wallet link from store.")
+    }
+}
+
 /// A user's account.
-#[derive(CandidType, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[derive(CandidType, Deserialize, Serialize, Debug, Eq, PartialEq, Clone)]
 pub struct Account {
     /// The user principal.
     ///
@@ -151,6 +310,21 @@ pub struct Account {
     hardware_wallet_accounts: Vec<NamedHardwareWalletAccount>,
     canisters: Vec<NamedCanister>,
     imported_tokens: Option<ImportedTokens>,
+    address_book: Option<AddressBook>,
+    /// Sub-accounts that the user has removed.
+    ///
+    /// Note: The subaccount index of a removed sub-account is never reused for a new sub-account.
+    /// If it were, any funds remaining in the removed sub-account would reappear under the name of
+    /// a new sub-account.  The removed sub-account may be restored instead.
+    archived_sub_accounts: Option<HashMap<u8, NamedSubAccount>>,
+    /// The names of the user's canister groups, in the order chosen by the user.
+    canister_groups: Option<Vec<String>>,
+    /// Incremented every time the account is modified, so that clients can detect concurrent edits.
+    ///
+    /// Note: Accounts that have not been modified since this field was introduced have no version,
+    /// which is equivalent to version 0.
+    version: Option<u64>,
+    preferences: Option<Preferences>,
     // default_account_transactions: Do not reuse this field. There are still accounts in stable memor with this unused field.
 }
 
@@ -164,24 +338,60 @@ impl Storable for Account {
     }
 }
 
-#[derive(CandidType, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[derive(CandidType, Deserialize, Serialize, Debug, Eq, PartialEq, Clone)]
 struct NamedSubAccount {
     name: String,
     account_identifier: AccountIdentifier,
     // transactions: Do not reuse this field. There are still accounts in stable memory with this unused field.
 }
 
-#[derive(CandidType, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[derive(CandidType, Deserialize, Serialize, Debug, Eq, PartialEq, Clone)]
 struct NamedHardwareWalletAccount {
     name: String,
     principal: PrincipalId,
     // transactions: Do not reuse this field. There are still accounts in stable memor with this unused field.
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
 pub struct NamedCanister {
     name: String,
     canister_id: CanisterId,
+    metadata: Option<CanisterMetadata>,
+    /// The name of the canister group that the canister belongs to, if any.
+    group: Option<String>,
+}
+
+/// Information that a user keeps about one of their canisters.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CanisterMetadata {
+    tags: Vec<String>,
+    note: Option<String>,
+    /// The cycles balance below which the user would like to be warned.
+    low_cycles_threshold: Option<u64>,
+}
+
+impl CanisterMetadata {
+    /// Checks that the metadata is within the limits we are prepared to store.
+    fn validate(&self) -> Result<(), UpdateCanisterMetadataResponse> {
+        const TAG_MAX_LENGTH: usize = 24;
+        const NOTE_MAX_LENGTH: usize = 500;
+
+        if self.tags.len() > MAX_CANISTER_TAGS as usize {
+            Err(UpdateCanisterMetadataResponse::TooManyTags {
+                limit: MAX_CANISTER_TAGS,
+            })
+        } else if self.tags.iter().any(|tag| tag.is_empty() || tag.len() > TAG_MAX_LENGTH) {
+            Err(UpdateCanisterMetadataResponse::InvalidTag)
+        } else if self.note.as_ref().is_some_and(|note| note.len() > NOTE_MAX_LENGTH) {
+            Err(UpdateCanisterMetadataResponse::NoteTooLong)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.note.is_none() && self.low_cycles_threshold.is_none()
+    }
 }
 
 impl NamedCanister {
@@ -213,13 +423,44 @@ impl PartialOrd for NamedCanister {
     }
 }
 
-#[derive(CandidType, Clone, Copy, Default, Deserialize, Debug, Eq, PartialEq)]
+#[derive(CandidType, Clone, Default, Deserialize, Serialize, Debug, Eq, PartialEq)]
 pub struct ImportedToken {
     ledger_canister_id: PrincipalId,
     index_canister_id: Option<PrincipalId>,
+    settings: Option<ImportedTokenSettings>,
+}
+
+/// How the user would like an imported token to be displayed.
+#[derive(CandidType, Clone, Default, Deserialize, Serialize, Debug, Eq, PartialEq)]
+pub struct ImportedTokenSettings {
+    /// The position of the token in the user's token list.  Tokens without a position come last.
+    display_order: Option<u32>,
+    hidden: bool,
+    /// A name chosen by the user, to be shown instead of the token's own name.
+    label: Option<String>,
+    /// Pinned tokens are listed before all other tokens.
+    pinned: bool,
 }
 
-#[derive(CandidType, Clone, Default, Deserialize, Debug, Eq, PartialEq)]
+impl ImportedToken {
+    /// A value used to decide how `ImportedToken`s are sorted: pinned tokens first, then by display order.
+    fn sorting_key(&self) -> (bool, u32) {
+        let settings = self.settings.as_ref();
+        (
+            !settings.is_some_and(|settings| settings.pinned),
+            settings.and_then(|settings| settings.display_order).unwrap_or(u32::MAX),
+        )
+    }
+
+    fn has_valid_label(&self) -> bool {
+        self.settings
+            .as_ref()
+            .and_then(|settings| settings.label.as_ref())
+            .map_or(true, |label| AccountsStore::validate_account_name(label))
+    }
+}
+
+#[derive(CandidType, Clone, Default, Deserialize, Serialize, Debug, Eq, PartialEq)]
 pub struct ImportedTokens {
     imported_tokens: Vec<ImportedToken>,
 }
@@ -229,6 +470,31 @@ pub enum SetImportedTokensResponse {
     Ok,
     AccountNotFound,
     TooManyImportedTokens { limit: i32 },
+    LabelTooLong,
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum AddImportedTokenResponse {
+    Ok,
+    AccountNotFound,
+    TooManyImportedTokens { limit: i32 },
+    LabelTooLong,
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RemoveImportedTokenRequest {
+    ledger_canister_id: PrincipalId,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum RemoveImportedTokenResponse {
+    Ok,
+    AccountNotFound,
+    TokenNotFound,
+    Conflict { current_version: u64 },
 }
 
 #[derive(CandidType, Debug, PartialEq)]
@@ -237,6 +503,205 @@ pub enum GetImportedTokensResponse {
     AccountNotFound,
 }
 
+/// An ICRC-1 account, as defined in the ICRC-1 standard.
+#[derive(CandidType, Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
+pub struct Icrc1Account {
+    owner: PrincipalId,
+    subaccount: Option<Vec<u8>>,
+}
+
+/// A destination that a user can save in their address book.
+#[derive(CandidType, Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
+pub enum AddressBookAddress {
+    Icp(AccountIdentifier),
+    Icrc1(Icrc1Account),
+}
+
+impl AddressBookAddress {
+    /// The length of a valid ICRC-1 subaccount.
+    const SUBACCOUNT_LENGTH: usize = 32;
+
+    /// Checks that the address is well formed.
+    ///
+    /// Note: `AccountIdentifier`s are validated when they are deserialized.
+    fn is_valid(&self) -> bool {
+        match self {
+            AddressBookAddress::Icp(_) => true,
+            AddressBookAddress::Icrc1(Icrc1Account { subaccount, .. }) => subaccount
+                .as_ref()
+                .map_or(true, |subaccount| subaccount.len() == Self::SUBACCOUNT_LENGTH),
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
+pub struct AddressBookEntry {
+    name: String,
+    address: AddressBookAddress,
+}
+
+#[derive(CandidType, Clone, Default, Deserialize, Serialize, Debug, Eq, PartialEq)]
+pub struct AddressBook {
+    entries: Vec<AddressBookEntry>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AddAddressBookEntryRequest {
+    name: String,
+    address: AddressBookAddress,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum AddAddressBookEntryResponse {
+    Ok,
+    AccountNotFound,
+    NameTooLong,
+    NameAlreadyTaken,
+    InvalidSubaccount,
+    TooManyEntries { limit: i32 },
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RenameAddressBookEntryRequest {
+    name: String,
+    new_name: String,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum RenameAddressBookEntryResponse {
+    Ok,
+    AccountNotFound,
+    EntryNotFound,
+    NameTooLong,
+    NameAlreadyTaken,
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RemoveAddressBookEntryRequest {
+    name: String,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum RemoveAddressBookEntryResponse {
+    Ok,
+    AccountNotFound,
+    EntryNotFound,
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum GetAddressBookResponse {
+    Ok(AddressBook),
+    AccountNotFound,
+}
+
+/// User interface preferences, such as hidden tokens, the default sub-account, the neuron sort order
+/// and the language.
+///
+/// The backend does not interpret the preferences; the frontend defines the format of `data` and
+/// uses `schema_version` to migrate preferences saved by older versions of the frontend.
+#[derive(CandidType, Clone, Default, Deserialize, Serialize, Debug, Eq, PartialEq)]
+pub struct Preferences {
+    schema_version: u32,
+    data: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetPreferencesRequest {
+    preferences: Preferences,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum SetPreferencesResponse {
+    Ok,
+    AccountNotFound,
+    PreferencesTooLarge { limit: i32 },
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum GetPreferencesResponse {
+    Ok(Preferences),
+    AccountNotFound,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum EnableTransactionIndexResponse {
+    Ok,
+    AccountNotFound,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum DisableTransactionIndexResponse {
+    Ok,
+    AccountNotFound,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetIndexedTransactionsRequest {
+    /// Only blocks below this height are returned.  Use the `next` cursor of the previous page, or
+    /// `None` to get the most recent blocks.
+    before: Option<BlockIndex>,
+    limit: u32,
+}
+
+/// A page of indexed blocks, newest first.
+#[derive(CandidType, Debug, PartialEq)]
+pub struct IndexedTransactionsPage {
+    block_heights: Vec<BlockIndex>,
+    /// The cursor for the next, older, page, if there is one.
+    next: Option<BlockIndex>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum GetIndexedTransactionsResponse {
+    Ok(IndexedTransactionsPage),
+    AccountNotFound,
+    NotEnabled,
+    /// The limit must be at least 1.  Larger limits than the maximum page size are reduced to it.
+    InvalidLimit,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum ResyncFromResponse {
+    Ok,
+    /// Ledger sync has not started yet, so there is nothing to rewind.
+    NotSynced,
+    /// Blocks below the given height are no longer recorded as processed, so sync cannot be
+    /// rewound to them.
+    BlockHeightTooLow {
+        lowest_block_height: BlockIndex,
+    },
+    /// Sync can only be rewound to a block that has been synced, or to the next block.
+    InvalidBlockHeight {
+        block_height_synced_up_to: BlockIndex,
+    },
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum RollbackMigrationResponse {
+    Ok,
+    NoMigrationInProgress,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VerifyStateRequest {
+    /// Whether to repair the discrepancies found, rather than only reporting them.
+    pub repair: bool,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum VerifyStateResponse {
+    Ok,
+    VerificationInProgress,
+}
+
 #[derive(Copy, Clone, CandidType, Deserialize, Debug, Eq, PartialEq)]
 pub enum TransactionType {
     Burn,
@@ -246,6 +711,9 @@ pub enum TransactionType {
     TransferFrom,
     StakeNeuronNotification,
     CreateCanister,
+    StakeNeuron,
+    TopUpNeuron,
+    TopUpCanister(CanisterId),
 }
 
 #[derive(Clone, CandidType, Deserialize, Debug, Eq, PartialEq)]
@@ -256,18 +724,32 @@ pub struct NeuronDetails {
     neuron_id: Option<NeuronId>,
 }
 
+impl Storable for NeuronDetails {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        candid::encode_one(self)
+            .expect("Failed to serialize neuron details")
+            .into()
+    }
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to parse neuron details from store.")
+    }
+}
+
 #[derive(CandidType)]
 pub enum CreateSubAccountResponse {
     Ok(SubAccountDetails),
     AccountNotFound,
     SubAccountLimitExceeded,
     NameTooLong,
+    Conflict { current_version: u64 },
 }
 
 #[derive(CandidType, Deserialize)]
 pub struct RenameSubAccountRequest {
     account_identifier: AccountIdentifier,
     new_name: String,
+    expected_version: Option<u64>,
 }
 
 #[derive(CandidType)]
@@ -276,91 +758,390 @@ pub enum RenameSubAccountResponse {
     AccountNotFound,
     SubAccountNotFound,
     NameTooLong,
+    Conflict { current_version: u64 },
 }
 
 #[derive(CandidType, Deserialize)]
-pub struct RegisterHardwareWalletRequest {
-    name: String,
-    principal: PrincipalId,
+pub struct RemoveSubAccountRequest {
+    account_identifier: AccountIdentifier,
+    expected_version: Option<u64>,
 }
 
-#[derive(CandidType)]
-pub enum RegisterHardwareWalletResponse {
+#[derive(CandidType, Debug, PartialEq)]
+pub enum RemoveSubAccountResponse {
     Ok,
     AccountNotFound,
-    HardwareWalletAlreadyRegistered,
-    HardwareWalletLimitExceeded,
-    NameTooLong,
-}
-
-#[derive(CandidType)]
-pub struct AccountDetails {
-    pub principal: PrincipalId,
-    pub account_identifier: AccountIdentifier,
-    pub sub_accounts: Vec<SubAccountDetails>,
-    pub hardware_wallet_accounts: Vec<HardwareWalletAccountDetails>,
+    SubAccountNotFound,
+    Conflict { current_version: u64 },
 }
 
-#[derive(CandidType)]
-pub struct SubAccountDetails {
-    name: String,
-    sub_account: Subaccount,
+#[derive(CandidType, Deserialize)]
+pub struct RestoreSubAccountRequest {
     account_identifier: AccountIdentifier,
+    expected_version: Option<u64>,
 }
 
-#[derive(CandidType)]
-pub struct HardwareWalletAccountDetails {
-    pub name: String,
-    pub principal: PrincipalId,
-    pub account_identifier: AccountIdentifier,
+#[derive(CandidType, Debug, PartialEq)]
+pub enum RestoreSubAccountResponse {
+    Ok,
+    AccountNotFound,
+    SubAccountNotFound,
+    Conflict { current_version: u64 },
 }
 
 #[derive(CandidType, Deserialize)]
-pub struct AttachCanisterRequest {
+pub struct RegisterHardwareWalletRequest {
     name: String,
-    canister_id: CanisterId,
+    principal: PrincipalId,
+    expected_version: Option<u64>,
 }
 
 #[derive(CandidType)]
-pub enum AttachCanisterResponse {
+pub enum RegisterHardwareWalletResponse {
     Ok,
-    CanisterLimitExceeded,
-    CanisterAlreadyAttached,
-    NameAlreadyTaken,
-    NameTooLong,
     AccountNotFound,
+    HardwareWalletAlreadyRegistered,
+    HardwareWalletLimitExceeded,
+    NameTooLong,
+    Conflict { current_version: u64 },
 }
 
 #[derive(CandidType, Deserialize)]
-pub struct RenameCanisterRequest {
-    name: String,
-    canister_id: CanisterId,
+pub struct UnregisterHardwareWalletRequest {
+    principal: PrincipalId,
+    expected_version: Option<u64>,
 }
 
-#[derive(CandidType)]
-pub enum RenameCanisterResponse {
+#[derive(CandidType, Debug, PartialEq)]
+pub enum UnregisterHardwareWalletResponse {
     Ok,
-    NameAlreadyTaken,
-    NameTooLong,
     AccountNotFound,
-    CanisterNotFound,
+    HardwareWalletNotFound,
+    Conflict { current_version: u64 },
 }
 
-#[derive(CandidType, Deserialize)]
-pub struct DetachCanisterRequest {
-    canister_id: CanisterId,
+/// A named sub-account in an account export.
+///
+/// Sub-accounts are identified by their subaccount index rather than by their account identifier, as
+/// the account identifier depends on the principal that owns the account.
+#[derive(CandidType, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct ExportedSubAccount {
+    name: String,
+    sub_account_id: u8,
 }
 
-#[derive(CandidType)]
-pub enum DetachCanisterResponse {
-    Ok,
-    CanisterNotFound,
-    AccountNotFound,
+#[derive(CandidType, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct ExportedHardwareWallet {
+    name: String,
+    principal: PrincipalId,
 }
 
-impl AccountsStore {
-    /// Determines whether a migration is being performed.
-    #[must_use]
+/// A user's full account record, in a form that can be imported into another account.
+#[derive(CandidType, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct AccountExport {
+    /// The version of the export format.  Incremented whenever the format changes.
+    version: u32,
+    /// The principal of the account that was exported.
+    principal: PrincipalId,
+    sub_accounts: Vec<ExportedSubAccount>,
+    archived_sub_accounts: Vec<ExportedSubAccount>,
+    hardware_wallet_accounts: Vec<ExportedHardwareWallet>,
+    canisters: Vec<NamedCanister>,
+    canister_groups: Vec<String>,
+    imported_tokens: Vec<ImportedToken>,
+    address_book: Vec<AddressBookEntry>,
+    preferences: Option<Preferences>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ExportAccountRequest {
+    /// The number of canisters to skip.  Use the `next_canisters_offset` of the previous page, or 0
+    /// for the first page.
+    canisters_offset: u32,
+}
+
+/// A page of an account export.
+///
+/// Every page has all of the account apart from its canisters, of which it has those from the
+/// requested offset.  The canisters of all the pages together make up the canisters of the export.
+#[derive(CandidType, Debug, PartialEq)]
+pub struct ExportAccountPage {
+    export: AccountExport,
+    /// The offset of the next page of canisters, or `None` if this is the last page.
+    next_canisters_offset: Option<u32>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum ExportAccountResponse {
+    Ok(ExportAccountPage),
+    AccountNotFound,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum ImportAccountResponse {
+    Ok,
+    AccountNotFound,
+    AccountNotEmpty,
+    UnsupportedVersion { version: u32 },
+    InvalidExport,
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DeleteAccountRequest {
+    /// The account identifier of the caller's main account.
+    confirmation: AccountIdentifier,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum DeleteAccountResponse {
+    Ok,
+    AccountNotFound,
+    ConfirmationMismatch,
+    Conflict { current_version: u64 },
+}
+
+/// An offer, made by the owner of an account, to move the account to a new principal.
+#[derive(CandidType, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct AccountTransferOffer {
+    new_principal: PrincipalId,
+    expires_at_timestamp_nanos: u64,
+}
+
+impl Storable for AccountTransferOffer {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        candid::encode_one(self)
+            .expect("Failed to serialize account transfer offer")
+            .into()
+    }
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to parse account transfer offer from store.")
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ProposeAccountTransferRequest {
+    new_principal: PrincipalId,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum ProposeAccountTransferResponse {
+    Ok { expires_at_timestamp_nanos: u64 },
+    AccountNotFound,
+    SamePrincipal,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum CancelAccountTransferResponse {
+    Ok,
+    OfferNotFound,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AcceptAccountTransferRequest {
+    old_principal: PrincipalId,
+    /// The expected version of the caller's account, which the transferred account replaces.
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum AcceptAccountTransferResponse {
+    Ok,
+    OfferNotFound,
+    OfferExpired,
+    AccountNotFound,
+    AccountNotEmpty,
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType)]
+pub struct AccountDetails {
+    pub principal: PrincipalId,
+    pub account_identifier: AccountIdentifier,
+    pub sub_accounts: Vec<SubAccountDetails>,
+    pub hardware_wallet_accounts: Vec<HardwareWalletAccountDetails>,
+    pub version: u64,
+    /// Sub-accounts that the user has removed, which may be restored with `restore_sub_account`.
+    pub archived_sub_accounts: Vec<SubAccountDetails>,
+}
+
+#[derive(CandidType)]
+pub struct SubAccountDetails {
+    name: String,
+    sub_account: Subaccount,
+    account_identifier: AccountIdentifier,
+}
+
+#[derive(CandidType)]
+pub struct HardwareWalletAccountDetails {
+    pub name: String,
+    pub principal: PrincipalId,
+    pub account_identifier: AccountIdentifier,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AttachCanisterRequest {
+    name: String,
+    canister_id: CanisterId,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType)]
+pub enum AttachCanisterResponse {
+    Ok,
+    CanisterLimitExceeded,
+    CanisterAlreadyAttached,
+    NameAlreadyTaken,
+    NameTooLong,
+    AccountNotFound,
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RenameCanisterRequest {
+    name: String,
+    canister_id: CanisterId,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType)]
+pub enum RenameCanisterResponse {
+    Ok,
+    NameAlreadyTaken,
+    NameTooLong,
+    AccountNotFound,
+    CanisterNotFound,
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UpdateCanisterMetadataRequest {
+    canister_id: CanisterId,
+    metadata: CanisterMetadata,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum UpdateCanisterMetadataResponse {
+    Ok,
+    AccountNotFound,
+    CanisterNotFound,
+    TooManyTags { limit: i32 },
+    InvalidTag,
+    NoteTooLong,
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum GetCanisterGroupsResponse {
+    Ok(Vec<String>),
+    AccountNotFound,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CreateCanisterGroupRequest {
+    name: String,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum CreateCanisterGroupResponse {
+    Ok,
+    AccountNotFound,
+    NameTooLong,
+    NameAlreadyTaken,
+    GroupLimitExceeded { limit: i32 },
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RenameCanisterGroupRequest {
+    name: String,
+    new_name: String,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum RenameCanisterGroupResponse {
+    Ok,
+    AccountNotFound,
+    GroupNotFound,
+    NameTooLong,
+    NameAlreadyTaken,
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DeleteCanisterGroupRequest {
+    name: String,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum DeleteCanisterGroupResponse {
+    Ok,
+    AccountNotFound,
+    GroupNotFound,
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ReorderCanisterGroupsRequest {
+    names: Vec<String>,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum ReorderCanisterGroupsResponse {
+    Ok,
+    AccountNotFound,
+    GroupsMismatch,
+    Conflict { current_version: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetCanisterGroupRequest {
+    canister_id: CanisterId,
+    group: Option<String>,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+pub enum SetCanisterGroupResponse {
+    Ok,
+    AccountNotFound,
+    CanisterNotFound,
+    GroupNotFound,
+    Conflict { current_version: u64 },
+}
+
+/// Filters for `get_canisters`.  Only canisters that match every filter given are returned.
+#[derive(CandidType, Default, Deserialize)]
+pub struct GetCanistersRequest {
+    tag: Option<String>,
+    /// The name of a canister group.
+    group: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DetachCanisterRequest {
+    canister_id: CanisterId,
+    expected_version: Option<u64>,
+}
+
+#[derive(CandidType)]
+pub enum DetachCanisterResponse {
+    Ok,
+    CanisterNotFound,
+    AccountNotFound,
+    Conflict { current_version: u64 },
+}
+
+impl AccountsStore {
+    /// Determines whether a migration is being performed.
+    #[must_use]
     pub fn migration_in_progress(&self) -> bool {
         self.accounts_db.migration_in_progress()
     }
@@ -369,6 +1150,77 @@ impl AccountsStore {
     /// Note: This is a pass-through to the underlying `AccountsDb::step_migration`.  Please see that for further details.
     pub fn step_migration(&mut self, step_size: u32) {
         self.accounts_db.step_migration(step_size);
+        self.discard_legacy_links_once_indexed();
+    }
+    /// Determines whether the index of sub-accounts and hardware wallets is being built.
+    ///
+    /// The index is built by `step_migration`.
+    #[must_use]
+    pub fn links_index_in_progress(&self) -> bool {
+        self.accounts_db.links_index_in_progress()
+    }
+    /// Discards the links kept by earlier releases, once the index in the accounts database
+    /// covers every account.
+    fn discard_legacy_links_once_indexed(&mut self) {
+        if !self.legacy_links.is_empty() && !self.accounts_db.links_index_in_progress() {
+            println!(
+                "Discarding {} legacy links to sub-accounts and hardware wallets.",
+                self.legacy_links.len()
+            );
+            self.legacy_links.clear();
+        }
+    }
+    /// The schema in which accounts are currently stored.
+    #[must_use]
+    pub fn schema_label(&self) -> SchemaLabel {
+        self.accounts_db.schema_label()
+    }
+    /// Starts migrating the accounts to the given, empty, database.
+    ///
+    /// Note: This is a pass-through to the underlying `AccountsDb::start_migration`.  Please see that for further details.
+    ///
+    /// # Errors
+    /// - If the migration cannot be started, e.g. because another migration is in progress.
+    pub fn start_migration(&mut self, db: AccountsDb) -> Result<(), MigrationError> {
+        self.accounts_db.start_migration(db)
+    }
+    /// Abandons a migration that has not been finalized, keeping the accounts in the current database.
+    pub fn rollback_migration(&mut self) -> RollbackMigrationResponse {
+        match self.accounts_db.rollback_migration() {
+            Ok(()) => RollbackMigrationResponse::Ok,
+            Err(_) => RollbackMigrationResponse::NoMigrationInProgress,
+        }
+    }
+    /// Progress reports for recent migrations and the migration in progress, if any.
+    #[must_use]
+    pub fn migration_progress(&self) -> Vec<MigrationProgress> {
+        self.accounts_db.migration_progress()
+    }
+    /// The schema in which accounts were stored when the canister was last upgraded, before any
+    /// migrations completed since.
+    #[must_use]
+    pub fn loaded_schema_label(&self) -> SchemaLabel {
+        self.accounts_db.loaded_schema_label()
+    }
+    /// The schema that the migration in progress, and any registered migrations following it, lead to.
+    #[must_use]
+    pub fn migration_target(&self) -> Option<SchemaLabel> {
+        self.accounts_db.migration_target()
+    }
+    /// Sets the schema that migrations are to lead to.
+    pub fn set_migration_target(&mut self, target: Option<SchemaLabel>) {
+        self.accounts_db.set_migration_target(target);
+    }
+    /// The state of the migrations, to be kept across upgrades.
+    #[must_use]
+    pub fn saved_migrations(&self) -> SavedMigrations {
+        self.accounts_db.saved_migrations()
+    }
+    /// Restores the state of the migrations after an upgrade.
+    ///
+    /// Note: This is a pass-through to the underlying `AccountsDb::restore_migrations`.  Please see that for further details.
+    pub fn restore_migrations(&mut self, saved: SavedMigrations, db: Option<AccountsDb>) {
+        self.accounts_db.restore_migrations(saved, db);
     }
     #[must_use]
     pub fn get_account(&self, caller: PrincipalId) -> Option<AccountDetails> {
@@ -378,16 +1230,22 @@ impl AccountsStore {
             // which will allow us to set the principal.
             let principal = account.principal?;
 
-            let sub_accounts = account
-                .sub_accounts
-                .iter()
-                .sorted_unstable_by_key(|(_, sub_account)| sub_account.name.clone())
-                .map(|(id, sa)| SubAccountDetails {
-                    name: sa.name.clone(),
-                    sub_account: convert_byte_to_sub_account(*id),
-                    account_identifier: sa.account_identifier,
-                })
-                .collect();
+            let sub_account_details = |sub_accounts: &HashMap<u8, NamedSubAccount>| {
+                sub_accounts
+                    .iter()
+                    .sorted_unstable_by_key(|(_, sub_account)| sub_account.name.clone())
+                    .map(|(id, sa)| SubAccountDetails {
+                        name: sa.name.clone(),
+                        sub_account: convert_byte_to_sub_account(*id),
+                        account_identifier: sa.account_identifier,
+                    })
+                    .collect()
+            };
+            let sub_accounts = sub_account_details(&account.sub_accounts);
+            let archived_sub_accounts = account
+                .archived_sub_accounts
+                .as_ref()
+                .map_or_else(Vec::new, sub_account_details);
 
             let hardware_wallet_accounts = account
                 .hardware_wallet_accounts
@@ -404,6 +1262,8 @@ impl AccountsStore {
                 account_identifier,
                 sub_accounts,
                 hardware_wallet_accounts,
+                version: account.version(),
+                archived_sub_accounts,
             })
         } else {
             None
@@ -421,35 +1281,42 @@ impl AccountsStore {
                 // This is an old account that needs a one-off fix to set the principal and update the transactions.
                 let mut account = account.clone();
                 account.principal = Some(caller);
-                self.accounts_db
-                    .db_insert_account(&account_identifier.to_vec(), account);
+                self.db_insert_account(&account_identifier.to_vec(), account);
             }
             false
         } else {
             let new_account = Account::new(caller, account_identifier);
-            self.accounts_db
-                .db_insert_account(&account_identifier.to_vec(), new_account);
+            self.db_insert_account(&account_identifier.to_vec(), new_account);
 
             true
         }
     }
 
     /// Creates a sub-account for the given user.
-    pub fn create_sub_account(&mut self, caller: PrincipalId, sub_account_name: String) -> CreateSubAccountResponse {
+    pub fn create_sub_account(
+        &mut self,
+        caller: PrincipalId,
+        sub_account_name: String,
+        expected_version: Option<u64>,
+    ) -> CreateSubAccountResponse {
         self.assert_account_limit();
         let account_identifier = AccountIdentifier::from(caller);
 
         if !Self::validate_account_name(&sub_account_name) {
             CreateSubAccountResponse::NameTooLong
         } else if let Some(mut account) = self.accounts_db.db_get_account(&account_identifier.to_vec()) {
-            let response = if let Some(sub_account_id) = (1..u8::MAX).find(|i| !account.sub_accounts.contains_key(i)) {
+            if let Err(current_version) = account.check_version(expected_version) {
+                return CreateSubAccountResponse::Conflict { current_version };
+            }
+            let response = if let Some(sub_account_id) =
+                (1..u8::MAX).find(|i| !account.sub_accounts.contains_key(i) && !account.is_archived_sub_account(*i))
+            {
                 let sub_account = convert_byte_to_sub_account(sub_account_id);
                 let sub_account_identifier = AccountIdentifier::new(caller, Some(sub_account));
                 let named_sub_account = NamedSubAccount::new(sub_account_name.clone(), sub_account_identifier);
 
                 account.sub_accounts.insert(sub_account_id, named_sub_account);
-                self.accounts_db
-                    .db_insert_account(&account_identifier.to_vec(), account);
+                self.update_account(&account_identifier.to_vec(), account);
 
                 CreateSubAccountResponse::Ok(SubAccountDetails {
                     name: sub_account_name,
@@ -460,17 +1327,7 @@ impl AccountsStore {
                 CreateSubAccountResponse::SubAccountLimitExceeded
             };
 
-            if let CreateSubAccountResponse::Ok(SubAccountDetails {
-                name: _,
-                sub_account,
-                account_identifier: sub_account_identifier,
-            }) = response
-            {
-                let sub_account_id = sub_account.0[31];
-                self.hardware_wallets_and_sub_accounts.insert(
-                    sub_account_identifier,
-                    AccountWrapper::SubAccount(account_identifier, sub_account_id),
-                );
+            if let CreateSubAccountResponse::Ok(_) = response {
                 self.accounts_db_stats.sub_accounts_count += 1;
             }
 
@@ -490,13 +1347,16 @@ impl AccountsStore {
         if !Self::validate_account_name(&request.new_name) {
             RenameSubAccountResponse::NameTooLong
         } else if let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) {
+            if let Err(current_version) = account.check_version(request.expected_version) {
+                return RenameSubAccountResponse::Conflict { current_version };
+            }
             if let Some(sub_account) = account
                 .sub_accounts
                 .values_mut()
                 .find(|sub_account| sub_account.account_identifier == request.account_identifier)
             {
                 sub_account.name = request.new_name;
-                self.accounts_db.db_insert_account(&account_identifier, account);
+                self.update_account(&account_identifier, account);
                 RenameSubAccountResponse::Ok
             } else {
                 RenameSubAccountResponse::SubAccountNotFound
@@ -506,39 +1366,116 @@ impl AccountsStore {
         }
     }
 
-    pub fn register_hardware_wallet(
+    /// Removes a sub-account from the user's account.
+    ///
+    /// The sub-account is archived rather than deleted, so that its subaccount index is not
+    /// reused for a differently named sub-account.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn remove_sub_account(
         &mut self,
         caller: PrincipalId,
-        request: RegisterHardwareWalletRequest,
-    ) -> RegisterHardwareWalletResponse {
-        let account_identifier = AccountIdentifier::from(caller);
+        request: RemoveSubAccountRequest,
+    ) -> RemoveSubAccountResponse {
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return RemoveSubAccountResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return RemoveSubAccountResponse::Conflict { current_version };
+        }
+        let Some(sub_account_id) = account
+            .sub_accounts
+            .iter()
+            .find(|(_, sub_account)| sub_account.account_identifier == request.account_identifier)
+            .map(|(sub_account_id, _)| *sub_account_id)
+        else {
+            return RemoveSubAccountResponse::SubAccountNotFound;
+        };
 
-        if !Self::validate_account_name(&request.name) {
-            RegisterHardwareWalletResponse::NameTooLong
-        } else if let Some(mut account) = self.accounts_db.db_get_account(&account_identifier.to_vec()).clone() {
-            let hardware_wallet_account_identifier = AccountIdentifier::from(request.principal);
+        if let Some(sub_account) = account.sub_accounts.remove(&sub_account_id) {
+            account
+                .archived_sub_accounts
+                .get_or_insert_with(HashMap::new)
+                .insert(sub_account_id, sub_account);
+        }
+        self.update_account(&account_identifier, account);
 
-            if account.hardware_wallet_accounts.len() == (u8::MAX as usize) {
-                RegisterHardwareWalletResponse::HardwareWalletLimitExceeded
-            } else if account
-                .hardware_wallet_accounts
-                .iter()
-                .any(|hw| hw.principal == request.principal)
-            {
-                RegisterHardwareWalletResponse::HardwareWalletAlreadyRegistered
-            } else {
-                account.hardware_wallet_accounts.push(NamedHardwareWalletAccount {
-                    name: request.name,
-                    principal: request.principal,
+        self.accounts_db_stats.sub_accounts_count = self.accounts_db_stats.sub_accounts_count.saturating_sub(1);
+        RemoveSubAccountResponse::Ok
+    }
+
+    /// Restores a removed sub-account, with the name it had when it was removed.
+    ///
+    /// Removed sub-accounts keep their subaccount indices, so a user who has used every index can
+    /// get a sub-account back only by restoring one.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn restore_sub_account(
+        &mut self,
+        caller: PrincipalId,
+        request: RestoreSubAccountRequest,
+    ) -> RestoreSubAccountResponse {
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return RestoreSubAccountResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return RestoreSubAccountResponse::Conflict { current_version };
+        }
+        let Some(archived) = account.archived_sub_accounts.as_mut() else {
+            return RestoreSubAccountResponse::SubAccountNotFound;
+        };
+        let Some(sub_account_id) = archived
+            .iter()
+            .find(|(_, sub_account)| sub_account.account_identifier == request.account_identifier)
+            .map(|(sub_account_id, _)| *sub_account_id)
+        else {
+            return RestoreSubAccountResponse::SubAccountNotFound;
+        };
+
+        if let Some(sub_account) = archived.remove(&sub_account_id) {
+            if archived.is_empty() {
+                account.archived_sub_accounts = None;
+            }
+            account.sub_accounts.insert(sub_account_id, sub_account);
+        }
+        self.update_account(&account_identifier, account);
+
+        self.accounts_db_stats.sub_accounts_count += 1;
+        RestoreSubAccountResponse::Ok
+    }
+
+    pub fn register_hardware_wallet(
+        &mut self,
+        caller: PrincipalId,
+        request: RegisterHardwareWalletRequest,
+    ) -> RegisterHardwareWalletResponse {
+        let account_identifier = AccountIdentifier::from(caller);
+
+        if !Self::validate_account_name(&request.name) {
+            RegisterHardwareWalletResponse::NameTooLong
+        } else if let Some(mut account) = self.accounts_db.db_get_account(&account_identifier.to_vec()).clone() {
+            if let Err(current_version) = account.check_version(request.expected_version) {
+                return RegisterHardwareWalletResponse::Conflict { current_version };
+            }
+            if account.hardware_wallet_accounts.len() == (u8::MAX as usize) {
+                RegisterHardwareWalletResponse::HardwareWalletLimitExceeded
+            } else if account
+                .hardware_wallet_accounts
+                .iter()
+                .any(|hw| hw.principal == request.principal)
+            {
+                RegisterHardwareWalletResponse::HardwareWalletAlreadyRegistered
+            } else {
+                account.hardware_wallet_accounts.push(NamedHardwareWalletAccount {
+                    name: request.name,
+                    principal: request.principal,
                 });
                 account
                     .hardware_wallet_accounts
                     .sort_unstable_by_key(|hw| hw.name.clone());
-                self.accounts_db
-                    .db_insert_account(&account_identifier.to_vec(), account);
+                self.update_account(&account_identifier.to_vec(), account);
 
                 self.accounts_db_stats.hardware_wallet_accounts_count += 1;
-                self.link_hardware_wallet_to_account(account_identifier, hardware_wallet_account_identifier);
                 RegisterHardwareWalletResponse::Ok
             }
         } else {
@@ -546,28 +1483,79 @@ impl AccountsStore {
         }
     }
 
+    /// Unlinks a hardware wallet from the user's account.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn unregister_hardware_wallet(
+        &mut self,
+        caller: PrincipalId,
+        request: UnregisterHardwareWalletRequest,
+    ) -> UnregisterHardwareWalletResponse {
+        let account_identifier = AccountIdentifier::from(caller);
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier.to_vec()) else {
+            return UnregisterHardwareWalletResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return UnregisterHardwareWalletResponse::Conflict { current_version };
+        }
+        let Some(index) = account
+            .hardware_wallet_accounts
+            .iter()
+            .position(|hw| hw.principal == request.principal)
+        else {
+            return UnregisterHardwareWalletResponse::HardwareWalletNotFound;
+        };
+
+        account.hardware_wallet_accounts.remove(index);
+        self.update_account(&account_identifier.to_vec(), account);
+
+        self.accounts_db_stats.hardware_wallet_accounts_count =
+            self.accounts_db_stats.hardware_wallet_accounts_count.saturating_sub(1);
+        UnregisterHardwareWalletResponse::Ok
+    }
+
     pub fn maybe_process_transaction(
         &mut self,
         transfer: &Operation,
         memo: Memo,
         block_height: BlockIndex,
     ) -> Result<(), String> {
-        if let Some(block_height_synced_up_to) = self.get_block_height_synced_up_to() {
-            let expected_block_height = block_height_synced_up_to + 1;
-            if block_height != block_height_synced_up_to + 1 {
+        if let Some(expected_block_height) = self.next_block_height_to_sync() {
+            if block_height != expected_block_height {
                 return Err(format!(
                     "Expected block height {expected_block_height}. Got block height {block_height}",
                 ));
             }
         }
 
+        self.process_block(transfer, memo, block_height);
+        self.block_height_synced_up_to = Some(block_height);
+        self.resyncing_from_first_block = false;
+
+        Ok(())
+    }
+
+    /// Processes a block that has been synced already, for example one that could not be decoded
+    /// the first time around.  Unlike `maybe_process_transaction`, the block height does not need
+    /// to follow on from the blocks processed so far and `block_height_synced_up_to` is unchanged.
+    ///
+    /// Processing a block more than once has no further effect: blocks queued as multi-part
+    /// transactions are recorded in `processed_blocks` until they fall below `processed_blocks_floor`.
+    pub fn replay_transaction(&mut self, transfer: &Operation, memo: Memo, block_height: BlockIndex) {
+        self.process_block(transfer, memo, block_height);
+    }
+
+    fn process_block(&mut self, transfer: &Operation, memo: Memo, block_height: BlockIndex) {
+        if !self.transaction_index.is_empty() {
+            self.index_transaction(transfer, block_height);
+        }
+
         match *transfer {
             Burn { .. } | Mint { .. } | Approve { .. } => {}
             Transfer {
                 from,
                 to,
                 spender: _,
-                amount: _,
+                amount,
                 fee: _,
             } => {
                 let default_transaction_type = if matches!(transfer, Transfer { .. }) {
@@ -577,19 +1565,97 @@ impl AccountsStore {
                 };
 
                 if self.store_has_account(to) {
+                } else if let Some(neuron_details) = self.neuron_accounts.get(&to.to_vec()) {
+                    // Top-ups are refreshed whoever sends them, so that neurons topped up from an
+                    // exchange are also picked up.  Small amounts count only from the controller.
+                    let (controller, neuron_memo) = (neuron_details.principal, neuron_details.memo);
+                    let from_controller = self
+                        .main_account_identifiers(&from)
+                        .contains(&AccountIdentifier::from(controller));
+                    if from_controller || amount.get_e8s() >= MIN_NEURON_TOP_UP_E8S {
+                        self.process_transaction_type(
+                            TransactionType::TopUpNeuron,
+                            controller,
+                            neuron_memo,
+                            to,
+                            block_height,
+                        );
+                    }
                 } else if self.store_has_account(from) {
                     if let Some(principal) = self.try_get_principal(&from) {
                         let transaction_type =
-                            Self::get_transaction_type(from, to, memo, &principal, default_transaction_type);
-                        self.process_transaction_type(transaction_type, principal, block_height);
+                            self.get_transaction_type(from, to, memo, &principal, default_transaction_type);
+                        self.process_transaction_type(transaction_type, principal, memo, to, block_height);
                     }
                 }
             }
         }
+    }
 
-        self.block_height_synced_up_to = Some(block_height);
+    /// Records a block against every user, with the transaction index enabled, whose main account,
+    /// sub-accounts or hardware wallets the block touches.
+    fn index_transaction(&mut self, transfer: &Operation, block_height: BlockIndex) {
+        let touched_accounts = match *transfer {
+            Burn { from, .. } => vec![from],
+            Mint { to, .. } => vec![to],
+            Transfer { from, to, .. } => vec![from, to],
+            Approve { from, spender, .. } => vec![from, spender],
+        };
+        let owners: Vec<AccountIdentifier> = touched_accounts
+            .iter()
+            .flat_map(|account_identifier| self.main_account_identifiers(account_identifier))
+            .unique()
+            .collect();
+        for owner in owners {
+            self.transaction_index.record(&owner, block_height);
+        }
+    }
 
-        Ok(())
+    /// The main accounts that an account belongs to: the owner of a sub-account, or the accounts
+    /// that a hardware wallet is linked to, together with the account itself.
+    ///
+    /// Anyone can register any principal as their hardware wallet, so a principal that has an
+    /// account of its own may also be linked to other accounts.  Its own account always comes first.
+    fn main_account_identifiers(&self, account_identifier: &AccountIdentifier) -> Vec<AccountIdentifier> {
+        let account_key = account_identifier.to_vec();
+        let linked_account_keys = self.linked_account_keys(account_identifier);
+        let own_account = (linked_account_keys.is_empty() || self.accounts_db.db_contains_account(&account_key))
+            .then_some(*account_identifier);
+        own_account
+            .into_iter()
+            .chain(
+                linked_account_keys
+                    .iter()
+                    .filter_map(|account_key| AccountIdentifier::from_slice(account_key).ok()),
+            )
+            .collect()
+    }
+
+    /// The keys of the accounts that a sub-account or hardware wallet belongs to.
+    ///
+    /// While the index in the accounts database is being built, the links kept by earlier
+    /// releases are consulted as well.  Those are not updated as accounts change, so each is
+    /// checked against the account it names.
+    fn linked_account_keys(&self, account_identifier: &AccountIdentifier) -> Vec<Vec<u8>> {
+        let linked_key = account_identifier.to_vec();
+        let mut account_keys = self.accounts_db.db_get_linked_account_keys(&linked_key);
+        if !self.accounts_db.links_index_in_progress() {
+            return account_keys;
+        }
+        if let Some(legacy_link) = self.legacy_links.get(&linked_key) {
+            for account_key in legacy_link.account_keys() {
+                let is_linked = self.accounts_db.db_get_account(&account_key).is_some_and(|account| {
+                    account
+                        .linked_account_identifiers()
+                        .any(|linked| linked == *account_identifier)
+                });
+                if is_linked && !account_keys.contains(&account_key) {
+                    account_keys.push(account_key);
+                }
+            }
+            account_keys.sort();
+        }
+        account_keys
     }
 
     pub fn mark_ledger_sync_complete(&mut self) {
@@ -602,6 +1668,106 @@ impl AccountsStore {
         .unwrap_or_else(|_| unreachable!("Not impossible, but centuries in the future"));
     }
 
+    /// Rewinds ledger sync so that blocks from the given height onwards are fetched and processed
+    /// again.  Blocks that have been processed already have no further effect, so this can be used
+    /// to repair state after an incident.
+    ///
+    /// Sync cannot be rewound below `processed_blocks_floor`, as the blocks that were processed
+    /// there are no longer recorded.
+    pub fn resync_from(&mut self, block_height: BlockIndex) -> ResyncFromResponse {
+        let Some(block_height_synced_up_to) = self.block_height_synced_up_to else {
+            return ResyncFromResponse::NotSynced;
+        };
+        if self.resyncing_from_first_block || block_height > block_height_synced_up_to + 1 {
+            return ResyncFromResponse::InvalidBlockHeight {
+                block_height_synced_up_to,
+            };
+        }
+        if block_height < self.processed_blocks_floor {
+            return ResyncFromResponse::BlockHeightTooLow {
+                lowest_block_height: self.processed_blocks_floor,
+            };
+        }
+        match block_height.checked_sub(1) {
+            Some(previous_block_height) => self.block_height_synced_up_to = Some(previous_block_height),
+            None => self.resyncing_from_first_block = true,
+        }
+        ResyncFromResponse::Ok
+    }
+
+    /// The height of the next block for ledger sync to process, or `None` if ledger sync has not started.
+    #[must_use]
+    pub fn next_block_height_to_sync(&self) -> Option<BlockIndex> {
+        if self.resyncing_from_first_block {
+            Some(0)
+        } else {
+            self.block_height_synced_up_to.map(|block_height| block_height + 1)
+        }
+    }
+
+    /// Records the tip of the ledger, to measure how far ledger sync is behind.
+    pub fn set_tip_of_chain(&mut self, tip_of_chain: BlockIndex) {
+        self.tip_of_chain = IgnoreEq(Some(tip_of_chain));
+    }
+
+    /// Schedules a block that could not be decoded to be fetched and processed again later.
+    ///
+    /// At most `MAX_BLOCKS_TO_REPLAY` blocks are kept, so checking for duplicates is cheap.
+    pub fn schedule_block_replay(&mut self, block_height: BlockIndex) {
+        if self
+            .blocks_to_replay
+            .entries()
+            .iter()
+            .any(|(_, scheduled)| *scheduled == block_height)
+        {
+            return;
+        }
+        let sequence_number = self
+            .blocks_to_replay
+            .last_entry()
+            .map_or(0, |(key, _)| replay_sequence_number(&key) + 1);
+        self.blocks_to_replay
+            .insert(&sequence_number.to_be_bytes(), block_height);
+        while self.blocks_to_replay.len() > MAX_BLOCKS_TO_REPLAY {
+            self.take_block_to_replay();
+        }
+    }
+
+    /// Raises `processed_blocks_floor` to the lowest block that may still be processed again, that
+    /// is the start of the resync window or the lowest block to replay, and prunes the processed
+    /// blocks below it.
+    pub fn prune_processed_blocks(&mut self) {
+        let Some(next_block_height_to_sync) = self.next_block_height_to_sync() else {
+            return;
+        };
+        let lowest_block_to_replay = self
+            .blocks_to_replay
+            .entries()
+            .into_iter()
+            .map(|(_, block_height)| block_height)
+            .min();
+        let floor = next_block_height_to_sync
+            .saturating_sub(RESYNC_WINDOW_BLOCKS)
+            .min(lowest_block_to_replay.unwrap_or(BlockIndex::MAX));
+        self.processed_blocks_floor = self.processed_blocks_floor.max(floor);
+        let keys: Vec<Vec<u8>> = self
+            .processed_blocks
+            .range(0_u64.to_be_bytes().to_vec()..self.processed_blocks_floor.to_be_bytes().to_vec())
+            .take(MAX_PROCESSED_BLOCKS_PRUNED_PER_CALL)
+            .map(|(key, ())| key)
+            .collect();
+        for key in keys {
+            self.processed_blocks.remove(&key);
+        }
+    }
+
+    /// Takes the next block to be fetched and processed again, if any.
+    pub fn take_block_to_replay(&mut self) -> Option<BlockIndex> {
+        let (key, block_height) = self.blocks_to_replay.first_entry()?;
+        self.blocks_to_replay.remove(&key);
+        Some(block_height)
+    }
+
     /// Initializes the `block_height_synced_up_to` value.
     ///
     /// # Panics
@@ -629,6 +1795,9 @@ impl AccountsStore {
             let account_identifier = AccountIdentifier::from(caller).to_vec();
 
             if let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) {
+                if let Err(current_version) = account.check_version(request.expected_version) {
+                    return AttachCanisterResponse::Conflict { current_version };
+                }
                 let mut index_to_remove: Option<usize> = None;
                 for (index, c) in account.canisters.iter().enumerate() {
                     if !request.name.is_empty() && c.name == request.name {
@@ -646,10 +1815,11 @@ impl AccountsStore {
                     }
                 }
 
-                if let Some(index) = index_to_remove {
-                    // Remove the previous attached canister before reattaching.
-                    account.canisters.remove(index);
-                }
+                // Remove the previous attached canister before reattaching.
+                let (metadata, group) = index_to_remove.map_or((None, None), |index| {
+                    let previous = account.canisters.remove(index);
+                    (previous.metadata, previous.group)
+                });
 
                 if account.canisters.len() >= u8::MAX as usize {
                     return AttachCanisterResponse::CanisterLimitExceeded;
@@ -657,122 +1827,926 @@ impl AccountsStore {
                 account.canisters.push(NamedCanister {
                     name: request.name,
                     canister_id: request.canister_id,
+                    metadata,
+                    group,
                 });
                 account.canisters.sort();
 
-                self.accounts_db.db_insert_account(&account_identifier, account);
+                self.update_account(&account_identifier, account);
+
+                AttachCanisterResponse::Ok
+            } else {
+                AttachCanisterResponse::AccountNotFound
+            }
+        } else {
+            AttachCanisterResponse::NameTooLong
+        }
+    }
+
+    pub fn rename_canister(&mut self, caller: PrincipalId, request: RenameCanisterRequest) -> RenameCanisterResponse {
+        if Self::validate_canister_name(&request.name) {
+            let account_identifier = AccountIdentifier::from(caller).to_vec();
+
+            if let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) {
+                if let Err(current_version) = account.check_version(request.expected_version) {
+                    return RenameCanisterResponse::Conflict { current_version };
+                }
+                if !request.name.is_empty() && account.canisters.iter().any(|c| c.name == request.name) {
+                    return RenameCanisterResponse::NameAlreadyTaken;
+                }
+
+                if let Some(index) = Self::find_canister_index(&account, request.canister_id) {
+                    let mut canister = account.canisters.remove(index);
+                    canister.name = request.name;
+                    account.canisters.push(canister);
+                    account.canisters.sort();
+                    self.update_account(&account_identifier, account);
+                    RenameCanisterResponse::Ok
+                } else {
+                    RenameCanisterResponse::CanisterNotFound
+                }
+            } else {
+                RenameCanisterResponse::AccountNotFound
+            }
+        } else {
+            RenameCanisterResponse::NameTooLong
+        }
+    }
+
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn detach_canister(&mut self, caller: PrincipalId, request: DetachCanisterRequest) -> DetachCanisterResponse {
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+
+        if let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) {
+            if let Err(current_version) = account.check_version(request.expected_version) {
+                return DetachCanisterResponse::Conflict { current_version };
+            }
+            if let Some(index) = Self::find_canister_index(&account, request.canister_id) {
+                account.canisters.remove(index);
+                self.update_account(&account_identifier, account);
+                DetachCanisterResponse::Ok
+            } else {
+                DetachCanisterResponse::CanisterNotFound
+            }
+        } else {
+            DetachCanisterResponse::AccountNotFound
+        }
+    }
+
+    /// Returns the caller's canisters.
+    ///
+    /// Canisters in groups come first, in the order of their groups, followed by canisters that are
+    /// not in any group.  Within a group, canisters are sorted as described in `NamedCanister::sorting_key`.
+    #[must_use]
+    pub fn get_canisters(&self, caller: PrincipalId) -> Vec<NamedCanister> {
+        let account_identifier = AccountIdentifier::from(caller);
+        if let Some(account) = self.accounts_db.db_get_account(&account_identifier.to_vec()) {
+            let group_positions: HashMap<&String, usize> = account
+                .canister_groups
+                .iter()
+                .flatten()
+                .enumerate()
+                .map(|(position, name)| (name, position))
+                .collect();
+            let mut canisters = account.canisters.clone();
+            canisters.sort_by_key(|canister| {
+                canister
+                    .group
+                    .as_ref()
+                    .and_then(|group| group_positions.get(group).copied())
+                    .unwrap_or(usize::MAX)
+            });
+            canisters
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Returns the names of the caller's canister groups, in order.
+    #[must_use]
+    pub fn get_canister_groups(&self, caller: PrincipalId) -> GetCanisterGroupsResponse {
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return GetCanisterGroupsResponse::AccountNotFound;
+        };
+
+        GetCanisterGroupsResponse::Ok(account.canister_groups.unwrap_or_default())
+    }
+
+    /// Adds a canister group to the end of the caller's list of groups.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn create_canister_group(
+        &mut self,
+        caller: PrincipalId,
+        request: CreateCanisterGroupRequest,
+    ) -> CreateCanisterGroupResponse {
+        if !Self::validate_canister_group_name(&request.name) {
+            return CreateCanisterGroupResponse::NameTooLong;
+        }
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return CreateCanisterGroupResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return CreateCanisterGroupResponse::Conflict { current_version };
+        }
+        let groups = account.canister_groups.get_or_insert_with(Vec::new);
+        if groups.contains(&request.name) {
+            return CreateCanisterGroupResponse::NameAlreadyTaken;
+        }
+        if groups.len() >= MAX_CANISTER_GROUPS as usize {
+            return CreateCanisterGroupResponse::GroupLimitExceeded {
+                limit: MAX_CANISTER_GROUPS,
+            };
+        }
+
+        groups.push(request.name);
+        self.update_account(&account_identifier, account);
+        CreateCanisterGroupResponse::Ok
+    }
+
+    /// Renames a canister group, keeping its canisters in it.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn rename_canister_group(
+        &mut self,
+        caller: PrincipalId,
+        request: RenameCanisterGroupRequest,
+    ) -> RenameCanisterGroupResponse {
+        if !Self::validate_canister_group_name(&request.new_name) {
+            return RenameCanisterGroupResponse::NameTooLong;
+        }
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return RenameCanisterGroupResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return RenameCanisterGroupResponse::Conflict { current_version };
+        }
+        let groups = account.canister_groups.get_or_insert_with(Vec::new);
+        let Some(index) = groups.iter().position(|name| *name == request.name) else {
+            return RenameCanisterGroupResponse::GroupNotFound;
+        };
+        if request.new_name == request.name {
+            return RenameCanisterGroupResponse::Ok;
+        }
+        if groups.contains(&request.new_name) {
+            return RenameCanisterGroupResponse::NameAlreadyTaken;
+        }
+
+        groups[index].clone_from(&request.new_name);
+        for canister in &mut account.canisters {
+            if canister.group.as_ref() == Some(&request.name) {
+                canister.group = Some(request.new_name.clone());
+            }
+        }
+        self.update_account(&account_identifier, account);
+        RenameCanisterGroupResponse::Ok
+    }
+
+    /// Deletes a canister group.  The canisters in the group are kept, but are no longer in any group.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn delete_canister_group(
+        &mut self,
+        caller: PrincipalId,
+        request: DeleteCanisterGroupRequest,
+    ) -> DeleteCanisterGroupResponse {
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return DeleteCanisterGroupResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return DeleteCanisterGroupResponse::Conflict { current_version };
+        }
+        let groups = account.canister_groups.get_or_insert_with(Vec::new);
+        let Some(index) = groups.iter().position(|name| *name == request.name) else {
+            return DeleteCanisterGroupResponse::GroupNotFound;
+        };
+
+        groups.remove(index);
+        for canister in &mut account.canisters {
+            if canister.group.as_ref() == Some(&request.name) {
+                canister.group = None;
+            }
+        }
+        self.update_account(&account_identifier, account);
+        DeleteCanisterGroupResponse::Ok
+    }
+
+    /// Puts the caller's groups in the given order.  The request must list every group exactly once.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn reorder_canister_groups(
+        &mut self,
+        caller: PrincipalId,
+        request: ReorderCanisterGroupsRequest,
+    ) -> ReorderCanisterGroupsResponse {
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return ReorderCanisterGroupsResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return ReorderCanisterGroupsResponse::Conflict { current_version };
+        }
+        let groups = account.canister_groups.get_or_insert_with(Vec::new);
+        let current: HashSet<&String> = groups.iter().collect();
+        let requested: HashSet<&String> = request.names.iter().collect();
+        if requested.len() != request.names.len() || requested != current {
+            return ReorderCanisterGroupsResponse::GroupsMismatch;
+        }
+
+        groups.clone_from(&request.names);
+        self.update_account(&account_identifier, account);
+        ReorderCanisterGroupsResponse::Ok
+    }
+
+    /// Moves a canister into a group, or out of any group if no group is given.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn set_canister_group(
+        &mut self,
+        caller: PrincipalId,
+        request: SetCanisterGroupRequest,
+    ) -> SetCanisterGroupResponse {
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return SetCanisterGroupResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return SetCanisterGroupResponse::Conflict { current_version };
+        }
+        if let Some(group) = &request.group {
+            if !account.canister_groups.iter().flatten().any(|name| name == group) {
+                return SetCanisterGroupResponse::GroupNotFound;
+            }
+        }
+        let Some(canister) = account
+            .canisters
+            .iter_mut()
+            .find(|canister| canister.canister_id == request.canister_id)
+        else {
+            return SetCanisterGroupResponse::CanisterNotFound;
+        };
+
+        canister.group.clone_from(&request.group);
+        self.update_account(&account_identifier, account);
+        SetCanisterGroupResponse::Ok
+    }
+
+    /// Returns the caller's canisters that have the requested tag and are in the requested group,
+    /// in the same order as `get_canisters`.
+    #[must_use]
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn get_filtered_canisters(&self, caller: PrincipalId, request: GetCanistersRequest) -> Vec<NamedCanister> {
+        self.get_canisters(caller)
+            .into_iter()
+            .filter(|canister| {
+                request.tag.as_ref().map_or(true, |tag| {
+                    canister
+                        .metadata
+                        .as_ref()
+                        .is_some_and(|metadata| metadata.tags.contains(tag))
+                })
+            })
+            .filter(|canister| {
+                request
+                    .group
+                    .as_ref()
+                    .map_or(true, |group| canister.group.as_ref() == Some(group))
+            })
+            .collect()
+    }
+
+    /// Replaces the metadata that the caller keeps about one of their canisters.
+    pub fn update_canister_metadata(
+        &mut self,
+        caller: PrincipalId,
+        request: UpdateCanisterMetadataRequest,
+    ) -> UpdateCanisterMetadataResponse {
+        let mut metadata = request.metadata;
+        if let Err(error) = metadata.validate() {
+            return error;
+        }
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return UpdateCanisterMetadataResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return UpdateCanisterMetadataResponse::Conflict { current_version };
+        }
+        let Some(canister) = account
+            .canisters
+            .iter_mut()
+            .find(|canister| canister.canister_id == request.canister_id)
+        else {
+            return UpdateCanisterMetadataResponse::CanisterNotFound;
+        };
+
+        metadata.tags.sort_unstable();
+        metadata.tags.dedup();
+        canister.metadata = if metadata.is_empty() { None } else { Some(metadata) };
+        self.update_account(&account_identifier, account);
+        UpdateCanisterMetadataResponse::Ok
+    }
+
+    // We skip the checks here since in this scenario we must store the canister otherwise the user
+    // won't be able to retrieve its Id.
+    //
+    // The canister is attached to the principal's own account.  Only if the principal has no account,
+    // as is the case for hardware wallets, is it attached to the account the principal is linked to.
+    // Anyone can register any principal as their hardware wallet, so if several accounts link the
+    // principal it is attached to none of them.  The canister can still be attached by hand.
+    pub fn attach_newly_created_canister(&mut self, principal: PrincipalId, canister_id: CanisterId) {
+        let own_account = AccountIdentifier::from(principal).to_vec();
+        let account_identifier = if self.accounts_db.db_contains_account(&own_account) {
+            own_account
+        } else {
+            match self.linked_account_keys(&AccountIdentifier::from(principal)).as_slice() {
+                [account_key] => account_key.clone(),
+                [] => return,
+                _ => {
+                    println!("Not attaching canister {canister_id} as {principal} is linked to more than one account.");
+                    return;
+                }
+            }
+        };
+        if let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) {
+            // We only attach if it doesn't already exist
+            if Self::find_canister_index(&account, canister_id).is_none() {
+                account.canisters.push(NamedCanister {
+                    name: String::new(),
+                    canister_id,
+                    metadata: None,
+                    group: None,
+                });
+                account.canisters.sort();
+                self.update_account(&account_identifier, account);
+            }
+        }
+    }
+
+    pub fn set_imported_tokens(
+        &mut self,
+        caller: PrincipalId,
+        new_imported_tokens: ImportedTokens,
+        expected_version: Option<u64>,
+    ) -> SetImportedTokensResponse {
+        if new_imported_tokens.imported_tokens.len() > (MAX_IMPORTED_TOKENS as usize) {
+            return SetImportedTokensResponse::TooManyImportedTokens {
+                limit: MAX_IMPORTED_TOKENS,
+            };
+        }
+        if !new_imported_tokens
+            .imported_tokens
+            .iter()
+            .all(ImportedToken::has_valid_label)
+        {
+            return SetImportedTokensResponse::LabelTooLong;
+        }
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return SetImportedTokensResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(expected_version) {
+            return SetImportedTokensResponse::Conflict { current_version };
+        }
+
+        account.imported_tokens = Some(new_imported_tokens);
+
+        self.update_account(&account_identifier, account);
+        SetImportedTokensResponse::Ok
+    }
+
+    /// Returns the caller's imported tokens, with pinned tokens first and then in display order.
+    pub fn get_imported_tokens(&mut self, caller: PrincipalId) -> GetImportedTokensResponse {
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return GetImportedTokensResponse::AccountNotFound;
+        };
+
+        let mut imported_tokens = account.imported_tokens.unwrap_or_default();
+        imported_tokens.imported_tokens.sort_by_key(ImportedToken::sorting_key);
+        GetImportedTokensResponse::Ok(imported_tokens)
+    }
+
+    /// Adds a single imported token, or replaces the token with the same ledger canister ID.
+    ///
+    /// Unlike `set_imported_tokens`, this leaves the caller's other imported tokens untouched, so
+    /// it is safe to use from several browser tabs at once.
+    pub fn add_imported_token(
+        &mut self,
+        caller: PrincipalId,
+        imported_token: ImportedToken,
+        expected_version: Option<u64>,
+    ) -> AddImportedTokenResponse {
+        if !imported_token.has_valid_label() {
+            return AddImportedTokenResponse::LabelTooLong;
+        }
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return AddImportedTokenResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(expected_version) {
+            return AddImportedTokenResponse::Conflict { current_version };
+        }
+        let imported_tokens = &mut account
+            .imported_tokens
+            .get_or_insert_with(ImportedTokens::default)
+            .imported_tokens;
+
+        if let Some(existing) = imported_tokens
+            .iter_mut()
+            .find(|token| token.ledger_canister_id == imported_token.ledger_canister_id)
+        {
+            *existing = imported_token;
+        } else if imported_tokens.len() >= MAX_IMPORTED_TOKENS as usize {
+            return AddImportedTokenResponse::TooManyImportedTokens {
+                limit: MAX_IMPORTED_TOKENS,
+            };
+        } else {
+            imported_tokens.push(imported_token);
+        }
+        self.update_account(&account_identifier, account);
+        AddImportedTokenResponse::Ok
+    }
+
+    /// Removes a single imported token, leaving the caller's other imported tokens untouched.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn remove_imported_token(
+        &mut self,
+        caller: PrincipalId,
+        request: RemoveImportedTokenRequest,
+    ) -> RemoveImportedTokenResponse {
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return RemoveImportedTokenResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return RemoveImportedTokenResponse::Conflict { current_version };
+        }
+        let imported_tokens = &mut account
+            .imported_tokens
+            .get_or_insert_with(ImportedTokens::default)
+            .imported_tokens;
+        let Some(index) = imported_tokens
+            .iter()
+            .position(|token| token.ledger_canister_id == request.ledger_canister_id)
+        else {
+            return RemoveImportedTokenResponse::TokenNotFound;
+        };
+
+        imported_tokens.remove(index);
+        self.update_account(&account_identifier, account);
+        RemoveImportedTokenResponse::Ok
+    }
+
+    /// Saves a named destination in the caller's address book.
+    pub fn add_address_book_entry(
+        &mut self,
+        caller: PrincipalId,
+        request: AddAddressBookEntryRequest,
+    ) -> AddAddressBookEntryResponse {
+        if !Self::validate_account_name(&request.name) {
+            return AddAddressBookEntryResponse::NameTooLong;
+        }
+        if !request.address.is_valid() {
+            return AddAddressBookEntryResponse::InvalidSubaccount;
+        }
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return AddAddressBookEntryResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return AddAddressBookEntryResponse::Conflict { current_version };
+        }
+
+        let address_book = account.address_book.get_or_insert_with(AddressBook::default);
+        if address_book.entries.iter().any(|entry| entry.name == request.name) {
+            return AddAddressBookEntryResponse::NameAlreadyTaken;
+        }
+        if address_book.entries.len() >= (MAX_ADDRESS_BOOK_ENTRIES as usize) {
+            return AddAddressBookEntryResponse::TooManyEntries {
+                limit: MAX_ADDRESS_BOOK_ENTRIES,
+            };
+        }
+        address_book.entries.push(AddressBookEntry {
+            name: request.name,
+            address: request.address,
+        });
+        address_book
+            .entries
+            .sort_unstable_by(|entry, other| entry.name.cmp(&other.name));
+
+        self.update_account(&account_identifier, account);
+        AddAddressBookEntryResponse::Ok
+    }
+
+    /// Changes the name of an entry in the caller's address book.
+    pub fn rename_address_book_entry(
+        &mut self,
+        caller: PrincipalId,
+        request: RenameAddressBookEntryRequest,
+    ) -> RenameAddressBookEntryResponse {
+        if !Self::validate_account_name(&request.new_name) {
+            return RenameAddressBookEntryResponse::NameTooLong;
+        }
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return RenameAddressBookEntryResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return RenameAddressBookEntryResponse::Conflict { current_version };
+        }
+
+        let address_book = account.address_book.get_or_insert_with(AddressBook::default);
+        if request.name != request.new_name && address_book.entries.iter().any(|entry| entry.name == request.new_name) {
+            return RenameAddressBookEntryResponse::NameAlreadyTaken;
+        }
+        let Some(entry) = address_book.entries.iter_mut().find(|entry| entry.name == request.name) else {
+            return RenameAddressBookEntryResponse::EntryNotFound;
+        };
+        entry.name = request.new_name;
+        address_book
+            .entries
+            .sort_unstable_by(|entry, other| entry.name.cmp(&other.name));
+
+        self.update_account(&account_identifier, account);
+        RenameAddressBookEntryResponse::Ok
+    }
+
+    /// Removes an entry from the caller's address book.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn remove_address_book_entry(
+        &mut self,
+        caller: PrincipalId,
+        request: RemoveAddressBookEntryRequest,
+    ) -> RemoveAddressBookEntryResponse {
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return RemoveAddressBookEntryResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return RemoveAddressBookEntryResponse::Conflict { current_version };
+        }
+
+        let address_book = account.address_book.get_or_insert_with(AddressBook::default);
+        let Some(index) = address_book.entries.iter().position(|entry| entry.name == request.name) else {
+            return RemoveAddressBookEntryResponse::EntryNotFound;
+        };
+        address_book.entries.remove(index);
+
+        self.update_account(&account_identifier, account);
+        RemoveAddressBookEntryResponse::Ok
+    }
+
+    #[must_use]
+    pub fn get_address_book(&self, caller: PrincipalId) -> GetAddressBookResponse {
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return GetAddressBookResponse::AccountNotFound;
+        };
+
+        GetAddressBookResponse::Ok(account.address_book.unwrap_or_default())
+    }
+
+    /// Replaces the caller's user interface preferences.
+    pub fn set_preferences(&mut self, caller: PrincipalId, request: SetPreferencesRequest) -> SetPreferencesResponse {
+        if request.preferences.data.len() > MAX_PREFERENCES_SIZE_BYTES as usize {
+            return SetPreferencesResponse::PreferencesTooLarge {
+                limit: MAX_PREFERENCES_SIZE_BYTES,
+            };
+        }
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return SetPreferencesResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return SetPreferencesResponse::Conflict { current_version };
+        }
+
+        account.preferences = Some(request.preferences);
+        self.update_account(&account_identifier, account);
+        SetPreferencesResponse::Ok
+    }
+
+    /// Returns the caller's user interface preferences.  Users who have not saved any preferences
+    /// get empty preferences with schema version 0.
+    #[must_use]
+    pub fn get_preferences(&self, caller: PrincipalId) -> GetPreferencesResponse {
+        let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return GetPreferencesResponse::AccountNotFound;
+        };
+
+        GetPreferencesResponse::Ok(account.preferences.unwrap_or_default())
+    }
 
-                AttachCanisterResponse::Ok
-            } else {
-                AttachCanisterResponse::AccountNotFound
-            }
-        } else {
-            AttachCanisterResponse::NameTooLong
+    /// Starts recording the ledger blocks that touch the caller's main account, sub-accounts and
+    /// hardware wallets.
+    ///
+    /// Only blocks synced after the index is enabled are recorded.
+    pub fn enable_transaction_index(&mut self, caller: PrincipalId) -> EnableTransactionIndexResponse {
+        let account_identifier = AccountIdentifier::from(caller);
+        if !self.accounts_db.db_contains_account(&account_identifier.to_vec()) {
+            return EnableTransactionIndexResponse::AccountNotFound;
         }
+
+        self.transaction_index.enable(&account_identifier);
+        EnableTransactionIndexResponse::Ok
     }
 
-    pub fn rename_canister(&mut self, caller: PrincipalId, request: RenameCanisterRequest) -> RenameCanisterResponse {
-        if Self::validate_canister_name(&request.name) {
-            let account_identifier = AccountIdentifier::from(caller).to_vec();
+    /// Stops recording the ledger blocks that touch the caller's accounts and discards those
+    /// recorded so far.
+    pub fn disable_transaction_index(&mut self, caller: PrincipalId) -> DisableTransactionIndexResponse {
+        let account_identifier = AccountIdentifier::from(caller);
+        if !self.accounts_db.db_contains_account(&account_identifier.to_vec()) {
+            return DisableTransactionIndexResponse::AccountNotFound;
+        }
 
-            if let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) {
-                if !request.name.is_empty() && account.canisters.iter().any(|c| c.name == request.name) {
-                    return RenameCanisterResponse::NameAlreadyTaken;
-                }
+        self.transaction_index.disable(&account_identifier);
+        DisableTransactionIndexResponse::Ok
+    }
 
-                if let Some(index) = Self::find_canister_index(&account, request.canister_id) {
-                    account.canisters.remove(index);
-                    account.canisters.push(NamedCanister {
-                        name: request.name,
-                        canister_id: request.canister_id,
-                    });
-                    account.canisters.sort();
-                    self.accounts_db.db_insert_account(&account_identifier, account);
-                    RenameCanisterResponse::Ok
-                } else {
-                    RenameCanisterResponse::CanisterNotFound
-                }
-            } else {
-                RenameCanisterResponse::AccountNotFound
-            }
-        } else {
-            RenameCanisterResponse::NameTooLong
+    /// Gets a page of the blocks recorded for the caller, newest first.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn get_indexed_transactions(
+        &self,
+        caller: PrincipalId,
+        request: GetIndexedTransactionsRequest,
+    ) -> GetIndexedTransactionsResponse {
+        let account_identifier = AccountIdentifier::from(caller);
+        if !self.accounts_db.db_contains_account(&account_identifier.to_vec()) {
+            return GetIndexedTransactionsResponse::AccountNotFound;
+        }
+        if request.limit == 0 {
+            return GetIndexedTransactionsResponse::InvalidLimit;
         }
+        let limit = request.limit.min(MAX_INDEXED_TRANSACTIONS_PAGE_SIZE) as usize;
+        let Some((block_heights, next)) = self.transaction_index.page(&account_identifier, request.before, limit)
+        else {
+            return GetIndexedTransactionsResponse::NotEnabled;
+        };
+        GetIndexedTransactionsResponse::Ok(IndexedTransactionsPage { block_heights, next })
     }
 
+    /// Returns a page of the caller's full account record as a versioned document.
+    ///
+    /// Note: Apart from canisters, an account is bounded in size by the limits on sub-accounts,
+    /// hardware wallets, imported tokens and address book entries, so only canisters are paginated.
+    #[must_use]
     #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
-    pub fn detach_canister(&mut self, caller: PrincipalId, request: DetachCanisterRequest) -> DetachCanisterResponse {
+    pub fn export_account(&self, caller: PrincipalId, request: ExportAccountRequest) -> ExportAccountResponse {
         let account_identifier = AccountIdentifier::from(caller).to_vec();
+        let Some(account) = self.accounts_db.db_get_account(&account_identifier) else {
+            return ExportAccountResponse::AccountNotFound;
+        };
 
-        if let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) {
-            if let Some(index) = Self::find_canister_index(&account, request.canister_id) {
-                account.canisters.remove(index);
-                self.accounts_db.db_insert_account(&account_identifier, account);
-                DetachCanisterResponse::Ok
-            } else {
-                DetachCanisterResponse::CanisterNotFound
-            }
-        } else {
-            DetachCanisterResponse::AccountNotFound
-        }
+        let mut export = account.into_export(caller);
+        let end = request
+            .canisters_offset
+            .saturating_add(MAX_EXPORTED_CANISTERS_PAGE_SIZE);
+        let next_canisters_offset = ((end as usize) < export.canisters.len()).then_some(end);
+        export.canisters = export
+            .canisters
+            .into_iter()
+            .skip(request.canisters_offset as usize)
+            .take(MAX_EXPORTED_CANISTERS_PAGE_SIZE as usize)
+            .collect();
+        ExportAccountResponse::Ok(ExportAccountPage {
+            export,
+            next_canisters_offset,
+        })
     }
 
-    #[must_use]
-    pub fn get_canisters(&self, caller: PrincipalId) -> Vec<NamedCanister> {
+    /// Restores an exported account record into the caller's account.
+    ///
+    /// The caller's account must exist and be empty.  Sub-accounts are recreated with the same
+    /// subaccount indices, but as they are derived from the caller's principal, they have new
+    /// account identifiers.  Any funds held by the original sub-accounts are not moved.
+    pub fn import_account(
+        &mut self,
+        caller: PrincipalId,
+        export: AccountExport,
+        expected_version: Option<u64>,
+    ) -> ImportAccountResponse {
+        if export.version != ACCOUNT_EXPORT_VERSION {
+            return ImportAccountResponse::UnsupportedVersion {
+                version: export.version,
+            };
+        }
+        if !Self::validate_account_export(&export) {
+            return ImportAccountResponse::InvalidExport;
+        }
         let account_identifier = AccountIdentifier::from(caller);
-        if let Some(account) = self.accounts_db.db_get_account(&account_identifier.to_vec()) {
-            account.canisters.clone()
-        } else {
-            Vec::new()
+        let Some(account) = self.accounts_db.db_get_account(&account_identifier.to_vec()) else {
+            return ImportAccountResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(expected_version) {
+            return ImportAccountResponse::Conflict { current_version };
         }
+        if !account.is_empty() {
+            return ImportAccountResponse::AccountNotEmpty;
+        }
+
+        self.restore_account_from_export(caller, account, export);
+        ImportAccountResponse::Ok
     }
 
-    // We skip the checks here since in this scenario we must store the canister otherwise the user
-    // won't be able to retrieve its Id.
-    pub fn attach_newly_created_canister(&mut self, principal: PrincipalId, canister_id: CanisterId) {
-        let account_identifier = AccountIdentifier::from(principal).to_vec();
+    /// Offers to move the caller's account to a new principal.
+    ///
+    /// The offer must be accepted by the new principal before it expires.  Any previous offer made
+    /// by the caller is replaced, and offers that have expired without being accepted are discarded.
+    pub fn propose_account_transfer(
+        &mut self,
+        caller: PrincipalId,
+        request: ProposeAccountTransferRequest,
+    ) -> ProposeAccountTransferResponse {
+        if request.new_principal == caller {
+            return ProposeAccountTransferResponse::SamePrincipal;
+        }
+        if !self
+            .accounts_db
+            .db_contains_account(&AccountIdentifier::from(caller).to_vec())
+        {
+            return ProposeAccountTransferResponse::AccountNotFound;
+        }
 
-        if let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) {
-            // We only attach if it doesn't already exist
-            if Self::find_canister_index(&account, canister_id).is_none() {
-                account.canisters.push(NamedCanister {
-                    name: String::new(),
-                    canister_id,
-                });
-                account.canisters.sort();
-                self.accounts_db.db_insert_account(&account_identifier, account);
+        self.purge_expired_account_transfer_offers();
+        let expires_at_timestamp_nanos = time().saturating_add(ACCOUNT_TRANSFER_OFFER_TIMEOUT_NANOS);
+        self.account_transfer_offers.insert(
+            caller.as_slice(),
+            AccountTransferOffer {
+                new_principal: request.new_principal,
+                expires_at_timestamp_nanos,
+            },
+        );
+        ProposeAccountTransferResponse::Ok {
+            expires_at_timestamp_nanos,
+        }
+    }
+
+    /// Discards the account transfer offers that can no longer be accepted.
+    ///
+    /// Every new offer purges the expired ones, so at any time the offers held are at most those
+    /// made during the last `ACCOUNT_TRANSFER_OFFER_TIMEOUT_NANOS`.
+    fn purge_expired_account_transfer_offers(&mut self) {
+        let now = time();
+        for (key, offer) in self.account_transfer_offers.entries() {
+            if offer.expires_at_timestamp_nanos < now {
+                self.account_transfer_offers.remove(&key);
             }
         }
     }
 
-    pub fn set_imported_tokens(
+    /// Withdraws the caller's pending account transfer offer.
+    pub fn cancel_account_transfer(&mut self, caller: PrincipalId) -> CancelAccountTransferResponse {
+        if !self.account_transfer_offers.contains_key(caller.as_slice()) {
+            return CancelAccountTransferResponse::OfferNotFound;
+        }
+        self.account_transfer_offers.remove(caller.as_slice());
+        CancelAccountTransferResponse::Ok
+    }
+
+    /// Accepts an account transfer offered to the caller, moving the offering account to the caller.
+    ///
+    /// The caller must not already have an account with any data in it.  As with `import_account`,
+    /// sub-accounts are recreated for the caller's principal and funds are not moved.  As with
+    /// `delete_account`, canister creations, top-ups and neuron stakes of the old principal that are
+    /// still waiting to be processed are abandoned, and their recorded outcomes are discarded.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn accept_account_transfer(
         &mut self,
         caller: PrincipalId,
-        new_imported_tokens: ImportedTokens,
-    ) -> SetImportedTokensResponse {
-        if new_imported_tokens.imported_tokens.len() > (MAX_IMPORTED_TOKENS as usize) {
-            return SetImportedTokensResponse::TooManyImportedTokens {
-                limit: MAX_IMPORTED_TOKENS,
-            };
+        request: AcceptAccountTransferRequest,
+    ) -> AcceptAccountTransferResponse {
+        let Some(offer) = self.account_transfer_offers.get(request.old_principal.as_slice()) else {
+            return AcceptAccountTransferResponse::OfferNotFound;
+        };
+        if offer.new_principal != caller {
+            return AcceptAccountTransferResponse::OfferNotFound;
         }
-        let account_identifier = AccountIdentifier::from(caller).to_vec();
-        let Some(mut account) = self.accounts_db.db_get_account(&account_identifier) else {
-            return SetImportedTokensResponse::AccountNotFound;
+        if offer.expires_at_timestamp_nanos < time() {
+            self.account_transfer_offers.remove(request.old_principal.as_slice());
+            return AcceptAccountTransferResponse::OfferExpired;
+        }
+        let old_account_identifier = AccountIdentifier::from(request.old_principal);
+        let Some(old_account) = self.accounts_db.db_get_account(&old_account_identifier.to_vec()) else {
+            self.account_transfer_offers.remove(request.old_principal.as_slice());
+            return AcceptAccountTransferResponse::AccountNotFound;
         };
+        let new_account_identifier = AccountIdentifier::from(caller);
+        let new_account = match self.accounts_db.db_get_account(&new_account_identifier.to_vec()) {
+            Some(account) if !account.is_empty() => return AcceptAccountTransferResponse::AccountNotEmpty,
+            Some(account) => account,
+            None => Account::new(caller, new_account_identifier),
+        };
+        if let Err(current_version) = new_account.check_version(request.expected_version) {
+            return AcceptAccountTransferResponse::Conflict { current_version };
+        }
 
-        account.imported_tokens = Some(new_imported_tokens);
+        self.account_transfer_offers.remove(request.old_principal.as_slice());
+        self.multi_part_transactions_processor.remove_for(request.old_principal);
+        self.operation_log.remove(request.old_principal);
+        self.remove_account(old_account_identifier, &old_account);
+        self.restore_account_from_export(caller, new_account, old_account.into_export(request.old_principal));
+        AcceptAccountTransferResponse::Ok
+    }
 
-        self.accounts_db.db_insert_account(&account_identifier, account);
-        SetImportedTokensResponse::Ok
+    /// Deletes all the data stored for the caller.
+    ///
+    /// As deletion cannot be undone, the caller must confirm it by providing the account identifier
+    /// of their main account.  Funds are not affected, as they are held by the ledger, but canister
+    /// creations, top-ups and neuron stakes that are still waiting to be processed are abandoned.
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn delete_account(&mut self, caller: PrincipalId, request: DeleteAccountRequest) -> DeleteAccountResponse {
+        let account_identifier = AccountIdentifier::from(caller);
+        if request.confirmation != account_identifier {
+            return DeleteAccountResponse::ConfirmationMismatch;
+        }
+        let Some(account) = self.accounts_db.db_get_account(&account_identifier.to_vec()) else {
+            return DeleteAccountResponse::AccountNotFound;
+        };
+        if let Err(current_version) = account.check_version(request.expected_version) {
+            return DeleteAccountResponse::Conflict { current_version };
+        }
+
+        self.account_transfer_offers.remove(caller.as_slice());
+        self.multi_part_transactions_processor.remove_for(caller);
+        self.remove_account(account_identifier, &account);
+        DeleteAccountResponse::Ok
     }
 
-    pub fn get_imported_tokens(&mut self, caller: PrincipalId) -> GetImportedTokensResponse {
-        let account_identifier = AccountIdentifier::from(caller).to_vec();
-        let Some(account) = self.accounts_db.db_get_account(&account_identifier) else {
-            return GetImportedTokensResponse::AccountNotFound;
+    /// Populates an empty account with the contents of an export, and links its sub-accounts and
+    /// hardware wallets.
+    ///
+    /// Note: The export is expected to have been validated.
+    fn restore_account_from_export(&mut self, caller: PrincipalId, mut account: Account, export: AccountExport) {
+        let account_identifier = AccountIdentifier::from(caller);
+        let named_sub_account = |sub_account: ExportedSubAccount| {
+            let sub_account_identifier =
+                AccountIdentifier::new(caller, Some(convert_byte_to_sub_account(sub_account.sub_account_id)));
+            (
+                sub_account.sub_account_id,
+                NamedSubAccount::new(sub_account.name, sub_account_identifier),
+            )
         };
+        account.sub_accounts = export.sub_accounts.into_iter().map(named_sub_account).collect();
+        if !export.archived_sub_accounts.is_empty() {
+            account.archived_sub_accounts = Some(
+                export
+                    .archived_sub_accounts
+                    .into_iter()
+                    .map(named_sub_account)
+                    .collect(),
+            );
+        }
+        account.hardware_wallet_accounts = export
+            .hardware_wallet_accounts
+            .into_iter()
+            .map(|hw| NamedHardwareWalletAccount {
+                name: hw.name,
+                principal: hw.principal,
+            })
+            .collect();
+        account
+            .hardware_wallet_accounts
+            .sort_unstable_by_key(|hw| hw.name.clone());
+        account.canisters = export.canisters;
+        account.canisters.sort();
+        if !export.canister_groups.is_empty() {
+            account.canister_groups = Some(export.canister_groups);
+        }
+        if !export.imported_tokens.is_empty() {
+            account.imported_tokens = Some(ImportedTokens {
+                imported_tokens: export.imported_tokens,
+            });
+        }
+        if !export.address_book.is_empty() {
+            let mut entries = export.address_book;
+            entries.sort_unstable_by(|entry, other| entry.name.cmp(&other.name));
+            account.address_book = Some(AddressBook { entries });
+        }
+        account.preferences = export.preferences;
+
+        self.accounts_db_stats.sub_accounts_count += account.sub_accounts.len() as u64;
+        self.accounts_db_stats.hardware_wallet_accounts_count += account.hardware_wallet_accounts.len() as u64;
+
+        self.update_account(&account_identifier.to_vec(), account);
+    }
 
-        GetImportedTokensResponse::Ok(account.imported_tokens.unwrap_or_default())
+    /// Removes an account, together with the links to its sub-accounts and hardware wallets.
+    fn remove_account(&mut self, account_identifier: AccountIdentifier, account: &Account) {
+        self.accounts_db_stats.sub_accounts_count = self
+            .accounts_db_stats
+            .sub_accounts_count
+            .saturating_sub(account.sub_accounts.len() as u64);
+        self.accounts_db_stats.hardware_wallet_accounts_count = self
+            .accounts_db_stats
+            .hardware_wallet_accounts_count
+            .saturating_sub(account.hardware_wallet_accounts.len() as u64);
+
+        self.transaction_index.disable(&account_identifier);
+        if let Some(principal) = account.principal {
+            self.operation_log.remove(principal);
+        }
+        self.db_remove_account(&account_identifier.to_vec());
     }
 
     #[must_use]
@@ -780,8 +2754,51 @@ impl AccountsStore {
         self.block_height_synced_up_to
     }
 
-    pub fn try_take_next_transaction_to_process(&mut self) -> Option<(BlockIndex, MultiPartTransactionToBeProcessed)> {
-        self.multi_part_transactions_processor.take_next()
+    /// Takes the next multi-part transaction that is due to be attempted, if any.
+    pub fn try_take_next_transaction_to_process(&mut self) -> Option<QueuedTransaction> {
+        self.multi_part_transactions_processor.take_next_due(time())
+    }
+
+    /// Schedules another attempt at a multi-part transaction that could not be completed yet.
+    pub fn retry_multi_part_transaction(&mut self, queued: QueuedTransaction) -> RetryOutcome {
+        self.multi_part_transactions_processor.retry(queued, time())
+    }
+
+    /// Records the outcome of processing a multi-part transaction.
+    pub fn record_operation_outcome(
+        &mut self,
+        block_height: BlockIndex,
+        transaction: &MultiPartTransactionToBeProcessed,
+        status: OperationStatus,
+        refund_block_height: Option<BlockIndex>,
+    ) {
+        self.operation_log.record(
+            transaction.principal(),
+            MultiPartOperation {
+                block_height,
+                kind: transaction.operation_kind(),
+                status,
+                refund_block_height,
+                completed_timestamp_nanos: Some(time()),
+            },
+        );
+    }
+
+    /// Gets the caller's multi-part transactions that are waiting to be processed, in processing
+    /// order, followed by the outcomes of those recently processed, newest first.
+    #[must_use]
+    pub fn get_pending_operations(&self, caller: PrincipalId) -> Vec<MultiPartOperation> {
+        self.multi_part_transactions_processor
+            .pending_for(caller)
+            .map(|(block_height, transaction)| MultiPartOperation {
+                block_height,
+                kind: transaction.operation_kind(),
+                status: OperationStatus::Pending,
+                refund_block_height: None,
+                completed_timestamp_nanos: None,
+            })
+            .chain(self.operation_log.get(caller))
+            .collect()
     }
 
     pub fn enqueue_multi_part_transaction(
@@ -811,9 +2828,23 @@ impl AccountsStore {
         stats.block_height_synced_up_to = self.block_height_synced_up_to;
         stats.seconds_since_last_ledger_sync = duration_since_last_sync.as_secs();
         stats.neurons_topped_up_count = self.neurons_topped_up_count;
-        stats.transactions_to_process_queue_length = self.multi_part_transactions_processor.get_queue_length();
+        let processor = &self.multi_part_transactions_processor;
+        stats.transactions_to_process_queue_length = processor.get_queue_length();
+        stats.transactions_to_retry_queue_length = Some(processor.get_retry_queue_length());
+        stats.dead_letter_transactions_count = Some(processor.get_dead_letter_count());
+        stats.blocks_behind_tip = self
+            .tip_of_chain
+            .0
+            .map(|tip_of_chain| tip_of_chain.saturating_sub(self.block_height_synced_up_to.unwrap_or(tip_of_chain)));
+        stats.blocks_to_replay_count = Some(u32::try_from(self.blocks_to_replay.len()).unwrap_or(u32::MAX));
         stats.migration_countdown = Some(self.accounts_db.migration_countdown());
         stats.accounts_db_stats_recomputed_on_upgrade = self.accounts_db_stats_recomputed_on_upgrade.0;
+        if let Some(report) = self.verification_report() {
+            stats.state_verification_in_progress = Some(report.status == VerificationStatus::InProgress);
+            stats.state_verification_accounts_verified = Some(report.accounts_verified);
+            stats.state_verification_discrepancies_count =
+                Some(u32::try_from(report.discrepancies_count).unwrap_or(u32::MAX));
+        }
     }
 
     #[must_use]
@@ -826,49 +2857,37 @@ impl AccountsStore {
     }
 
     fn store_has_account(&mut self, account_identifier: AccountIdentifier) -> bool {
-        self.accounts_db.db_get_account(&account_identifier.to_vec()).is_some()
-            || self.hardware_wallets_and_sub_accounts.contains_key(&account_identifier)
+        let account_key = account_identifier.to_vec();
+        self.accounts_db.db_contains_account(&account_key) || !self.linked_account_keys(&account_identifier).is_empty()
     }
 
     fn try_get_principal(&self, account_identifier: &AccountIdentifier) -> Option<PrincipalId> {
         if let Some(account) = self.accounts_db.db_get_account(&account_identifier.to_vec()) {
-            account.principal
-        } else {
-            match self.hardware_wallets_and_sub_accounts.get(account_identifier) {
-                Some(AccountWrapper::SubAccount(account_identifier, _)) => {
-                    let account = self.accounts_db
-                        .db_get_account
-                        (&account_identifier.to_vec())
-                        .unwrap_or_else(|| panic!("BROKEN STATE: Account identifier {account_identifier} exists in `hardware_wallets_and_sub_accounts`, but not in `accounts`."));
-                    account.principal
-                }
-                Some(AccountWrapper::HardwareWallet(linked_account_identifiers)) => linked_account_identifiers
-                    .iter()
-                    .filter_map(|account_identifier| self.accounts_db.db_get_account(&account_identifier.to_vec()))
-                    .find_map(|a| {
-                        a.hardware_wallet_accounts
-                            .iter()
-                            .find(|hw| *account_identifier == AccountIdentifier::from(hw.principal))
-                            .map(|hw| hw.principal)
-                    }),
-                None => None,
-            }
+            return account.principal;
         }
+        // The account identifier may belong to a sub-account, whose principal is that of its
+        // account, or to a hardware wallet, which has a principal of its own.
+        self.linked_account_keys(account_identifier)
+            .iter()
+            .map(|account_key| {
+                self.accounts_db.db_get_account(account_key).unwrap_or_else(|| {
+                    panic!("BROKEN STATE: Account identifier {account_identifier} is linked to an account that does not exist.")
+                })
+            })
+            .find_map(|account| {
+                let hardware_wallet_principal = account
+                    .hardware_wallet_accounts
+                    .iter()
+                    .find(|hw| *account_identifier == AccountIdentifier::from(hw.principal))
+                    .map(|hw| hw.principal);
+                hardware_wallet_principal.or(account.principal)
+            })
     }
 
-    fn link_hardware_wallet_to_account(
-        &mut self,
-        account_identifier: AccountIdentifier,
-        hardware_wallet_account_identifier: AccountIdentifier,
-    ) {
-        self.hardware_wallets_and_sub_accounts
-            .entry(hardware_wallet_account_identifier)
-            .and_modify(|account_wrapper| {
-                if let AccountWrapper::HardwareWallet(account_identifiers) = account_wrapper {
-                    account_identifiers.push(account_identifier);
-                }
-            })
-            .or_insert_with(|| AccountWrapper::HardwareWallet(vec![account_identifier]));
+    /// Saves a modified account, incrementing its version.
+    fn update_account(&mut self, account_key: &[u8], mut account: Account) {
+        account.version = Some(account.version() + 1);
+        self.db_insert_account(account_key, account);
     }
 
     fn validate_account_name(name: &str) -> bool {
@@ -877,6 +2896,70 @@ impl AccountsStore {
         name.len() <= ACCOUNT_NAME_MAX_LENGTH
     }
 
+    /// Checks that an account export respects the same limits as the endpoints that build an account.
+    fn validate_account_export(export: &AccountExport) -> bool {
+        let sub_account_ids: HashSet<u8> = export
+            .sub_accounts
+            .iter()
+            .chain(&export.archived_sub_accounts)
+            .map(|sub_account| sub_account.sub_account_id)
+            .collect();
+        let hardware_wallets: HashSet<PrincipalId> =
+            export.hardware_wallet_accounts.iter().map(|hw| hw.principal).collect();
+        let canister_ids: HashSet<CanisterId> = export.canisters.iter().map(|c| c.canister_id).collect();
+        let canister_groups: HashSet<&String> = export.canister_groups.iter().collect();
+        let imported_token_ledgers: HashSet<PrincipalId> = export
+            .imported_tokens
+            .iter()
+            .map(|token| token.ledger_canister_id)
+            .collect();
+        let address_book_names: HashSet<&String> = export.address_book.iter().map(|entry| &entry.name).collect();
+
+        sub_account_ids.len() == export.sub_accounts.len() + export.archived_sub_accounts.len()
+            && !sub_account_ids.contains(&0)
+            && !sub_account_ids.contains(&u8::MAX)
+            && export
+                .sub_accounts
+                .iter()
+                .chain(&export.archived_sub_accounts)
+                .all(|sub_account| Self::validate_account_name(&sub_account.name))
+            && hardware_wallets.len() == export.hardware_wallet_accounts.len()
+            && export.hardware_wallet_accounts.len() < u8::MAX as usize
+            && export
+                .hardware_wallet_accounts
+                .iter()
+                .all(|hw| Self::validate_account_name(&hw.name))
+            && canister_ids.len() == export.canisters.len()
+            && export.canisters.len() <= u8::MAX as usize
+            && export.canisters.iter().all(|c| {
+                Self::validate_canister_name(&c.name)
+                    && c.metadata.as_ref().map_or(true, |metadata| metadata.validate().is_ok())
+                    && c.group.as_ref().map_or(true, |group| canister_groups.contains(group))
+            })
+            && canister_groups.len() == export.canister_groups.len()
+            && export.canister_groups.len() <= MAX_CANISTER_GROUPS as usize
+            && export
+                .canister_groups
+                .iter()
+                .all(|name| Self::validate_canister_group_name(name))
+            && imported_token_ledgers.len() == export.imported_tokens.len()
+            && export.imported_tokens.len() <= MAX_IMPORTED_TOKENS as usize
+            && export.imported_tokens.iter().all(ImportedToken::has_valid_label)
+            && address_book_names.len() == export.address_book.len()
+            && export.address_book.len() <= MAX_ADDRESS_BOOK_ENTRIES as usize
+            && export
+                .address_book
+                .iter()
+                .all(|entry| Self::validate_account_name(&entry.name) && entry.address.is_valid())
+            && export.preferences.as_ref().map_or(true, |preferences| {
+                preferences.data.len() <= MAX_PREFERENCES_SIZE_BYTES as usize
+            })
+    }
+
+    fn validate_canister_group_name(name: &str) -> bool {
+        !name.is_empty() && Self::validate_canister_name(name)
+    }
+
     fn validate_canister_name(name: &str) -> bool {
         const CANISTER_NAME_MAX_LENGTH: usize = 24;
 
@@ -885,6 +2968,7 @@ impl AccountsStore {
 
     #[allow(clippy::too_many_arguments)]
     fn get_transaction_type(
+        &self,
         from: AccountIdentifier,
         to: AccountIdentifier,
         memo: Memo,
@@ -898,6 +2982,10 @@ impl AccountsStore {
         } else if memo.0 > 0 {
             if Self::is_create_canister_transaction(memo, &to, principal) {
                 TransactionType::CreateCanister
+            } else if Self::is_stake_neuron_transaction(memo, &to, principal) {
+                TransactionType::StakeNeuron
+            } else if let Some(canister_id) = self.find_topped_up_canister(memo, &from, &to) {
+                TransactionType::TopUpCanister(canister_id)
             } else {
                 default_transaction_type
             }
@@ -920,6 +3008,39 @@ impl AccountsStore {
         false
     }
 
+    /// Finds the canister that a transfer tops up, if the transfer is a top-up of a canister
+    /// attached to the sender's account.
+    fn find_topped_up_canister(
+        &self,
+        memo: Memo,
+        from: &AccountIdentifier,
+        to: &AccountIdentifier,
+    ) -> Option<CanisterId> {
+        // Topping up a canister involves sending ICP to the CMC subaccount of the canister, the NNS
+        // Dapp canister then notifies the CMC of the transfer.
+        if memo != MEMO_TOP_UP_CANISTER {
+            return None;
+        }
+        self.main_account_identifiers(from)
+            .iter()
+            .filter_map(|account_identifier| self.accounts_db.db_get_account(&account_identifier.to_vec()))
+            .flat_map(|account| account.canisters)
+            .map(|canister| canister.canister_id)
+            .find(|canister_id| {
+                let subaccount = (&canister_id.get()).into();
+                *to == AccountIdentifier::new(CYCLES_MINTING_CANISTER_ID.into(), Some(subaccount))
+            })
+    }
+
+    fn is_stake_neuron_transaction(memo: Memo, to: &AccountIdentifier, principal: &PrincipalId) -> bool {
+        // Staking a neuron involves sending ICP to a governance subaccount derived from the
+        // controller and a nonce, which is used as the memo.  The neuron is then claimed by
+        // calling `claim_or_refresh_neuron_from_account`.
+        let subaccount = compute_neuron_staking_subaccount(*principal, memo.0);
+        let expected_to = AccountIdentifier::new(GOVERNANCE_CANISTER_ID.into(), Some(subaccount));
+        *to == expected_to
+    }
+
     /// Certain transaction types require additional processing (Stake Neuron, Create Canister,
     /// etc). Each time we detect one of these transaction types we need to add the details to the
     /// `multi_part_transactions_processor` which will work through the required actions in the
@@ -929,14 +3050,80 @@ impl AccountsStore {
         &mut self,
         transaction_type: TransactionType,
         principal: PrincipalId,
+        memo: Memo,
+        to: AccountIdentifier,
         block_height: BlockIndex,
     ) {
-        if transaction_type == TransactionType::CreateCanister {
-            self.multi_part_transactions_processor.push(
-                block_height,
-                MultiPartTransactionToBeProcessed::CreateCanisterV2(principal),
-            );
-        };
+        match transaction_type {
+            TransactionType::CreateCanister => {
+                self.enqueue_new_multi_part_transaction(
+                    block_height,
+                    MultiPartTransactionToBeProcessed::CreateCanisterV2(principal),
+                );
+            }
+            TransactionType::StakeNeuron => {
+                if self.neuron_accounts.len() < MAX_NEURON_ACCOUNTS || self.neuron_accounts.contains_key(&to.to_vec()) {
+                    self.neuron_accounts.insert(
+                        &to.to_vec(),
+                        NeuronDetails {
+                            account_identifier: to,
+                            principal,
+                            memo,
+                            neuron_id: None,
+                        },
+                    );
+                } else {
+                    println!("WARNING: Not watching neuron account {to} for top-ups: the limit of {MAX_NEURON_ACCOUNTS} has been reached.");
+                }
+                self.enqueue_new_multi_part_transaction(
+                    block_height,
+                    MultiPartTransactionToBeProcessed::StakeNeuron(principal, memo),
+                );
+            }
+            TransactionType::TopUpNeuron => {
+                self.enqueue_new_multi_part_transaction(
+                    block_height,
+                    MultiPartTransactionToBeProcessed::TopUpNeuron(principal, memo),
+                );
+            }
+            TransactionType::TopUpCanister(canister_id) => {
+                self.enqueue_new_multi_part_transaction(
+                    block_height,
+                    MultiPartTransactionToBeProcessed::TopUpCanisterV2(principal, canister_id),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// Enqueues a multi-part transaction unless the block has been queued before.
+    fn enqueue_new_multi_part_transaction(
+        &mut self,
+        block_height: BlockIndex,
+        transaction: MultiPartTransactionToBeProcessed,
+    ) {
+        let block_key = block_height.to_be_bytes();
+        let already_processed =
+            block_height < self.processed_blocks_floor || self.processed_blocks.contains_key(&block_key);
+        if !already_processed {
+            self.processed_blocks.insert(&block_key, ());
+            self.multi_part_transactions_processor.push(block_height, transaction);
+        }
+    }
+
+    /// Records the ID of a neuron that has been claimed after being staked.
+    pub fn mark_neuron_created(&mut self, principal: PrincipalId, memo: Memo, neuron_id: NeuronId) {
+        let subaccount = compute_neuron_staking_subaccount(principal, memo.0);
+        let account_identifier = AccountIdentifier::new(GOVERNANCE_CANISTER_ID.into(), Some(subaccount));
+        if let Some(mut neuron_details) = self.neuron_accounts.get(&account_identifier.to_vec()) {
+            neuron_details.neuron_id = Some(neuron_id);
+            self.neuron_accounts
+                .insert(&account_identifier.to_vec(), neuron_details);
+        }
+    }
+
+    pub fn mark_neuron_topped_up(&mut self) {
+        self.neurons_topped_up_count += 1;
     }
     fn assert_account_limit(&self) {
         let db_accounts_len = self.accounts_db.db_accounts_len();
@@ -952,22 +3139,39 @@ impl StableState for AccountsStore {
         // Accounts are now in stable structures and no longer in a simple map
         // on the heap. So we don't need to encode them here.
         let empty_accounts = BTreeMap::<Vec<u8>, candid::Empty>::new();
+        // Likewise, the links to sub-accounts and hardware wallets are indexed by the accounts
+        // database.  Links decoded from the heap of an earlier release are kept here until the
+        // index is complete.
+        let legacy_hardware_wallets_and_sub_accounts: HashMap<AccountIdentifier, AccountWrapper> =
+            match &self.legacy_links {
+                HeapOrStableMap::Map(links) => links
+                    .iter()
+                    .filter_map(|(key, link)| Some((AccountIdentifier::from_slice(key).ok()?, link.clone())))
+                    .collect(),
+                HeapOrStableMap::StableBTreeMap(_) => HashMap::new(),
+            };
         Candid((
             empty_accounts,
-            &self.hardware_wallets_and_sub_accounts,
+            legacy_hardware_wallets_and_sub_accounts,
             // TODO: Remove pending_transactions
             HashMap::<(AccountIdentifier, AccountIdentifier), (TransactionType, u64)>::new(),
             // Transactions are unused but we need to encode them for backwards
             // compatibility.
             VecDeque::<candid::Empty>::new(),
-            // Neuron accounts are unused but we need to encode them for
-            // backwards compatibility.
-            HashMap::<AccountIdentifier, candid::Empty>::new(),
+            // The neuron accounts, multi-part transactions, account transfer offers and blocks to
+            // replay are in their own stable memory partitions, so empty values are encoded in
+            // their place.  Downgrading to a release that predates the partitions requires a
+            // one-off release that copies them back to the heap.
+            HashMap::<AccountIdentifier, NeuronDetails>::new(),
             &self.block_height_synced_up_to,
-            &self.multi_part_transactions_processor,
+            EncodedMultiPartTransactions::default(),
             &self.last_ledger_sync_timestamp_nanos,
             &self.neurons_topped_up_count,
             Some(&self.accounts_db_stats),
+            Some(HashMap::<PrincipalId, AccountTransferOffer>::new()),
+            Some(VecDeque::<BlockIndex>::new()),
+            Some(&self.resyncing_from_first_block),
+            Some(&self.processed_blocks_floor),
         ))
         .into_bytes()
         .unwrap()
@@ -979,17 +3183,27 @@ impl StableState for AccountsStore {
             // Accounts are now in stable structures and no longer in a simple
             // map on the heap. So we don't need to decode them here.
             _accounts,
-            mut hardware_wallets_and_sub_accounts,
+            // Releases that predate the index of links to sub-accounts and hardware wallets store
+            // the links here.  They are consulted while the index is being built.
+            hardware_wallets_and_sub_accounts,
             pending_transactions,
             // Transactions are unused but we need to decode something for backwards
             // compatibility.
             _transactions,
-            _neuron_accounts,
+            // The neuron accounts, multi-part transactions, account transfer offers and blocks to
+            // replay are stored here by releases that predate their partitions.  They are moved to
+            // stable memory in State::new_restored if their partitions are new, and are otherwise
+            // discarded.
+            neuron_accounts,
             block_height_synced_up_to,
             multi_part_transactions_processor,
             last_ledger_sync_timestamp_nanos,
             neurons_topped_up_count,
             accounts_db_stats_maybe,
+            account_transfer_offers,
+            blocks_to_replay,
+            resyncing_from_first_block,
+            processed_blocks_floor,
         ): (
             candid::Reserved,
             HashMap<AccountIdentifier, AccountWrapper>,
@@ -997,19 +3211,29 @@ impl StableState for AccountsStore {
             candid::Reserved,
             HashMap<AccountIdentifier, NeuronDetails>,
             Option<BlockIndex>,
-            MultiPartTransactionsProcessor,
+            EncodedMultiPartTransactions,
             u64,
             u64,
             Option<AccountsDbStats>,
+            Option<HashMap<PrincipalId, AccountTransferOffer>>,
+            Option<VecDeque<BlockIndex>>,
+            Option<bool>,
+            Option<BlockIndex>,
         ) = Candid::from_bytes(bytes).map(|c| c.0)?;
-
-        // Remove duplicate links between hardware wallets and user accounts
-        for hw_or_sub in hardware_wallets_and_sub_accounts.values_mut() {
-            if let AccountWrapper::HardwareWallet(ids) = hw_or_sub {
-                let mut unique = HashSet::new();
-                ids.retain(|id| unique.insert(*id));
-            }
-        }
+        let resyncing_from_first_block = resyncing_from_first_block.unwrap_or_default();
+        // Releases that predate the floor did not record the blocks they processed, so every
+        // block before the next one to sync is treated as processed.
+        let processed_blocks_floor = processed_blocks_floor.unwrap_or_else(|| {
+            let next_block_height_to_sync = if resyncing_from_first_block {
+                Some(0)
+            } else {
+                block_height_synced_up_to.map(|block_height| block_height + 1)
+            };
+            let lowest_block_to_replay = blocks_to_replay.iter().flatten().copied().min();
+            next_block_height_to_sync
+                .unwrap_or_default()
+                .min(lowest_block_to_replay.unwrap_or(BlockIndex::MAX))
+        });
 
         let accounts_db_stats_recomputed_on_upgrade = IgnoreEq(Some(accounts_db_stats_maybe.is_none()));
         let Some(accounts_db_stats) = accounts_db_stats_maybe else {
@@ -1021,14 +3245,50 @@ impl StableState for AccountsStore {
             // will be replaced with an AccountsDbAsUnboundedStableBTreeMap in
             // State::from(Partitions) so it doesn't matter what we set here.
             accounts_db: AccountsDbAsProxy::default(),
-            hardware_wallets_and_sub_accounts,
+            legacy_links: HeapOrStableMap::Map(
+                hardware_wallets_and_sub_accounts
+                    .into_iter()
+                    .map(|(account_identifier, link)| (account_identifier.to_vec(), link))
+                    .collect(),
+            ),
             pending_transactions,
             block_height_synced_up_to,
-            multi_part_transactions_processor,
+            resyncing_from_first_block,
+            multi_part_transactions_processor: MultiPartTransactionsProcessor::from(multi_part_transactions_processor),
             accounts_db_stats,
             accounts_db_stats_recomputed_on_upgrade,
             last_ledger_sync_timestamp_nanos,
             neurons_topped_up_count,
+            neuron_accounts: HeapOrStableMap::Map(
+                neuron_accounts
+                    .into_iter()
+                    .map(|(account_identifier, neuron_details)| (account_identifier.to_vec(), neuron_details))
+                    .collect(),
+            ),
+            account_transfer_offers: HeapOrStableMap::Map(
+                account_transfer_offers
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(principal, offer)| (principal.as_slice().to_vec(), offer))
+                    .collect(),
+            ),
+            // The transaction index and operation log are in their own stable memory partitions
+            // and are loaded in State::new_restored.  Unlike the multi-part transactions above,
+            // they have never been stored on the heap.
+            transaction_index: TransactionIndex::default(),
+            operation_log: OperationLog::default(),
+            processed_blocks: HeapOrStableMap::default(),
+            processed_blocks_floor,
+            blocks_to_replay: HeapOrStableMap::Map(
+                blocks_to_replay
+                    .unwrap_or_default()
+                    .into_iter()
+                    .zip(0_u64..)
+                    .map(|(block_height, sequence_number)| (sequence_number.to_be_bytes().to_vec(), block_height))
+                    .collect(),
+            ),
+            tip_of_chain: IgnoreEq::default(),
+            verification: IgnoreEq::default(),
         })
     }
 }
@@ -1043,8 +3303,105 @@ impl Account {
             hardware_wallet_accounts: Vec::new(),
             canisters: Vec::new(),
             imported_tokens: None,
+            address_book: None,
+            archived_sub_accounts: None,
+            canister_groups: None,
+            version: None,
+            preferences: None,
+        }
+    }
+
+    /// The number of times the account has been modified.
+    fn version(&self) -> u64 {
+        self.version.unwrap_or_default()
+    }
+
+    /// The account identifiers of the sub-accounts and hardware wallets that belong to the account.
+    ///
+    /// These are the entries of the account in the secondary index kept by the accounts database.
+    fn linked_account_identifiers(&self) -> impl Iterator<Item = AccountIdentifier> + '_ {
+        self.sub_accounts
+            .values()
+            .map(|sub_account| sub_account.account_identifier)
+            .chain(
+                self.hardware_wallet_accounts
+                    .iter()
+                    .map(|hw| AccountIdentifier::from(hw.principal)),
+            )
+    }
+
+    /// Checks that the account has not been modified since the client read it.
+    ///
+    /// Clients that do not provide an expected version always pass the check.  On failure, the
+    /// current version is returned.
+    fn check_version(&self, expected_version: Option<u64>) -> Result<(), u64> {
+        match expected_version {
+            Some(expected_version) if expected_version != self.version() => Err(self.version()),
+            _ => Ok(()),
         }
     }
+
+    /// Converts the account into a document that can be imported into another account.
+    fn into_export(self, principal: PrincipalId) -> AccountExport {
+        let export_sub_accounts = |sub_accounts: &HashMap<u8, NamedSubAccount>| -> Vec<ExportedSubAccount> {
+            sub_accounts
+                .iter()
+                .sorted_unstable_by_key(|(id, _)| **id)
+                .map(|(id, sub_account)| ExportedSubAccount {
+                    name: sub_account.name.clone(),
+                    sub_account_id: *id,
+                })
+                .collect()
+        };
+
+        AccountExport {
+            version: ACCOUNT_EXPORT_VERSION,
+            principal,
+            sub_accounts: export_sub_accounts(&self.sub_accounts),
+            archived_sub_accounts: self
+                .archived_sub_accounts
+                .as_ref()
+                .map_or_else(Vec::new, export_sub_accounts),
+            hardware_wallet_accounts: self
+                .hardware_wallet_accounts
+                .iter()
+                .map(|hw| ExportedHardwareWallet {
+                    name: hw.name.clone(),
+                    principal: hw.principal,
+                })
+                .collect(),
+            canisters: self.canisters,
+            canister_groups: self.canister_groups.unwrap_or_default(),
+            imported_tokens: self.imported_tokens.unwrap_or_default().imported_tokens,
+            address_book: self.address_book.unwrap_or_default().entries,
+            preferences: self.preferences,
+        }
+    }
+
+    /// Determines whether the account holds nothing beyond its principal.
+    fn is_empty(&self) -> bool {
+        self.sub_accounts.is_empty()
+            && self.archived_sub_accounts.as_ref().map_or(true, HashMap::is_empty)
+            && self.hardware_wallet_accounts.is_empty()
+            && self.canisters.is_empty()
+            && self.canister_groups.as_ref().map_or(true, Vec::is_empty)
+            && self
+                .imported_tokens
+                .as_ref()
+                .map_or(true, |tokens| tokens.imported_tokens.is_empty())
+            && self
+                .address_book
+                .as_ref()
+                .map_or(true, |address_book| address_book.entries.is_empty())
+            && self.preferences.is_none()
+    }
+
+    /// Determines whether the given subaccount index belongs to a removed sub-account.
+    fn is_archived_sub_account(&self, sub_account_id: u8) -> bool {
+        self.archived_sub_accounts
+            .as_ref()
+            .is_some_and(|archived| archived.contains_key(&sub_account_id))
+    }
 }
 
 impl NamedSubAccount {
@@ -1056,6 +3413,13 @@ impl NamedSubAccount {
     }
 }
 
+/// The sequence number that is the key of a block to replay.
+fn replay_sequence_number(key: &[u8]) -> u64 {
+    let bytes = <[u8; 8]>::try_from(key)
+        .unwrap_or_else(|_| unreachable!("Blocks to replay are keyed by an 8-byte sequence number."));
+    u64::from_be_bytes(bytes)
+}
+
 fn convert_byte_to_sub_account(byte: u8) -> Subaccount {
     let mut bytes = [0u8; 32];
     bytes[31] = byte;
@@ -1,5 +1,10 @@
 //! Account store constructors.
-use super::{AccountsDb, AccountsDbAsProxy, AccountsStore};
+use super::schema::accounts_in_unbounded_stable_btree_map::ProductionMemoryType;
+use super::{
+    AccountsDb, AccountsDbAsProxy, AccountsStore, HeapOrStableMap, MultiPartTransactionsProcessor, OperationLog,
+    TransactionIndex,
+};
+use ic_stable_structures::Memory;
 use std::mem;
 
 impl From<AccountsDb> for AccountsStore {
@@ -26,4 +31,74 @@ impl AccountsStore {
     pub fn replace_accounts_db(&mut self, accounts_db: AccountsDb) -> AccountsDbAsProxy {
         mem::replace(&mut self.accounts_db, AccountsDbAsProxy::from(accounts_db))
     }
+    /// Adds a `transaction_index` to the store.
+    ///
+    /// As with `accounts_db`, the transaction index is kept in its own virtual memory and is
+    /// added after the rest of the accounts store has been recovered.
+    ///
+    /// # Returns
+    /// - The original transaction index.
+    #[must_use]
+    pub fn replace_transaction_index(&mut self, transaction_index: TransactionIndex) -> TransactionIndex {
+        mem::replace(&mut self.transaction_index, transaction_index)
+    }
+    /// Adds an `operation_log` to the store.
+    ///
+    /// As with `accounts_db`, the operation log is kept in its own virtual memory.
+    ///
+    /// # Returns
+    /// - The original operation log.
+    #[must_use]
+    pub fn replace_operation_log(&mut self, operation_log: OperationLog) -> OperationLog {
+        mem::replace(&mut self.operation_log, operation_log)
+    }
+    /// Loads the links from sub-accounts and hardware wallets to their accounts that an earlier
+    /// release kept in the given virtual memory, if any.
+    ///
+    /// Releases that kept the links on the heap leave the memory empty; the links decoded from the
+    /// heap are then used.  Either way, the links are discarded if the index in the accounts
+    /// database is already complete.
+    pub fn load_legacy_links(&mut self, memory: ProductionMemoryType) {
+        if memory.size() > 0 {
+            self.legacy_links = HeapOrStableMap::init(memory);
+        }
+        self.discard_legacy_links_once_indexed();
+    }
+    /// Moves the multi-part transactions waiting to be processed into the given virtual memory.
+    ///
+    /// If the memory is new, as it is when upgrading from a release that kept the transactions on
+    /// the heap, the transactions decoded from the heap are copied into it.  Otherwise the
+    /// transactions already stored there are used.
+    pub fn load_multi_part_transactions(&mut self, memory: ProductionMemoryType) {
+        let on_heap = mem::take(&mut self.multi_part_transactions_processor);
+        self.multi_part_transactions_processor = MultiPartTransactionsProcessor::init_or_migrate(memory, on_heap);
+    }
+    /// Moves the governance accounts of staked neurons into the given virtual memory.
+    ///
+    /// As with the multi-part transactions, a new memory is populated with the neuron accounts
+    /// decoded from the heap.
+    pub fn load_neuron_accounts(&mut self, memory: ProductionMemoryType) {
+        let on_heap = mem::take(&mut self.neuron_accounts);
+        self.neuron_accounts = HeapOrStableMap::init_or_migrate(memory, on_heap);
+    }
+    /// Moves the pending account transfer offers into the given virtual memory.
+    ///
+    /// As with the multi-part transactions, a new memory is populated with the offers decoded from the heap.
+    pub fn load_account_transfer_offers(&mut self, memory: ProductionMemoryType) {
+        let on_heap = mem::take(&mut self.account_transfer_offers);
+        self.account_transfer_offers = HeapOrStableMap::init_or_migrate(memory, on_heap);
+    }
+    /// Moves the blocks waiting to be replayed into the given virtual memory.
+    ///
+    /// As with the multi-part transactions, a new memory is populated with the blocks decoded from the heap.
+    pub fn load_blocks_to_replay(&mut self, memory: ProductionMemoryType) {
+        let on_heap = mem::take(&mut self.blocks_to_replay);
+        self.blocks_to_replay = HeapOrStableMap::init_or_migrate(memory, on_heap);
+    }
+    /// Loads the heights of the blocks queued as multi-part transactions from the given virtual memory.
+    ///
+    /// These have never been stored on the heap, so a new memory starts out empty.
+    pub fn load_processed_blocks(&mut self, memory: ProductionMemoryType) {
+        self.processed_blocks = HeapOrStableMap::init(memory);
+    }
 }
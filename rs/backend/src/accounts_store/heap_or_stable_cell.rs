@@ -0,0 +1,108 @@
+//! A value that lives either on the heap or in a stable memory partition.
+//!
+//! This is the counterpart of `HeapOrStableMap` for state that is read and written as a whole,
+//! such as the TVL state.  Keeping such state in its own partition means
+//! that it does not have to be serialized in `pre_upgrade`.
+use super::schema::accounts_in_unbounded_stable_btree_map::ProductionMemoryType;
+use core::fmt;
+use ic_stable_structures::cell::Cell as StableCell;
+use ic_stable_structures::Storable;
+
+pub enum HeapOrStableCell<V>
+where
+    V: Storable + Clone,
+{
+    /// A value on the heap, used in tests and before stable memory has been set up.
+    Heap(V),
+    /// A value in a stable memory partition.
+    StableCell(StableCell<V, ProductionMemoryType>),
+}
+
+impl<V> Default for HeapOrStableCell<V>
+where
+    V: Storable + Clone + Default,
+{
+    fn default() -> Self {
+        HeapOrStableCell::Heap(V::default())
+    }
+}
+
+impl<V> fmt::Debug for HeapOrStableCell<V>
+where
+    V: Storable + Clone + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeapOrStableCell::Heap(value) => write!(f, "Heap({value:?})"),
+            HeapOrStableCell::StableCell(cell) => write!(f, "StableCell({:?})", cell.get()),
+        }
+    }
+}
+
+/// Checks whether two cells contain the same value.
+#[cfg(test)]
+impl<V> PartialEq for HeapOrStableCell<V>
+where
+    V: Storable + Clone + PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+#[cfg(test)]
+impl<V> Eq for HeapOrStableCell<V> where V: Storable + Clone + Eq {}
+
+impl<V> HeapOrStableCell<V>
+where
+    V: Storable + Clone,
+{
+    /// Creates a cell in the given memory, or loads the value already stored there.
+    ///
+    /// If the memory is new, the cell holds `initial_value`.  This moves data decoded from the heap
+    /// of a release that predates the partition into stable memory.
+    ///
+    /// # Panics
+    /// - If the memory contains something other than a cell.
+    #[must_use]
+    pub fn init(memory: ProductionMemoryType, initial_value: V) -> Self {
+        HeapOrStableCell::StableCell(
+            StableCell::init(memory, initial_value)
+                .unwrap_or_else(|err| panic!("Failed to initialize a stable cell: {err:?}")),
+        )
+    }
+
+    #[must_use]
+    pub fn get(&self) -> &V {
+        match self {
+            HeapOrStableCell::Heap(value) => value,
+            HeapOrStableCell::StableCell(cell) => cell.get(),
+        }
+    }
+
+    /// Replaces the value.
+    ///
+    /// # Panics
+    /// - If the value is too large to be stored.
+    pub fn set(&mut self, value: V) {
+        match self {
+            HeapOrStableCell::Heap(heap_value) => *heap_value = value,
+            HeapOrStableCell::StableCell(cell) => {
+                cell.set(value)
+                    .unwrap_or_else(|err| panic!("Failed to save a stable cell: {err:?}"));
+            }
+        }
+    }
+
+    /// Modifies the value, saving it to stable memory if necessary.
+    pub fn update<R>(&mut self, f: impl FnOnce(&mut V) -> R) -> R {
+        match self {
+            HeapOrStableCell::Heap(value) => f(value),
+            HeapOrStableCell::StableCell(_) => {
+                let mut value = self.get().clone();
+                let result = f(&mut value);
+                self.set(value);
+                result
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! A map that lives either on the heap or in a stable memory partition.
+//!
+//! Per-user data that is kept in its own partition, such as the transaction index, starts out on
+//! the heap when the accounts store is created or decoded and is moved to stable memory when the
+//! state is set up.  Tests typically use the heap.
+use super::schema::accounts_in_unbounded_stable_btree_map::ProductionMemoryType;
+use core::fmt;
+use ic_stable_structures::btreemap::BTreeMap as StableBTreeMap;
+use ic_stable_structures::{Memory, Storable};
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops::Range;
+
+pub enum HeapOrStableMap<V>
+where
+    V: Storable + Clone,
+{
+    /// A map on the heap, used in tests and before stable memory has been set up.
+    Map(BTreeMap<Vec<u8>, V>),
+    /// A map in a stable memory partition.
+    StableBTreeMap(StableBTreeMap<Vec<u8>, V, ProductionMemoryType>),
+}
+
+impl<V> Default for HeapOrStableMap<V>
+where
+    V: Storable + Clone,
+{
+    fn default() -> Self {
+        HeapOrStableMap::Map(BTreeMap::new())
+    }
+}
+
+impl<V> fmt::Debug for HeapOrStableMap<V>
+where
+    V: Storable + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeapOrStableMap::Map(map) => write!(f, "Map{{.. {} entries}}", map.len()),
+            HeapOrStableMap::StableBTreeMap(map) => write!(f, "StableBTreeMap{{.. {} entries}}", map.len()),
+        }
+    }
+}
+
+/// Checks whether two maps contain the same data.
+#[cfg(test)]
+impl<V> PartialEq for HeapOrStableMap<V>
+where
+    V: Storable + Clone + PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.entries() == other.entries()
+    }
+}
+#[cfg(test)]
+impl<V> Eq for HeapOrStableMap<V> where V: Storable + Clone + Eq {}
+
+impl<V> HeapOrStableMap<V>
+where
+    V: Storable + Clone,
+{
+    /// Creates a map in the given memory, or loads the map already stored there.
+    #[must_use]
+    pub fn init(memory: ProductionMemoryType) -> Self {
+        HeapOrStableMap::StableBTreeMap(StableBTreeMap::init(memory))
+    }
+
+    /// Creates a map in the given memory, or loads the map already stored there.
+    ///
+    /// If the memory is new, the map is populated with the entries of `heap`.  This moves data
+    /// decoded from the heap of a release that predates the partition into stable memory.
+    #[must_use]
+    pub fn init_or_migrate(memory: ProductionMemoryType, heap: Self) -> Self {
+        let is_new = memory.size() == 0;
+        let mut map = Self::init(memory);
+        if is_new {
+            for (key, value) in heap.entries() {
+                map.insert(&key, value);
+            }
+        }
+        map
+    }
+
+    #[must_use]
+    pub fn len(&self) -> u64 {
+        match self {
+            HeapOrStableMap::Map(map) => map.len() as u64,
+            HeapOrStableMap::StableBTreeMap(map) => map.len(),
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[must_use]
+    pub fn get(&self, key: &[u8]) -> Option<V> {
+        match self {
+            HeapOrStableMap::Map(map) => map.get(key).cloned(),
+            HeapOrStableMap::StableBTreeMap(map) => map.get(&key.to_vec()),
+        }
+    }
+
+    #[must_use]
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        match self {
+            HeapOrStableMap::Map(map) => map.contains_key(key),
+            HeapOrStableMap::StableBTreeMap(map) => map.contains_key(&key.to_vec()),
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: V) {
+        match self {
+            HeapOrStableMap::Map(map) => {
+                map.insert(key.to_vec(), value);
+            }
+            HeapOrStableMap::StableBTreeMap(map) => {
+                map.insert(key.to_vec(), value);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        match self {
+            HeapOrStableMap::Map(map) => {
+                map.remove(key);
+            }
+            HeapOrStableMap::StableBTreeMap(map) => {
+                map.remove(&key.to_vec());
+            }
+        }
+    }
+
+    /// Removes all entries.
+    ///
+    /// A map in stable memory is replaced by a new, empty map in the same memory, so this takes
+    /// the same time however many entries there are.
+    pub fn clear(&mut self) {
+        match mem::take(self) {
+            HeapOrStableMap::Map(_) => {}
+            HeapOrStableMap::StableBTreeMap(map) => {
+                *self = HeapOrStableMap::StableBTreeMap(StableBTreeMap::new(map.into_memory()));
+            }
+        }
+    }
+
+    /// The entries with keys in the given range, in key order.
+    pub fn range(&self, range: Range<Vec<u8>>) -> Box<dyn Iterator<Item = (Vec<u8>, V)> + '_> {
+        match self {
+            HeapOrStableMap::Map(map) => Box::new(map.range(range).map(|(key, value)| (key.clone(), value.clone()))),
+            HeapOrStableMap::StableBTreeMap(map) => Box::new(map.range(range)),
+        }
+    }
+
+    /// The entry with the smallest key, if any.
+    #[must_use]
+    pub fn first_entry(&self) -> Option<(Vec<u8>, V)> {
+        match self {
+            HeapOrStableMap::Map(map) => map.first_key_value().map(|(key, value)| (key.clone(), value.clone())),
+            HeapOrStableMap::StableBTreeMap(map) => map.first_key_value(),
+        }
+    }
+
+    /// The entry with the largest key, if any.
+    #[must_use]
+    pub fn last_entry(&self) -> Option<(Vec<u8>, V)> {
+        match self {
+            HeapOrStableMap::Map(map) => map.last_key_value().map(|(key, value)| (key.clone(), value.clone())),
+            HeapOrStableMap::StableBTreeMap(map) => map.last_key_value(),
+        }
+    }
+
+    /// All entries, in key order.
+    ///
+    /// Note: This reads the whole map, so is intended for migrations and tests.
+    #[must_use]
+    pub fn entries(&self) -> Vec<(Vec<u8>, V)> {
+        match self {
+            HeapOrStableMap::Map(map) => map.iter().map(|(key, value)| (key.clone(), value.clone())).collect(),
+            HeapOrStableMap::StableBTreeMap(map) => map.iter().collect(),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! A per-principal record of the outcomes of multi-part transactions.
+//!
+//! Multi-part transactions, such as creating a canister, are completed in the background.  The
+//! outcome of each is recorded here so that users can find out whether, and why, one failed.  Only
+//! the most recent outcomes are kept.
+use super::heap_or_stable_map::HeapOrStableMap;
+use super::schema::accounts_in_unbounded_stable_btree_map::ProductionMemoryType;
+use crate::multi_part_transactions_processor::MultiPartOperation;
+use candid::CandidType;
+use core::fmt;
+use ic_base_types::PrincipalId;
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+
+/// The maximum number of outcomes kept per principal.  When exceeded, the oldest are dropped.
+pub const MAX_OPERATION_OUTCOMES_PER_PRINCIPAL: usize = 50;
+
+/// The outcomes recorded for one principal, oldest first.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct OperationOutcomes {
+    outcomes: VecDeque<MultiPartOperation>,
+}
+
+impl Storable for OperationOutcomes {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        candid::encode_one(self)
+            .expect("Failed to serialize operation outcomes")
+            .into()
+    }
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to parse operation outcomes from store.")
+    }
+}
+
+/// Outcomes of multi-part transactions, keyed by the principal on whose behalf they were processed.
+#[derive(Default)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct OperationLog {
+    map: HeapOrStableMap<OperationOutcomes>,
+}
+
+impl fmt::Debug for OperationLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OperationLog{{{:?}}}", self.map)
+    }
+}
+
+impl OperationLog {
+    /// Creates a log in the given memory, or loads the log already stored there.
+    #[must_use]
+    pub fn init(memory: ProductionMemoryType) -> Self {
+        OperationLog {
+            map: HeapOrStableMap::init(memory),
+        }
+    }
+
+    /// Records the outcome of a multi-part transaction, dropping the oldest outcome if necessary.
+    pub fn record(&mut self, principal: PrincipalId, outcome: MultiPartOperation) {
+        let key = principal.as_slice();
+        let mut outcomes = self.map.get(key).unwrap_or_default();
+        outcomes.outcomes.push_back(outcome);
+        while outcomes.outcomes.len() > MAX_OPERATION_OUTCOMES_PER_PRINCIPAL {
+            outcomes.outcomes.pop_front();
+        }
+        self.map.insert(key, outcomes);
+    }
+
+    /// The outcomes recorded for a principal, newest first.
+    #[must_use]
+    pub fn get(&self, principal: PrincipalId) -> Vec<MultiPartOperation> {
+        self.map
+            .get(principal.as_slice())
+            .map(|outcomes| outcomes.outcomes.into_iter().rev().collect())
+            .unwrap_or_default()
+    }
+
+    /// Discards the outcomes recorded for a principal.
+    pub fn remove(&mut self, principal: PrincipalId) {
+        self.map.remove(principal.as_slice());
+    }
+}
@@ -6,6 +6,8 @@ pub mod map;
 pub mod proxy;
 
 // Mechanics
+pub mod account_links;
+pub mod compact_account;
 use crate::accounts_store::Account;
 use candid::{CandidType, Deserialize};
 use core::ops::RangeBounds;
@@ -53,6 +55,28 @@ pub trait AccountsDbTrait {
     /// Removes an account from the data store.
     fn db_remove_account(&mut self, account_key: &[u8]);
 
+    // Secondary index
+
+    /// Gets the keys of the accounts that a sub-account or hardware wallet belongs to.
+    ///
+    /// The index is maintained by `db_insert_account()` and `db_remove_account()`, as described
+    /// in [`account_links`].
+    ///
+    /// # Arguments
+    /// - `linked_key`: the account identifier of the sub-account or hardware wallet, as bytes.
+    fn db_get_linked_account_keys(&self, linked_key: &[u8]) -> Vec<Vec<u8>>;
+    /// Checks if a sub-account or hardware wallet belongs to any account in the data store.
+    fn db_contains_linked_account(&self, linked_key: &[u8]) -> bool {
+        !self.db_get_linked_account_keys(linked_key).is_empty()
+    }
+    /// Iterates over the entries of the secondary index in order, starting from `start`.
+    fn db_link_entries(&self, start: Vec<u8>) -> Box<dyn Iterator<Item = Vec<u8>> + '_>;
+    /// Removes an entry from the secondary index, leaving the accounts as they are.
+    ///
+    /// Note: The index is otherwise maintained by `db_insert_account()` and `db_remove_account()`.
+    /// This is for repairing entries that do not match any account.
+    fn db_remove_link_entry(&mut self, entry: &[u8]);
+
     // Statistics
 
     /// Returns the number of accounts in the data store.
@@ -127,6 +151,11 @@ pub enum SchemaLabel {
     /// data, mostly consisting of transactions, is serialized into a single large blob in the
     /// `pre_upgrade` hook.
     AccountsInStableMemory = 1,
+    /// As `AccountsInStableMemory`, but accounts are stored in a compact, versioned binary
+    /// encoding rather than as Candid.
+    /// Implemented by: [`accounts_in_unbounded_stable_btree_map::AccountsDbAsUnboundedStableBTreeMap`]
+    /// with [`compact_account::CompactAccount`] values.
+    CompactAccountsInStableMemory = 2,
 }
 
 impl Default for SchemaLabel {
@@ -0,0 +1,124 @@
+//! The secondary index from sub-accounts and hardware wallets to the accounts they belong to.
+//!
+//! Every `AccountsDbTrait` implementation keeps this index up to date as accounts are inserted
+//! and removed, deriving it from the sub-accounts and hardware wallets in each account.  Archived
+//! sub-accounts are not indexed.
+//!
+//! An index entry is the account identifier of a sub-account or hardware wallet followed by the
+//! key of an account it belongs to.  All the accounts that a sub-account or hardware wallet
+//! belongs to are therefore found in one range of entries.  A hardware wallet may be linked to
+//! several accounts.
+//!
+//! Accounts stored by releases that predate the index are indexed in steps, see
+//! [`super::proxy::AccountsDbAsProxy::step_migration`].  While that is in progress, the index
+//! contains the [`BUILD_MARKER`] entry.
+use super::Account;
+use std::collections::BTreeSet;
+
+/// An entry that is in the index while the index is being built.
+///
+/// Index entries are never empty, so the marker cannot be mistaken for one, and it sorts before
+/// every entry so it is never found when looking up a sub-account or hardware wallet.
+pub const BUILD_MARKER: Vec<u8> = Vec::new();
+
+/// The length of the account identifier of a sub-account or hardware wallet at the start of an entry.
+const LINKED_KEY_LEN: usize = 32;
+
+/// The index entries of an account.
+#[must_use]
+pub fn entries(account_key: &[u8], account: &Account) -> BTreeSet<Vec<u8>> {
+    account
+        .linked_account_identifiers()
+        .map(|linked| [&linked.to_vec()[..], account_key].concat())
+        .collect()
+}
+
+/// The index entries to remove and the index entries to add when the entries of an account
+/// change from `old` to `new`.
+#[must_use]
+pub fn changes(old: &BTreeSet<Vec<u8>>, new: &BTreeSet<Vec<u8>>) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    (
+        old.difference(new).cloned().collect(),
+        new.difference(old).cloned().collect(),
+    )
+}
+
+/// Extracts the keys of the accounts that a sub-account or hardware wallet belongs to.
+///
+/// # Arguments
+/// - `linked_key`: The account identifier of the sub-account or hardware wallet, as bytes.
+/// - `entries`: Index entries in order, starting from `linked_key`.
+#[must_use]
+pub fn account_keys(linked_key: &[u8], entries: impl Iterator<Item = Vec<u8>>) -> Vec<Vec<u8>> {
+    entries
+        .take_while(|entry| entry.starts_with(linked_key))
+        .map(|entry| entry[linked_key.len()..].to_vec())
+        .collect()
+}
+
+/// Extracts the key of the account from an index entry, or `None` if the entry is too short to
+/// have one.
+#[must_use]
+pub fn entry_account_key(entry: &[u8]) -> Option<&[u8]> {
+    entry
+        .get(LINKED_KEY_LEN..)
+        .filter(|account_key| !account_key.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::toy_account;
+    use super::*;
+    use crate::accounts_store::{AccountIdentifier, NamedHardwareWalletAccount, NamedSubAccount, PrincipalId};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn changes_should_cover_added_and_removed_links() {
+        let account_key = vec![7, 7];
+        let sub_account = AccountIdentifier::from(PrincipalId::new_user_test_id(100));
+        let hardware_wallet = PrincipalId::new_user_test_id(101);
+        let mut old = toy_account(1, 0);
+        old.sub_accounts
+            .insert(1, NamedSubAccount::new("sub".to_string(), sub_account));
+        let mut new = toy_account(1, 0);
+        new.hardware_wallet_accounts.push(NamedHardwareWalletAccount {
+            name: "hw".to_string(),
+            principal: hardware_wallet,
+        });
+
+        let sub_account_entry = [&sub_account.to_vec()[..], &account_key[..]].concat();
+        let hardware_wallet_entry = [&AccountIdentifier::from(hardware_wallet).to_vec()[..], &account_key[..]].concat();
+        let old = entries(&account_key, &old);
+        let new = entries(&account_key, &new);
+        assert_eq!(
+            changes(&old, &new),
+            (vec![sub_account_entry.clone()], vec![hardware_wallet_entry])
+        );
+        assert_eq!(changes(&BTreeSet::new(), &old), (vec![], vec![sub_account_entry]));
+        assert_eq!(changes(&old, &old), (vec![], vec![]));
+    }
+
+    #[test]
+    fn account_keys_should_stop_at_the_next_linked_account() {
+        let linked_key = vec![5; 32];
+        let entries = vec![
+            [&linked_key[..], &[1, 2][..]].concat(),
+            [&linked_key[..], &[3][..]].concat(),
+            [&[6; 32][..], &[4][..]].concat(),
+        ];
+        assert_eq!(
+            account_keys(&linked_key, entries.into_iter()),
+            vec![vec![1, 2], vec![3]]
+        );
+    }
+
+    #[test]
+    fn entry_account_key_should_follow_the_linked_key() {
+        assert_eq!(
+            entry_account_key(&[&[5; 32][..], &[1, 2][..]].concat()),
+            Some(&[1, 2][..])
+        );
+        assert_eq!(entry_account_key(&[5; 32]), None);
+        assert_eq!(entry_account_key(&BUILD_MARKER), None);
+    }
+}
@@ -4,98 +4,191 @@
 //! Data is stored in a [`ic_stable_structures::btreemap::BTreeMap`](https://docs.rs/ic-stable-structures/0.6.0/ic_stable_structures/btreemap/struct.BTreeMap.html)
 //! with values that are [`ic_stable_structures::storable::Bound::Unbounded`](https://docs.rs/ic-stable-structures/0.6.0/ic_stable_structures/storable/enum.Bound.html#variant.Unbounded)
 //! as described on the [dfinity forum](https://forum.dfinity.org/t/stable-structures-removing-the-bounded-size-requirement/21167).
+//! The secondary index from sub-accounts and hardware wallets to their accounts is kept in a
+//! second `StableBTreeMap`, in its own memory.
+//!
+//! Accounts are stored as Candid by default.  The `CompactAccountsInStableMemory` schema uses the
+//! same database with accounts stored as [`super::compact_account::CompactAccount`].
 
-use super::{Account, AccountsDbTrait};
+use super::{account_links, Account, AccountsDbTrait};
 use core::ops::RangeBounds;
 use ic_stable_structures::memory_manager::VirtualMemory;
 use ic_stable_structures::DefaultMemoryImpl;
-use ic_stable_structures::{btreemap::BTreeMap as StableBTreeMap, Memory};
+use ic_stable_structures::{btreemap::BTreeMap as StableBTreeMap, Memory, Storable};
 #[cfg(test)]
 use std::collections::BTreeMap as StdBTreeMap;
+use std::collections::BTreeSet;
 use std::fmt;
 
 pub type ProductionMemoryType = VirtualMemory<DefaultMemoryImpl>;
 
-pub struct AccountsDbAsUnboundedStableBTreeMap<M>
+pub struct AccountsDbAsUnboundedStableBTreeMap<M, V = Account>
 where
     M: Memory,
+    V: Storable + From<Account> + Into<Account>,
 {
-    accounts: StableBTreeMap<Vec<u8>, Account, M>,
+    accounts: StableBTreeMap<Vec<u8>, V, M>,
+    /// The secondary index from sub-accounts and hardware wallets to their accounts.
+    links: StableBTreeMap<Vec<u8>, (), M>,
+    /// The next account to index, while the secondary index is being built.
+    next_to_index: Vec<u8>,
 }
 
-impl<M> AccountsDbAsUnboundedStableBTreeMap<M>
+impl<M, V> AccountsDbAsUnboundedStableBTreeMap<M, V>
 where
     M: Memory,
+    V: Storable + From<Account> + Into<Account>,
 {
     /// Creates a new, empty database.
-    pub fn new(memory: M) -> Self {
+    pub fn new(memory: M, links_memory: M) -> Self {
         Self {
             accounts: StableBTreeMap::new(memory),
+            links: StableBTreeMap::new(links_memory),
+            next_to_index: Vec::new(),
         }
     }
     /// Loads a database.
-    pub fn load(memory: M) -> Self {
-        Self {
+    ///
+    /// Releases that predate the secondary index have no links memory.  In that case the index is
+    /// built from the accounts in steps, see `step_links_index`.  A build that was interrupted by
+    /// an upgrade starts again from the first account.
+    pub fn load(memory: M, links_memory: M) -> Self {
+        let links_are_new = links_memory.size() == 0;
+        let mut db = Self {
             accounts: StableBTreeMap::load(memory),
+            links: StableBTreeMap::init(links_memory),
+            next_to_index: Vec::new(),
+        };
+        if links_are_new {
+            db.links.insert(account_links::BUILD_MARKER, ());
+        }
+        db
+    }
+    /// Determines whether the secondary index is being built.
+    ///
+    /// Until it is built, accounts that have not been indexed yet are missing from lookups.
+    #[must_use]
+    pub fn links_index_in_progress(&self) -> bool {
+        self.links.contains_key(&account_links::BUILD_MARKER)
+    }
+    /// Indexes the next accounts, while the secondary index is being built.
+    ///
+    /// Accounts that are inserted or modified in the meantime are indexed as usual, so an account
+    /// that is indexed again gains no duplicate entries.
+    pub fn step_links_index(&mut self, step_size: u32) {
+        if !self.links_index_in_progress() {
+            return;
+        }
+        let (accounts, next_to_index) = {
+            let mut range = self.accounts.range(self.next_to_index.clone()..);
+            let accounts: Vec<_> = (&mut range)
+                .take(usize::try_from(step_size).unwrap_or(usize::MAX))
+                .collect();
+            (accounts, range.next().map(|(key, _account)| key))
+        };
+        for (account_key, account) in accounts {
+            for entry in account_links::entries(&account_key, &account.into()) {
+                self.links.insert(entry, ());
+            }
+        }
+        if let Some(next_to_index) = next_to_index {
+            self.next_to_index = next_to_index;
+        } else {
+            self.links.remove(&account_links::BUILD_MARKER);
+            self.next_to_index = Vec::new();
+        }
+    }
+    /// Updates the secondary index for an account whose index entries have changed.
+    fn update_links(&mut self, old: &BTreeSet<Vec<u8>>, new: &BTreeSet<Vec<u8>>) {
+        let (removed, added) = account_links::changes(old, new);
+        for entry in removed {
+            self.links.remove(&entry);
+        }
+        for entry in added {
+            self.links.insert(entry, ());
         }
     }
 }
 
 #[cfg(test)]
-impl Default for AccountsDbAsUnboundedStableBTreeMap<DefaultMemoryImpl> {
+impl<V> Default for AccountsDbAsUnboundedStableBTreeMap<DefaultMemoryImpl, V>
+where
+    V: Storable + From<Account> + Into<Account>,
+{
     fn default() -> Self {
-        Self::new(DefaultMemoryImpl::default())
+        Self::new(DefaultMemoryImpl::default(), DefaultMemoryImpl::default())
     }
 }
 
-impl<M> AccountsDbTrait for AccountsDbAsUnboundedStableBTreeMap<M>
+impl<M, V> AccountsDbTrait for AccountsDbAsUnboundedStableBTreeMap<M, V>
 where
     M: Memory,
+    V: Storable + From<Account> + Into<Account>,
 {
     fn db_insert_account(&mut self, account_key: &[u8], account: Account) {
-        self.accounts.insert(account_key.to_vec(), account);
+        let new_links = account_links::entries(account_key, &account);
+        let old_links = self
+            .accounts
+            .insert(account_key.to_vec(), V::from(account))
+            .map(|old| account_links::entries(account_key, &old.into()))
+            .unwrap_or_default();
+        self.update_links(&old_links, &new_links);
     }
     fn db_contains_account(&self, account_key: &[u8]) -> bool {
         self.accounts.contains_key(&account_key.to_vec())
     }
     fn db_get_account(&self, account_key: &[u8]) -> Option<Account> {
-        self.accounts.get(&account_key.to_vec()) // TODO: Change the trait to &Vec.
+        self.accounts.get(&account_key.to_vec()).map(Into::into) // TODO: Change the trait to &Vec.
     }
     fn db_remove_account(&mut self, account_key: &[u8]) {
-        self.accounts.remove(&account_key.to_vec());
+        if let Some(old) = self.accounts.remove(&account_key.to_vec()) {
+            self.update_links(&account_links::entries(account_key, &old.into()), &BTreeSet::new());
+        }
+    }
+    fn db_get_linked_account_keys(&self, linked_key: &[u8]) -> Vec<Vec<u8>> {
+        let entries = self.links.range(linked_key.to_vec()..).map(|(entry, ())| entry);
+        account_links::account_keys(linked_key, entries)
+    }
+    fn db_link_entries(&self, start: Vec<u8>) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+        Box::new(self.links.range(start..).map(|(entry, ())| entry))
+    }
+    fn db_remove_link_entry(&mut self, entry: &[u8]) {
+        self.links.remove(&entry.to_vec());
     }
     fn db_accounts_len(&self) -> u64 {
         self.accounts.len()
     }
     fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Account)> + '_> {
-        let iterator = self.accounts.iter();
+        let iterator = self.accounts.iter().map(|(key, value)| (key, value.into()));
         Box::new(iterator)
     }
     fn first_key_value(&self) -> Option<(Vec<u8>, Account)> {
-        self.accounts.first_key_value()
+        self.accounts.first_key_value().map(|(key, value)| (key, value.into()))
     }
     fn last_key_value(&self) -> Option<(Vec<u8>, Account)> {
-        self.accounts.last_key_value()
+        self.accounts.last_key_value().map(|(key, value)| (key, value.into()))
     }
     fn values(&self) -> Box<dyn Iterator<Item = Account> + '_> {
-        let iterator = self.accounts.iter().map(|(_key, value)| value);
+        let iterator = self.accounts.iter().map(|(_key, value)| value.into());
         Box::new(iterator)
     }
     fn range(&self, key_range: impl RangeBounds<Vec<u8>>) -> Box<dyn Iterator<Item = (Vec<u8>, Account)> + '_> {
-        let iterator = self.accounts.range(key_range);
+        let iterator = self.accounts.range(key_range).map(|(key, value)| (key, value.into()));
         Box::new(iterator)
     }
 }
 
-impl<M> fmt::Debug for AccountsDbAsUnboundedStableBTreeMap<M>
+impl<M, V> fmt::Debug for AccountsDbAsUnboundedStableBTreeMap<M, V>
 where
     M: Memory,
+    V: Storable + From<Account> + Into<Account>,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         write!(
             f,
-            "AccountsDbAsUnboundedStableBTreeMap {{ accounts: StableBTreeMap{{.. {} entries}} }}",
-            self.accounts.len()
+            "AccountsDbAsUnboundedStableBTreeMap {{ accounts: StableBTreeMap{{.. {} entries}}, links: StableBTreeMap{{.. {} entries}} }}",
+            self.accounts.len(),
+            self.links.len()
         )
     }
 }
@@ -107,10 +200,11 @@ mod tests {
     use super::*;
     use crate::accounts_store::schema::tests::toy_account;
     use crate::accounts_store::schema::AccountsDbTrait;
+    use crate::accounts_store::NamedSubAccount;
     use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
 
     // Test that the AccountsDbTrait implementation works.
-    test_accounts_db!(AccountsDbAsUnboundedStableBTreeMap::default());
+    test_accounts_db!(AccountsDbAsUnboundedStableBTreeMap::<_, Account>::default());
 
     #[test]
     fn should_be_able_to_load_existing_database() {
@@ -119,21 +213,77 @@ mod tests {
         raw_memory.grow(5);
         let memory_manager = MemoryManager::init(raw_memory);
         let random_memory_id = MemoryId::new(9);
+        let links_memory_id = MemoryId::new(10);
         // ... and some accounts to store.
         let accounts: StdBTreeMap<_, _> = vec![(b"key"[..].to_owned(), toy_account(1, 2))].into_iter().collect();
         // Store the accounts in a new database.
-        let mut new_db = AccountsDbAsUnboundedStableBTreeMap::new(memory_manager.get(random_memory_id));
+        let mut new_db = AccountsDbAsUnboundedStableBTreeMap::<_, Account>::new(
+            memory_manager.get(random_memory_id),
+            memory_manager.get(links_memory_id),
+        );
         for (key, account) in accounts.iter() {
             new_db.db_insert_account(&key, account.clone());
         }
         let new_accounts: StdBTreeMap<_, _> = new_db.range(..).collect();
         assert_eq!(accounts, new_accounts, "Failed to store accounts in new database.");
         // Load the accounts from a new database using the same memory.
-        let loaded_db = AccountsDbAsUnboundedStableBTreeMap::load(memory_manager.get(random_memory_id));
+        let loaded_db = AccountsDbAsUnboundedStableBTreeMap::<_, Account>::load(
+            memory_manager.get(random_memory_id),
+            memory_manager.get(links_memory_id),
+        );
         let loaded_accounts: StdBTreeMap<_, _> = loaded_db.range(..).collect();
         assert_eq!(
             new_accounts, loaded_accounts,
             "Failed to load accounts from existing stable memory."
         );
     }
+
+    #[test]
+    fn loading_a_database_without_links_should_index_its_accounts_in_steps() {
+        let raw_memory = DefaultMemoryImpl::default();
+        let memory_manager = MemoryManager::init(raw_memory);
+        // Store accounts with a release that predates the secondary index.
+        let mut accounts = StableBTreeMap::<Vec<u8>, Account, _>::new(memory_manager.get(MemoryId::new(9)));
+        let sub_accounts: Vec<_> = (0..3u8)
+            .map(|index| {
+                let mut account = toy_account(u64::from(index), 0);
+                let sub_account = toy_account(100 + u64::from(index), 0).account_identifier;
+                account
+                    .sub_accounts
+                    .insert(1, NamedSubAccount::new("sub".to_string(), sub_account));
+                accounts.insert(vec![index], account);
+                sub_account
+            })
+            .collect();
+        let indexed = |db: &AccountsDbAsUnboundedStableBTreeMap<_, Account>| {
+            sub_accounts
+                .iter()
+                .filter(|sub_account| db.db_contains_linked_account(&sub_account.to_vec()))
+                .count()
+        };
+        // Loading the database does not index the accounts straight away.
+        let mut loaded_db = AccountsDbAsUnboundedStableBTreeMap::load(
+            memory_manager.get(MemoryId::new(9)),
+            memory_manager.get(MemoryId::new(10)),
+        );
+        assert!(loaded_db.links_index_in_progress());
+        assert_eq!(indexed(&loaded_db), 0);
+        // Accounts are indexed a step at a time.
+        loaded_db.step_links_index(2);
+        assert!(loaded_db.links_index_in_progress());
+        assert_eq!(indexed(&loaded_db), 2);
+        loaded_db.step_links_index(2);
+        assert!(!loaded_db.links_index_in_progress());
+        assert_eq!(
+            loaded_db.db_get_linked_account_keys(&sub_accounts[2].to_vec()),
+            vec![vec![2]]
+        );
+        // The completed index is loaded as it is.
+        let reloaded_db = AccountsDbAsUnboundedStableBTreeMap::load(
+            memory_manager.get(MemoryId::new(9)),
+            memory_manager.get(MemoryId::new(10)),
+        );
+        assert!(!reloaded_db.links_index_in_progress());
+        assert_eq!(indexed(&reloaded_db), 3);
+    }
 }
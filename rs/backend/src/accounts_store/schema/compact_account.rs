@@ -0,0 +1,130 @@
+//! The account encoding of the `CompactAccountsInStableMemory` schema.
+//!
+//! Accounts in the `AccountsInStableMemory` schema are Candid encoded, so every record carries a
+//! type table with the hashes of all its field names, and every read has to parse that table.
+//! Here accounts are encoded as a version byte followed by CBOR in which struct fields are
+//! identified by their position.
+//!
+//! Apart from the encoding, the schema is the same as `AccountsInStableMemory`, so it is
+//! implemented by [`super::accounts_in_unbounded_stable_btree_map::AccountsDbAsUnboundedStableBTreeMap`]
+//! with `CompactAccount` values.
+use super::Account;
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+
+/// An account, as stored in the `CompactAccountsInStableMemory` schema.
+///
+/// Note: Struct fields are identified by position, so fields of the types stored in an account
+/// MUST NOT be reordered or removed, and new fields MUST be optional and appended.  Any other
+/// change needs a new encoding version.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompactAccount(pub Account);
+
+impl CompactAccount {
+    /// The version of the encoding written by this code.
+    pub const ENCODING_VERSION: u8 = 1;
+}
+
+impl From<Account> for CompactAccount {
+    fn from(account: Account) -> Self {
+        CompactAccount(account)
+    }
+}
+
+impl From<CompactAccount> for Account {
+    fn from(account: CompactAccount) -> Self {
+        account.0
+    }
+}
+
+impl Storable for CompactAccount {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut serializer = serde_cbor::Serializer::new(vec![Self::ENCODING_VERSION]).packed_format();
+        self.0.serialize(&mut serializer).expect("Failed to serialize account");
+        serializer.into_inner().into()
+    }
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        match bytes.split_first() {
+            Some((&Self::ENCODING_VERSION, encoded)) => {
+                CompactAccount(serde_cbor::from_slice(encoded).expect("Failed to parse account from store."))
+            }
+            other => panic!(
+                "Unsupported account encoding version: {:?}",
+                other.map(|(version, _encoded)| version)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::accounts_in_unbounded_stable_btree_map::AccountsDbAsUnboundedStableBTreeMap;
+    use super::super::tests::{test_accounts_db, toy_account};
+    use super::super::AccountsDbTrait;
+    use super::*;
+    use crate::accounts_store::NamedSubAccount;
+    use ic_stable_structures::btreemap::BTreeMap as StableBTreeMap;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use ic_stable_structures::DefaultMemoryImpl;
+    use pretty_assertions::assert_eq;
+
+    // Test that the AccountsDbTrait implementation works with compact accounts.
+    test_accounts_db!(AccountsDbAsUnboundedStableBTreeMap::<_, CompactAccount>::default());
+
+    #[test]
+    fn compact_encoding_should_round_trip() {
+        let account = toy_account(7, 3);
+        let bytes = CompactAccount(account.clone()).to_bytes();
+        assert_eq!(bytes[0], CompactAccount::ENCODING_VERSION);
+        assert_eq!(CompactAccount::from_bytes(bytes).0, account);
+    }
+
+    #[test]
+    fn compact_encoding_should_be_smaller_than_candid() {
+        for num_canisters in [0, 1, 10] {
+            let account = toy_account(1, num_canisters);
+            let candid_len = account.to_bytes().len();
+            let compact_len = CompactAccount(account).to_bytes().len();
+            assert!(
+                compact_len < candid_len,
+                "Compact encoding of an account with {num_canisters} canisters takes {compact_len} bytes; Candid takes {candid_len}."
+            );
+        }
+    }
+
+    #[test]
+    fn loading_a_database_without_links_should_index_its_compact_accounts_in_steps() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut account = toy_account(1, 0);
+        let sub_account = toy_account(2, 0).account_identifier;
+        account
+            .sub_accounts
+            .insert(1, NamedSubAccount::new("sub".to_string(), sub_account));
+        let mut accounts = StableBTreeMap::<Vec<u8>, CompactAccount, _>::new(memory_manager.get(MemoryId::new(0)));
+        accounts.insert(b"key".to_vec(), CompactAccount(account));
+
+        let mut loaded_db = AccountsDbAsUnboundedStableBTreeMap::<_, CompactAccount>::load(
+            memory_manager.get(MemoryId::new(0)),
+            memory_manager.get(MemoryId::new(1)),
+        );
+        assert!(loaded_db.links_index_in_progress());
+        assert!(!loaded_db.db_contains_linked_account(&sub_account.to_vec()));
+        while loaded_db.links_index_in_progress() {
+            loaded_db.step_links_index(1);
+        }
+        assert_eq!(
+            loaded_db.db_get_linked_account_keys(&sub_account.to_vec()),
+            vec![b"key".to_vec()]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported account encoding version")]
+    fn unknown_encoding_version_should_be_rejected() {
+        let mut bytes = CompactAccount(toy_account(1, 1)).to_bytes().into_owned();
+        bytes[0] = CompactAccount::ENCODING_VERSION + 1;
+        let _ = CompactAccount::from_bytes(Cow::Owned(bytes));
+    }
+}
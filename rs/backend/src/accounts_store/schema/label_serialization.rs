@@ -49,6 +49,7 @@ impl TryFrom<u32> for SchemaLabel {
         match value {
             0 => Ok(Self::Map),
             1 => Ok(Self::AccountsInStableMemory),
+            2 => Ok(Self::CompactAccountsInStableMemory),
             other => Err(SchemaLabelError::InvalidLabel(other)),
         }
     }
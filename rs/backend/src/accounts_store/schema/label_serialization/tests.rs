@@ -47,3 +47,12 @@ fn adequate_slice_should_succeed() {
         );
     }
 }
+
+#[test]
+fn every_label_should_round_trip() {
+    use strum::IntoEnumIterator;
+    for label in SchemaLabel::iter() {
+        let label_bytes = SchemaLabelBytes::from(label);
+        assert_eq!(Ok(label), SchemaLabel::try_from(&label_bytes));
+    }
+}
@@ -2,20 +2,39 @@
 
 #[cfg(test)]
 use super::AccountsDbBTreeMapTrait;
-use super::{Account, AccountsDbTrait};
+use super::{account_links, Account, AccountsDbTrait};
 use core::fmt;
 use core::ops::RangeBounds;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 #[derive(Default)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub struct AccountsDbAsMap {
     accounts: BTreeMap<Vec<u8>, Account>,
+    /// The secondary index from sub-accounts and hardware wallets to their accounts.
+    links: BTreeSet<Vec<u8>>,
+}
+
+impl AccountsDbAsMap {
+    /// Updates the secondary index for an account whose index entries have changed.
+    fn update_links(&mut self, old: &BTreeSet<Vec<u8>>, new: &BTreeSet<Vec<u8>>) {
+        let (removed, added) = account_links::changes(old, new);
+        for entry in removed {
+            self.links.remove(&entry);
+        }
+        self.links.extend(added);
+    }
 }
 
 impl AccountsDbTrait for AccountsDbAsMap {
     fn db_insert_account(&mut self, account_key: &[u8], account: Account) {
-        self.accounts.insert(account_key.to_vec(), account);
+        let new_links = account_links::entries(account_key, &account);
+        let old_links = self
+            .accounts
+            .insert(account_key.to_vec(), account)
+            .map(|old| account_links::entries(account_key, &old))
+            .unwrap_or_default();
+        self.update_links(&old_links, &new_links);
     }
     fn db_contains_account(&self, account_key: &[u8]) -> bool {
         self.accounts.contains_key(account_key)
@@ -24,7 +43,18 @@ impl AccountsDbTrait for AccountsDbAsMap {
         self.accounts.get(account_key).cloned()
     }
     fn db_remove_account(&mut self, account_key: &[u8]) {
-        self.accounts.remove(account_key);
+        if let Some(old) = self.accounts.remove(account_key) {
+            self.update_links(&account_links::entries(account_key, &old), &BTreeSet::new());
+        }
+    }
+    fn db_get_linked_account_keys(&self, linked_key: &[u8]) -> Vec<Vec<u8>> {
+        account_links::account_keys(linked_key, self.links.range(linked_key.to_vec()..).cloned())
+    }
+    fn db_link_entries(&self, start: Vec<u8>) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+        Box::new(self.links.range(start..).cloned())
+    }
+    fn db_remove_link_entry(&mut self, entry: &[u8]) {
+        self.links.remove(entry);
     }
     fn db_accounts_len(&self) -> u64 {
         self.accounts.len() as u64
@@ -60,7 +90,11 @@ impl AccountsDbTrait for AccountsDbAsMap {
 impl AccountsDbBTreeMapTrait for AccountsDbAsMap {
     #[cfg(test)]
     fn from_map(map: BTreeMap<Vec<u8>, Account>) -> Self {
-        Self { accounts: map }
+        let links = map
+            .iter()
+            .flat_map(|(account_key, account)| account_links::entries(account_key, account))
+            .collect();
+        Self { accounts: map, links }
     }
     #[cfg(test)]
     fn as_map(&self) -> &BTreeMap<Vec<u8>, Account> {
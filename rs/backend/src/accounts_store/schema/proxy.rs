@@ -2,12 +2,16 @@
 //!
 //! The proxy manages migrations from one implementation to another.
 use super::accounts_in_unbounded_stable_btree_map::{AccountsDbAsUnboundedStableBTreeMap, ProductionMemoryType};
-use super::{map::AccountsDbAsMap, Account, AccountsDbTrait};
+use super::compact_account::CompactAccount;
+use super::{map::AccountsDbAsMap, Account, AccountsDbTrait, SchemaLabel};
 use core::fmt;
 use core::ops::RangeBounds;
+use registry::RegisteredMigration;
 
 mod enum_boilerplate;
 mod migration;
+pub mod registry;
+pub use migration::{MigrationError, MigrationProgress, MigrationStatus, SavedMigration, SavedMigrations};
 
 /// An accounts database delegates API calls to underlying implementations.
 ///
@@ -17,6 +21,7 @@ mod migration;
 ///   use and how to migrate from one database to another.
 /// - It is the responsibility of the post-install hook to look at any
 ///   version information and set up the db accordingly.
+/// - The migrations between schemas are listed in the [`registry`].
 ///
 /// # Current data storage
 /// - Accounts are stored as a map.  No migrations are undertaken.
@@ -24,6 +29,13 @@ mod migration;
 pub struct AccountsDbAsProxy {
     authoritative_db: AccountsDb,
     migration: Option<Migration>,
+    /// The schema that the migration in progress, and any registered migrations following it,
+    /// lead to.  Cleared once it is reached or a migration fails.
+    migration_target: Option<SchemaLabel>,
+    /// The most recent migrations that have been completed, aborted or rolled back, oldest first.
+    finished_migrations: Vec<MigrationProgress>,
+    /// The schema of the authoritative database when it was loaded, before any migrations completed since.
+    loaded_schema_label: SchemaLabel,
 }
 
 impl Default for AccountsDbAsProxy {
@@ -33,25 +45,32 @@ impl Default for AccountsDbAsProxy {
 }
 
 struct Migration {
+    /// The registered migration being performed.
+    registered: &'static RegisteredMigration,
     /// The database being migrated to
     db: AccountsDb,
     /// The next account to migrate.
     next_to_migrate: Option<Vec<u8>>,
+    /// The number of accounts copied by migration steps so far.
+    accounts_migrated: u64,
 }
 
 impl fmt::Debug for Migration {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // Note: The `next_to_migrate` field is rarely interesting so is omitted.
-        //       The database type and number of entries suffices.
-        self.db.fmt(f)
+        //       The schemas, database type and number of entries suffice.
+        write!(f, "{:?}: {:?}", self.registered, self.db)
     }
 }
 
 impl From<AccountsDb> for AccountsDbAsProxy {
     fn from(db: AccountsDb) -> Self {
         AccountsDbAsProxy {
+            loaded_schema_label: db.schema_label(),
             authoritative_db: db,
             migration: None,
+            migration_target: None,
+            finished_migrations: Vec::new(),
         }
     }
 }
@@ -60,14 +79,69 @@ impl From<AccountsDb> for AccountsDbAsProxy {
 pub enum AccountsDb {
     Map(AccountsDbAsMap),
     UnboundedStableBTreeMap(AccountsDbAsUnboundedStableBTreeMap<ProductionMemoryType>),
+    CompactStableBTreeMap(AccountsDbAsUnboundedStableBTreeMap<ProductionMemoryType, CompactAccount>),
+}
+
+impl AccountsDb {
+    /// The schema implemented by the database.
+    #[must_use]
+    pub fn schema_label(&self) -> SchemaLabel {
+        match self {
+            AccountsDb::Map(_) => SchemaLabel::Map,
+            AccountsDb::UnboundedStableBTreeMap(_) => SchemaLabel::AccountsInStableMemory,
+            AccountsDb::CompactStableBTreeMap(_) => SchemaLabel::CompactAccountsInStableMemory,
+        }
+    }
+    /// Determines whether the index of sub-accounts and hardware wallets is being built.
+    #[must_use]
+    pub fn links_index_in_progress(&self) -> bool {
+        match self {
+            AccountsDb::Map(_) => false,
+            AccountsDb::UnboundedStableBTreeMap(db) => db.links_index_in_progress(),
+            AccountsDb::CompactStableBTreeMap(db) => db.links_index_in_progress(),
+        }
+    }
+    /// Indexes the sub-accounts and hardware wallets of the next accounts, while the index is being built.
+    pub fn step_links_index(&mut self, step_size: u32) {
+        match self {
+            AccountsDb::Map(_) => {}
+            AccountsDb::UnboundedStableBTreeMap(db) => db.step_links_index(step_size),
+            AccountsDb::CompactStableBTreeMap(db) => db.step_links_index(step_size),
+        }
+    }
+}
+
+impl AccountsDbAsProxy {
+    /// The schema of the authoritative database.
+    #[must_use]
+    pub fn schema_label(&self) -> SchemaLabel {
+        self.authoritative_db.schema_label()
+    }
+    /// The schema of the authoritative database when it was loaded, before any migrations completed since.
+    #[must_use]
+    pub fn loaded_schema_label(&self) -> SchemaLabel {
+        self.loaded_schema_label
+    }
+    /// Determines whether the index of sub-accounts and hardware wallets in the authoritative
+    /// database is being built.
+    ///
+    /// Note: The database being migrated to builds its own index as accounts are copied to it.
+    #[must_use]
+    pub fn links_index_in_progress(&self) -> bool {
+        self.authoritative_db.links_index_in_progress()
+    }
 }
 
 impl AccountsDbTrait for AccountsDbAsProxy {
     /// Inserts into all the underlying databases.
+    ///
+    /// The database being migrated to receives the account as transformed by the migration.
     fn db_insert_account(&mut self, account_key: &[u8], account: Account) {
         self.authoritative_db.db_insert_account(account_key, account.clone());
         if let Some(migration) = &mut self.migration {
-            migration.db.db_insert_account(account_key, account);
+            migration
+                .db
+                .db_insert_account(account_key, (migration.registered.transform)(account));
         }
     }
     /// Checks the authoritative database.
@@ -85,6 +159,23 @@ impl AccountsDbTrait for AccountsDbAsProxy {
             migration.db.db_remove_account(account_key);
         }
     }
+    /// Looks up the linked accounts in the authoritative database.
+    ///
+    /// The database being migrated to builds its own index as accounts are copied to it.
+    fn db_get_linked_account_keys(&self, linked_key: &[u8]) -> Vec<Vec<u8>> {
+        self.authoritative_db.db_get_linked_account_keys(linked_key)
+    }
+    /// Iterates over the index of the authoritative database.
+    fn db_link_entries(&self, start: Vec<u8>) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+        self.authoritative_db.db_link_entries(start)
+    }
+    /// Removes an index entry from all underlying databases.
+    fn db_remove_link_entry(&mut self, entry: &[u8]) {
+        self.authoritative_db.db_remove_link_entry(entry);
+        if let Some(migration) = self.migration.as_mut() {
+            migration.db.db_remove_link_entry(entry);
+        }
+    }
     /// Gets the length from the authoritative database.
     fn db_accounts_len(&self) -> u64 {
         self.authoritative_db.db_accounts_len()
@@ -122,3 +213,11 @@ impl PartialEq for AccountsDbAsProxy {
 }
 #[cfg(test)]
 impl Eq for AccountsDbAsProxy {}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::test_accounts_db;
+    use super::AccountsDbAsProxy;
+
+    test_accounts_db!(AccountsDbAsProxy::default());
+}
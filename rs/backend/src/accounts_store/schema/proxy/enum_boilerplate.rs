@@ -11,6 +11,9 @@ impl AccountsDbTrait for AccountsDb {
             AccountsDb::UnboundedStableBTreeMap(unbounded_stable_btree_map_db) => {
                 unbounded_stable_btree_map_db.db_insert_account(account_key, account);
             }
+            AccountsDb::CompactStableBTreeMap(compact_stable_btree_map_db) => {
+                compact_stable_btree_map_db.db_insert_account(account_key, account);
+            }
         }
     }
     fn db_contains_account(&self, account_key: &[u8]) -> bool {
@@ -19,6 +22,9 @@ impl AccountsDbTrait for AccountsDb {
             AccountsDb::UnboundedStableBTreeMap(unbounded_stable_btree_map_db) => {
                 unbounded_stable_btree_map_db.db_contains_account(account_key)
             }
+            AccountsDb::CompactStableBTreeMap(compact_stable_btree_map_db) => {
+                compact_stable_btree_map_db.db_contains_account(account_key)
+            }
         }
     }
     fn db_get_account(&self, account_key: &[u8]) -> Option<Account> {
@@ -27,6 +33,9 @@ impl AccountsDbTrait for AccountsDb {
             AccountsDb::UnboundedStableBTreeMap(unbounded_stable_btree_map_db) => {
                 unbounded_stable_btree_map_db.db_get_account(account_key)
             }
+            AccountsDb::CompactStableBTreeMap(compact_stable_btree_map_db) => {
+                compact_stable_btree_map_db.db_get_account(account_key)
+            }
         }
     }
     fn db_remove_account(&mut self, account_key: &[u8]) {
@@ -35,6 +44,42 @@ impl AccountsDbTrait for AccountsDb {
             AccountsDb::UnboundedStableBTreeMap(unbounded_stable_btree_map_db) => {
                 unbounded_stable_btree_map_db.db_remove_account(account_key);
             }
+            AccountsDb::CompactStableBTreeMap(compact_stable_btree_map_db) => {
+                compact_stable_btree_map_db.db_remove_account(account_key);
+            }
+        }
+    }
+    fn db_get_linked_account_keys(&self, linked_key: &[u8]) -> Vec<Vec<u8>> {
+        match self {
+            AccountsDb::Map(map_db) => map_db.db_get_linked_account_keys(linked_key),
+            AccountsDb::UnboundedStableBTreeMap(unbounded_stable_btree_map_db) => {
+                unbounded_stable_btree_map_db.db_get_linked_account_keys(linked_key)
+            }
+            AccountsDb::CompactStableBTreeMap(compact_stable_btree_map_db) => {
+                compact_stable_btree_map_db.db_get_linked_account_keys(linked_key)
+            }
+        }
+    }
+    fn db_link_entries(&self, start: Vec<u8>) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+        match self {
+            AccountsDb::Map(map_db) => map_db.db_link_entries(start),
+            AccountsDb::UnboundedStableBTreeMap(unbounded_stable_btree_map_db) => {
+                unbounded_stable_btree_map_db.db_link_entries(start)
+            }
+            AccountsDb::CompactStableBTreeMap(compact_stable_btree_map_db) => {
+                compact_stable_btree_map_db.db_link_entries(start)
+            }
+        }
+    }
+    fn db_remove_link_entry(&mut self, entry: &[u8]) {
+        match self {
+            AccountsDb::Map(map_db) => map_db.db_remove_link_entry(entry),
+            AccountsDb::UnboundedStableBTreeMap(unbounded_stable_btree_map_db) => {
+                unbounded_stable_btree_map_db.db_remove_link_entry(entry);
+            }
+            AccountsDb::CompactStableBTreeMap(compact_stable_btree_map_db) => {
+                compact_stable_btree_map_db.db_remove_link_entry(entry);
+            }
         }
     }
     fn db_accounts_len(&self) -> u64 {
@@ -43,12 +88,16 @@ impl AccountsDbTrait for AccountsDb {
             AccountsDb::UnboundedStableBTreeMap(unbounded_stable_btree_map_db) => {
                 unbounded_stable_btree_map_db.db_accounts_len()
             }
+            AccountsDb::CompactStableBTreeMap(compact_stable_btree_map_db) => {
+                compact_stable_btree_map_db.db_accounts_len()
+            }
         }
     }
     fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Account)> + '_> {
         match self {
             AccountsDb::Map(map_db) => map_db.iter(),
             AccountsDb::UnboundedStableBTreeMap(unbounded_stable_btree_map_db) => unbounded_stable_btree_map_db.iter(),
+            AccountsDb::CompactStableBTreeMap(compact_stable_btree_map_db) => compact_stable_btree_map_db.iter(),
         }
     }
     fn first_key_value(&self) -> Option<(Vec<u8>, Account)> {
@@ -57,6 +106,9 @@ impl AccountsDbTrait for AccountsDb {
             AccountsDb::UnboundedStableBTreeMap(unbounded_stable_btree_map_db) => {
                 unbounded_stable_btree_map_db.first_key_value()
             }
+            AccountsDb::CompactStableBTreeMap(compact_stable_btree_map_db) => {
+                compact_stable_btree_map_db.first_key_value()
+            }
         }
     }
     fn last_key_value(&self) -> Option<(Vec<u8>, Account)> {
@@ -65,6 +117,9 @@ impl AccountsDbTrait for AccountsDb {
             AccountsDb::UnboundedStableBTreeMap(unbounded_stable_btree_map_db) => {
                 unbounded_stable_btree_map_db.last_key_value()
             }
+            AccountsDb::CompactStableBTreeMap(compact_stable_btree_map_db) => {
+                compact_stable_btree_map_db.last_key_value()
+            }
         }
     }
     fn range(&self, key_range: impl RangeBounds<Vec<u8>>) -> Box<dyn Iterator<Item = (Vec<u8>, Account)> + '_> {
@@ -73,6 +128,9 @@ impl AccountsDbTrait for AccountsDb {
             AccountsDb::UnboundedStableBTreeMap(unbounded_stable_btree_map_db) => {
                 unbounded_stable_btree_map_db.range(key_range)
             }
+            AccountsDb::CompactStableBTreeMap(compact_stable_btree_map_db) => {
+                compact_stable_btree_map_db.range(key_range)
+            }
         }
     }
 }
@@ -1,6 +1,87 @@
 //! Code for migration from the authoritative database to a new database.
-use super::{AccountsDbAsProxy, AccountsDbTrait};
+use super::registry::RegisteredMigration;
+use super::{AccountsDb, AccountsDbAsProxy, AccountsDbTrait, Migration, SchemaLabel};
+use candid::{CandidType, Deserialize};
 use ic_cdk::{eprintln, println};
+use ic_stable_structures::{storable::Bound, Storable};
+use std::borrow::Cow;
+
+#[cfg(test)]
+mod tests;
+
+/// How far a migration has got.
+#[derive(Clone, Debug, Eq, PartialEq, CandidType, Deserialize)]
+pub enum MigrationStatus {
+    /// Accounts are being copied to the new database.
+    InProgress,
+    /// The new database has been made authoritative.
+    Completed,
+    /// A sanity check failed when finalizing the migration, so the new database was discarded.
+    Aborted { reason: String },
+    /// The migration was rolled back before it was finalized, so the new database was discarded.
+    RolledBack,
+}
+
+/// Progress report for one migration.
+#[derive(Clone, Debug, Eq, PartialEq, CandidType, Deserialize)]
+pub struct MigrationProgress {
+    pub from: SchemaLabel,
+    pub to: SchemaLabel,
+    pub status: MigrationStatus,
+    /// The number of accounts copied to the new database by migration steps.
+    pub accounts_migrated: u64,
+    /// The number of accounts in the authoritative database.
+    pub accounts_total: u64,
+}
+
+/// The state of the accounts migrations that is kept across upgrades.
+#[derive(Clone, Debug, Default, Eq, PartialEq, CandidType, Deserialize)]
+pub struct SavedMigrations {
+    /// The migration in progress, if any.
+    pub in_progress: Option<SavedMigration>,
+    /// The schema that the migration in progress, and any registered migrations following it, lead to.
+    pub target: Option<SchemaLabel>,
+    /// The most recent migrations that are no longer in progress, oldest first.
+    pub finished: Vec<MigrationProgress>,
+}
+
+/// A migration in progress, as kept across upgrades.
+///
+/// The database being migrated to is kept in the stable memory partitions of its schema.
+#[derive(Clone, Debug, Eq, PartialEq, CandidType, Deserialize)]
+pub struct SavedMigration {
+    pub from: SchemaLabel,
+    pub to: SchemaLabel,
+    /// The next account to migrate.
+    pub next_to_migrate: Option<Vec<u8>>,
+    /// The number of accounts copied by migration steps so far.
+    pub accounts_migrated: u64,
+}
+
+impl Storable for SavedMigrations {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        candid::encode_one(self).expect("Failed to serialize migrations").into()
+    }
+    /// Parse the migrations.  On error, return a blank new structure: a migration in progress is
+    /// then started afresh, if it is still requested.
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap_or_default()
+    }
+}
+
+/// Reasons why a migration cannot be started or rolled back.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MigrationError {
+    /// Only one migration may be in progress at a time.
+    MigrationInProgress,
+    /// No registered migration leads directly from the authoritative schema to the requested schema.
+    NoMigrationPath { from: SchemaLabel, to: SchemaLabel },
+    /// The database being migrated to must start out empty.
+    TargetNotEmpty,
+    /// There is no migration to roll back.
+    NoMigrationInProgress,
+}
 
 impl AccountsDbAsProxy {
     /// The default number of accounts to move in a migration step.
@@ -9,6 +90,8 @@ impl AccountsDbAsProxy {
     pub const MIGRATION_STEP_SIZE_MAX: u32 = 1000;
     /// The progress meter count reserved for finalizing a migration.
     pub const MIGRATION_FINALIZATION_BLOCKS: u32 = 1;
+    /// The maximum number of finished migrations to report.  When exceeded, the oldest are dropped.
+    pub const MAX_FINISHED_MIGRATIONS: usize = 10;
 
     /// Determines whether a migration is in progress.
     #[must_use]
@@ -16,6 +99,158 @@ impl AccountsDbAsProxy {
         self.migration.is_some()
     }
 
+    /// Starts migrating the accounts to the given, empty, database.
+    ///
+    /// The migration is looked up in the registry by the schemas of the authoritative and new
+    /// databases.  Migrations that span several schemas are performed one at a time, in the order
+    /// given by [`RegisteredMigration::path`], towards the `migration_target`.
+    ///
+    /// # Errors
+    /// - If a migration is already in progress.
+    /// - If there is no registered migration between the two schemas.
+    /// - If the new database is not empty.
+    pub fn start_migration(&mut self, db: AccountsDb) -> Result<(), MigrationError> {
+        let from = self.authoritative_db.schema_label();
+        let to = db.schema_label();
+        let registered = RegisteredMigration::find(from, to).ok_or(MigrationError::NoMigrationPath { from, to })?;
+        self.start_registered_migration(db, registered)
+    }
+
+    /// Starts the given migration to the given, empty, database.
+    fn start_registered_migration(
+        &mut self,
+        db: AccountsDb,
+        registered: &'static RegisteredMigration,
+    ) -> Result<(), MigrationError> {
+        if self.migration.is_some() {
+            return Err(MigrationError::MigrationInProgress);
+        }
+        if db.db_accounts_len() != 0 {
+            return Err(MigrationError::TargetNotEmpty);
+        }
+        println!(
+            "Starting migration {registered:?}: {:?} -> {db:?}",
+            self.authoritative_db
+        );
+        self.migration = Some(Migration {
+            registered,
+            db,
+            next_to_migrate: self.authoritative_db.first_key_value().map(|(key, _account)| key),
+            accounts_migrated: 0,
+        });
+        Ok(())
+    }
+
+    /// Abandons a migration that has not been finalized.
+    ///
+    /// The authoritative database is unaffected; the partially filled new database is discarded.
+    ///
+    /// # Errors
+    /// - If there is no migration in progress.
+    pub fn rollback_migration(&mut self) -> Result<(), MigrationError> {
+        let migration = self.migration.take().ok_or(MigrationError::NoMigrationInProgress)?;
+        println!("Rolling back migration {migration:?}");
+        self.finish_migration(&migration, MigrationStatus::RolledBack);
+        Ok(())
+    }
+
+    /// The schema that the migration in progress, and any registered migrations following it, lead to.
+    #[must_use]
+    pub fn migration_target(&self) -> Option<SchemaLabel> {
+        self.migration_target
+    }
+
+    /// Sets the schema that migrations are to lead to.
+    ///
+    /// The migrations themselves are started by the caller, one at a time, as each needs a new database.
+    pub fn set_migration_target(&mut self, target: Option<SchemaLabel>) {
+        self.migration_target = target;
+    }
+
+    /// The state of the migrations, to be kept across upgrades.
+    #[must_use]
+    pub fn saved_migrations(&self) -> SavedMigrations {
+        SavedMigrations {
+            in_progress: self.migration.as_ref().map(|migration| SavedMigration {
+                from: migration.registered.from,
+                to: migration.registered.to,
+                next_to_migrate: migration.next_to_migrate.clone(),
+                accounts_migrated: migration.accounts_migrated,
+            }),
+            target: self.migration_target,
+            finished: self.finished_migrations.clone(),
+        }
+    }
+
+    /// Restores the state of the migrations after an upgrade.
+    ///
+    /// The migration that was in progress continues with the given database, which holds the
+    /// accounts it has copied so far.  If the migration no longer matches the authoritative
+    /// database or the registry, it is dropped, so that it is started afresh if still requested.
+    pub fn restore_migrations(&mut self, saved: SavedMigrations, db: Option<AccountsDb>) {
+        self.finished_migrations = saved.finished;
+        self.migration_target = saved.target;
+        let (Some(in_progress), Some(db)) = (saved.in_progress, db) else {
+            return;
+        };
+        let registered = RegisteredMigration::find(in_progress.from, in_progress.to);
+        match registered {
+            Some(registered)
+                if registered.from == self.authoritative_db.schema_label() && registered.to == db.schema_label() =>
+            {
+                println!(
+                    "Resuming migration {registered:?}: {:?} -> {db:?}",
+                    self.authoritative_db
+                );
+                self.migration = Some(Migration {
+                    registered,
+                    db,
+                    next_to_migrate: in_progress.next_to_migrate,
+                    accounts_migrated: in_progress.accounts_migrated,
+                });
+            }
+            _ => {
+                println!("WARNING: Dropping migration {in_progress:?} saved before the upgrade.");
+                self.migration_target = None;
+            }
+        }
+    }
+
+    /// Progress reports for the most recent migrations, oldest first, followed by the migration in
+    /// progress, if any.
+    #[must_use]
+    pub fn migration_progress(&self) -> Vec<MigrationProgress> {
+        let in_progress = self
+            .migration
+            .as_ref()
+            .map(|migration| self.progress_of(migration, MigrationStatus::InProgress));
+        self.finished_migrations.iter().cloned().chain(in_progress).collect()
+    }
+
+    fn progress_of(&self, migration: &Migration, status: MigrationStatus) -> MigrationProgress {
+        MigrationProgress {
+            from: migration.registered.from,
+            to: migration.registered.to,
+            status,
+            accounts_migrated: migration.accounts_migrated,
+            accounts_total: self.authoritative_db.db_accounts_len(),
+        }
+    }
+
+    /// Records the outcome of a migration that is no longer in progress.
+    ///
+    /// The migration target is given up on if the migration did not complete, and cleared once reached.
+    fn finish_migration(&mut self, migration: &Migration, status: MigrationStatus) {
+        if status != MigrationStatus::Completed || self.migration_target == Some(migration.registered.to) {
+            self.migration_target = None;
+        }
+        let progress = self.progress_of(migration, status);
+        self.finished_migrations.push(progress);
+        if self.finished_migrations.len() > Self::MAX_FINISHED_MIGRATIONS {
+            self.finished_migrations.remove(0);
+        }
+    }
+
     /// Migration countdown; when it reaches zero, the migration is complete.
     ///
     /// Note: This is a rough estimate of the number of blocks needed to complete the migration.
@@ -41,18 +276,33 @@ impl AccountsDbAsProxy {
 
     /// Advances the migration by one step.
     ///
+    /// Each step continues from where the previous step stopped, so a step that fails and is
+    /// rolled back by the runtime is simply retried.  Accounts are passed through the migration's
+    /// transform as they are copied.
+    ///
+    /// If the index of sub-accounts and hardware wallets in the authoritative database is being
+    /// built, the step indexes accounts instead.  The migration continues once the index is complete.
+    ///
     /// # Arguments
     /// - `step_size`: The maximum number of accounts to migrate on this step.
     ///   - This may be no larger than `Self::MIGRATION_STEP_SIZE_MAX`.  If it is larger, it will be reduced.
     pub fn step_migration(&mut self, step_size: u32) {
         // Ensure that the step size is modest:
         let step_size = step_size.clamp(1, Self::MIGRATION_STEP_SIZE_MAX);
+        if self.authoritative_db.links_index_in_progress() {
+            println!("Stepping the index of linked accounts: {:?}", self.authoritative_db);
+            self.authoritative_db.step_links_index(step_size);
+            return;
+        }
         if let Some(migration) = &mut self.migration {
             if let Some(next_to_migrate) = &migration.next_to_migrate {
                 println!("Stepping migration: {:?} -> {:?}", self.authoritative_db, migration.db);
                 let mut range = self.authoritative_db.range(next_to_migrate.clone()..);
                 for (key, account) in (&mut range).take(usize::try_from(step_size).unwrap_or(usize::MAX)) {
-                    migration.db.db_insert_account(&key, account);
+                    migration
+                        .db
+                        .db_insert_account(&key, (migration.registered.transform)(account));
+                    migration.accounts_migrated += 1;
                 }
                 migration.next_to_migrate = range.next().map(|(key, _account)| key.clone());
             } else {
@@ -65,49 +315,62 @@ impl AccountsDbAsProxy {
     ///
     /// The migration will be cancelled, instead of completed, if an invariant check fails:
     ///     - The old and new databases have different lengths.
+    ///     - The first or last account in the new database differs from the transformed account
+    ///       in the old database.
     ///     - (More checks MAY be added in future.)
     pub fn complete_migration(&mut self) {
         if let Some(migration) = self.migration.take() {
-            // Sanity check before calling migration complete:
-            {
-                // Number of accounts should be the same:
-                let old = self.authoritative_db.db_accounts_len();
-                let new = migration.db.db_accounts_len();
-                if old != new {
-                    eprintln!("MIGRATION ERROR: Account migration failed: Old and new account databases have different lengths: {old} -> {new}\n Migration will be aborted.");
-                    return;
-                }
-            }
-            {
-                // The first account in the BTreeMap should be the same.
-                // Given that keys are random this effectively a random account.
-                //
-                // Note: IF the migration intentionally modifies accounts, this check will have to be adjusted to compare just the invariant aspects of the account.
-                let old = self.authoritative_db.first_key_value();
-                let new = migration.db.first_key_value();
-                if old != new {
-                    eprintln!("MIGRATION ERROR: Old and new account databases have different first entries: {old:?} -> {new:?}\n Migration will be aborted.");
-                    return;
-                }
-            }
-            {
-                // The last account in the BTreeMap should be the same.
-                // Given that keys are random this effectively a random account.
-                //
-                // Note: IF the migration intentionally modifies accounts, this check will have to be adjusted to compare just the invariant aspects of the account.
-                let old = self.authoritative_db.last_key_value();
-                let new = migration.db.last_key_value();
-                if old != new {
-                    eprintln!("MIGRATION ERROR: Old and new account databases have different last entries: {old:?} -> {new:?}\n Migration will be aborted.");
-                    return;
-                }
+            if let Err(reason) = self.check_migration(&migration) {
+                eprintln!("MIGRATION ERROR: {reason}\n Migration will be aborted.");
+                self.finish_migration(&migration, MigrationStatus::Aborted { reason });
+                return;
             }
             // Sanity checks passed.  Make the new database authoritative:
             println!(
                 "Account migration complete: {:?} -> {:?}",
                 self.authoritative_db, migration.db
             );
+            self.finish_migration(&migration, MigrationStatus::Completed);
             self.authoritative_db = migration.db;
         }
     }
+
+    /// Sanity checks before calling a migration complete.
+    fn check_migration(&self, migration: &Migration) -> Result<(), String> {
+        let transform =
+            |entry: Option<(Vec<u8>, _)>| entry.map(|(key, account)| (key, (migration.registered.transform)(account)));
+        {
+            // Number of accounts should be the same:
+            let old = self.authoritative_db.db_accounts_len();
+            let new = migration.db.db_accounts_len();
+            if old != new {
+                return Err(format!(
+                    "Account migration failed: Old and new account databases have different lengths: {old} -> {new}"
+                ));
+            }
+        }
+        {
+            // The first account in the BTreeMap should be the same, after the transformation.
+            // Given that keys are random this effectively a random account.
+            let old = transform(self.authoritative_db.first_key_value());
+            let new = migration.db.first_key_value();
+            if old != new {
+                return Err(format!(
+                    "Old and new account databases have different first entries: {old:?} -> {new:?}"
+                ));
+            }
+        }
+        {
+            // The last account in the BTreeMap should be the same, after the transformation.
+            // Given that keys are random this effectively a random account.
+            let old = transform(self.authoritative_db.last_key_value());
+            let new = migration.db.last_key_value();
+            if old != new {
+                return Err(format!(
+                    "Old and new account databases have different last entries: {old:?} -> {new:?}"
+                ));
+            }
+        }
+        Ok(())
+    }
 }
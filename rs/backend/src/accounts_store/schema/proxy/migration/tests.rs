@@ -0,0 +1,265 @@
+use super::super::registry::RegisteredMigration;
+use super::*;
+use crate::accounts_store::schema::accounts_in_unbounded_stable_btree_map::AccountsDbAsUnboundedStableBTreeMap;
+use crate::accounts_store::schema::map::AccountsDbAsMap;
+use crate::accounts_store::schema::tests::toy_account;
+use crate::accounts_store::Account;
+use crate::state::partitions::{PartitionType, Partitions};
+use ic_stable_structures::DefaultMemoryImpl;
+use pretty_assertions::assert_eq;
+
+/// A migration that rewrites accounts, to check that transforms are applied.
+static DROP_CANISTERS: RegisteredMigration = RegisteredMigration {
+    from: SchemaLabel::Map,
+    to: SchemaLabel::Map,
+    transform: without_canisters,
+};
+
+fn without_canisters(mut account: Account) -> Account {
+    account.canisters.clear();
+    account
+}
+
+fn account_key(account_index: u64) -> Vec<u8> {
+    vec![account_index as u8, 1, 2, 3]
+}
+
+/// Creates a proxy backed by a map with the given number of accounts.
+fn proxy_with_accounts(num_accounts: u64) -> AccountsDbAsProxy {
+    let mut proxy = AccountsDbAsProxy::default();
+    for account_index in 0..num_accounts {
+        proxy.db_insert_account(&account_key(account_index), toy_account(account_index, 2));
+    }
+    proxy
+}
+
+fn stable_db() -> AccountsDb {
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    AccountsDb::UnboundedStableBTreeMap(AccountsDbAsUnboundedStableBTreeMap::new(
+        partitions.get(PartitionType::Accounts.memory_id()),
+        partitions.get(PartitionType::AccountLinks.memory_id()),
+    ))
+}
+
+fn run_to_completion(proxy: &mut AccountsDbAsProxy) {
+    while proxy.migration_in_progress() {
+        proxy.step_migration(3);
+    }
+}
+
+#[test]
+fn registered_migrations_form_a_path_from_the_first_schema() {
+    let path = RegisteredMigration::path(SchemaLabel::Map, SchemaLabel::CompactAccountsInStableMemory).unwrap();
+    assert_eq!(
+        path.iter()
+            .map(|migration| (migration.from, migration.to))
+            .collect::<Vec<_>>(),
+        vec![
+            (SchemaLabel::Map, SchemaLabel::AccountsInStableMemory),
+            (
+                SchemaLabel::AccountsInStableMemory,
+                SchemaLabel::CompactAccountsInStableMemory
+            ),
+        ]
+    );
+    assert_eq!(
+        RegisteredMigration::path(SchemaLabel::Map, SchemaLabel::Map).map(|path| path.len()),
+        Some(0)
+    );
+    assert!(RegisteredMigration::path(SchemaLabel::AccountsInStableMemory, SchemaLabel::Map).is_none());
+}
+
+#[test]
+fn migration_moves_accounts_in_steps() {
+    let mut proxy = proxy_with_accounts(10);
+    let expected: Vec<_> = proxy.iter().collect();
+    proxy
+        .start_migration(stable_db())
+        .expect("Failed to start the migration");
+    assert_eq!(proxy.migration_countdown(), 2);
+
+    proxy.step_migration(3);
+    assert_eq!(
+        proxy.migration_progress(),
+        vec![MigrationProgress {
+            from: SchemaLabel::Map,
+            to: SchemaLabel::AccountsInStableMemory,
+            status: MigrationStatus::InProgress,
+            accounts_migrated: 3,
+            accounts_total: 10,
+        }]
+    );
+
+    run_to_completion(&mut proxy);
+    assert_eq!(
+        proxy.authoritative_db.schema_label(),
+        SchemaLabel::AccountsInStableMemory
+    );
+    assert_eq!(proxy.iter().collect::<Vec<_>>(), expected);
+    assert_eq!(proxy.migration_countdown(), 0);
+    assert_eq!(
+        proxy.migration_progress(),
+        vec![MigrationProgress {
+            from: SchemaLabel::Map,
+            to: SchemaLabel::AccountsInStableMemory,
+            status: MigrationStatus::Completed,
+            accounts_migrated: 10,
+            accounts_total: 10,
+        }]
+    );
+}
+
+#[test]
+fn migration_transforms_accounts() {
+    let mut proxy = proxy_with_accounts(5);
+    proxy
+        .start_registered_migration(AccountsDb::Map(AccountsDbAsMap::default()), &DROP_CANISTERS)
+        .expect("Failed to start the migration");
+    proxy.step_migration(2);
+    // Accounts written during the migration are transformed too, whether or not they have been migrated yet.
+    proxy.db_insert_account(&account_key(0), toy_account(0, 4));
+    proxy.db_insert_account(&account_key(7), toy_account(7, 4));
+    // Until the migration is complete, the original accounts are served.
+    assert_eq!(proxy.db_get_account(&account_key(7)), Some(toy_account(7, 4)));
+
+    run_to_completion(&mut proxy);
+    assert_eq!(proxy.db_accounts_len(), 6);
+    for (_key, account) in proxy.iter() {
+        assert!(account.canisters.is_empty());
+    }
+    assert_eq!(
+        proxy
+            .migration_progress()
+            .last()
+            .map(|progress| progress.status.clone()),
+        Some(MigrationStatus::Completed)
+    );
+}
+
+#[test]
+fn migration_with_inconsistent_data_is_aborted() {
+    let mut proxy = proxy_with_accounts(5);
+    proxy
+        .start_migration(stable_db())
+        .expect("Failed to start the migration");
+    proxy.step_migration(10);
+    // Corrupt the new database behind the proxy's back.
+    if let Some(migration) = &mut proxy.migration {
+        migration.db.db_remove_account(&account_key(4));
+    }
+    proxy.step_migration(10);
+    assert!(!proxy.migration_in_progress());
+    assert_eq!(proxy.authoritative_db.schema_label(), SchemaLabel::Map);
+    assert!(matches!(
+        proxy.migration_progress()[0].status,
+        MigrationStatus::Aborted { .. }
+    ));
+}
+
+#[test]
+fn unfinished_migration_can_be_rolled_back() {
+    let mut proxy = proxy_with_accounts(10);
+    let expected: Vec<_> = proxy.iter().collect();
+    assert_eq!(proxy.rollback_migration(), Err(MigrationError::NoMigrationInProgress));
+    proxy
+        .start_migration(stable_db())
+        .expect("Failed to start the migration");
+    proxy.step_migration(3);
+
+    assert_eq!(proxy.rollback_migration(), Ok(()));
+    assert!(!proxy.migration_in_progress());
+    assert_eq!(proxy.authoritative_db.schema_label(), SchemaLabel::Map);
+    assert_eq!(proxy.iter().collect::<Vec<_>>(), expected);
+    assert_eq!(
+        proxy.migration_progress(),
+        vec![MigrationProgress {
+            from: SchemaLabel::Map,
+            to: SchemaLabel::AccountsInStableMemory,
+            status: MigrationStatus::RolledBack,
+            accounts_migrated: 3,
+            accounts_total: 10,
+        }]
+    );
+    // Stepping after a rollback does nothing.
+    proxy.step_migration(3);
+    assert_eq!(proxy.authoritative_db.schema_label(), SchemaLabel::Map);
+}
+
+#[test]
+fn migrations_are_started_only_if_registered_and_one_at_a_time() {
+    let mut proxy = proxy_with_accounts(3);
+    assert_eq!(
+        proxy.start_migration(AccountsDb::Map(AccountsDbAsMap::default())),
+        Err(MigrationError::NoMigrationPath {
+            from: SchemaLabel::Map,
+            to: SchemaLabel::Map
+        })
+    );
+    let mut non_empty = stable_db();
+    non_empty.db_insert_account(&account_key(9), toy_account(9, 0));
+    assert_eq!(proxy.start_migration(non_empty), Err(MigrationError::TargetNotEmpty));
+
+    proxy
+        .start_migration(stable_db())
+        .expect("Failed to start the migration");
+    assert_eq!(
+        proxy.start_migration(stable_db()),
+        Err(MigrationError::MigrationInProgress)
+    );
+}
+
+#[test]
+fn saved_migration_is_restored_with_its_database() {
+    let mut proxy = proxy_with_accounts(10);
+    proxy
+        .start_migration(stable_db())
+        .expect("Failed to start the migration");
+    proxy.set_migration_target(Some(SchemaLabel::CompactAccountsInStableMemory));
+    proxy.step_migration(3);
+    let saved = proxy.saved_migrations();
+    let db = proxy.migration.take().map(|migration| migration.db);
+
+    let mut restored = proxy_with_accounts(10);
+    restored.restore_migrations(saved.clone(), db);
+    assert_eq!(restored.saved_migrations(), saved);
+    run_to_completion(&mut restored);
+    assert_eq!(restored.schema_label(), SchemaLabel::AccountsInStableMemory);
+    assert_eq!(restored.migration_progress()[0].accounts_migrated, 10);
+    // The target is further on, so is kept for the next migration.
+    assert_eq!(
+        restored.migration_target(),
+        Some(SchemaLabel::CompactAccountsInStableMemory)
+    );
+}
+
+#[test]
+fn saved_migration_that_does_not_match_the_database_is_dropped() {
+    let mut proxy = proxy_with_accounts(10);
+    proxy
+        .start_migration(stable_db())
+        .expect("Failed to start the migration");
+    proxy.set_migration_target(Some(SchemaLabel::AccountsInStableMemory));
+    let saved = proxy.saved_migrations();
+
+    let mut restored = AccountsDbAsProxy::from(stable_db());
+    restored.restore_migrations(saved, Some(stable_db()));
+    assert!(!restored.migration_in_progress());
+    assert_eq!(restored.migration_target(), None);
+}
+
+#[test]
+fn finished_migrations_are_bounded() {
+    let mut proxy = proxy_with_accounts(3);
+    for _ in 0..=AccountsDbAsProxy::MAX_FINISHED_MIGRATIONS {
+        proxy
+            .start_migration(stable_db())
+            .expect("Failed to start the migration");
+        proxy.step_migration(1);
+        proxy.rollback_migration().expect("Failed to roll back the migration");
+    }
+    assert_eq!(
+        proxy.migration_progress().len(),
+        AccountsDbAsProxy::MAX_FINISHED_MIGRATIONS
+    );
+    assert_eq!(proxy.migration_progress()[0].accounts_migrated, 1);
+}
@@ -0,0 +1,75 @@
+//! The migrations that the proxy knows how to perform, keyed by schema.
+//!
+//! To introduce a new schema, add a label for it and append a migration from the current schema
+//! to the new one.  The migration may rewrite accounts as they are copied, for example to fill in
+//! new fields or to drop obsolete ones.
+use super::super::{Account, SchemaLabel};
+use core::fmt;
+
+/// A migration of the accounts database from one schema to another.
+pub struct RegisteredMigration {
+    /// The schema of the authoritative database when the migration starts.
+    pub from: SchemaLabel,
+    /// The schema of the database being migrated to.
+    pub to: SchemaLabel,
+    /// Applied to every account as it is written to the new database.
+    pub transform: fn(Account) -> Account,
+}
+
+impl fmt::Debug for RegisteredMigration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} -> {:?}", self.from, self.to)
+    }
+}
+
+/// All registered migrations, in the order in which they are applied.
+///
+/// Each migration starts from the schema that the previous migration ends with.
+pub const MIGRATIONS: &[RegisteredMigration] = &[
+    RegisteredMigration {
+        from: SchemaLabel::Map,
+        to: SchemaLabel::AccountsInStableMemory,
+        transform: unchanged,
+    },
+    RegisteredMigration {
+        from: SchemaLabel::AccountsInStableMemory,
+        to: SchemaLabel::CompactAccountsInStableMemory,
+        transform: unchanged,
+    },
+];
+
+/// The transform of a migration that only moves accounts to a different kind of storage.
+#[must_use]
+pub fn unchanged(account: Account) -> Account {
+    account
+}
+
+impl RegisteredMigration {
+    /// Finds the migration from one schema directly to another.
+    #[must_use]
+    pub fn find(from: SchemaLabel, to: SchemaLabel) -> Option<&'static RegisteredMigration> {
+        MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == from && migration.to == to)
+    }
+
+    /// The migrations needed to get from one schema to another, in the order in which they have
+    /// to be applied.
+    ///
+    /// Returns `None` if the target schema cannot be reached, e.g. because it predates `from`.
+    #[must_use]
+    pub fn path(from: SchemaLabel, to: SchemaLabel) -> Option<Vec<&'static RegisteredMigration>> {
+        let mut path = Vec::new();
+        let mut schema = from;
+        while schema != to {
+            // Every migration is used at most once, so a longer path would have to be a loop.
+            if path.len() == MIGRATIONS.len() {
+                return None;
+            }
+            let next = MIGRATIONS.iter().find(|migration| migration.from == schema)?;
+            path.push(next);
+            schema = next.to;
+        }
+        Some(path)
+    }
+}
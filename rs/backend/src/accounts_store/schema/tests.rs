@@ -1,6 +1,8 @@
 //! Generic tests for account storage.
 
-use super::super::{AccountIdentifier, CanisterId, NamedCanister, PrincipalId};
+use super::super::{
+    AccountIdentifier, CanisterId, NamedCanister, NamedHardwareWalletAccount, NamedSubAccount, PrincipalId,
+};
 use super::*;
 use pretty_assertions::assert_eq;
 use std::collections::{BTreeMap, BTreeSet, HashMap};
@@ -11,6 +13,8 @@ pub fn toy_canister(account_index: u64, canister_index: u64) -> NamedCanister {
     NamedCanister {
         name: format!("canister_{account_index}_{canister_index}"),
         canister_id,
+        metadata: None,
+        group: None,
     }
 }
 
@@ -25,6 +29,11 @@ pub fn toy_account(account_index: u64, num_canisters: u64) -> Account {
         hardware_wallet_accounts: Vec::new(),
         canisters: Vec::new(),
         imported_tokens: None,
+        address_book: None,
+        archived_sub_accounts: None,
+        canister_groups: None,
+        version: None,
+        preferences: None,
     };
     // Attaches canisters to the account.
     for canister_index in 0..num_canisters {
@@ -289,6 +298,46 @@ where
     assert_eq!(expected_values, actual_values);
 }
 
+/// Verifies that sub-accounts and hardware wallets are indexed as accounts are inserted and removed.
+pub fn assert_links_are_indexed<D>(mut storage: D)
+where
+    D: AccountsDbTrait,
+{
+    let sub_account = AccountIdentifier::from(PrincipalId::new_user_test_id(100));
+    let hardware_wallet = PrincipalId::new_user_test_id(101);
+    let linked_account = |account_index| {
+        let mut account = toy_account(account_index, 1);
+        account
+            .sub_accounts
+            .insert(1, NamedSubAccount::new("sub".to_string(), sub_account));
+        account.hardware_wallet_accounts.push(NamedHardwareWalletAccount {
+            name: "hw".to_string(),
+            principal: hardware_wallet,
+        });
+        account
+    };
+    let hardware_wallet = AccountIdentifier::from(hardware_wallet).to_vec();
+    let sub_account = sub_account.to_vec();
+    // Accounts without links are not in the index.
+    storage.db_insert_account(&[1], toy_account(1, 1));
+    assert!(!storage.db_contains_linked_account(&sub_account));
+    // Links are indexed when an account is inserted or updated.
+    storage.db_insert_account(&[1], linked_account(1));
+    storage.db_insert_account(&[2, 2], linked_account(2));
+    assert_eq!(storage.db_get_linked_account_keys(&sub_account).len(), 2);
+    assert_eq!(
+        storage.db_get_linked_account_keys(&hardware_wallet),
+        vec![vec![1], vec![2, 2]]
+    );
+    // Links that are no longer in an account are dropped from the index.
+    storage.db_insert_account(&[1], toy_account(1, 1));
+    assert_eq!(storage.db_get_linked_account_keys(&hardware_wallet), vec![vec![2, 2]]);
+    // As are the links of removed accounts.
+    storage.db_remove_account(&[2, 2]);
+    assert!(!storage.db_contains_linked_account(&hardware_wallet));
+    assert!(!storage.db_contains_linked_account(&sub_account));
+}
+
 /// Verifies that a database, created from a map, returns that same map.
 ///
 /// Note: This applies only to some implementations; it is not generally expected of all db
@@ -351,6 +400,11 @@ macro_rules! test_accounts_db {
         fn map_accounts_db_should_iterate_over_values() {
             crate::accounts_store::schema::tests::assert_iterates_over_values($implementation);
         }
+
+        #[test]
+        fn map_accounts_db_should_index_links() {
+            crate::accounts_store::schema::tests::assert_links_are_indexed($implementation);
+        }
     };
 }
 pub(crate) use test_accounts_db;
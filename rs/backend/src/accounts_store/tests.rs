@@ -1,6 +1,12 @@
 use super::histogram::AccountsStoreHistogram;
+use super::operation_log::MAX_OPERATION_OUTCOMES_PER_PRINCIPAL;
+use super::transaction_index::MAX_INDEXED_BLOCKS_PER_ACCOUNT;
 use super::*;
+use crate::accounts_store::schema::accounts_in_unbounded_stable_btree_map::AccountsDbAsUnboundedStableBTreeMap;
 use crate::accounts_store::toy_data::{toy_account, ToyAccountSize};
+use crate::multi_part_transactions_processor::{OperationKind, INITIAL_RETRY_DELAY_NANOS, MAX_ATTEMPTS};
+use crate::state::partitions::{PartitionType, Partitions};
+use ic_stable_structures::DefaultMemoryImpl;
 use icp_ledger::Tokens;
 use pretty_assertions::assert_eq;
 use std::str::FromStr;
@@ -17,9 +23,9 @@ fn create_sub_account() {
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
     let mut store = setup_test_store();
 
-    store.create_sub_account(principal, "AAA".to_string());
-    store.create_sub_account(principal, "BBB".to_string());
-    store.create_sub_account(principal, "CCC".to_string());
+    store.create_sub_account(principal, "AAA".to_string(), None);
+    store.create_sub_account(principal, "BBB".to_string(), None);
+    store.create_sub_account(principal, "CCC".to_string(), None);
 
     let sub_accounts = store.get_account(principal).unwrap().sub_accounts;
 
@@ -34,9 +40,9 @@ fn rename_sub_account() {
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
     let mut store = setup_test_store();
 
-    store.create_sub_account(principal, "AAA".to_string());
-    store.create_sub_account(principal, "BBB".to_string());
-    store.create_sub_account(principal, "CCC".to_string());
+    store.create_sub_account(principal, "AAA".to_string(), None);
+    store.create_sub_account(principal, "BBB".to_string(), None);
+    store.create_sub_account(principal, "CCC".to_string(), None);
 
     let sub_accounts = store.get_account(principal).unwrap().sub_accounts;
 
@@ -45,6 +51,7 @@ fn rename_sub_account() {
         RenameSubAccountRequest {
             account_identifier: sub_accounts[1].account_identifier,
             new_name: "BBB123".to_string(),
+            expected_version: None,
         },
     );
 
@@ -68,6 +75,7 @@ fn register_hardware_wallet() {
         RegisterHardwareWalletRequest {
             name: "HW1".to_string(),
             principal: hw1,
+            expected_version: None,
         },
     );
     let res2 = store.register_hardware_wallet(
@@ -75,6 +83,7 @@ fn register_hardware_wallet() {
         RegisterHardwareWalletRequest {
             name: "HW2".to_string(),
             principal: hw2,
+            expected_version: None,
         },
     );
 
@@ -108,6 +117,7 @@ fn register_hardware_wallet_hardware_wallet_already_registered() {
         RegisterHardwareWalletRequest {
             name: "HW1".to_string(),
             principal: hw1,
+            expected_version: None,
         },
     );
     let res2 = store.register_hardware_wallet(
@@ -115,6 +125,7 @@ fn register_hardware_wallet_hardware_wallet_already_registered() {
         RegisterHardwareWalletRequest {
             name: "HW2".to_string(),
             principal: hw1,
+            expected_version: None,
         },
     );
 
@@ -135,610 +146,3288 @@ fn register_hardware_wallet_hardware_wallet_already_registered() {
 }
 
 #[test]
-fn attach_canister_followed_by_get_canisters() {
+fn remove_sub_account() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
     let mut store = setup_test_store();
+
+    store.create_sub_account(principal, "AAA".to_string(), None);
+    store.create_sub_account(principal, "BBB".to_string(), None);
+    let sub_accounts = store.get_account(principal).unwrap().sub_accounts;
+    let removed_account_identifier = sub_accounts[0].account_identifier;
+
+    let result = store.remove_sub_account(
+        principal,
+        RemoveSubAccountRequest {
+            account_identifier: removed_account_identifier,
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, RemoveSubAccountResponse::Ok);
+    let sub_accounts = store.get_account(principal).unwrap().sub_accounts;
+    assert_eq!(1, sub_accounts.len());
+    assert_eq!("BBB", sub_accounts[0].name);
+    assert!(!store.store_has_account(removed_account_identifier));
+
+    let mut stats = Stats::default();
+    store.get_stats(&mut stats);
+    assert_eq!(1, stats.sub_accounts_count);
+
+    let mut expected_histogram = test_store_histogram();
+    *expected_histogram.sub_accounts(0) -= 1;
+    *expected_histogram.sub_accounts(1) += 1;
+    expected_histogram.remove_empty_buckets();
+    assert_eq!(expected_histogram, store.get_histogram());
+}
+
+#[test]
+fn removed_sub_account_index_is_not_reused() {
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut store = setup_test_store();
 
-    let canister_ids: Vec<_> = [
-        TEST_ACCOUNT_2,
-        TEST_ACCOUNT_3,
-        TEST_ACCOUNT_4,
-        TEST_ACCOUNT_5,
-        TEST_ACCOUNT_6,
-    ]
-    .iter()
-    .map(|&id| CanisterId::from_str(id).unwrap())
-    .collect();
+    store.create_sub_account(principal, "AAA".to_string(), None);
+    let removed = store.get_account(principal).unwrap().sub_accounts[0].account_identifier;
+    store.remove_sub_account(
+        principal,
+        RemoveSubAccountRequest {
+            account_identifier: removed,
+            expected_version: None,
+        },
+    );
 
-    for (index, canister_id) in canister_ids.iter().enumerate() {
-        let result = store.attach_canister(
+    let CreateSubAccountResponse::Ok(SubAccountDetails { account_identifier, .. }) =
+        store.create_sub_account(principal, "BBB".to_string(), None)
+    else {
+        panic!("Failed to create a sub-account");
+    };
+
+    assert_ne!(removed, account_identifier);
+    assert_eq!(
+        AccountIdentifier::new(principal, Some(convert_byte_to_sub_account(2))),
+        account_identifier
+    );
+}
+
+#[test]
+fn removed_sub_account_can_be_restored_once_every_index_is_used() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut store = setup_test_store();
+    for index in 1..u8::MAX {
+        assert!(matches!(
+            store.create_sub_account(principal, format!("sub_{index}"), None),
+            CreateSubAccountResponse::Ok(_)
+        ));
+    }
+    assert!(matches!(
+        store.create_sub_account(principal, "one too many".to_string(), None),
+        CreateSubAccountResponse::SubAccountLimitExceeded
+    ));
+    let removed = AccountIdentifier::new(principal, Some(convert_byte_to_sub_account(7)));
+    assert_eq!(
+        store.remove_sub_account(
             principal,
-            AttachCanisterRequest {
-                name: index.to_string(),
-                canister_id: *canister_id,
+            RemoveSubAccountRequest {
+                account_identifier: removed,
+                expected_version: None,
+            },
+        ),
+        RemoveSubAccountResponse::Ok
+    );
+
+    // The index of the removed sub-account is not reused for a new sub-account...
+    assert!(matches!(
+        store.create_sub_account(principal, "new".to_string(), None),
+        CreateSubAccountResponse::SubAccountLimitExceeded
+    ));
+    let account = store.get_account(principal).unwrap();
+    assert_eq!(account.sub_accounts.len(), 253);
+    assert_eq!(account.archived_sub_accounts.len(), 1);
+    assert_eq!(account.archived_sub_accounts[0].account_identifier, removed);
+
+    // ... but the removed sub-account may be restored, with its original name.
+    assert_eq!(
+        store.restore_sub_account(
+            principal,
+            RestoreSubAccountRequest {
+                account_identifier: removed,
+                expected_version: Some(account.version),
+            },
+        ),
+        RestoreSubAccountResponse::Ok
+    );
+    let account = store.get_account(principal).unwrap();
+    assert_eq!(account.sub_accounts.len(), 254);
+    assert!(account.archived_sub_accounts.is_empty());
+    let restored = account
+        .sub_accounts
+        .iter()
+        .find(|sub_account| sub_account.account_identifier == removed)
+        .unwrap();
+    assert_eq!(restored.name, "sub_7");
+    assert!(store.store_has_account(removed));
+    let mut stats = Stats::default();
+    store.get_stats(&mut stats);
+    assert_eq!(stats.sub_accounts_count, 254);
+
+    assert_eq!(
+        store.restore_sub_account(
+            principal,
+            RestoreSubAccountRequest {
+                account_identifier: removed,
+                expected_version: None,
+            },
+        ),
+        RestoreSubAccountResponse::SubAccountNotFound
+    );
+}
+
+#[test]
+fn remove_sub_account_not_found() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut store = setup_test_store();
+
+    let result = store.remove_sub_account(
+        principal,
+        RemoveSubAccountRequest {
+            account_identifier: AccountIdentifier::new(principal, Some(convert_byte_to_sub_account(1))),
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, RemoveSubAccountResponse::SubAccountNotFound);
+}
+
+#[test]
+fn remove_sub_account_account_not_found() {
+    let non_existing_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    let mut store = setup_test_store();
+
+    let result = store.remove_sub_account(
+        non_existing_principal,
+        RemoveSubAccountRequest {
+            account_identifier: AccountIdentifier::new(non_existing_principal, Some(convert_byte_to_sub_account(1))),
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, RemoveSubAccountResponse::AccountNotFound);
+}
+
+#[test]
+fn unregister_hardware_wallet() {
+    let principal1 = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let principal2 = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    let mut store = setup_test_store();
+
+    let hw = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    let hw_account_identifier = AccountIdentifier::from(hw);
+
+    // The same hardware wallet is registered with two accounts.
+    for principal in [principal1, principal2] {
+        store.register_hardware_wallet(
+            principal,
+            RegisterHardwareWalletRequest {
+                name: "HW".to_string(),
+                principal: hw,
+                expected_version: None,
             },
         );
+    }
 
-        assert!(matches!(result, AttachCanisterResponse::Ok));
+    let result = store.unregister_hardware_wallet(
+        principal1,
+        UnregisterHardwareWalletRequest {
+            principal: hw,
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, UnregisterHardwareWalletResponse::Ok);
+    assert!(store
+        .get_account(principal1)
+        .unwrap()
+        .hardware_wallet_accounts
+        .is_empty());
+    assert_eq!(1, store.get_account(principal2).unwrap().hardware_wallet_accounts.len());
+    // The hardware wallet is still linked to the second account.
+    assert_eq!(
+        store.main_account_identifiers(&hw_account_identifier),
+        vec![AccountIdentifier::from(principal2)]
+    );
+    let mut stats = Stats::default();
+    store.get_stats(&mut stats);
+    assert_eq!(1, stats.hardware_wallet_accounts_count);
+
+    let result = store.unregister_hardware_wallet(
+        principal2,
+        UnregisterHardwareWalletRequest {
+            principal: hw,
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, UnregisterHardwareWalletResponse::Ok);
+    assert!(!store.store_has_account(hw_account_identifier));
+    store.get_stats(&mut stats);
+    assert_eq!(0, stats.hardware_wallet_accounts_count);
+    assert_eq!(test_store_histogram(), store.get_histogram());
+}
+
+#[test]
+fn unregister_hardware_wallet_not_found() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut store = setup_test_store();
+    let hw = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+
+    let result = store.unregister_hardware_wallet(
+        principal,
+        UnregisterHardwareWalletRequest {
+            principal: hw,
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, UnregisterHardwareWalletResponse::HardwareWalletNotFound);
+}
+
+/// Populates an account with one of everything, so that exports are not trivial.
+fn populate_account_for_export(store: &mut AccountsStore, principal: PrincipalId) {
+    store.create_sub_account(principal, "AAA".to_string(), None);
+    store.create_sub_account(principal, "BBB".to_string(), None);
+    let removed = store.get_account(principal).unwrap().sub_accounts[0].account_identifier;
+    store.remove_sub_account(
+        principal,
+        RemoveSubAccountRequest {
+            account_identifier: removed,
+            expected_version: None,
+        },
+    );
+    store.register_hardware_wallet(
+        principal,
+        RegisterHardwareWalletRequest {
+            name: "HW".to_string(),
+            principal: PrincipalId::from_str(TEST_ACCOUNT_3).unwrap(),
+            expected_version: None,
+        },
+    );
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "CAN".to_string(),
+            canister_id: CanisterId::from_str(TEST_ACCOUNT_4).unwrap(),
+            expected_version: None,
+        },
+    );
+    store.set_imported_tokens(
+        principal,
+        ImportedTokens {
+            imported_tokens: vec![ImportedToken {
+                ledger_canister_id: PrincipalId::from_str(TEST_ACCOUNT_5).unwrap(),
+                index_canister_id: None,
+                settings: None,
+            }],
+        },
+        None,
+    );
+    store.add_address_book_entry(principal, add_address_book_entry_request("Bob", icp_address(1)));
+}
+
+fn export_account_page(store: &AccountsStore, principal: PrincipalId, canisters_offset: u32) -> ExportAccountPage {
+    match store.export_account(principal, ExportAccountRequest { canisters_offset }) {
+        ExportAccountResponse::Ok(page) => page,
+        ExportAccountResponse::AccountNotFound => panic!("Account not found"),
     }
+}
 
-    let canisters = store.get_canisters(principal);
+/// Exports an account, joining the canisters of all the pages.
+fn export_account(store: &AccountsStore, principal: PrincipalId) -> AccountExport {
+    let ExportAccountPage {
+        mut export,
+        mut next_canisters_offset,
+    } = export_account_page(store, principal, 0);
+    while let Some(canisters_offset) = next_canisters_offset {
+        let page = export_account_page(store, principal, canisters_offset);
+        export.canisters.extend(page.export.canisters);
+        next_canisters_offset = page.next_canisters_offset;
+    }
+    export
+}
 
-    let expected: Vec<_> = canister_ids
-        .into_iter()
-        .enumerate()
-        .map(|(index, canister_id)| NamedCanister {
-            name: index.to_string(),
-            canister_id,
+#[test]
+fn export_account_contains_the_whole_account() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut store = setup_test_store();
+    populate_account_for_export(&mut store, principal);
+
+    let export = export_account(&store, principal);
+
+    assert_eq!(export.version, ACCOUNT_EXPORT_VERSION);
+    assert_eq!(export.principal, principal);
+    assert_eq!(
+        export.sub_accounts,
+        vec![ExportedSubAccount {
+            name: "BBB".to_string(),
+            sub_account_id: 2,
+        }]
+    );
+    assert_eq!(
+        export.archived_sub_accounts,
+        vec![ExportedSubAccount {
+            name: "AAA".to_string(),
+            sub_account_id: 1,
+        }]
+    );
+    assert_eq!(export.hardware_wallet_accounts.len(), 1);
+    assert_eq!(export.canisters, store.get_canisters(principal));
+    assert_eq!(export.imported_tokens.len(), 1);
+    assert_eq!(export.address_book.len(), 1);
+}
+
+#[test]
+fn export_account_account_not_found() {
+    let store = setup_test_store();
+    let non_existing_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+
+    assert_eq!(
+        store.export_account(non_existing_principal, ExportAccountRequest { canisters_offset: 0 }),
+        ExportAccountResponse::AccountNotFound
+    );
+}
+
+#[test]
+fn export_account_paginates_canisters() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut store = setup_test_store();
+    for index in 0..(MAX_EXPORTED_CANISTERS_PAGE_SIZE + 5) {
+        store.attach_newly_created_canister(principal, CanisterId::from(u64::from(index)));
+    }
+
+    let first_page = export_account_page(&store, principal, 0);
+    assert_eq!(
+        first_page.export.canisters.len(),
+        MAX_EXPORTED_CANISTERS_PAGE_SIZE as usize
+    );
+    assert_eq!(first_page.next_canisters_offset, Some(MAX_EXPORTED_CANISTERS_PAGE_SIZE));
+    let last_page = export_account_page(&store, principal, MAX_EXPORTED_CANISTERS_PAGE_SIZE);
+    assert_eq!(last_page.export.canisters.len(), 5);
+    assert_eq!(last_page.next_canisters_offset, None);
+    // Apart from canisters, every page has the whole account.
+    assert_eq!(
+        last_page.export,
+        AccountExport {
+            canisters: last_page.export.canisters.clone(),
+            ..first_page.export
+        }
+    );
+    assert_eq!(
+        export_account(&store, principal).canisters,
+        store.get_canisters(principal)
+    );
+}
+
+#[test]
+fn import_account_restores_the_account_for_a_new_principal() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    let mut store = setup_test_store();
+    populate_account_for_export(&mut store, principal);
+    let export = export_account(&store, principal);
+
+    let result = store.import_account(new_principal, export.clone(), None);
+
+    assert_eq!(result, ImportAccountResponse::Ok);
+    let imported = export_account(&store, new_principal);
+    assert_eq!(
+        imported,
+        AccountExport {
+            principal: new_principal,
+            ..export
+        }
+    );
+    // Sub-accounts are recreated for the new principal.
+    let sub_accounts = store.get_account(new_principal).unwrap().sub_accounts;
+    assert_eq!(
+        sub_accounts[0].account_identifier,
+        AccountIdentifier::new(new_principal, Some(convert_byte_to_sub_account(2)))
+    );
+    assert!(store.store_has_account(sub_accounts[0].account_identifier));
+    // The archived sub-account index is still not reused.
+    let CreateSubAccountResponse::Ok(SubAccountDetails { sub_account, .. }) =
+        store.create_sub_account(new_principal, "CCC".to_string(), None)
+    else {
+        panic!("Failed to create a sub-account");
+    };
+    assert_eq!(sub_account, convert_byte_to_sub_account(3));
+
+    let mut stats = Stats::default();
+    store.get_stats(&mut stats);
+    assert_eq!(3, stats.sub_accounts_count);
+    assert_eq!(2, stats.hardware_wallet_accounts_count);
+}
+
+#[test]
+fn import_account_account_not_empty() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut store = setup_test_store();
+    populate_account_for_export(&mut store, principal);
+    let export = export_account(&store, principal);
+
+    let result = store.import_account(principal, export, None);
+
+    assert_eq!(result, ImportAccountResponse::AccountNotEmpty);
+}
+
+#[test]
+fn import_account_account_not_found() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let non_existing_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    let mut store = setup_test_store();
+    let export = export_account(&store, principal);
+
+    let result = store.import_account(non_existing_principal, export, None);
+
+    assert_eq!(result, ImportAccountResponse::AccountNotFound);
+}
+
+#[test]
+fn import_account_unsupported_version() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    let mut store = setup_test_store();
+    let export = AccountExport {
+        version: ACCOUNT_EXPORT_VERSION + 1,
+        ..export_account(&store, principal)
+    };
+
+    let result = store.import_account(new_principal, export, None);
+
+    assert_eq!(
+        result,
+        ImportAccountResponse::UnsupportedVersion {
+            version: ACCOUNT_EXPORT_VERSION + 1
+        }
+    );
+}
+
+#[test]
+fn import_account_invalid_export() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    let mut store = setup_test_store();
+    populate_account_for_export(&mut store, principal);
+    let export = export_account(&store, principal);
+    // The same subaccount index may not be used by both an active and an archived sub-account.
+    let export = AccountExport {
+        sub_accounts: export.archived_sub_accounts.clone(),
+        ..export
+    };
+
+    let result = store.import_account(new_principal, export, None);
+
+    assert_eq!(result, ImportAccountResponse::InvalidExport);
+    assert!(store.get_account(new_principal).unwrap().sub_accounts.is_empty());
+}
+
+#[test]
+fn import_account_duplicate_imported_tokens() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    let mut store = setup_test_store();
+    populate_account_for_export(&mut store, principal);
+    let mut export = export_account(&store, principal);
+    let imported_token = export.imported_tokens[0].clone();
+    export.imported_tokens.push(imported_token);
+
+    let result = store.import_account(new_principal, export, None);
+
+    assert_eq!(result, ImportAccountResponse::InvalidExport);
+    assert_eq!(
+        store.get_imported_tokens(new_principal),
+        GetImportedTokensResponse::Ok(ImportedTokens::default())
+    );
+}
+
+fn propose_account_transfer(store: &mut AccountsStore, old_principal: PrincipalId, new_principal: PrincipalId) {
+    let result = store.propose_account_transfer(old_principal, ProposeAccountTransferRequest { new_principal });
+    assert!(matches!(result, ProposeAccountTransferResponse::Ok { .. }));
+}
+
+#[test]
+fn accept_account_transfer_moves_the_account() {
+    let old_principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_6).unwrap();
+    let mut store = setup_test_store();
+    populate_account_for_export(&mut store, old_principal);
+    let export = export_account(&store, old_principal);
+    let old_sub_account = store.get_account(old_principal).unwrap().sub_accounts[0].account_identifier;
+    let hw_account_identifier = AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_3).unwrap());
+    let mut stats_before = Stats::default();
+    store.get_stats(&mut stats_before);
+
+    propose_account_transfer(&mut store, old_principal, new_principal);
+    let result = store.accept_account_transfer(
+        new_principal,
+        AcceptAccountTransferRequest {
+            old_principal,
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, AcceptAccountTransferResponse::Ok);
+    assert!(store.get_account(old_principal).is_none());
+    assert_eq!(
+        export_account(&store, new_principal),
+        AccountExport {
+            principal: new_principal,
+            ..export
+        }
+    );
+    assert!(!store.store_has_account(old_sub_account));
+    let new_sub_account = store.get_account(new_principal).unwrap().sub_accounts[0].account_identifier;
+    assert!(store.store_has_account(new_sub_account));
+    assert_eq!(
+        store.main_account_identifiers(&hw_account_identifier),
+        vec![AccountIdentifier::from(new_principal)]
+    );
+    let mut stats_after = Stats::default();
+    store.get_stats(&mut stats_after);
+    assert_eq!(stats_before.accounts_count, stats_after.accounts_count);
+    assert_eq!(stats_before.sub_accounts_count, stats_after.sub_accounts_count);
+    assert_eq!(
+        stats_before.hardware_wallet_accounts_count,
+        stats_after.hardware_wallet_accounts_count
+    );
+    // The offer has been used up.
+    assert_eq!(
+        store.accept_account_transfer(
+            new_principal,
+            AcceptAccountTransferRequest {
+                old_principal,
+                expected_version: None,
+            }
+        ),
+        AcceptAccountTransferResponse::OfferNotFound
+    );
+}
+
+#[test]
+fn accept_account_transfer_discards_pending_operations_and_outcomes() {
+    let old_principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_6).unwrap();
+    let mut store = setup_test_store();
+    store.record_operation_outcome(
+        4,
+        &MultiPartTransactionToBeProcessed::CreateCanisterV2(old_principal),
+        OperationStatus::Succeeded,
+        None,
+    );
+    store.enqueue_multi_part_transaction(5, MultiPartTransactionToBeProcessed::CreateCanisterV2(old_principal));
+
+    propose_account_transfer(&mut store, old_principal, new_principal);
+    let result = store.accept_account_transfer(
+        new_principal,
+        AcceptAccountTransferRequest {
+            old_principal,
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, AcceptAccountTransferResponse::Ok);
+    assert_eq!(store.get_pending_operations(old_principal), vec![]);
+    assert_eq!(store.get_pending_operations(new_principal), vec![]);
+    assert_eq!(store.try_take_next_transaction_to_process(), None);
+}
+
+#[test]
+fn accept_account_transfer_offer_for_another_principal() {
+    let old_principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    let other_principal = PrincipalId::from_str(TEST_ACCOUNT_4).unwrap();
+    let mut store = setup_test_store();
+
+    propose_account_transfer(&mut store, old_principal, new_principal);
+    let result = store.accept_account_transfer(
+        other_principal,
+        AcceptAccountTransferRequest {
+            old_principal,
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, AcceptAccountTransferResponse::OfferNotFound);
+    assert!(store.get_account(old_principal).is_some());
+}
+
+#[test]
+fn accept_account_transfer_offer_expired() {
+    let old_principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    let mut store = setup_test_store();
+
+    propose_account_transfer(&mut store, old_principal, new_principal);
+    crate::time::testing::set_time(crate::time::time() + ACCOUNT_TRANSFER_OFFER_TIMEOUT_NANOS + 1);
+    let result = store.accept_account_transfer(
+        new_principal,
+        AcceptAccountTransferRequest {
+            old_principal,
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, AcceptAccountTransferResponse::OfferExpired);
+    assert!(store.get_account(old_principal).is_some());
+    assert!(store.account_transfer_offers.is_empty());
+}
+
+#[test]
+fn propose_account_transfer_purges_expired_offers() {
+    let old_principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let other_old_principal = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    let mut store = setup_test_store();
+
+    propose_account_transfer(&mut store, old_principal, new_principal);
+    crate::time::testing::set_time(crate::time::time() + ACCOUNT_TRANSFER_OFFER_TIMEOUT_NANOS + 1);
+    propose_account_transfer(&mut store, other_old_principal, new_principal);
+
+    assert!(!store.account_transfer_offers.contains_key(old_principal.as_slice()));
+    assert!(store
+        .account_transfer_offers
+        .contains_key(other_old_principal.as_slice()));
+}
+
+#[test]
+fn accept_account_transfer_account_not_empty() {
+    let old_principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    let mut store = setup_test_store();
+    store.create_sub_account(new_principal, "AAA".to_string(), None);
+
+    propose_account_transfer(&mut store, old_principal, new_principal);
+    let result = store.accept_account_transfer(
+        new_principal,
+        AcceptAccountTransferRequest {
+            old_principal,
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, AcceptAccountTransferResponse::AccountNotEmpty);
+    assert!(store.get_account(old_principal).is_some());
+}
+
+#[test]
+fn propose_account_transfer_to_same_principal() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut store = setup_test_store();
+
+    let result = store.propose_account_transfer(
+        principal,
+        ProposeAccountTransferRequest {
+            new_principal: principal,
+        },
+    );
+
+    assert_eq!(result, ProposeAccountTransferResponse::SamePrincipal);
+}
+
+#[test]
+fn cancel_account_transfer() {
+    let old_principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    let mut store = setup_test_store();
+
+    propose_account_transfer(&mut store, old_principal, new_principal);
+    assert_eq!(
+        store.cancel_account_transfer(old_principal),
+        CancelAccountTransferResponse::Ok
+    );
+    assert_eq!(
+        store.cancel_account_transfer(old_principal),
+        CancelAccountTransferResponse::OfferNotFound
+    );
+    assert_eq!(
+        store.accept_account_transfer(
+            new_principal,
+            AcceptAccountTransferRequest {
+                old_principal,
+                expected_version: None,
+            }
+        ),
+        AcceptAccountTransferResponse::OfferNotFound
+    );
+}
+
+#[test]
+fn account_transfer_offers_survive_upgrade() {
+    let old_principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    let mut store = setup_test_store();
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    store.load_account_transfer_offers(partitions.get(PartitionType::AccountTransferOffers.memory_id()));
+    propose_account_transfer(&mut store, old_principal, new_principal);
+
+    let mut decoded = AccountsStore::decode(store.encode()).unwrap();
+    decoded.load_account_transfer_offers(partitions.get(PartitionType::AccountTransferOffers.memory_id()));
+
+    assert_eq!(decoded.account_transfer_offers, store.account_transfer_offers);
+    assert_eq!(decoded.account_transfer_offers.len(), 1);
+}
+
+fn account_version(store: &AccountsStore, principal: PrincipalId) -> u64 {
+    store.get_account(principal).unwrap().version
+}
+
+#[test]
+fn account_version_is_incremented_by_every_modification() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut store = setup_test_store();
+    assert_eq!(account_version(&store, principal), 0);
+
+    store.create_sub_account(principal, "AAA".to_string(), None);
+    assert_eq!(account_version(&store, principal), 1);
+
+    store.attach_newly_created_canister(principal, CanisterId::from_str(TEST_ACCOUNT_2).unwrap());
+    assert_eq!(account_version(&store, principal), 2);
+
+    // Failed modifications do not change the version.
+    store.create_sub_account(principal, "a name that is much too long".to_string(), None);
+    assert_eq!(account_version(&store, principal), 2);
+}
+
+#[test]
+fn mutation_with_expected_version_succeeds_if_the_account_is_unchanged() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut store = setup_test_store();
+    let version = account_version(&store, principal);
+
+    let result = store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "ABC".to_string(),
+            canister_id: CanisterId::from_str(TEST_ACCOUNT_2).unwrap(),
+            expected_version: Some(version),
+        },
+    );
+
+    assert!(matches!(result, AttachCanisterResponse::Ok));
+    assert_eq!(account_version(&store, principal), version + 1);
+}
+
+#[test]
+fn mutation_with_stale_expected_version_is_rejected() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut store = setup_test_store();
+    let stale_version = account_version(&store, principal);
+    // Another tab modifies the account.
+    store.create_sub_account(principal, "AAA".to_string(), None);
+
+    let result = store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "ABC".to_string(),
+            canister_id: CanisterId::from_str(TEST_ACCOUNT_2).unwrap(),
+            expected_version: Some(stale_version),
+        },
+    );
+
+    assert!(matches!(
+        result,
+        AttachCanisterResponse::Conflict { current_version } if current_version == stale_version + 1
+    ));
+    assert!(store.get_canisters(principal).is_empty());
+    assert_eq!(
+        store.set_imported_tokens(principal, ImportedTokens::default(), Some(stale_version)),
+        SetImportedTokensResponse::Conflict {
+            current_version: stale_version + 1
+        }
+    );
+    assert_eq!(
+        store.remove_address_book_entry(
+            principal,
+            RemoveAddressBookEntryRequest {
+                name: "Bob".to_string(),
+                expected_version: Some(stale_version),
+            }
+        ),
+        RemoveAddressBookEntryResponse::Conflict {
+            current_version: stale_version + 1
+        }
+    );
+    assert!(matches!(
+        store.create_sub_account(principal, "BBB".to_string(), Some(stale_version)),
+        CreateSubAccountResponse::Conflict { current_version } if current_version == stale_version + 1
+    ));
+    let export = export_account(&store, principal);
+    assert_eq!(
+        store.import_account(principal, export, Some(stale_version)),
+        ImportAccountResponse::Conflict {
+            current_version: stale_version + 1
+        }
+    );
+    assert_eq!(
+        store.delete_account(
+            principal,
+            DeleteAccountRequest {
+                confirmation: AccountIdentifier::from(principal),
+                expected_version: Some(stale_version),
+            }
+        ),
+        DeleteAccountResponse::Conflict {
+            current_version: stale_version + 1
+        }
+    );
+    assert_eq!(account_version(&store, principal), stale_version + 1);
+}
+
+#[test]
+fn accept_account_transfer_with_stale_expected_version_is_rejected() {
+    let old_principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_6).unwrap();
+    let mut store = setup_test_store();
+    store.add_account(new_principal);
+    let stale_version = account_version(&store, new_principal);
+    // Another tab modifies the account, leaving it empty.
+    store.set_imported_tokens(new_principal, ImportedTokens::default(), None);
+
+    propose_account_transfer(&mut store, old_principal, new_principal);
+    let result = store.accept_account_transfer(
+        new_principal,
+        AcceptAccountTransferRequest {
+            old_principal,
+            expected_version: Some(stale_version),
+        },
+    );
+
+    assert_eq!(
+        result,
+        AcceptAccountTransferResponse::Conflict {
+            current_version: stale_version + 1
+        }
+    );
+    assert!(store.get_account(old_principal).is_some());
+}
+
+#[test]
+fn delete_account() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let other_principal = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    let mut store = setup_test_store();
+    populate_account_for_export(&mut store, principal);
+    let sub_account = store.get_account(principal).unwrap().sub_accounts[0].account_identifier;
+    let hw = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    // The hardware wallet is shared with another account.
+    store.register_hardware_wallet(
+        other_principal,
+        RegisterHardwareWalletRequest {
+            name: "HW".to_string(),
+            principal: hw,
+            expected_version: None,
+        },
+    );
+
+    let result = store.delete_account(
+        principal,
+        DeleteAccountRequest {
+            confirmation: AccountIdentifier::from(principal),
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, DeleteAccountResponse::Ok);
+    assert!(store.get_account(principal).is_none());
+    assert!(!store.store_has_account(sub_account));
+    assert_eq!(
+        store.main_account_identifiers(&AccountIdentifier::from(hw)),
+        vec![AccountIdentifier::from(other_principal)]
+    );
+    let mut stats = Stats::default();
+    store.get_stats(&mut stats);
+    assert_eq!(1, stats.accounts_count);
+    assert_eq!(0, stats.sub_accounts_count);
+    assert_eq!(1, stats.hardware_wallet_accounts_count);
+}
+
+#[test]
+fn delete_account_confirmation_mismatch() {
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut store = setup_test_store();
+
+    let result = store.delete_account(
+        principal,
+        DeleteAccountRequest {
+            confirmation: AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_2).unwrap()),
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, DeleteAccountResponse::ConfirmationMismatch);
+    assert!(store.get_account(principal).is_some());
+}
+
+#[test]
+fn delete_account_account_not_found() {
+    let non_existing_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    let mut store = setup_test_store();
+
+    let result = store.delete_account(
+        non_existing_principal,
+        DeleteAccountRequest {
+            confirmation: AccountIdentifier::from(non_existing_principal),
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, DeleteAccountResponse::AccountNotFound);
+}
+
+#[test]
+fn attach_canister_followed_by_get_canisters() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_ids: Vec<_> = [
+        TEST_ACCOUNT_2,
+        TEST_ACCOUNT_3,
+        TEST_ACCOUNT_4,
+        TEST_ACCOUNT_5,
+        TEST_ACCOUNT_6,
+    ]
+    .iter()
+    .map(|&id| CanisterId::from_str(id).unwrap())
+    .collect();
+
+    for (index, canister_id) in canister_ids.iter().enumerate() {
+        let result = store.attach_canister(
+            principal,
+            AttachCanisterRequest {
+                name: index.to_string(),
+                canister_id: *canister_id,
+                expected_version: None,
+            },
+        );
+
+        assert!(matches!(result, AttachCanisterResponse::Ok));
+    }
+
+    let canisters = store.get_canisters(principal);
+
+    let expected: Vec<_> = canister_ids
+        .into_iter()
+        .enumerate()
+        .map(|(index, canister_id)| NamedCanister {
+            name: index.to_string(),
+            canister_id,
+            metadata: None,
+            group: None,
+        })
+        .collect();
+
+    assert_eq!(expected.len(), canisters.len());
+    for i in 0..canisters.len() {
+        assert_eq!(expected[i], canisters[i]);
+    }
+}
+
+#[test]
+fn attach_canister_name_already_taken() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id1 = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
+
+    let result1 = store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "ABC".to_string(),
+            canister_id: canister_id1,
+            expected_version: None,
+        },
+    );
+    let result2 = store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "ABC".to_string(),
+            canister_id: canister_id2,
+            expected_version: None,
+        },
+    );
+
+    assert!(matches!(result1, AttachCanisterResponse::Ok));
+    assert!(matches!(result2, AttachCanisterResponse::NameAlreadyTaken));
+}
+
+#[test]
+fn attach_canister_name_too_long() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id1 = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
+
+    let result1 = store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "ABCDEFGHIJKLMNOPQRSTUVWX".to_string(),
+            canister_id: canister_id1,
+            expected_version: None,
+        },
+    );
+    let result2 = store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "ABCDEFGHIJKLMNOPQRSTUVWXY".to_string(),
+            canister_id: canister_id2,
+            expected_version: None,
+        },
+    );
+
+    assert!(matches!(result1, AttachCanisterResponse::Ok));
+    assert!(matches!(result2, AttachCanisterResponse::NameTooLong));
+}
+
+#[test]
+fn attach_canister_canister_already_attached() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+
+    let result1 = store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "ABC".to_string(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+    let result2 = store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "XYZ".to_string(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+
+    assert!(matches!(result1, AttachCanisterResponse::Ok));
+    assert!(matches!(result2, AttachCanisterResponse::CanisterAlreadyAttached));
+}
+
+#[test]
+fn attach_canister_substitutes_empty_name_canister() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "".to_string(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+
+    let initial_canisters = store.get_canisters(principal);
+    assert!(initial_canisters[0].name.is_empty());
+
+    let name = "XYZ";
+    let result = store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: name.to_string(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+
+    let canisters = store.get_canisters(principal);
+    assert_eq!(name, canisters[0].name);
+    assert!(matches!(result, AttachCanisterResponse::Ok));
+}
+
+fn canister_metadata(tags: &[&str], note: Option<&str>) -> CanisterMetadata {
+    CanisterMetadata {
+        tags: tags.iter().map(ToString::to_string).collect(),
+        note: note.map(ToString::to_string),
+        low_cycles_threshold: Some(1_000_000_000_000),
+    }
+}
+
+fn attach_canisters_for_metadata_tests(store: &mut AccountsStore, principal: PrincipalId) -> Vec<CanisterId> {
+    let canister_ids: Vec<_> = [TEST_ACCOUNT_2, TEST_ACCOUNT_3, TEST_ACCOUNT_4]
+        .iter()
+        .map(|&id| CanisterId::from_str(id).unwrap())
+        .collect();
+    for (index, canister_id) in canister_ids.iter().enumerate() {
+        store.attach_canister(
+            principal,
+            AttachCanisterRequest {
+                name: index.to_string(),
+                canister_id: *canister_id,
+                expected_version: None,
+            },
+        );
+    }
+    canister_ids
+}
+
+fn canisters_with_tag(tag: &str) -> GetCanistersRequest {
+    GetCanistersRequest {
+        tag: Some(tag.to_string()),
+        ..GetCanistersRequest::default()
+    }
+}
+
+#[test]
+fn update_canister_metadata_followed_by_get_canisters_with_tag() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let canister_ids = attach_canisters_for_metadata_tests(&mut store, principal);
+
+    for (canister_id, tags) in canister_ids
+        .iter()
+        .zip([&["prod", "dex"][..], &["test"][..], &["prod"][..]])
+    {
+        let result = store.update_canister_metadata(
+            principal,
+            UpdateCanisterMetadataRequest {
+                canister_id: *canister_id,
+                metadata: canister_metadata(tags, Some("A note")),
+                expected_version: None,
+            },
+        );
+        assert_eq!(result, UpdateCanisterMetadataResponse::Ok);
+    }
+
+    let prod_canisters: Vec<_> = store
+        .get_filtered_canisters(principal, canisters_with_tag("prod"))
+        .into_iter()
+        .map(|canister| canister.canister_id)
+        .collect();
+    assert_eq!(prod_canisters, vec![canister_ids[0], canister_ids[2]]);
+    assert!(store
+        .get_filtered_canisters(principal, canisters_with_tag("staging"))
+        .is_empty());
+    // Tags are stored sorted.
+    assert_eq!(
+        store.get_canisters(principal)[0].metadata,
+        Some(canister_metadata(&["dex", "prod"], Some("A note")))
+    );
+}
+
+#[test]
+fn update_canister_metadata_with_empty_metadata_clears_it() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let canister_id = attach_canisters_for_metadata_tests(&mut store, principal)[0];
+
+    store.update_canister_metadata(
+        principal,
+        UpdateCanisterMetadataRequest {
+            canister_id,
+            metadata: canister_metadata(&["prod"], None),
+            expected_version: None,
+        },
+    );
+    let result = store.update_canister_metadata(
+        principal,
+        UpdateCanisterMetadataRequest {
+            canister_id,
+            metadata: CanisterMetadata::default(),
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, UpdateCanisterMetadataResponse::Ok);
+    assert_eq!(store.get_canisters(principal)[0].metadata, None);
+}
+
+#[test]
+fn rename_canister_keeps_metadata() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let canister_id = attach_canisters_for_metadata_tests(&mut store, principal)[0];
+    let metadata = canister_metadata(&["prod"], Some("A note"));
+    store.update_canister_metadata(
+        principal,
+        UpdateCanisterMetadataRequest {
+            canister_id,
+            metadata: metadata.clone(),
+            expected_version: None,
+        },
+    );
+
+    let result = store.rename_canister(
+        principal,
+        RenameCanisterRequest {
+            name: "renamed".to_string(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+
+    assert!(matches!(result, RenameCanisterResponse::Ok));
+    let canister = store
+        .get_canisters(principal)
+        .into_iter()
+        .find(|canister| canister.canister_id == canister_id)
+        .unwrap();
+    assert_eq!(canister.metadata, Some(metadata));
+}
+
+#[test]
+fn update_canister_metadata_invalid() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let canister_id = attach_canisters_for_metadata_tests(&mut store, principal)[0];
+    let too_many_tags: Vec<String> = (0..=MAX_CANISTER_TAGS).map(|i| i.to_string()).collect();
+    let too_many_tags: Vec<&str> = too_many_tags.iter().map(String::as_str).collect();
+    let long_note = "a".repeat(501);
+
+    for (metadata, expected) in [
+        (
+            canister_metadata(&too_many_tags, None),
+            UpdateCanisterMetadataResponse::TooManyTags {
+                limit: MAX_CANISTER_TAGS,
+            },
+        ),
+        (
+            canister_metadata(&[""], None),
+            UpdateCanisterMetadataResponse::InvalidTag,
+        ),
+        (
+            canister_metadata(&["a_tag_that_is_much_too_long"], None),
+            UpdateCanisterMetadataResponse::InvalidTag,
+        ),
+        (
+            canister_metadata(&[], Some(long_note.as_str())),
+            UpdateCanisterMetadataResponse::NoteTooLong,
+        ),
+    ] {
+        let result = store.update_canister_metadata(
+            principal,
+            UpdateCanisterMetadataRequest {
+                canister_id,
+                metadata,
+                expected_version: None,
+            },
+        );
+        assert_eq!(result, expected);
+    }
+    assert_eq!(store.get_canisters(principal)[0].metadata, None);
+}
+
+#[test]
+fn update_canister_metadata_canister_not_found() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let result = store.update_canister_metadata(
+        principal,
+        UpdateCanisterMetadataRequest {
+            canister_id: CanisterId::from_str(TEST_ACCOUNT_2).unwrap(),
+            metadata: canister_metadata(&["prod"], None),
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, UpdateCanisterMetadataResponse::CanisterNotFound);
+}
+
+fn create_canister_group(store: &mut AccountsStore, principal: PrincipalId, name: &str) {
+    let result = store.create_canister_group(
+        principal,
+        CreateCanisterGroupRequest {
+            name: name.to_string(),
+            expected_version: None,
+        },
+    );
+    assert_eq!(result, CreateCanisterGroupResponse::Ok);
+}
+
+fn set_canister_group(store: &mut AccountsStore, principal: PrincipalId, canister_id: CanisterId, group: Option<&str>) {
+    let result = store.set_canister_group(
+        principal,
+        SetCanisterGroupRequest {
+            canister_id,
+            group: group.map(ToString::to_string),
+            expected_version: None,
+        },
+    );
+    assert_eq!(result, SetCanisterGroupResponse::Ok);
+}
+
+fn canister_ids_in_order(store: &AccountsStore, principal: PrincipalId) -> Vec<CanisterId> {
+    store
+        .get_canisters(principal)
+        .into_iter()
+        .map(|canister| canister.canister_id)
+        .collect()
+}
+
+#[test]
+fn get_canisters_orders_canisters_by_group() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let canister_ids = attach_canisters_for_metadata_tests(&mut store, principal);
+    create_canister_group(&mut store, principal, "B");
+    create_canister_group(&mut store, principal, "A");
+
+    set_canister_group(&mut store, principal, canister_ids[1], Some("A"));
+    set_canister_group(&mut store, principal, canister_ids[2], Some("B"));
+
+    // Groups are ordered by creation, not by name, and ungrouped canisters come last.
+    assert_eq!(
+        canister_ids_in_order(&store, principal),
+        vec![canister_ids[2], canister_ids[1], canister_ids[0]]
+    );
+    assert_eq!(
+        store.get_canister_groups(principal),
+        GetCanisterGroupsResponse::Ok(vec!["B".to_string(), "A".to_string()])
+    );
+
+    let result = store.reorder_canister_groups(
+        principal,
+        ReorderCanisterGroupsRequest {
+            names: vec!["A".to_string(), "B".to_string()],
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, ReorderCanisterGroupsResponse::Ok);
+    assert_eq!(
+        canister_ids_in_order(&store, principal),
+        vec![canister_ids[1], canister_ids[2], canister_ids[0]]
+    );
+}
+
+#[test]
+fn get_canisters_in_group() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let canister_ids = attach_canisters_for_metadata_tests(&mut store, principal);
+    create_canister_group(&mut store, principal, "A");
+    create_canister_group(&mut store, principal, "B");
+    set_canister_group(&mut store, principal, canister_ids[0], Some("B"));
+    set_canister_group(&mut store, principal, canister_ids[2], Some("B"));
+    let in_group = |store: &AccountsStore, group: &str| -> Vec<CanisterId> {
+        store
+            .get_filtered_canisters(
+                principal,
+                GetCanistersRequest {
+                    group: Some(group.to_string()),
+                    ..GetCanistersRequest::default()
+                },
+            )
+            .into_iter()
+            .map(|canister| canister.canister_id)
+            .collect()
+    };
+
+    assert_eq!(in_group(&store, "B"), vec![canister_ids[0], canister_ids[2]]);
+    assert_eq!(in_group(&store, "A"), vec![]);
+    assert_eq!(in_group(&store, "C"), vec![]);
+}
+
+#[test]
+fn rename_canister_group_keeps_canisters_in_the_group() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let canister_id = attach_canisters_for_metadata_tests(&mut store, principal)[0];
+    create_canister_group(&mut store, principal, "A");
+    create_canister_group(&mut store, principal, "B");
+    set_canister_group(&mut store, principal, canister_id, Some("A"));
+
+    assert_eq!(
+        store.rename_canister_group(
+            principal,
+            RenameCanisterGroupRequest {
+                name: "A".to_string(),
+                new_name: "B".to_string(),
+                expected_version: None,
+            },
+        ),
+        RenameCanisterGroupResponse::NameAlreadyTaken
+    );
+    // Renaming a group to its own name changes nothing.
+    let version = store.get_account(principal).unwrap().version;
+    assert_eq!(
+        store.rename_canister_group(
+            principal,
+            RenameCanisterGroupRequest {
+                name: "A".to_string(),
+                new_name: "A".to_string(),
+                expected_version: Some(version),
+            },
+        ),
+        RenameCanisterGroupResponse::Ok
+    );
+    assert_eq!(store.get_account(principal).unwrap().version, version);
+    let result = store.rename_canister_group(
+        principal,
+        RenameCanisterGroupRequest {
+            name: "A".to_string(),
+            new_name: "C".to_string(),
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, RenameCanisterGroupResponse::Ok);
+    assert_eq!(store.get_canisters(principal)[0].group, Some("C".to_string()));
+    assert_eq!(
+        store.get_canister_groups(principal),
+        GetCanisterGroupsResponse::Ok(vec!["C".to_string(), "B".to_string()])
+    );
+}
+
+#[test]
+fn delete_canister_group_ungroups_its_canisters() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let canister_id = attach_canisters_for_metadata_tests(&mut store, principal)[0];
+    create_canister_group(&mut store, principal, "A");
+    set_canister_group(&mut store, principal, canister_id, Some("A"));
+
+    let result = store.delete_canister_group(
+        principal,
+        DeleteCanisterGroupRequest {
+            name: "A".to_string(),
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, DeleteCanisterGroupResponse::Ok);
+    assert!(store
+        .get_canisters(principal)
+        .iter()
+        .all(|canister| canister.group.is_none()));
+    assert_eq!(
+        store.get_canister_groups(principal),
+        GetCanisterGroupsResponse::Ok(vec![])
+    );
+    assert_eq!(
+        store.delete_canister_group(
+            principal,
+            DeleteCanisterGroupRequest {
+                name: "A".to_string(),
+                expected_version: None
+            }
+        ),
+        DeleteCanisterGroupResponse::GroupNotFound
+    );
+}
+
+#[test]
+fn create_canister_group_invalid() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    for i in 0..MAX_CANISTER_GROUPS {
+        create_canister_group(&mut store, principal, &i.to_string());
+    }
+
+    for (name, expected) in [
+        ("", CreateCanisterGroupResponse::NameTooLong),
+        (
+            "a_group_name_that_is_too_long",
+            CreateCanisterGroupResponse::NameTooLong,
+        ),
+        ("0", CreateCanisterGroupResponse::NameAlreadyTaken),
+        (
+            "new",
+            CreateCanisterGroupResponse::GroupLimitExceeded {
+                limit: MAX_CANISTER_GROUPS,
+            },
+        ),
+    ] {
+        let result = store.create_canister_group(
+            principal,
+            CreateCanisterGroupRequest {
+                name: name.to_string(),
+                expected_version: None,
+            },
+        );
+        assert_eq!(result, expected);
+    }
+}
+
+#[test]
+fn reorder_canister_groups_mismatch() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    create_canister_group(&mut store, principal, "A");
+    create_canister_group(&mut store, principal, "B");
+
+    for names in [vec!["A"], vec!["A", "B", "C"], vec!["A", "A"]] {
+        let result = store.reorder_canister_groups(
+            principal,
+            ReorderCanisterGroupsRequest {
+                names: names.into_iter().map(ToString::to_string).collect(),
+                expected_version: None,
+            },
+        );
+        assert_eq!(result, ReorderCanisterGroupsResponse::GroupsMismatch);
+    }
+}
+
+#[test]
+fn set_canister_group_group_not_found() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let canister_id = attach_canisters_for_metadata_tests(&mut store, principal)[0];
+
+    let result = store.set_canister_group(
+        principal,
+        SetCanisterGroupRequest {
+            canister_id,
+            group: Some("A".to_string()),
+            expected_version: None,
+        },
+    );
+
+    assert_eq!(result, SetCanisterGroupResponse::GroupNotFound);
+}
+
+#[test]
+fn attach_newly_created_canister_attaches_if_not_present() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+
+    let canisters = store.get_canisters(principal);
+    assert_eq!(canisters.len(), 0);
+
+    store.attach_newly_created_canister(principal, canister_id);
+
+    let final_canisters = store.get_canisters(principal);
+    assert_eq!(final_canisters.len(), 1);
+}
+
+#[test]
+fn attach_newly_created_canister_does_not_attach_if_present() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+
+    let name = "XYZ";
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: name.to_string(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+
+    let canisters = store.get_canisters(principal);
+    assert_eq!(canisters.len(), 1);
+
+    store.attach_newly_created_canister(principal, canister_id);
+
+    let final_canisters = store.get_canisters(principal);
+    assert_eq!(final_canisters.len(), 1);
+    assert_eq!(final_canisters[0].name, name);
+}
+
+#[test]
+fn attach_canister_and_rename() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+
+    let initial_name = "ABC".to_string();
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: initial_name.clone(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+
+    let canisters = store.get_canisters(principal);
+    assert_eq!(initial_name, canisters[0].name);
+
+    let final_name = "DEF".to_string();
+    store.rename_canister(
+        principal,
+        RenameCanisterRequest {
+            name: final_name.clone(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+
+    let canisters = store.get_canisters(principal);
+    assert_eq!(final_name, canisters[0].name);
+}
+
+#[test]
+fn rename_to_taken_name_fails() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
+
+    let name1 = "ABC".to_string();
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: name1.clone(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+    let name2 = "DEF".to_string();
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: name2.clone(),
+            canister_id: canister_id2,
+            expected_version: None,
+        },
+    );
+    let canisters = store.get_canisters(principal);
+    assert_eq!(name1, canisters[0].name);
+    assert_eq!(name2, canisters[1].name);
+
+    let response = store.rename_canister(
+        principal,
+        RenameCanisterRequest {
+            name: name1.clone(),
+            canister_id: canister_id2,
+            expected_version: None,
+        },
+    );
+
+    assert!(matches!(response, RenameCanisterResponse::NameAlreadyTaken));
+
+    let canisters = store.get_canisters(principal);
+    assert_eq!(name1, canisters[0].name);
+    assert_eq!(name2, canisters[1].name);
+}
+
+#[test]
+fn rename_to_long_name_fails() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+
+    let long_name = "ABCDEFGHIJKLMNOPQRSTUVWXY".to_string();
+    let name = "DEF".to_string();
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: name.clone(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+    let response = store.rename_canister(
+        principal,
+        RenameCanisterRequest {
+            name: long_name,
+            canister_id,
+            expected_version: None,
+        },
+    );
+    let canisters = store.get_canisters(principal);
+    assert_eq!(name, canisters[0].name);
+    assert!(matches!(response, RenameCanisterResponse::NameTooLong));
+}
+
+#[test]
+fn rename_not_found_canister() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
+
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "DEF".to_string(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+    let response = store.rename_canister(
+        principal,
+        RenameCanisterRequest {
+            name: "ABC".to_string(),
+            canister_id: canister_id2,
+            expected_version: None,
+        },
+    );
+    assert!(matches!(response, RenameCanisterResponse::CanisterNotFound));
+}
+
+#[test]
+fn rename_not_found_account() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let principal2 = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+
+    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "DEF".to_string(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+    let response = store.rename_canister(
+        principal2,
+        RenameCanisterRequest {
+            name: "ABC".to_string(),
+            canister_id,
+            expected_version: None,
+        },
+    );
+    assert!(matches!(response, RenameCanisterResponse::AccountNotFound));
+}
+
+#[test]
+fn canisters_ordered_by_name_if_exists_then_by_id() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id1 = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
+    let canister_id3 = CanisterId::from_str(TEST_ACCOUNT_4).unwrap();
+    let canister_id4 = CanisterId::from_str(TEST_ACCOUNT_5).unwrap();
+
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "".to_string(),
+            canister_id: canister_id1,
+            expected_version: None,
+        },
+    );
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "ABC".to_string(),
+            canister_id: canister_id2,
+            expected_version: None,
+        },
+    );
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "XYZ".to_string(),
+            canister_id: canister_id3,
+            expected_version: None,
+        },
+    );
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "".to_string(),
+            canister_id: canister_id4,
+            expected_version: None,
+        },
+    );
+
+    let canisters = store.get_canisters(principal);
+
+    assert_eq!(4, canisters.len());
+    assert_eq!(canister_id2, canisters[0].canister_id);
+    assert_eq!(canister_id3, canisters[1].canister_id);
+    assert_eq!(canister_id4, canisters[2].canister_id);
+    assert_eq!(canister_id1, canisters[3].canister_id);
+}
+
+#[test]
+fn detach_canister() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id1 = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
+
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "ABC".to_string(),
+            canister_id: canister_id1,
+            expected_version: None,
+        },
+    );
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "XYZ".to_string(),
+            canister_id: canister_id2,
+            expected_version: None,
+        },
+    );
+
+    let result = store.detach_canister(
+        principal,
+        DetachCanisterRequest {
+            canister_id: canister_id1,
+            expected_version: None,
+        },
+    );
+
+    assert!(matches!(result, DetachCanisterResponse::Ok));
+
+    let canisters = store.get_canisters(principal);
+
+    assert_eq!(1, canisters.len());
+    assert_eq!(canister_id2, canisters[0].canister_id);
+}
+
+#[test]
+fn detach_canister_canister_not_found() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let canister_id1 = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
+
+    store.attach_canister(
+        principal,
+        AttachCanisterRequest {
+            name: "ABC".to_string(),
+            canister_id: canister_id1,
+            expected_version: None,
+        },
+    );
+
+    let result = store.detach_canister(
+        principal,
+        DetachCanisterRequest {
+            canister_id: canister_id2,
+            expected_version: None,
+        },
+    );
+
+    assert!(matches!(result, DetachCanisterResponse::CanisterNotFound));
+
+    let canisters = store.get_canisters(principal);
+
+    assert_eq!(1, canisters.len());
+    assert_eq!(canister_id1, canisters[0].canister_id);
+}
+
+#[test]
+fn set_and_get_imported_tokens() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let ledger_canister_id = PrincipalId::new_user_test_id(101);
+    let index_canister_id = PrincipalId::new_user_test_id(102);
+
+    assert_eq!(
+        store.get_imported_tokens(principal),
+        GetImportedTokensResponse::Ok(ImportedTokens::default())
+    );
+
+    let imported_token = ImportedToken {
+        ledger_canister_id,
+        index_canister_id: Some(index_canister_id),
+        settings: None,
+    };
+
+    assert_eq!(
+        store.set_imported_tokens(
+            principal,
+            ImportedTokens {
+                imported_tokens: vec![imported_token.clone()],
+            },
+            None,
+        ),
+        SetImportedTokensResponse::Ok
+    );
+
+    assert_eq!(
+        store.get_imported_tokens(principal),
+        GetImportedTokensResponse::Ok(ImportedTokens {
+            imported_tokens: vec![imported_token],
+        })
+    );
+}
+
+#[test]
+fn set_and_get_imported_tokens_without_index_canister() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let ledger_canister_id = PrincipalId::new_user_test_id(101);
+
+    assert_eq!(
+        store.get_imported_tokens(principal),
+        GetImportedTokensResponse::Ok(ImportedTokens::default())
+    );
+
+    let imported_token = ImportedToken {
+        ledger_canister_id,
+        index_canister_id: None,
+        settings: None,
+    };
+
+    assert_eq!(
+        store.set_imported_tokens(
+            principal,
+            ImportedTokens {
+                imported_tokens: vec![imported_token.clone()],
+            },
+            None,
+        ),
+        SetImportedTokensResponse::Ok
+    );
+
+    assert_eq!(
+        store.get_imported_tokens(principal),
+        GetImportedTokensResponse::Ok(ImportedTokens {
+            imported_tokens: vec![imported_token],
+        })
+    );
+}
+
+fn get_unique_imported_tokens(count: u64) -> Vec<ImportedToken> {
+    (0..count)
+        .map(|i| ImportedToken {
+            ledger_canister_id: PrincipalId::new_user_test_id(i),
+            index_canister_id: Some(PrincipalId::new_user_test_id(i + 1000)),
+            settings: None,
+        })
+        .collect()
+}
+
+#[test]
+fn set_and_get_20_imported_tokens() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    assert_eq!(
+        store.get_imported_tokens(principal),
+        GetImportedTokensResponse::Ok(ImportedTokens::default())
+    );
+
+    let imported_tokens = get_unique_imported_tokens(20);
+
+    assert_eq!(
+        store.set_imported_tokens(
+            principal,
+            ImportedTokens {
+                imported_tokens: imported_tokens.clone()
+            },
+            None,
+        ),
+        SetImportedTokensResponse::Ok
+    );
+
+    assert_eq!(
+        store.get_imported_tokens(principal),
+        GetImportedTokensResponse::Ok(ImportedTokens { imported_tokens })
+    );
+}
+
+#[test]
+fn set_imported_tokens_account_not_found() {
+    let mut store = setup_test_store();
+    let non_existing_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    assert_eq!(
+        store.set_imported_tokens(non_existing_principal, ImportedTokens::default(), None),
+        SetImportedTokensResponse::AccountNotFound
+    );
+}
+
+#[test]
+fn set_imported_tokens_too_many() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    let imported_tokens = get_unique_imported_tokens(21);
+
+    assert_eq!(
+        store.set_imported_tokens(principal, ImportedTokens { imported_tokens },, None),
+        SetImportedTokensResponse::TooManyImportedTokens { limit: 20 }
+    );
+}
+
+#[test]
+fn get_imported_tokens_account_not_found() {
+    let mut store = setup_test_store();
+    let non_existing_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    assert_eq!(
+        store.get_imported_tokens(non_existing_principal),
+        GetImportedTokensResponse::AccountNotFound
+    );
+}
+
+fn imported_token_with_settings(index: u64, settings: ImportedTokenSettings) -> ImportedToken {
+    ImportedToken {
+        ledger_canister_id: PrincipalId::new_user_test_id(index),
+        index_canister_id: None,
+        settings: Some(settings),
+    }
+}
+
+#[test]
+fn get_imported_tokens_sorts_pinned_tokens_first_then_by_display_order() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let unordered = imported_token_with_settings(0, ImportedTokenSettings::default());
+    let second = imported_token_with_settings(
+        1,
+        ImportedTokenSettings {
+            display_order: Some(2),
+            ..ImportedTokenSettings::default()
+        },
+    );
+    let first = imported_token_with_settings(
+        2,
+        ImportedTokenSettings {
+            display_order: Some(1),
+            hidden: true,
+            ..ImportedTokenSettings::default()
+        },
+    );
+    let pinned = imported_token_with_settings(
+        3,
+        ImportedTokenSettings {
+            display_order: Some(3),
+            label: Some("Favourite".to_string()),
+            pinned: true,
+            ..ImportedTokenSettings::default()
+        },
+    );
+    store.set_imported_tokens(
+        principal,
+        ImportedTokens {
+            imported_tokens: vec![unordered.clone(), second.clone(), first.clone(), pinned.clone()],
+        },
+        None,
+    );
+
+    assert_eq!(
+        store.get_imported_tokens(principal),
+        GetImportedTokensResponse::Ok(ImportedTokens {
+            imported_tokens: vec![pinned, first, second, unordered],
         })
-        .collect();
-
-    assert_eq!(expected.len(), canisters.len());
-    for i in 0..canisters.len() {
-        assert_eq!(expected[i], canisters[i]);
-    }
+    );
 }
 
 #[test]
-fn attach_canister_name_already_taken() {
+fn set_imported_tokens_label_too_long() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let imported_token = imported_token_with_settings(
+        0,
+        ImportedTokenSettings {
+            label: Some("a".repeat(25)),
+            ..ImportedTokenSettings::default()
+        },
+    );
 
-    let canister_id1 = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
-    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
+    assert_eq!(
+        store.set_imported_tokens(
+            principal,
+            ImportedTokens {
+                imported_tokens: vec![imported_token.clone()],
+            },
+            None,
+        ),
+        SetImportedTokensResponse::LabelTooLong
+    );
+    assert_eq!(
+        store.add_imported_token(principal, imported_token, None),
+        AddImportedTokenResponse::LabelTooLong
+    );
+}
 
-    let result1 = store.attach_canister(
+#[test]
+fn add_imported_token_keeps_other_tokens() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let imported_tokens = get_unique_imported_tokens(2);
+    store.set_imported_tokens(
         principal,
-        AttachCanisterRequest {
-            name: "ABC".to_string(),
-            canister_id: canister_id1,
+        ImportedTokens {
+            imported_tokens: imported_tokens.clone(),
         },
+        None,
     );
-    let result2 = store.attach_canister(
+    let new_token = get_unique_imported_tokens(3).pop().unwrap();
+
+    assert_eq!(
+        store.add_imported_token(principal, new_token.clone(), None),
+        AddImportedTokenResponse::Ok
+    );
+
+    let mut expected = imported_tokens;
+    expected.push(new_token);
+    assert_eq!(
+        store.get_imported_tokens(principal),
+        GetImportedTokensResponse::Ok(ImportedTokens {
+            imported_tokens: expected,
+        })
+    );
+}
+
+#[test]
+fn add_imported_token_replaces_token_with_same_ledger() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let imported_tokens = get_unique_imported_tokens(2);
+    store.set_imported_tokens(
         principal,
-        AttachCanisterRequest {
-            name: "ABC".to_string(),
-            canister_id: canister_id2,
+        ImportedTokens {
+            imported_tokens: imported_tokens.clone(),
         },
+        None,
     );
+    let updated_token = ImportedToken {
+        settings: Some(ImportedTokenSettings {
+            hidden: true,
+            ..ImportedTokenSettings::default()
+        }),
+        ..imported_tokens[0].clone()
+    };
 
-    assert!(matches!(result1, AttachCanisterResponse::Ok));
-    assert!(matches!(result2, AttachCanisterResponse::NameAlreadyTaken));
+    assert_eq!(
+        store.add_imported_token(principal, updated_token.clone(), None),
+        AddImportedTokenResponse::Ok
+    );
+
+    assert_eq!(
+        store.get_imported_tokens(principal),
+        GetImportedTokensResponse::Ok(ImportedTokens {
+            imported_tokens: vec![updated_token, imported_tokens[1].clone()],
+        })
+    );
 }
 
 #[test]
-fn attach_canister_name_too_long() {
+fn add_imported_token_too_many() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let mut imported_tokens = get_unique_imported_tokens(MAX_IMPORTED_TOKENS as u64 + 1);
+    let extra_token = imported_tokens.pop().unwrap();
+    store.set_imported_tokens(principal, ImportedTokens { imported_tokens }, None);
 
-    let canister_id1 = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
-    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
+    assert_eq!(
+        store.add_imported_token(principal, extra_token, None),
+        AddImportedTokenResponse::TooManyImportedTokens {
+            limit: MAX_IMPORTED_TOKENS
+        }
+    );
+}
 
-    let result1 = store.attach_canister(
+#[test]
+fn remove_imported_token() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let imported_tokens = get_unique_imported_tokens(2);
+    store.set_imported_tokens(
         principal,
-        AttachCanisterRequest {
-            name: "ABCDEFGHIJKLMNOPQRSTUVWX".to_string(),
-            canister_id: canister_id1,
+        ImportedTokens {
+            imported_tokens: imported_tokens.clone(),
         },
+        None,
     );
-    let result2 = store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: "ABCDEFGHIJKLMNOPQRSTUVWXY".to_string(),
-            canister_id: canister_id2,
-        },
+    let request = || RemoveImportedTokenRequest {
+        ledger_canister_id: imported_tokens[0].ledger_canister_id,
+        expected_version: None,
+    };
+
+    assert_eq!(
+        store.remove_imported_token(principal, request()),
+        RemoveImportedTokenResponse::Ok
+    );
+    assert_eq!(
+        store.remove_imported_token(principal, request()),
+        RemoveImportedTokenResponse::TokenNotFound
+    );
+    assert_eq!(
+        store.get_imported_tokens(principal),
+        GetImportedTokensResponse::Ok(ImportedTokens {
+            imported_tokens: vec![imported_tokens[1].clone()],
+        })
     );
+}
 
-    assert!(matches!(result1, AttachCanisterResponse::Ok));
-    assert!(matches!(result2, AttachCanisterResponse::NameTooLong));
+fn preferences(data: &[u8]) -> Preferences {
+    Preferences {
+        schema_version: 1,
+        data: ByteBuf::from(data),
+    }
 }
 
 #[test]
-fn attach_canister_canister_already_attached() {
+fn set_and_get_preferences() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
 
-    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
-
-    let result1 = store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: "ABC".to_string(),
-            canister_id,
-        },
+    assert_eq!(
+        store.get_preferences(principal),
+        GetPreferencesResponse::Ok(Preferences::default())
     );
-    let result2 = store.attach_canister(
+
+    let result = store.set_preferences(
         principal,
-        AttachCanisterRequest {
-            name: "XYZ".to_string(),
-            canister_id,
+        SetPreferencesRequest {
+            preferences: preferences(br#"{"language":"en"}"#),
+            expected_version: None,
         },
     );
 
-    assert!(matches!(result1, AttachCanisterResponse::Ok));
-    assert!(matches!(result2, AttachCanisterResponse::CanisterAlreadyAttached));
+    assert_eq!(result, SetPreferencesResponse::Ok);
+    assert_eq!(
+        store.get_preferences(principal),
+        GetPreferencesResponse::Ok(preferences(br#"{"language":"en"}"#))
+    );
 }
 
 #[test]
-fn attach_canister_substitutes_empty_name_canister() {
+fn set_preferences_too_large() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let max_size = MAX_PREFERENCES_SIZE_BYTES as usize;
 
-    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+    assert_eq!(
+        store.set_preferences(
+            principal,
+            SetPreferencesRequest {
+                preferences: preferences(&vec![0; max_size]),
+                expected_version: None,
+            },
+        ),
+        SetPreferencesResponse::Ok
+    );
+    assert_eq!(
+        store.set_preferences(
+            principal,
+            SetPreferencesRequest {
+                preferences: preferences(&vec![0; max_size + 1]),
+                expected_version: None,
+            },
+        ),
+        SetPreferencesResponse::PreferencesTooLarge {
+            limit: MAX_PREFERENCES_SIZE_BYTES
+        }
+    );
+}
 
-    store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: "".to_string(),
-            canister_id,
-        },
+#[test]
+fn preferences_account_not_found() {
+    let mut store = setup_test_store();
+    let non_existing_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+
+    assert_eq!(
+        store.set_preferences(
+            non_existing_principal,
+            SetPreferencesRequest {
+                preferences: preferences(b"{}"),
+                expected_version: None,
+            },
+        ),
+        SetPreferencesResponse::AccountNotFound
+    );
+    assert_eq!(
+        store.get_preferences(non_existing_principal),
+        GetPreferencesResponse::AccountNotFound
     );
+}
 
-    let initial_canisters = store.get_canisters(principal);
-    assert!(initial_canisters[0].name.is_empty());
+fn transfer(from: AccountIdentifier, to: AccountIdentifier) -> Operation {
+    Transfer {
+        amount: Tokens::from_e8s(100_000),
+        fee: Tokens::from_e8s(10_000),
+        spender: None,
+        from,
+        to,
+    }
+}
 
-    let name = "XYZ";
-    let result = store.attach_canister(
+/// Takes the next multi-part transaction to process, without its retry state.
+fn next_transaction_to_process(store: &mut AccountsStore) -> Option<(BlockIndex, MultiPartTransactionToBeProcessed)> {
+    store
+        .try_take_next_transaction_to_process()
+        .map(|queued| (queued.block_height, queued.transaction))
+}
+
+fn indexed_transactions(
+    store: &AccountsStore,
+    principal: PrincipalId,
+    before: Option<BlockIndex>,
+    limit: u32,
+) -> IndexedTransactionsPage {
+    match store.get_indexed_transactions(principal, GetIndexedTransactionsRequest { before, limit }) {
+        GetIndexedTransactionsResponse::Ok(page) => page,
+        response => panic!("Failed to get indexed transactions: {response:?}"),
+    }
+}
+
+#[test]
+fn transaction_index_records_blocks_touching_main_sub_and_hardware_wallet_accounts() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let main_account = AccountIdentifier::from(principal);
+    let other_account = AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_2).unwrap());
+    let stranger = AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_5).unwrap());
+    let hardware_wallet = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    store.create_sub_account(principal, "AAA".to_string(), None);
+    store.register_hardware_wallet(
         principal,
-        AttachCanisterRequest {
-            name: name.to_string(),
-            canister_id,
+        RegisterHardwareWalletRequest {
+            name: "HW".to_string(),
+            principal: hardware_wallet,
+            expected_version: None,
         },
     );
+    let sub_account = store.get_account(principal).unwrap().sub_accounts[0].account_identifier;
 
-    let canisters = store.get_canisters(principal);
-    assert_eq!(name, canisters[0].name);
-    assert!(matches!(result, AttachCanisterResponse::Ok));
+    assert_eq!(
+        store.enable_transaction_index(principal),
+        EnableTransactionIndexResponse::Ok
+    );
+    let blocks = [
+        transfer(main_account, other_account),
+        transfer(other_account, stranger),
+        transfer(stranger, sub_account),
+        transfer(main_account, sub_account),
+        Mint {
+            amount: Tokens::from_e8s(100_000),
+            to: AccountIdentifier::from(hardware_wallet),
+        },
+    ];
+    for (block_height, block) in (4..).zip(blocks.iter()) {
+        store.maybe_process_transaction(block, Memo(0), block_height).unwrap();
+    }
+
+    assert_eq!(
+        indexed_transactions(&store, principal, None, 10),
+        IndexedTransactionsPage {
+            block_heights: vec![8, 7, 6, 4],
+            next: None,
+        }
+    );
+    // Users who have not enabled the index have nothing recorded.
+    assert_eq!(
+        store.get_indexed_transactions(
+            PrincipalId::from_str(TEST_ACCOUNT_2).unwrap(),
+            GetIndexedTransactionsRequest {
+                before: None,
+                limit: 10
+            }
+        ),
+        GetIndexedTransactionsResponse::NotEnabled
+    );
 }
 
 #[test]
-fn attach_newly_created_canister_attaches_if_not_present() {
+fn get_indexed_transactions_paginates_newest_first() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let main_account = AccountIdentifier::from(principal);
+    let other_account = AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_2).unwrap());
+    store.enable_transaction_index(principal);
+    for block_height in 4..9 {
+        store
+            .maybe_process_transaction(&transfer(main_account, other_account), Memo(0), block_height)
+            .unwrap();
+    }
 
-    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+    let first_page = indexed_transactions(&store, principal, None, 2);
+    assert_eq!(
+        first_page,
+        IndexedTransactionsPage {
+            block_heights: vec![8, 7],
+            next: Some(7),
+        }
+    );
+    let second_page = indexed_transactions(&store, principal, first_page.next, 2);
+    assert_eq!(
+        second_page,
+        IndexedTransactionsPage {
+            block_heights: vec![6, 5],
+            next: Some(5),
+        }
+    );
+    let last_page = indexed_transactions(&store, principal, second_page.next, 2);
+    assert_eq!(
+        last_page,
+        IndexedTransactionsPage {
+            block_heights: vec![4],
+            next: None,
+        }
+    );
+}
 
-    let canisters = store.get_canisters(principal);
-    assert_eq!(canisters.len(), 0);
+#[test]
+fn get_indexed_transactions_invalid_limit() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    store.enable_transaction_index(principal);
 
-    store.attach_newly_created_canister(principal, canister_id);
+    assert_eq!(
+        store.get_indexed_transactions(principal, GetIndexedTransactionsRequest { before: None, limit: 0 }),
+        GetIndexedTransactionsResponse::InvalidLimit
+    );
+}
 
-    let final_canisters = store.get_canisters(principal);
-    assert_eq!(final_canisters.len(), 1);
+#[test]
+fn replayed_blocks_are_indexed_in_order() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let main_account = AccountIdentifier::from(principal);
+    let other_account = AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_2).unwrap());
+    store.enable_transaction_index(principal);
+    for block_height in [4, 6] {
+        store
+            .maybe_process_transaction(&transfer(main_account, other_account), Memo(0), block_height)
+            .unwrap();
+    }
+
+    store.replay_transaction(&transfer(main_account, other_account), Memo(0), 5);
+    store.replay_transaction(&transfer(main_account, other_account), Memo(0), 5);
+
+    assert_eq!(
+        indexed_transactions(&store, principal, None, 10),
+        IndexedTransactionsPage {
+            block_heights: vec![6, 5, 4],
+            next: None,
+        }
+    );
 }
 
 #[test]
-fn attach_newly_created_canister_does_not_attach_if_present() {
+fn transaction_index_keeps_only_the_most_recent_blocks() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let main_account = AccountIdentifier::from(principal);
+    let other_account = AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_2).unwrap());
+    store.enable_transaction_index(principal);
+    let first_block_height = 4;
+    let last_block_height = first_block_height + MAX_INDEXED_BLOCKS_PER_ACCOUNT as u64 + 9;
+    for block_height in first_block_height..=last_block_height {
+        store
+            .maybe_process_transaction(&transfer(main_account, other_account), Memo(0), block_height)
+            .unwrap();
+    }
 
-    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+    let (block_heights, _) = store.transaction_index.page(&main_account, None, usize::MAX).unwrap();
+    assert_eq!(block_heights.len(), MAX_INDEXED_BLOCKS_PER_ACCOUNT);
+    assert_eq!(block_heights.first(), Some(&last_block_height));
+    assert_eq!(block_heights.last(), Some(&(first_block_height + 10)));
+}
 
-    let name = "XYZ";
-    store.attach_canister(
+#[test]
+fn disable_transaction_index_discards_indexed_blocks() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let main_account = AccountIdentifier::from(principal);
+    let other_account = AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_2).unwrap());
+    store.enable_transaction_index(principal);
+    store
+        .maybe_process_transaction(&transfer(main_account, other_account), Memo(0), 4)
+        .unwrap();
+
+    assert_eq!(
+        store.disable_transaction_index(principal),
+        DisableTransactionIndexResponse::Ok
+    );
+    store
+        .maybe_process_transaction(&transfer(main_account, other_account), Memo(0), 5)
+        .unwrap();
+    assert_eq!(
+        store.get_indexed_transactions(
+            principal,
+            GetIndexedTransactionsRequest {
+                before: None,
+                limit: 10
+            }
+        ),
+        GetIndexedTransactionsResponse::NotEnabled
+    );
+
+    // Re-enabling starts from an empty index.
+    store.enable_transaction_index(principal);
+    assert_eq!(
+        indexed_transactions(&store, principal, None, 10),
+        IndexedTransactionsPage {
+            block_heights: vec![],
+            next: None,
+        }
+    );
+}
+
+#[test]
+fn transaction_index_of_a_principal_registered_as_someone_elses_hardware_wallet() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let victim = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    let victim_account = AccountIdentifier::from(victim);
+    let stranger = AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_5).unwrap());
+    // Anyone may register any principal as their hardware wallet, including one with an account.
+    store.register_hardware_wallet(
         principal,
-        AttachCanisterRequest {
-            name: name.to_string(),
-            canister_id,
+        RegisterHardwareWalletRequest {
+            name: "HW".to_string(),
+            principal: victim,
+            expected_version: None,
         },
     );
+    store.enable_transaction_index(principal);
+    store.enable_transaction_index(victim);
 
-    let canisters = store.get_canisters(principal);
-    assert_eq!(canisters.len(), 1);
+    store
+        .maybe_process_transaction(&transfer(victim_account, stranger), Memo(0), 4)
+        .unwrap();
 
-    store.attach_newly_created_canister(principal, canister_id);
+    assert_eq!(
+        store.main_account_identifiers(&victim_account),
+        vec![victim_account, AccountIdentifier::from(principal)]
+    );
+    for owner in [victim, principal] {
+        assert_eq!(
+            indexed_transactions(&store, owner, None, 10),
+            IndexedTransactionsPage {
+                block_heights: vec![4],
+                next: None,
+            }
+        );
+    }
+}
+
+#[test]
+fn transaction_index_account_not_found() {
+    let mut store = setup_test_store();
+    let non_existing_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+
+    assert_eq!(
+        store.enable_transaction_index(non_existing_principal),
+        EnableTransactionIndexResponse::AccountNotFound
+    );
+    assert_eq!(
+        store.disable_transaction_index(non_existing_principal),
+        DisableTransactionIndexResponse::AccountNotFound
+    );
+    assert_eq!(
+        store.get_indexed_transactions(
+            non_existing_principal,
+            GetIndexedTransactionsRequest {
+                before: None,
+                limit: 10
+            }
+        ),
+        GetIndexedTransactionsResponse::AccountNotFound
+    );
+}
 
-    let final_canisters = store.get_canisters(principal);
-    assert_eq!(final_canisters.len(), 1);
-    assert_eq!(final_canisters[0].name, name);
+fn canister_top_up_account(canister_id: CanisterId) -> AccountIdentifier {
+    AccountIdentifier::new(CYCLES_MINTING_CANISTER_ID.into(), Some((&canister_id.get()).into()))
 }
 
 #[test]
-fn attach_canister_and_rename() {
+fn top_up_of_attached_canister_is_queued_for_notification() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
-
-    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
-
-    let initial_name = "ABC".to_string();
+    let canister_id = CanisterId::from(1);
     store.attach_canister(
         principal,
         AttachCanisterRequest {
-            name: initial_name.clone(),
+            name: "ABC".to_string(),
             canister_id,
+            expected_version: None,
         },
     );
+    store.create_sub_account(principal, "AAA".to_string(), None);
+    let sub_account = store.get_account(principal).unwrap().sub_accounts[0].account_identifier;
 
-    let canisters = store.get_canisters(principal);
-    assert_eq!(initial_name, canisters[0].name);
+    store
+        .maybe_process_transaction(
+            &transfer(sub_account, canister_top_up_account(canister_id)),
+            MEMO_TOP_UP_CANISTER,
+            4,
+        )
+        .unwrap();
 
-    let final_name = "DEF".to_string();
-    store.rename_canister(
-        principal,
-        RenameCanisterRequest {
-            name: final_name.clone(),
-            canister_id,
-        },
+    assert_eq!(
+        next_transaction_to_process(&mut store),
+        Some((
+            4,
+            MultiPartTransactionToBeProcessed::TopUpCanisterV2(principal, canister_id)
+        ))
     );
+}
 
-    let canisters = store.get_canisters(principal);
-    assert_eq!(final_name, canisters[0].name);
+#[test]
+fn top_up_of_unattached_canister_is_ignored() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    store
+        .maybe_process_transaction(
+            &transfer(
+                AccountIdentifier::from(principal),
+                canister_top_up_account(CanisterId::from(1)),
+            ),
+            MEMO_TOP_UP_CANISTER,
+            4,
+        )
+        .unwrap();
+
+    assert_eq!(next_transaction_to_process(&mut store), None);
+}
+
+fn neuron_account(controller: PrincipalId, memo: Memo) -> AccountIdentifier {
+    AccountIdentifier::new(
+        GOVERNANCE_CANISTER_ID.into(),
+        Some(compute_neuron_staking_subaccount(controller, memo.0)),
+    )
 }
 
 #[test]
-fn rename_to_taken_name_fails() {
+fn stake_neuron_transfer_is_queued_for_claiming() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let memo = Memo(12345);
+    let neuron_account = neuron_account(principal, memo);
 
-    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
-    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
+    store
+        .maybe_process_transaction(&transfer(AccountIdentifier::from(principal), neuron_account), memo, 4)
+        .unwrap();
 
-    let name1 = "ABC".to_string();
-    store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: name1.clone(),
-            canister_id,
-        },
-    );
-    let name2 = "DEF".to_string();
-    store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: name2.clone(),
-            canister_id: canister_id2,
-        },
+    assert_eq!(
+        next_transaction_to_process(&mut store),
+        Some((4, MultiPartTransactionToBeProcessed::StakeNeuron(principal, memo)))
     );
-    let canisters = store.get_canisters(principal);
-    assert_eq!(name1, canisters[0].name);
-    assert_eq!(name2, canisters[1].name);
-
-    let response = store.rename_canister(
-        principal,
-        RenameCanisterRequest {
-            name: name1.clone(),
-            canister_id: canister_id2,
-        },
+    store.mark_neuron_created(principal, memo, NeuronId(7));
+    assert_eq!(
+        store
+            .neuron_accounts
+            .get(&neuron_account.to_vec())
+            .and_then(|neuron| neuron.neuron_id),
+        Some(NeuronId(7))
     );
+}
 
-    assert!(matches!(response, RenameCanisterResponse::NameAlreadyTaken));
+#[test]
+fn neuron_top_up_is_queued_for_refreshing() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let stranger = AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_5).unwrap());
+    let memo = Memo(12345);
+    let neuron_account = neuron_account(principal, memo);
+    store
+        .maybe_process_transaction(&transfer(AccountIdentifier::from(principal), neuron_account), memo, 4)
+        .unwrap();
+    let _stake = next_transaction_to_process(&mut store);
+
+    // Top-ups are usually sent with memo 0, and may come from accounts not known to the dapp.
+    let top_up = Transfer {
+        amount: Tokens::from_e8s(MIN_NEURON_TOP_UP_E8S),
+        fee: Tokens::from_e8s(10_000),
+        spender: None,
+        from: stranger,
+        to: neuron_account,
+    };
+    store.maybe_process_transaction(&top_up, Memo(0), 5).unwrap();
 
-    let canisters = store.get_canisters(principal);
-    assert_eq!(name1, canisters[0].name);
-    assert_eq!(name2, canisters[1].name);
+    assert_eq!(
+        next_transaction_to_process(&mut store),
+        Some((5, MultiPartTransactionToBeProcessed::TopUpNeuron(principal, memo)))
+    );
 }
 
 #[test]
-fn rename_to_long_name_fails() {
+fn small_neuron_top_ups_are_refreshed_only_if_sent_by_the_controller() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let stranger = AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_5).unwrap());
+    let memo = Memo(12345);
+    let neuron_account = neuron_account(principal, memo);
+    store
+        .maybe_process_transaction(&transfer(AccountIdentifier::from(principal), neuron_account), memo, 4)
+        .unwrap();
+    let _stake = next_transaction_to_process(&mut store);
 
-    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+    // Dust from anyone else does not make the dapp call governance.
+    store
+        .maybe_process_transaction(&transfer(stranger, neuron_account), Memo(0), 5)
+        .unwrap();
+    assert_eq!(next_transaction_to_process(&mut store), None);
 
-    let long_name = "ABCDEFGHIJKLMNOPQRSTUVWXY".to_string();
-    let name = "DEF".to_string();
-    store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: name.clone(),
-            canister_id,
-        },
-    );
-    let response = store.rename_canister(
-        principal,
-        RenameCanisterRequest {
-            name: long_name,
-            canister_id,
-        },
+    store
+        .maybe_process_transaction(
+            &transfer(AccountIdentifier::from(principal), neuron_account),
+            Memo(0),
+            6,
+        )
+        .unwrap();
+    assert_eq!(
+        next_transaction_to_process(&mut store),
+        Some((6, MultiPartTransactionToBeProcessed::TopUpNeuron(principal, memo)))
     );
-    let canisters = store.get_canisters(principal);
-    assert_eq!(name, canisters[0].name);
-    assert!(matches!(response, RenameCanisterResponse::NameTooLong));
 }
 
 #[test]
-fn rename_not_found_canister() {
+fn transfer_to_governance_subaccount_of_another_controller_is_not_a_stake() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let other_principal = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    let memo = Memo(12345);
 
-    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
-    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
+    store
+        .maybe_process_transaction(
+            &transfer(
+                AccountIdentifier::from(principal),
+                neuron_account(other_principal, memo),
+            ),
+            memo,
+            4,
+        )
+        .unwrap();
+
+    assert_eq!(next_transaction_to_process(&mut store), None);
+}
 
-    store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: "DEF".to_string(),
-            canister_id,
-        },
-    );
-    let response = store.rename_canister(
+/// Sets up a store in which `TEST_ACCOUNT_1` has registered `TEST_ACCOUNT_3` as a hardware wallet.
+fn setup_test_store_with_hardware_wallet() -> (AccountsStore, PrincipalId, PrincipalId) {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let hardware_wallet = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    store.register_hardware_wallet(
         principal,
-        RenameCanisterRequest {
-            name: "ABC".to_string(),
-            canister_id: canister_id2,
+        RegisterHardwareWalletRequest {
+            name: "HW".to_string(),
+            principal: hardware_wallet,
+            expected_version: None,
         },
     );
-    assert!(matches!(response, RenameCanisterResponse::CanisterNotFound));
+    (store, principal, hardware_wallet)
 }
 
 #[test]
-fn rename_not_found_account() {
-    let mut store = setup_test_store();
-    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
-    let principal2 = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+fn stake_neuron_transfer_from_hardware_wallet_is_attributed_to_the_hardware_wallet() {
+    let (mut store, _principal, hardware_wallet) = setup_test_store_with_hardware_wallet();
+    let memo = Memo(12345);
 
-    let canister_id = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
+    store
+        .maybe_process_transaction(
+            &transfer(
+                AccountIdentifier::from(hardware_wallet),
+                neuron_account(hardware_wallet, memo),
+            ),
+            memo,
+            4,
+        )
+        .unwrap();
 
-    store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: "DEF".to_string(),
-            canister_id,
-        },
+    assert_eq!(
+        next_transaction_to_process(&mut store),
+        Some((4, MultiPartTransactionToBeProcessed::StakeNeuron(hardware_wallet, memo)))
     );
-    let response = store.rename_canister(
-        principal2,
-        RenameCanisterRequest {
-            name: "ABC".to_string(),
-            canister_id,
-        },
+}
+
+#[test]
+fn create_canister_transfer_from_hardware_wallet_is_attributed_to_the_hardware_wallet() {
+    let (mut store, principal, hardware_wallet) = setup_test_store_with_hardware_wallet();
+    let cmc_account = AccountIdentifier::new(CYCLES_MINTING_CANISTER_ID.into(), Some((&hardware_wallet).into()));
+
+    store
+        .maybe_process_transaction(
+            &transfer(AccountIdentifier::from(hardware_wallet), cmc_account),
+            MEMO_CREATE_CANISTER,
+            4,
+        )
+        .unwrap();
+
+    assert_eq!(
+        next_transaction_to_process(&mut store),
+        Some((4, MultiPartTransactionToBeProcessed::CreateCanisterV2(hardware_wallet)))
     );
-    assert!(matches!(response, RenameCanisterResponse::AccountNotFound));
+
+    // The new canister is attached to the account that the hardware wallet is linked to.
+    let canister_id = CanisterId::from(1);
+    store.attach_newly_created_canister(hardware_wallet, canister_id);
+    let canister_ids: Vec<CanisterId> = store
+        .get_canisters(principal)
+        .iter()
+        .map(|canister| canister.canister_id)
+        .collect();
+    assert_eq!(canister_ids, vec![canister_id]);
 }
 
 #[test]
-fn canisters_ordered_by_name_if_exists_then_by_id() {
+fn canisters_created_by_a_principal_with_an_account_are_attached_only_to_its_own_account() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
-
-    let canister_id1 = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
-    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
-    let canister_id3 = CanisterId::from_str(TEST_ACCOUNT_4).unwrap();
-    let canister_id4 = CanisterId::from_str(TEST_ACCOUNT_5).unwrap();
-
-    store.attach_canister(
+    let hardware_wallet = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    store.register_hardware_wallet(
         principal,
-        AttachCanisterRequest {
-            name: "".to_string(),
-            canister_id: canister_id1,
+        RegisterHardwareWalletRequest {
+            name: "HW".to_string(),
+            principal: hardware_wallet,
+            expected_version: None,
         },
     );
-    store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: "ABC".to_string(),
-            canister_id: canister_id2,
+
+    let canister_id = CanisterId::from(1);
+    store.attach_newly_created_canister(hardware_wallet, canister_id);
+    let canister_ids = |principal| -> Vec<CanisterId> {
+        store
+            .get_canisters(principal)
+            .iter()
+            .map(|canister| canister.canister_id)
+            .collect()
+    };
+    assert_eq!(canister_ids(hardware_wallet), vec![canister_id]);
+    assert_eq!(canister_ids(principal), Vec::<CanisterId>::new());
+}
+
+#[test]
+fn canisters_created_by_a_hardware_wallet_linked_to_several_accounts_are_not_attached() {
+    let (mut store, principal, hardware_wallet) = setup_test_store_with_hardware_wallet();
+    let stranger = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    store.register_hardware_wallet(
+        stranger,
+        RegisterHardwareWalletRequest {
+            name: "HW".to_string(),
+            principal: hardware_wallet,
+            expected_version: None,
         },
     );
-    store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: "XYZ".to_string(),
-            canister_id: canister_id3,
+
+    let canister_id = CanisterId::from(1);
+    store.attach_newly_created_canister(hardware_wallet, canister_id);
+    assert!(store.get_canisters(principal).is_empty());
+    assert!(store.get_canisters(stranger).is_empty());
+
+    // Once the stranger unlinks the hardware wallet, new canisters are attached to the remaining account.
+    store.unregister_hardware_wallet(
+        stranger,
+        UnregisterHardwareWalletRequest {
+            principal: hardware_wallet,
+            expected_version: None,
         },
     );
-    store.attach_canister(
+    store.attach_newly_created_canister(hardware_wallet, canister_id);
+    let canister_ids: Vec<CanisterId> = store
+        .get_canisters(principal)
+        .iter()
+        .map(|canister| canister.canister_id)
+        .collect();
+    assert_eq!(canister_ids, vec![canister_id]);
+    assert!(store.get_canisters(stranger).is_empty());
+}
+
+#[test]
+fn transfer_from_unregistered_hardware_wallet_is_ignored() {
+    let (mut store, principal, hardware_wallet) = setup_test_store_with_hardware_wallet();
+    let memo = Memo(12345);
+    store.unregister_hardware_wallet(
         principal,
-        AttachCanisterRequest {
-            name: "".to_string(),
-            canister_id: canister_id4,
+        UnregisterHardwareWalletRequest {
+            principal: hardware_wallet,
+            expected_version: None,
         },
     );
 
-    let canisters = store.get_canisters(principal);
-
-    assert_eq!(4, canisters.len());
-    assert_eq!(canister_id2, canisters[0].canister_id);
-    assert_eq!(canister_id3, canisters[1].canister_id);
-    assert_eq!(canister_id4, canisters[2].canister_id);
-    assert_eq!(canister_id1, canisters[3].canister_id);
+    store
+        .maybe_process_transaction(
+            &transfer(
+                AccountIdentifier::from(hardware_wallet),
+                neuron_account(hardware_wallet, memo),
+            ),
+            memo,
+            4,
+        )
+        .unwrap();
+
+    assert_eq!(next_transaction_to_process(&mut store), None);
 }
 
 #[test]
-fn detach_canister() {
+fn neuron_accounts_survive_upgrade() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let memo = Memo(12345);
+    store
+        .maybe_process_transaction(
+            &transfer(AccountIdentifier::from(principal), neuron_account(principal, memo)),
+            memo,
+            4,
+        )
+        .unwrap();
+
+    // Neuron accounts held on the heap, as by a release that predates the partition, are moved into it.
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    store.load_neuron_accounts(partitions.get(PartitionType::NeuronAccounts.memory_id()));
+    assert_eq!(store.neuron_accounts.len(), 1);
+
+    // The neuron accounts are not serialized with the heap, but are loaded from the partition after an upgrade.
+    let mut decoded = AccountsStore::decode(store.encode()).unwrap();
+    assert!(decoded.neuron_accounts.is_empty());
+    decoded.load_neuron_accounts(partitions.get(PartitionType::NeuronAccounts.memory_id()));
+    assert_eq!(decoded.neuron_accounts, store.neuron_accounts);
+    assert_eq!(decoded.neuron_accounts.len(), 1);
+}
 
-    let canister_id1 = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
-    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
-
-    store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: "ABC".to_string(),
-            canister_id: canister_id1,
-        },
-    );
-    store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: "XYZ".to_string(),
-            canister_id: canister_id2,
+#[test]
+fn get_pending_operations_lists_queued_transactions_then_outcomes() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let other_principal = PrincipalId::from_str(TEST_ACCOUNT_2).unwrap();
+    crate::time::testing::set_time(1_000);
+    let create_canister = MultiPartTransactionToBeProcessed::CreateCanisterV2(principal);
+    store.record_operation_outcome(
+        4,
+        &create_canister,
+        OperationStatus::Failed {
+            error: "Insufficient funds".to_string(),
         },
+        Some(6),
     );
+    store.record_operation_outcome(5, &create_canister, OperationStatus::Succeeded, None);
+    store.enqueue_multi_part_transaction(7, MultiPartTransactionToBeProcessed::StakeNeuron(principal, Memo(9)));
+    store.enqueue_multi_part_transaction(8, MultiPartTransactionToBeProcessed::CreateCanisterV2(other_principal));
 
-    let result = store.detach_canister(
-        principal,
-        DetachCanisterRequest {
-            canister_id: canister_id1,
-        },
+    assert_eq!(
+        store.get_pending_operations(principal),
+        vec![
+            MultiPartOperation {
+                block_height: 7,
+                kind: OperationKind::StakeNeuron { memo: 9 },
+                status: OperationStatus::Pending,
+                refund_block_height: None,
+                completed_timestamp_nanos: None,
+            },
+            MultiPartOperation {
+                block_height: 5,
+                kind: OperationKind::CreateCanister,
+                status: OperationStatus::Succeeded,
+                refund_block_height: None,
+                completed_timestamp_nanos: Some(1_000),
+            },
+            MultiPartOperation {
+                block_height: 4,
+                kind: OperationKind::CreateCanister,
+                status: OperationStatus::Failed {
+                    error: "Insufficient funds".to_string()
+                },
+                refund_block_height: Some(6),
+                completed_timestamp_nanos: Some(1_000),
+            },
+        ]
     );
-
-    assert!(matches!(result, DetachCanisterResponse::Ok));
-
-    let canisters = store.get_canisters(principal);
-
-    assert_eq!(1, canisters.len());
-    assert_eq!(canister_id2, canisters[0].canister_id);
+    assert_eq!(store.get_pending_operations(other_principal).len(), 1);
 }
 
 #[test]
-fn detach_canister_canister_not_found() {
+fn operation_log_keeps_only_the_most_recent_outcomes() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let create_canister = MultiPartTransactionToBeProcessed::CreateCanisterV2(principal);
+    let num_outcomes = MAX_OPERATION_OUTCOMES_PER_PRINCIPAL as u64 + 5;
+    for block_height in 0..num_outcomes {
+        store.record_operation_outcome(block_height, &create_canister, OperationStatus::Succeeded, None);
+    }
 
-    let canister_id1 = CanisterId::from_str(TEST_ACCOUNT_2).unwrap();
-    let canister_id2 = CanisterId::from_str(TEST_ACCOUNT_3).unwrap();
+    let operations = store.get_pending_operations(principal);
+    assert_eq!(operations.len(), MAX_OPERATION_OUTCOMES_PER_PRINCIPAL);
+    assert_eq!(
+        operations.first().map(|operation| operation.block_height),
+        Some(num_outcomes - 1)
+    );
+    assert_eq!(operations.last().map(|operation| operation.block_height), Some(5));
+}
 
-    store.attach_canister(
-        principal,
-        AttachCanisterRequest {
-            name: "ABC".to_string(),
-            canister_id: canister_id1,
-        },
+#[test]
+fn delete_account_discards_pending_operations_and_outcomes() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    store.record_operation_outcome(
+        4,
+        &MultiPartTransactionToBeProcessed::CreateCanisterV2(principal),
+        OperationStatus::Succeeded,
+        None,
     );
+    store.enqueue_multi_part_transaction(5, MultiPartTransactionToBeProcessed::CreateCanisterV2(principal));
 
-    let result = store.detach_canister(
+    store.delete_account(
         principal,
-        DetachCanisterRequest {
-            canister_id: canister_id2,
+        DeleteAccountRequest {
+            confirmation: AccountIdentifier::from(principal),
+            expected_version: None,
         },
     );
 
-    assert!(matches!(result, DetachCanisterResponse::CanisterNotFound));
+    assert_eq!(store.get_pending_operations(principal), vec![]);
+}
 
-    let canisters = store.get_canisters(principal);
+fn icp_address(index: u64) -> AddressBookAddress {
+    AddressBookAddress::Icp(AccountIdentifier::from(PrincipalId::new_user_test_id(index)))
+}
+
+fn icrc1_address(index: u64, subaccount: Option<Vec<u8>>) -> AddressBookAddress {
+    AddressBookAddress::Icrc1(Icrc1Account {
+        owner: PrincipalId::new_user_test_id(index),
+        subaccount,
+    })
+}
 
-    assert_eq!(1, canisters.len());
-    assert_eq!(canister_id1, canisters[0].canister_id);
+fn add_address_book_entry_request(name: &str, address: AddressBookAddress) -> AddAddressBookEntryRequest {
+    AddAddressBookEntryRequest {
+        name: name.to_string(),
+        address,
+        expected_version: None,
+    }
 }
 
 #[test]
-fn set_and_get_imported_tokens() {
+fn add_and_get_address_book_entries() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
-    let ledger_canister_id = PrincipalId::new_user_test_id(101);
-    let index_canister_id = PrincipalId::new_user_test_id(102);
 
     assert_eq!(
-        store.get_imported_tokens(principal),
-        GetImportedTokensResponse::Ok(ImportedTokens::default())
+        store.get_address_book(principal),
+        GetAddressBookResponse::Ok(AddressBook::default())
     );
 
-    let imported_token = ImportedToken {
-        ledger_canister_id,
-        index_canister_id: Some(index_canister_id),
-    };
-
     assert_eq!(
-        store.set_imported_tokens(
+        store.add_address_book_entry(principal, add_address_book_entry_request("Exchange", icp_address(101))),
+        AddAddressBookEntryResponse::Ok
+    );
+    assert_eq!(
+        store.add_address_book_entry(
             principal,
-            ImportedTokens {
-                imported_tokens: vec![imported_token.clone()],
-            },
+            add_address_book_entry_request("Alice", icrc1_address(102, Some(vec![7; 32])))
         ),
-        SetImportedTokensResponse::Ok
+        AddAddressBookEntryResponse::Ok
+    );
+    assert_eq!(
+        store.add_address_book_entry(
+            principal,
+            add_address_book_entry_request("Bob", icrc1_address(103, None))
+        ),
+        AddAddressBookEntryResponse::Ok
     );
 
+    // Entries are sorted by name.
     assert_eq!(
-        store.get_imported_tokens(principal),
-        GetImportedTokensResponse::Ok(ImportedTokens {
-            imported_tokens: vec![imported_token],
+        store.get_address_book(principal),
+        GetAddressBookResponse::Ok(AddressBook {
+            entries: vec![
+                AddressBookEntry {
+                    name: "Alice".to_string(),
+                    address: icrc1_address(102, Some(vec![7; 32])),
+                },
+                AddressBookEntry {
+                    name: "Bob".to_string(),
+                    address: icrc1_address(103, None),
+                },
+                AddressBookEntry {
+                    name: "Exchange".to_string(),
+                    address: icp_address(101),
+                },
+            ],
         })
     );
 }
 
 #[test]
-fn set_and_get_imported_tokens_without_index_canister() {
+fn add_address_book_entry_name_already_taken() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
-    let ledger_canister_id = PrincipalId::new_user_test_id(101);
 
     assert_eq!(
-        store.get_imported_tokens(principal),
-        GetImportedTokensResponse::Ok(ImportedTokens::default())
+        store.add_address_book_entry(principal, add_address_book_entry_request("Alice", icp_address(101))),
+        AddAddressBookEntryResponse::Ok
     );
+    assert_eq!(
+        store.add_address_book_entry(principal, add_address_book_entry_request("Alice", icp_address(102))),
+        AddAddressBookEntryResponse::NameAlreadyTaken
+    );
+}
 
-    let imported_token = ImportedToken {
-        ledger_canister_id,
-        index_canister_id: None,
-    };
+#[test]
+fn add_address_book_entry_name_too_long() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
 
     assert_eq!(
-        store.set_imported_tokens(
+        store.add_address_book_entry(
             principal,
-            ImportedTokens {
-                imported_tokens: vec![imported_token.clone()],
-            },
+            add_address_book_entry_request("ABCDEFGHIJKLMNOPQRSTUVWXY", icp_address(101))
         ),
-        SetImportedTokensResponse::Ok
-    );
-
-    assert_eq!(
-        store.get_imported_tokens(principal),
-        GetImportedTokensResponse::Ok(ImportedTokens {
-            imported_tokens: vec![imported_token],
-        })
+        AddAddressBookEntryResponse::NameTooLong
     );
 }
 
-fn get_unique_imported_tokens(count: u64) -> Vec<ImportedToken> {
-    (0..count)
-        .map(|i| ImportedToken {
-            ledger_canister_id: PrincipalId::new_user_test_id(i),
-            index_canister_id: Some(PrincipalId::new_user_test_id(i + 1000)),
-        })
-        .collect()
-}
-
 #[test]
-fn set_and_get_20_imported_tokens() {
+fn add_address_book_entry_invalid_subaccount() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
 
     assert_eq!(
-        store.get_imported_tokens(principal),
-        GetImportedTokensResponse::Ok(ImportedTokens::default())
+        store.add_address_book_entry(
+            principal,
+            add_address_book_entry_request("Alice", icrc1_address(101, Some(vec![1; 31])))
+        ),
+        AddAddressBookEntryResponse::InvalidSubaccount
     );
+    assert_eq!(
+        store.get_address_book(principal),
+        GetAddressBookResponse::Ok(AddressBook::default())
+    );
+}
 
-    let imported_tokens = get_unique_imported_tokens(20);
+#[test]
+fn add_address_book_entry_too_many() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
 
+    for index in 0..MAX_ADDRESS_BOOK_ENTRIES {
+        assert_eq!(
+            store.add_address_book_entry(
+                principal,
+                add_address_book_entry_request(&format!("entry_{index}"), icp_address(index as u64))
+            ),
+            AddAddressBookEntryResponse::Ok
+        );
+    }
     assert_eq!(
-        store.set_imported_tokens(
+        store.add_address_book_entry(
             principal,
-            ImportedTokens {
-                imported_tokens: imported_tokens.clone()
-            },
+            add_address_book_entry_request("one too many", icp_address(999))
         ),
-        SetImportedTokensResponse::Ok
-    );
-
-    assert_eq!(
-        store.get_imported_tokens(principal),
-        GetImportedTokensResponse::Ok(ImportedTokens { imported_tokens })
+        AddAddressBookEntryResponse::TooManyEntries {
+            limit: MAX_ADDRESS_BOOK_ENTRIES
+        }
     );
 }
 
 #[test]
-fn set_imported_tokens_account_not_found() {
+fn add_address_book_entry_account_not_found() {
     let mut store = setup_test_store();
     let non_existing_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+
     assert_eq!(
-        store.set_imported_tokens(non_existing_principal, ImportedTokens::default()),
-        SetImportedTokensResponse::AccountNotFound
+        store.add_address_book_entry(
+            non_existing_principal,
+            add_address_book_entry_request("Alice", icp_address(101))
+        ),
+        AddAddressBookEntryResponse::AccountNotFound
+    );
+    assert_eq!(
+        store.get_address_book(non_existing_principal),
+        GetAddressBookResponse::AccountNotFound
     );
 }
 
 #[test]
-fn set_imported_tokens_too_many() {
+fn rename_address_book_entry() {
     let mut store = setup_test_store();
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
 
-    let imported_tokens = get_unique_imported_tokens(21);
+    store.add_address_book_entry(principal, add_address_book_entry_request("Alice", icp_address(101)));
+    store.add_address_book_entry(principal, add_address_book_entry_request("Bob", icp_address(102)));
 
     assert_eq!(
-        store.set_imported_tokens(principal, ImportedTokens { imported_tokens },),
-        SetImportedTokensResponse::TooManyImportedTokens { limit: 20 }
+        store.rename_address_book_entry(
+            principal,
+            RenameAddressBookEntryRequest {
+                name: "Alice".to_string(),
+                new_name: "Carol".to_string(),
+                expected_version: None,
+            }
+        ),
+        RenameAddressBookEntryResponse::Ok
+    );
+    assert_eq!(
+        store.rename_address_book_entry(
+            principal,
+            RenameAddressBookEntryRequest {
+                name: "Carol".to_string(),
+                new_name: "Bob".to_string(),
+                expected_version: None,
+            }
+        ),
+        RenameAddressBookEntryResponse::NameAlreadyTaken
+    );
+    assert_eq!(
+        store.rename_address_book_entry(
+            principal,
+            RenameAddressBookEntryRequest {
+                name: "Alice".to_string(),
+                new_name: "Dave".to_string(),
+                expected_version: None,
+            }
+        ),
+        RenameAddressBookEntryResponse::EntryNotFound
+    );
+
+    assert_eq!(
+        store.get_address_book(principal),
+        GetAddressBookResponse::Ok(AddressBook {
+            entries: vec![
+                AddressBookEntry {
+                    name: "Bob".to_string(),
+                    address: icp_address(102),
+                },
+                AddressBookEntry {
+                    name: "Carol".to_string(),
+                    address: icp_address(101),
+                },
+            ],
+        })
     );
 }
 
 #[test]
-fn get_imported_tokens_account_not_found() {
+fn remove_address_book_entry() {
     let mut store = setup_test_store();
-    let non_existing_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+
+    store.add_address_book_entry(principal, add_address_book_entry_request("Alice", icp_address(101)));
+    store.add_address_book_entry(principal, add_address_book_entry_request("Bob", icp_address(102)));
+
     assert_eq!(
-        store.get_imported_tokens(non_existing_principal),
-        GetImportedTokensResponse::AccountNotFound
+        store.remove_address_book_entry(
+            principal,
+            RemoveAddressBookEntryRequest {
+                name: "Alice".to_string(),
+                expected_version: None,
+            }
+        ),
+        RemoveAddressBookEntryResponse::Ok
+    );
+    assert_eq!(
+        store.remove_address_book_entry(
+            principal,
+            RemoveAddressBookEntryRequest {
+                name: "Alice".to_string(),
+                expected_version: None,
+            }
+        ),
+        RemoveAddressBookEntryResponse::EntryNotFound
+    );
+
+    assert_eq!(
+        store.get_address_book(principal),
+        GetAddressBookResponse::Ok(AddressBook {
+            entries: vec![AddressBookEntry {
+                name: "Bob".to_string(),
+                address: icp_address(102),
+            }],
+        })
     );
 }
 
@@ -748,8 +3437,8 @@ fn sub_account_name_too_long() {
 
     let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
 
-    let res1 = store.create_sub_account(principal, "ABCDEFGHIJKLMNOPQRSTUVWX".to_string());
-    let res2 = store.create_sub_account(principal, "ABCDEFGHIJKLMNOPQRSTUVWXY".to_string());
+    let res1 = store.create_sub_account(principal, "ABCDEFGHIJKLMNOPQRSTUVWX".to_string(), None);
+    let res2 = store.create_sub_account(principal, "ABCDEFGHIJKLMNOPQRSTUVWXY".to_string(), None);
 
     assert!(matches!(res1, CreateSubAccountResponse::Ok(_)));
     assert!(matches!(res2, CreateSubAccountResponse::NameTooLong));
@@ -768,6 +3457,7 @@ fn hardware_wallet_account_name_too_long() {
         RegisterHardwareWalletRequest {
             name: "ABCDEFGHIJKLMNOPQRSTUVWX".to_string(),
             principal: hw1,
+            expected_version: None,
         },
     );
 
@@ -776,6 +3466,7 @@ fn hardware_wallet_account_name_too_long() {
         RegisterHardwareWalletRequest {
             name: "ABCDEFGHIJKLMNOPQRSTUVWXY".to_string(),
             principal: hw2,
+            expected_version: None,
         },
     );
 
@@ -819,7 +3510,7 @@ fn get_stats() {
     assert_eq!(4, stats.accounts_count);
 
     for i in 1..10 {
-        store.create_sub_account(principal3, i.to_string());
+        store.create_sub_account(principal3, i.to_string(), None);
         store.get_stats(&mut stats);
         assert_eq!(i, stats.sub_accounts_count);
     }
@@ -831,6 +3522,7 @@ fn get_stats() {
         RegisterHardwareWalletRequest {
             name: "HW1".to_string(),
             principal: hw1,
+            expected_version: None,
         },
     );
     store.register_hardware_wallet(
@@ -838,6 +3530,7 @@ fn get_stats() {
         RegisterHardwareWalletRequest {
             name: "HW2".to_string(),
             principal: hw2,
+            expected_version: None,
         },
     );
 
@@ -901,7 +3594,7 @@ fn get_histogram() {
 
     // Sub-accounts should be counted correctly:
     for i in 0..10 {
-        store.create_sub_account(principal3, i.to_string());
+        store.create_sub_account(principal3, i.to_string(), None);
 
         // The histogram entry for the number of sub-accounts will have changed from 0 to 1, 2 etc for one account:
         *expected_histogram.sub_accounts(i) -= 1;
@@ -925,6 +3618,7 @@ fn get_histogram() {
             RegisterHardwareWalletRequest {
                 name: "HW1".to_string(),
                 principal: hw1,
+                expected_version: None,
             },
         );
         store.register_hardware_wallet(
@@ -932,6 +3626,7 @@ fn get_histogram() {
             RegisterHardwareWalletRequest {
                 name: "HW2".to_string(),
                 principal: hw2,
+                expected_version: None,
             },
         );
         // The two accounts (principal3 and principal4) have 1 hardware wallet each, so the 1 bucket should be incremented in each histogram:
@@ -951,6 +3646,7 @@ fn get_histogram() {
         let attach_canister_request = AttachCanisterRequest {
             name: format!("canister_{canister_index}"),
             canister_id,
+            expected_version: None,
         };
         store.attach_canister(principal4, attach_canister_request);
         *expected_histogram.canisters(canister_index as usize) -= 1;
@@ -1039,3 +3735,418 @@ fn accounts_should_implement_storable() {
 }
 
 crate::accounts_store::schema::tests::test_accounts_db!(AccountsStore::default());
+
+#[test]
+fn multi_part_transactions_are_retried_with_backoff_and_counted_in_stats() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let create_canister = MultiPartTransactionToBeProcessed::CreateCanisterV2(principal);
+    crate::time::testing::set_time(1_000);
+    store.enqueue_multi_part_transaction(7, create_canister.clone());
+
+    let queued = store.try_take_next_transaction_to_process().unwrap();
+    assert_eq!(queued.attempts, 0);
+    assert_eq!(
+        store.retry_multi_part_transaction(queued),
+        RetryOutcome::Scheduled {
+            next_attempt_timestamp_nanos: 1_000 + INITIAL_RETRY_DELAY_NANOS
+        }
+    );
+    // The transaction is still pending, but not due yet.
+    assert_eq!(
+        store.get_pending_operations(principal)[0].status,
+        OperationStatus::Pending
+    );
+    assert_eq!(store.try_take_next_transaction_to_process(), None);
+    let mut stats = Stats::default();
+    store.get_stats(&mut stats);
+    assert_eq!(stats.transactions_to_process_queue_length, 0);
+    assert_eq!(stats.transactions_to_retry_queue_length, Some(1));
+    assert_eq!(stats.dead_letter_transactions_count, Some(0));
+
+    crate::time::testing::set_time(1_000 + INITIAL_RETRY_DELAY_NANOS);
+    let queued = store.try_take_next_transaction_to_process().unwrap();
+    assert_eq!((queued.block_height, queued.attempts), (7, 1));
+    let queued = QueuedTransaction {
+        attempts: MAX_ATTEMPTS - 1,
+        ..queued
+    };
+    assert_eq!(store.retry_multi_part_transaction(queued), RetryOutcome::GaveUp);
+    store.get_stats(&mut stats);
+    assert_eq!(stats.transactions_to_retry_queue_length, Some(0));
+    assert_eq!(stats.dead_letter_transactions_count, Some(1));
+}
+
+#[test]
+fn resync_from_rewinds_ledger_sync() {
+    let mut store = setup_test_store();
+    assert_eq!(store.get_block_height_synced_up_to(), Some(3));
+
+    assert_eq!(
+        store.resync_from(5),
+        ResyncFromResponse::InvalidBlockHeight {
+            block_height_synced_up_to: 3
+        }
+    );
+    assert_eq!(store.get_block_height_synced_up_to(), Some(3));
+
+    assert_eq!(store.resync_from(2), ResyncFromResponse::Ok);
+    assert_eq!(store.get_block_height_synced_up_to(), Some(1));
+    let transfer = Burn {
+        amount: Tokens::from_e8s(500_000_000),
+        from: AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_1).unwrap()),
+        spender: None,
+    };
+    store.maybe_process_transaction(&transfer, Memo(0), 2).unwrap();
+    assert_eq!(store.get_block_height_synced_up_to(), Some(2));
+}
+
+#[test]
+fn resync_from_can_rewind_to_the_first_block() {
+    let mut store = setup_test_store();
+    assert_eq!(store.resync_from(0), ResyncFromResponse::Ok);
+    assert_eq!(store.next_block_height_to_sync(), Some(0));
+    // Rewinding again is rejected until the first block has been processed.
+    assert_eq!(
+        store.resync_from(0),
+        ResyncFromResponse::InvalidBlockHeight {
+            block_height_synced_up_to: 3
+        }
+    );
+
+    let mut decoded = AccountsStore::decode(store.encode()).unwrap();
+    assert_eq!(decoded.next_block_height_to_sync(), Some(0));
+    let transfer = Burn {
+        amount: Tokens::from_e8s(500_000_000),
+        from: AccountIdentifier::from(PrincipalId::from_str(TEST_ACCOUNT_1).unwrap()),
+        spender: None,
+    };
+    assert!(decoded.maybe_process_transaction(&transfer, Memo(0), 1).is_err());
+    decoded.maybe_process_transaction(&transfer, Memo(0), 0).unwrap();
+    assert_eq!(decoded.get_block_height_synced_up_to(), Some(0));
+    assert_eq!(decoded.next_block_height_to_sync(), Some(1));
+}
+
+#[test]
+fn replaying_a_block_after_its_outcome_has_left_the_operation_log_does_not_enqueue_it_again() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let memo = Memo(12345);
+    let stake = transfer(AccountIdentifier::from(principal), neuron_account(principal, memo));
+    store.maybe_process_transaction(&stake, memo, 4).unwrap();
+    let queued = store.try_take_next_transaction_to_process().unwrap();
+    for block_height in 4..(5 + MAX_OPERATION_OUTCOMES_PER_PRINCIPAL as u64) {
+        store.record_operation_outcome(block_height, &queued.transaction, OperationStatus::Succeeded, None);
+    }
+    assert!(!store
+        .get_pending_operations(principal)
+        .iter()
+        .any(|operation| operation.block_height == 4));
+
+    store.replay_transaction(&stake, memo, 4);
+    assert_eq!(store.try_take_next_transaction_to_process(), None);
+}
+
+#[test]
+fn processed_blocks_are_kept_in_their_own_partition() {
+    let mut store = setup_test_store();
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    store.load_processed_blocks(partitions.get(PartitionType::ProcessedBlocks.memory_id()));
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let memo = Memo(12345);
+    let stake = transfer(AccountIdentifier::from(principal), neuron_account(principal, memo));
+    store.maybe_process_transaction(&stake, memo, 4).unwrap();
+    let _queued = store.try_take_next_transaction_to_process().unwrap();
+
+    let mut decoded = AccountsStore::decode(store.encode()).unwrap();
+    decoded.load_processed_blocks(partitions.get(PartitionType::ProcessedBlocks.memory_id()));
+    decoded.replay_transaction(&stake, memo, 4);
+    assert_eq!(decoded.try_take_next_transaction_to_process(), None);
+}
+
+#[test]
+fn processed_blocks_below_the_resync_window_are_pruned() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let memo = Memo(12345);
+    let stake = transfer(AccountIdentifier::from(principal), neuron_account(principal, memo));
+    store.maybe_process_transaction(&stake, memo, 4).unwrap();
+    let _queued = store.try_take_next_transaction_to_process().unwrap();
+
+    // Still within the resync window.
+    store.prune_processed_blocks();
+    assert!(store.processed_blocks.contains_key(&4_u64.to_be_bytes()));
+
+    store.block_height_synced_up_to = Some(RESYNC_WINDOW_BLOCKS + 10);
+    store.prune_processed_blocks();
+    assert!(store.processed_blocks.is_empty());
+    assert_eq!(
+        store.resync_from(4),
+        ResyncFromResponse::BlockHeightTooLow {
+            lowest_block_height: 11
+        }
+    );
+    // Pruned blocks are still treated as processed.
+    store.replay_transaction(&stake, memo, 4);
+    assert_eq!(store.try_take_next_transaction_to_process(), None);
+
+    let decoded = AccountsStore::decode(store.encode()).unwrap();
+    assert_eq!(decoded.processed_blocks_floor, 11);
+}
+
+#[test]
+fn processed_blocks_are_not_pruned_above_the_lowest_block_to_replay() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let memo = Memo(12345);
+    let stake = transfer(AccountIdentifier::from(principal), neuron_account(principal, memo));
+    store.schedule_block_replay(2);
+    store.block_height_synced_up_to = Some(RESYNC_WINDOW_BLOCKS + 10);
+
+    store.prune_processed_blocks();
+    assert_eq!(store.processed_blocks_floor, 2);
+    assert_eq!(store.take_block_to_replay(), Some(2));
+    store.replay_transaction(&stake, memo, 2);
+    assert_eq!(store.try_take_next_transaction_to_process().unwrap().block_height, 2);
+}
+
+#[test]
+fn resync_from_requires_ledger_sync_to_have_started() {
+    let mut store = AccountsStore::default();
+    assert_eq!(store.resync_from(1), ResyncFromResponse::NotSynced);
+}
+
+#[test]
+fn reprocessing_a_block_does_not_enqueue_its_transaction_again() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let memo = Memo(12345);
+    let stake = transfer(AccountIdentifier::from(principal), neuron_account(principal, memo));
+    store.maybe_process_transaction(&stake, memo, 4).unwrap();
+
+    // Still queued.
+    assert_eq!(store.resync_from(4), ResyncFromResponse::Ok);
+    store.maybe_process_transaction(&stake, memo, 4).unwrap();
+    assert_eq!(store.get_pending_operations(principal).len(), 1);
+
+    // Already processed.
+    let queued = store.try_take_next_transaction_to_process().unwrap();
+    store.record_operation_outcome(4, &queued.transaction, OperationStatus::Succeeded, None);
+    store.replay_transaction(&stake, memo, 4);
+    assert_eq!(store.try_take_next_transaction_to_process(), None);
+    assert_eq!(store.get_pending_operations(principal).len(), 1);
+    assert_eq!(store.get_block_height_synced_up_to(), Some(4));
+}
+
+#[test]
+fn replaying_a_block_processes_it_out_of_order() {
+    let mut store = setup_test_store();
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let memo = Memo(12345);
+    let stake = transfer(AccountIdentifier::from(principal), neuron_account(principal, memo));
+
+    store.replay_transaction(&stake, memo, 2);
+
+    assert_eq!(
+        next_transaction_to_process(&mut store),
+        Some((2, MultiPartTransactionToBeProcessed::StakeNeuron(principal, memo)))
+    );
+    assert_eq!(store.get_block_height_synced_up_to(), Some(3));
+}
+
+#[test]
+fn blocks_to_replay_are_deduplicated_and_survive_upgrade() {
+    let mut store = setup_test_store();
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    store.load_blocks_to_replay(partitions.get(PartitionType::BlocksToReplay.memory_id()));
+    store.schedule_block_replay(2);
+    store.schedule_block_replay(1);
+    store.schedule_block_replay(2);
+
+    let mut decoded = AccountsStore::decode(store.encode()).unwrap();
+    decoded.load_blocks_to_replay(partitions.get(PartitionType::BlocksToReplay.memory_id()));
+
+    let mut stats = Stats::default();
+    decoded.get_stats(&mut stats);
+    assert_eq!(stats.blocks_to_replay_count, Some(2));
+    assert_eq!(decoded.take_block_to_replay(), Some(2));
+    assert_eq!(decoded.take_block_to_replay(), Some(1));
+    assert_eq!(decoded.take_block_to_replay(), None);
+}
+
+#[test]
+fn multi_part_transactions_are_kept_in_their_own_partition() {
+    let (mut store, principal, _hardware_wallet) = setup_test_store_with_hardware_wallet();
+    let create_canister = MultiPartTransactionToBeProcessed::CreateCanisterV2(principal);
+    store.enqueue_multi_part_transaction(4, create_canister.clone());
+
+    // Data held on the heap, as by a release that predates the partitions, is moved into new partitions.
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    store.load_multi_part_transactions(partitions.get(PartitionType::MultiPartTransactions.memory_id()));
+
+    // The transactions are not serialized with the heap.
+    let mut decoded = AccountsStore::decode(store.encode()).unwrap();
+    assert_eq!(decoded.multi_part_transactions_processor.get_queue_length(), 0);
+
+    // After an upgrade, the partition is used.
+    decoded.load_multi_part_transactions(partitions.get(PartitionType::MultiPartTransactions.memory_id()));
+    assert_eq!(next_transaction_to_process(&mut decoded), Some((4, create_canister)));
+    assert_eq!(next_transaction_to_process(&mut decoded), None);
+}
+
+#[test]
+fn account_transfer_offers_and_blocks_to_replay_are_kept_in_their_own_partitions() {
+    let old_principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let new_principal = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    let mut store = setup_test_store();
+    propose_account_transfer(&mut store, old_principal, new_principal);
+    store.schedule_block_replay(7);
+
+    // Data held on the heap, as by a release that predates the partitions, is moved into new partitions.
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    store.load_account_transfer_offers(partitions.get(PartitionType::AccountTransferOffers.memory_id()));
+    store.load_blocks_to_replay(partitions.get(PartitionType::BlocksToReplay.memory_id()));
+    store.schedule_block_replay(8);
+
+    // After an upgrade, the partitions are used.
+    let mut decoded = AccountsStore::decode(store.encode()).unwrap();
+    decoded.load_account_transfer_offers(partitions.get(PartitionType::AccountTransferOffers.memory_id()));
+    decoded.load_blocks_to_replay(partitions.get(PartitionType::BlocksToReplay.memory_id()));
+    assert_eq!(decoded.account_transfer_offers, store.account_transfer_offers);
+    assert_eq!(decoded.take_block_to_replay(), Some(7));
+    assert_eq!(decoded.take_block_to_replay(), Some(8));
+    assert_eq!(decoded.take_block_to_replay(), None);
+}
+
+#[test]
+fn links_are_kept_with_the_accounts_in_stable_memory() {
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    let mut store = AccountsStore::from(AccountsDb::UnboundedStableBTreeMap(
+        AccountsDbAsUnboundedStableBTreeMap::new(
+            partitions.get(PartitionType::Accounts.memory_id()),
+            partitions.get(PartitionType::AccountLinks.memory_id()),
+        ),
+    ));
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let hardware_wallet = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    store.add_account(principal);
+    let CreateSubAccountResponse::Ok(SubAccountDetails {
+        account_identifier: sub_account,
+        ..
+    }) = store.create_sub_account(principal, "AAA".to_string(), None)
+    else {
+        panic!("Failed to create a sub-account");
+    };
+    store.register_hardware_wallet(
+        principal,
+        RegisterHardwareWalletRequest {
+            name: "HW".to_string(),
+            principal: hardware_wallet,
+            expected_version: None,
+        },
+    );
+    let hardware_wallet_account = AccountIdentifier::from(hardware_wallet);
+
+    // The links are not serialized with the heap.
+    let mut decoded = AccountsStore::decode(store.encode()).unwrap();
+    assert!(!decoded.store_has_account(sub_account));
+    assert!(!decoded.store_has_account(hardware_wallet_account));
+
+    // But are restored with the accounts database after an upgrade.
+    let _deserialized_accounts_db = decoded.replace_accounts_db(AccountsDb::UnboundedStableBTreeMap(
+        AccountsDbAsUnboundedStableBTreeMap::load(
+            partitions.get(PartitionType::Accounts.memory_id()),
+            partitions.get(PartitionType::AccountLinks.memory_id()),
+        ),
+    ));
+    assert!(decoded.store_has_account(sub_account));
+    assert!(decoded.store_has_account(hardware_wallet_account));
+    assert_eq!(decoded.try_get_principal(&sub_account), Some(principal));
+    assert_eq!(
+        decoded.try_get_principal(&hardware_wallet_account),
+        Some(hardware_wallet)
+    );
+}
+
+#[test]
+fn legacy_links_are_used_until_the_index_is_built() {
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    let mut store = AccountsStore::from(AccountsDb::UnboundedStableBTreeMap(
+        AccountsDbAsUnboundedStableBTreeMap::new(
+            partitions.get(PartitionType::Accounts.memory_id()),
+            partitions.get(PartitionType::AccountLinks.memory_id()),
+        ),
+    ));
+    let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+    let hardware_wallet = PrincipalId::from_str(TEST_ACCOUNT_3).unwrap();
+    let account_identifier = AccountIdentifier::from(principal);
+    let hardware_wallet_account = AccountIdentifier::from(hardware_wallet);
+    store.add_account(principal);
+    let CreateSubAccountResponse::Ok(SubAccountDetails {
+        account_identifier: sub_account,
+        ..
+    }) = store.create_sub_account(principal, "AAA".to_string(), None)
+    else {
+        panic!("Failed to create a sub-account");
+    };
+    store.register_hardware_wallet(
+        principal,
+        RegisterHardwareWalletRequest {
+            name: "HW".to_string(),
+            principal: hardware_wallet,
+            expected_version: None,
+        },
+    );
+    // An earlier release kept the links in their own partition...
+    let mut legacy_links =
+        HeapOrStableMap::init(partitions.get(PartitionType::HardwareWalletsAndSubAccounts.memory_id()));
+    legacy_links.insert(&sub_account.to_vec(), AccountWrapper::SubAccount(account_identifier, 1));
+    legacy_links.insert(
+        &hardware_wallet_account.to_vec(),
+        AccountWrapper::HardwareWallet(vec![account_identifier]),
+    );
+    // ... and had no index.
+    let links_memory = Partitions::from(DefaultMemoryImpl::default()).get(PartitionType::AccountLinks.memory_id());
+    let mut upgraded = AccountsStore::decode(store.encode()).unwrap();
+    let _deserialized_accounts_db = upgraded.replace_accounts_db(AccountsDb::UnboundedStableBTreeMap(
+        AccountsDbAsUnboundedStableBTreeMap::load(partitions.get(PartitionType::Accounts.memory_id()), links_memory),
+    ));
+    upgraded.load_legacy_links(partitions.get(PartitionType::HardwareWalletsAndSubAccounts.memory_id()));
+
+    // The legacy links are used while the index is built.
+    assert!(upgraded.links_index_in_progress());
+    assert_eq!(upgraded.try_get_principal(&sub_account), Some(principal));
+    assert_eq!(
+        upgraded.try_get_principal(&hardware_wallet_account),
+        Some(hardware_wallet)
+    );
+    // Legacy links are not updated, so they are checked against the accounts.
+    upgraded.unregister_hardware_wallet(
+        principal,
+        UnregisterHardwareWalletRequest {
+            principal: hardware_wallet,
+            expected_version: None,
+        },
+    );
+    assert!(!upgraded.store_has_account(hardware_wallet_account));
+
+    // Once the index is complete, the legacy links are discarded.
+    while upgraded.links_index_in_progress() {
+        upgraded.step_migration(1);
+    }
+    assert!(upgraded.legacy_links.is_empty());
+    assert_eq!(upgraded.try_get_principal(&sub_account), Some(principal));
+    assert!(!upgraded.store_has_account(hardware_wallet_account));
+}
+
+#[test]
+fn stats_show_how_far_ledger_sync_is_behind_the_tip() {
+    let mut store = setup_test_store();
+    let mut stats = Stats::default();
+    store.get_stats(&mut stats);
+    assert_eq!(stats.blocks_behind_tip, None);
+
+    store.set_tip_of_chain(2_003);
+    store.get_stats(&mut stats);
+    assert_eq!(stats.blocks_behind_tip, Some(2_000));
+}
@@ -5,8 +5,8 @@ use crate::accounts_store::{
     RegisterHardwareWalletRequest,
 };
 
-#[cfg(test)]
 use crate::accounts_store::AccountIdentifier;
+use crate::perf::AccountDecodingBenchmark;
 
 #[cfg(test)]
 use std::collections::HashMap;
@@ -62,6 +62,11 @@ pub fn toy_account(account_index: u64, size: ToyAccountSize) -> Account {
         hardware_wallet_accounts: Vec::new(),
         canisters: Vec::new(),
         imported_tokens: None,
+        address_book: None,
+        archived_sub_accounts: None,
+        canister_groups: None,
+        version: None,
+        preferences: None,
     };
     // Creates linked sub-accounts:
     // Note: Successive accounts have 0, 1, 2 ... MAX_SUB_ACCOUNTS_PER_ACCOUNT-1 sub accounts, restarting at 0.
@@ -78,6 +83,8 @@ pub fn toy_account(account_index: u64, size: ToyAccountSize) -> Account {
         let canister = NamedCanister {
             name: format!("canister_{account_index}_{canister_index}"),
             canister_id,
+            metadata: None,
+            group: None,
         };
         account.canisters.push(canister);
     }
@@ -132,7 +139,11 @@ impl AccountsStore {
             // Creates linked sub-accounts:
             // Note: Successive accounts have 0, 1, 2 ... MAX_SUB_ACCOUNTS_PER_ACCOUNT-1 sub accounts, restarting at 0.
             for subaccount_index in 0..(toy_account_index % (MAX_SUB_ACCOUNTS_PER_ACCOUNT + 1)) {
-                self.create_sub_account(account, format!("sub_account_{toy_account_index}_{subaccount_index}"));
+                self.create_sub_account(
+                    account,
+                    format!("sub_account_{toy_account_index}_{subaccount_index}"),
+                    None,
+                );
             }
             // Creates linked hardware wallets:
             // Note: Successive accounts have 0, 1, 2 ... MAX_HARDWARE_WALLETS_PER_ACCOUNT-1 hardware wallets, restarting at 0.
@@ -143,6 +154,7 @@ impl AccountsStore {
                     RegisterHardwareWalletRequest {
                         name: format!("hw_wallet_{toy_account_index}_{hardware_wallet_index}"),
                         principal,
+                        expected_version: None,
                     },
                 );
             }
@@ -152,6 +164,7 @@ impl AccountsStore {
                 let attach_canister_request = AttachCanisterRequest {
                     name: format!("canister_{toy_account_index}_{canister_index}"),
                     canister_id,
+                    expected_version: None,
                 };
                 self.attach_canister(account, attach_canister_request);
             }
@@ -159,6 +172,18 @@ impl AccountsStore {
         index_range_start
     }
 
+    /// Measures the cost of decoding a toy account in each of the encodings in which accounts may be stored.
+    ///
+    /// # Returns
+    /// - `None` if there is no toy account with the given index.
+    #[must_use]
+    pub fn benchmark_account_decoding(&self, toy_account_index: u64) -> Option<AccountDecodingBenchmark> {
+        let account_identifier = AccountIdentifier::from(PrincipalId::new_user_test_id(toy_account_index));
+        self.accounts_db
+            .db_get_account(&account_identifier.to_vec())
+            .map(|account| AccountDecodingBenchmark::measure(&account))
+    }
+
     /// Creates an account store with the given number of test accounts.
     #[cfg(test)]
     pub fn with_toy_accounts(num_accounts: u64) -> AccountsStore {
@@ -0,0 +1,138 @@
+//! An opt-in index of the ledger blocks that involve each user's accounts.
+//!
+//! For every user who has enabled the index, the heights of the most recent blocks that touch
+//! their main account, sub-accounts or hardware wallets are kept.  The index is stored in its own
+//! stable memory partition, so it is not serialized with the rest of the heap on upgrade.
+//!
+//! Each indexed block is a separate entry, so indexing a block costs the same however many blocks
+//! an account already has.  The map holds two kinds of entries:
+//! - The main account identifier of each user who has enabled the index, with the number of
+//!   blocks indexed for them.
+//! - The main account identifier followed by the big-endian height of each indexed block, with
+//!   the block height.  Entries for an account are therefore in ascending order of block height.
+use super::heap_or_stable_map::HeapOrStableMap;
+use super::schema::accounts_in_unbounded_stable_btree_map::ProductionMemoryType;
+use core::fmt;
+use icp_ledger::{AccountIdentifier, BlockIndex};
+
+/// The maximum number of block heights kept per account.  When exceeded, the oldest are dropped.
+pub const MAX_INDEXED_BLOCKS_PER_ACCOUNT: usize = 1_000;
+
+/// The key of the entry for a block indexed for an account.
+fn block_key(account_identifier: &AccountIdentifier, block_height: BlockIndex) -> Vec<u8> {
+    let mut key = account_identifier.to_vec();
+    key.extend_from_slice(&block_height.to_be_bytes());
+    key
+}
+
+/// Indexed blocks, keyed by the main account identifier of each user who has enabled the index.
+#[derive(Default)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct TransactionIndex {
+    map: HeapOrStableMap<u64>,
+}
+
+impl fmt::Debug for TransactionIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TransactionIndex{{{:?}}}", self.map)
+    }
+}
+
+impl TransactionIndex {
+    /// Creates an index in the given memory, or loads the index already stored there.
+    #[must_use]
+    pub fn init(memory: ProductionMemoryType) -> Self {
+        TransactionIndex {
+            map: HeapOrStableMap::init(memory),
+        }
+    }
+
+    /// Whether no account has the index enabled.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Whether an account has the index enabled.
+    #[must_use]
+    pub fn is_enabled(&self, account_identifier: &AccountIdentifier) -> bool {
+        self.map.contains_key(&account_identifier.to_vec())
+    }
+
+    /// Starts indexing blocks for an account.  Blocks that are already indexed are kept.
+    pub fn enable(&mut self, account_identifier: &AccountIdentifier) {
+        if !self.is_enabled(account_identifier) {
+            self.map.insert(&account_identifier.to_vec(), 0);
+        }
+    }
+
+    /// Stops indexing blocks for an account and discards the blocks indexed so far.
+    pub fn disable(&mut self, account_identifier: &AccountIdentifier) {
+        let block_keys: Vec<Vec<u8>> = self
+            .map
+            .range(block_key(account_identifier, 0)..block_key(account_identifier, BlockIndex::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in block_keys {
+            self.map.remove(&key);
+        }
+        self.map.remove(&account_identifier.to_vec());
+    }
+
+    /// Records that a block touches an account.  Does nothing if the account does not have the
+    /// index enabled or the block is indexed already.
+    pub fn record(&mut self, account_identifier: &AccountIdentifier, block_height: BlockIndex) {
+        let account_key = account_identifier.to_vec();
+        let Some(mut count) = self.map.get(&account_key) else {
+            return;
+        };
+        let key = block_key(account_identifier, block_height);
+        if self.map.contains_key(&key) {
+            return;
+        }
+        self.map.insert(&key, block_height);
+        count += 1;
+        if count > MAX_INDEXED_BLOCKS_PER_ACCOUNT as u64 {
+            let oldest = self
+                .map
+                .range(block_key(account_identifier, 0)..block_key(account_identifier, BlockIndex::MAX))
+                .next()
+                .map(|(key, _)| key);
+            if let Some(oldest) = oldest {
+                self.map.remove(&oldest);
+                count -= 1;
+            }
+        }
+        self.map.insert(&account_key, count);
+    }
+
+    /// Gets up to `limit` block heights below `before`, newest first, together with the cursor for
+    /// the next page, if there are more.  Returns `None` if the account does not have the index
+    /// enabled.
+    ///
+    /// Note: Reads all the blocks below `before`, of which there are at most
+    /// `MAX_INDEXED_BLOCKS_PER_ACCOUNT`.
+    #[must_use]
+    pub fn page(
+        &self,
+        account_identifier: &AccountIdentifier,
+        before: Option<BlockIndex>,
+        limit: usize,
+    ) -> Option<(Vec<BlockIndex>, Option<BlockIndex>)> {
+        if !self.is_enabled(account_identifier) {
+            return None;
+        }
+        let older: Vec<BlockIndex> = self
+            .map
+            .range(block_key(account_identifier, 0)..block_key(account_identifier, before.unwrap_or(BlockIndex::MAX)))
+            .map(|(_, block_height)| block_height)
+            .collect();
+        let block_heights: Vec<BlockIndex> = older.iter().rev().take(limit).copied().collect();
+        let next = if older.len() > block_heights.len() {
+            block_heights.last().copied()
+        } else {
+            None
+        };
+        Some((block_heights, next))
+    }
+}
@@ -0,0 +1,393 @@
+//! Checks that the accounts store is internally consistent, optionally repairing what it finds.
+//!
+//! A verification walks over all the accounts a few at a time, so that it can run on timers
+//! without hitting the instruction limit.  For every account it checks that the sub-accounts and
+//! hardware wallets are in the index of links kept by the accounts database.  Then it walks over
+//! the index, checking that every entry belongs to an account that has that sub-account or
+//! hardware wallet.  Finally, the sub-accounts and hardware wallets counted are compared with the
+//! `AccountsDbStats`.
+//!
+//! Accounts may change while a verification is in progress.  Accounts that have not been visited
+//! yet are checked as they are when visited; changes to accounts that have already been visited
+//! are applied to the counts.
+//!
+//! The verification is saved on upgrade and continues from where it was afterwards.
+use super::{Account, AccountsDbTrait, AccountsStore, VerifyStateResponse};
+use crate::accounts_store::schema::{account_links, SchemaLabel};
+use candid::{CandidType, Deserialize};
+use ic_cdk::println;
+use ic_stable_structures::{storable::Bound, Storable};
+use icp_ledger::AccountIdentifier;
+use std::borrow::Cow;
+
+#[cfg(test)]
+mod tests;
+
+/// How far a verification has got.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, CandidType, Deserialize)]
+pub enum VerificationStatus {
+    /// Accounts are being checked.
+    InProgress,
+    /// All accounts have been checked.
+    Completed,
+}
+
+/// An inconsistency found by a verification.
+#[derive(Clone, Debug, Eq, PartialEq, CandidType, Deserialize)]
+pub enum Discrepancy {
+    /// The number of sub-accounts in the stats differs from the number in the accounts.
+    SubAccountsCount { recorded: u64, counted: u64 },
+    /// The number of hardware wallets in the stats differs from the number in the accounts.
+    HardwareWalletAccountsCount { recorded: u64, counted: u64 },
+    /// Sub-accounts or hardware wallets of the account are missing from the index of links.
+    UnindexedLinks { account_identifier: AccountIdentifier },
+    /// An entry in the index of links belongs to an account that does not exist or does not have
+    /// the sub-account or hardware wallet.  The entry is described in [`account_links`].
+    OrphanedLink { entry: Vec<u8> },
+    /// The schema recorded in stable memory is not the schema the accounts were loaded from.
+    SchemaLabel { recorded: SchemaLabel, actual: SchemaLabel },
+}
+
+/// The maximum number of discrepancies kept in a report.  Further discrepancies are counted but
+/// not kept.
+pub const MAX_RECORDED_DISCREPANCIES: usize = 1_000;
+
+/// The maximum number of discrepancies returned in a page of a report.
+pub const MAX_DISCREPANCIES_PAGE_SIZE: u32 = 100;
+
+/// Progress report and findings of a verification.
+#[derive(Clone, Debug, Eq, PartialEq, CandidType, Deserialize)]
+pub struct VerificationReport {
+    /// Whether discrepancies are repaired as they are found.
+    pub repair: bool,
+    pub status: VerificationStatus,
+    /// The number of accounts checked so far.
+    pub accounts_verified: u64,
+    /// The number of entries in the index of links checked so far.
+    pub links_verified: u64,
+    /// The number of discrepancies found so far, including any that are not kept.
+    pub discrepancies_count: u64,
+    /// The first discrepancies found, up to `MAX_RECORDED_DISCREPANCIES`.
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetStateVerificationRequest {
+    /// The number of discrepancies to skip.  Use the `next_discrepancies_offset` of the previous
+    /// page, or 0 for the first page.
+    pub discrepancies_offset: u32,
+}
+
+/// A page of a verification report.
+///
+/// Every page has all of the report apart from its discrepancies, of which it has those from the
+/// requested offset.
+#[derive(CandidType, Debug, PartialEq)]
+pub struct VerificationReportPage {
+    pub report: VerificationReport,
+    /// The offset of the next page of discrepancies, or `None` if this is the last page.
+    pub next_discrepancies_offset: Option<u32>,
+}
+
+/// A verification of the accounts store.
+#[derive(Clone, Debug, Eq, PartialEq, CandidType, Deserialize)]
+pub struct Verification {
+    report: VerificationReport,
+    /// The next account to check.  `None` once all accounts have been visited.
+    next_to_verify: Option<Vec<u8>>,
+    /// The next entry of the index of links to check, once all accounts have been visited.  `None`
+    /// once all entries have been checked.
+    next_link_to_verify: Option<Vec<u8>>,
+    /// The number of sub-accounts in the accounts visited so far.
+    sub_accounts_count: u64,
+    /// The number of hardware wallets in the accounts visited so far.
+    hardware_wallet_accounts_count: u64,
+}
+
+/// The verification saved on upgrade.
+#[derive(Clone, Debug, Default, Eq, PartialEq, CandidType, Deserialize)]
+pub struct SavedVerification(Option<Verification>);
+
+impl Storable for SavedVerification {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        candid::encode_one(self)
+            .expect("Failed to serialize verification")
+            .into()
+    }
+    /// Parse the verification.  On error, return a blank new structure: the verification is then
+    /// lost and may be started again.
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap_or_default()
+    }
+}
+
+impl Verification {
+    /// Determines whether an account has been visited by the verification.
+    fn has_visited(&self, account_key: &[u8]) -> bool {
+        self.next_to_verify
+            .as_ref()
+            .is_none_or(|next_to_verify| account_key < next_to_verify.as_slice())
+    }
+}
+
+impl AccountsStore {
+    /// The default number of accounts to check in a verification step.
+    pub const VERIFICATION_STEP_SIZE: u32 = 500;
+
+    /// Starts verifying the accounts store, unless a verification is already in progress.
+    pub fn start_verification(&mut self, repair: bool) -> VerifyStateResponse {
+        if self.verification_in_progress() {
+            return VerifyStateResponse::VerificationInProgress;
+        }
+        println!("Starting state verification, repair: {repair}");
+        self.verification.0 = Some(Verification {
+            report: VerificationReport {
+                repair,
+                status: VerificationStatus::InProgress,
+                accounts_verified: 0,
+                links_verified: 0,
+                discrepancies_count: 0,
+                discrepancies: Vec::new(),
+            },
+            next_to_verify: self.accounts_db.first_key_value().map(|(key, _account)| key),
+            next_link_to_verify: Some(Vec::new()),
+            sub_accounts_count: 0,
+            hardware_wallet_accounts_count: 0,
+        });
+        VerifyStateResponse::Ok
+    }
+
+    /// Determines whether a verification is in progress.
+    #[must_use]
+    pub fn verification_in_progress(&self) -> bool {
+        self.verification
+            .0
+            .as_ref()
+            .is_some_and(|verification| verification.report.status == VerificationStatus::InProgress)
+    }
+
+    /// The report of the verification in progress or, if there is none, of the latest verification.
+    #[must_use]
+    pub fn verification_report(&self) -> Option<VerificationReport> {
+        self.verification
+            .0
+            .as_ref()
+            .map(|verification| verification.report.clone())
+    }
+
+    /// A page of the report of the verification in progress or, if there is none, of the latest
+    /// verification.
+    #[must_use]
+    #[allow(clippy::needless_pass_by_value)] // The pattern here is to pass a request by value.
+    pub fn verification_report_page(&self, request: GetStateVerificationRequest) -> Option<VerificationReportPage> {
+        let mut report = self.verification_report()?;
+        let end = request.discrepancies_offset.saturating_add(MAX_DISCREPANCIES_PAGE_SIZE);
+        let next_discrepancies_offset = ((end as usize) < report.discrepancies.len()).then_some(end);
+        report.discrepancies = report
+            .discrepancies
+            .into_iter()
+            .skip(request.discrepancies_offset as usize)
+            .take(MAX_DISCREPANCIES_PAGE_SIZE as usize)
+            .collect();
+        Some(VerificationReportPage {
+            report,
+            next_discrepancies_offset,
+        })
+    }
+
+    /// Records a discrepancy found in the verification in progress.  Only the first
+    /// `MAX_RECORDED_DISCREPANCIES` are kept; the rest are counted.
+    pub fn record_discrepancy(&mut self, discrepancy: Discrepancy) {
+        println!("State verification discrepancy: {discrepancy:?}");
+        if let Some(verification) = &mut self.verification.0 {
+            verification.report.discrepancies_count += 1;
+            if verification.report.discrepancies.len() < MAX_RECORDED_DISCREPANCIES {
+                verification.report.discrepancies.push(discrepancy);
+            }
+        }
+    }
+
+    /// The verification to save on upgrade.
+    #[must_use]
+    pub fn saved_verification(&self) -> SavedVerification {
+        SavedVerification(self.verification.0.clone())
+    }
+
+    /// Restores the verification saved before an upgrade.
+    pub fn restore_verification(&mut self, saved: SavedVerification) {
+        self.verification.0 = saved.0;
+    }
+
+    /// Checks the next accounts or, once all accounts have been checked, the next entries of the
+    /// index of links.  Once those have been checked too, the verification is completed.
+    ///
+    /// # Arguments
+    /// - `step_size`: The maximum number of accounts or index entries to check on this step.
+    pub fn step_verification(&mut self, step_size: u32) {
+        let Some(verification) = self.verification.0.as_ref() else {
+            return;
+        };
+        if verification.report.status != VerificationStatus::InProgress {
+            return;
+        }
+        let step_size = usize::try_from(step_size.max(1)).unwrap_or(usize::MAX);
+        match (
+            verification.next_to_verify.clone(),
+            verification.next_link_to_verify.clone(),
+        ) {
+            (Some(next_to_verify), _) => self.step_accounts_verification(next_to_verify, step_size),
+            (None, Some(next_link_to_verify)) => self.step_links_verification(next_link_to_verify, step_size),
+            (None, None) => self.complete_verification(),
+        }
+    }
+
+    /// Checks the accounts from `next_to_verify`.
+    fn step_accounts_verification(&mut self, next_to_verify: Vec<u8>, step_size: usize) {
+        let (accounts, next_to_verify) = {
+            let mut range = self.accounts_db.range(next_to_verify..);
+            let accounts: Vec<_> = (&mut range).take(step_size).collect();
+            (accounts, range.next().map(|(key, _account)| key))
+        };
+        for (account_key, account) in accounts {
+            self.verify_account(&account_key, &account);
+        }
+        if let Some(verification) = &mut self.verification.0 {
+            verification.next_to_verify = next_to_verify;
+        }
+    }
+
+    /// Checks the entries of the index of links from `next_link_to_verify`.
+    fn step_links_verification(&mut self, next_link_to_verify: Vec<u8>, step_size: usize) {
+        let (entries, next_link_to_verify) = {
+            let mut entries = self.accounts_db.db_link_entries(next_link_to_verify);
+            let batch: Vec<_> = (&mut entries).take(step_size).collect();
+            (batch, entries.next())
+        };
+        for entry in entries {
+            self.verify_link_entry(&entry);
+        }
+        if let Some(verification) = &mut self.verification.0 {
+            verification.next_link_to_verify = next_link_to_verify;
+        }
+    }
+
+    /// Checks one account and counts its sub-accounts and hardware wallets.
+    fn verify_account(&mut self, account_key: &[u8], account: &Account) {
+        // While the index is being built, accounts that have not been indexed yet are expected.
+        let unindexed = !self.accounts_db.links_index_in_progress()
+            && account.linked_account_identifiers().any(|linked| {
+                !self
+                    .accounts_db
+                    .db_get_linked_account_keys(&linked.to_vec())
+                    .iter()
+                    .any(|linked_account_key| linked_account_key == account_key)
+            });
+        let Some(verification) = &mut self.verification.0 else {
+            return;
+        };
+        verification.report.accounts_verified += 1;
+        verification.sub_accounts_count += account.sub_accounts.len() as u64;
+        verification.hardware_wallet_accounts_count += account.hardware_wallet_accounts.len() as u64;
+        let repair = verification.report.repair;
+        if unindexed {
+            self.record_discrepancy(Discrepancy::UnindexedLinks {
+                account_identifier: account.account_identifier,
+            });
+            if repair {
+                // Removing the account drops whatever is indexed; inserting it indexes everything.
+                self.db_remove_account(account_key);
+                self.db_insert_account(account_key, account.clone());
+            }
+        }
+    }
+
+    /// Checks that an entry of the index of links belongs to an account with that sub-account or
+    /// hardware wallet.
+    fn verify_link_entry(&mut self, entry: &[u8]) {
+        if entry == account_links::BUILD_MARKER {
+            return;
+        }
+        let orphaned = account_links::entry_account_key(entry).is_none_or(|account_key| {
+            self.accounts_db
+                .db_get_account(account_key)
+                .is_none_or(|account| !account_links::entries(account_key, &account).contains(entry))
+        });
+        let Some(verification) = &mut self.verification.0 else {
+            return;
+        };
+        verification.report.links_verified += 1;
+        let repair = verification.report.repair;
+        if orphaned {
+            self.record_discrepancy(Discrepancy::OrphanedLink { entry: entry.to_vec() });
+            if repair {
+                self.accounts_db.db_remove_link_entry(entry);
+            }
+        }
+    }
+
+    /// Compares the counts with the stats and marks the verification as complete.
+    fn complete_verification(&mut self) {
+        let Some(verification) = self.verification.0.as_ref() else {
+            return;
+        };
+        let repair = verification.report.repair;
+        let counted_sub_accounts = verification.sub_accounts_count;
+        let counted_hardware_wallets = verification.hardware_wallet_accounts_count;
+        if self.accounts_db_stats.sub_accounts_count != counted_sub_accounts {
+            self.record_discrepancy(Discrepancy::SubAccountsCount {
+                recorded: self.accounts_db_stats.sub_accounts_count,
+                counted: counted_sub_accounts,
+            });
+            if repair {
+                self.accounts_db_stats.sub_accounts_count = counted_sub_accounts;
+            }
+        }
+        if self.accounts_db_stats.hardware_wallet_accounts_count != counted_hardware_wallets {
+            self.record_discrepancy(Discrepancy::HardwareWalletAccountsCount {
+                recorded: self.accounts_db_stats.hardware_wallet_accounts_count,
+                counted: counted_hardware_wallets,
+            });
+            if repair {
+                self.accounts_db_stats.hardware_wallet_accounts_count = counted_hardware_wallets;
+            }
+        }
+        if let Some(verification) = &mut self.verification.0 {
+            verification.report.status = VerificationStatus::Completed;
+            println!("State verification complete: {:?}", verification.report);
+        }
+    }
+
+    /// Applies a change to an account that has already been visited by the verification in
+    /// progress to the counts.
+    ///
+    /// Must be called before the change is saved.
+    ///
+    /// # Arguments
+    /// - `new`: The account after the change, or `None` if the account is being removed.
+    pub(super) fn count_account_change_for_verification(&mut self, account_key: &[u8], new: Option<&Account>) {
+        if !self.verification.0.as_ref().is_some_and(|verification| {
+            verification.report.status == VerificationStatus::InProgress && verification.has_visited(account_key)
+        }) {
+            return;
+        }
+        let old = self.accounts_db.db_get_account(account_key);
+        if let Some(verification) = &mut self.verification.0 {
+            let count = |account: Option<&Account>| {
+                account.map_or((0, 0), |account| {
+                    (
+                        account.sub_accounts.len() as u64,
+                        account.hardware_wallet_accounts.len() as u64,
+                    )
+                })
+            };
+            let (old_sub_accounts, old_hardware_wallets) = count(old.as_ref());
+            let (new_sub_accounts, new_hardware_wallets) = count(new);
+            verification.sub_accounts_count =
+                (verification.sub_accounts_count + new_sub_accounts).saturating_sub(old_sub_accounts);
+            verification.hardware_wallet_accounts_count = (verification.hardware_wallet_accounts_count
+                + new_hardware_wallets)
+                .saturating_sub(old_hardware_wallets);
+        }
+    }
+}
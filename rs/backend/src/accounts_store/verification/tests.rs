@@ -0,0 +1,283 @@
+use super::*;
+use crate::accounts_store::schema::accounts_in_unbounded_stable_btree_map::AccountsDbAsUnboundedStableBTreeMap;
+use crate::accounts_store::schema::proxy::AccountsDb;
+use crate::accounts_store::{
+    AccountsDbStats, CreateSubAccountResponse, PrincipalId, RegisterHardwareWalletRequest, SubAccountDetails,
+};
+use crate::state::partitions::{PartitionType, Partitions};
+use ic_stable_structures::DefaultMemoryImpl;
+use pretty_assertions::assert_eq;
+
+/// Adds an account with a sub-account and a hardware wallet.
+fn add_account_with_links(store: &mut AccountsStore, index: u64) -> PrincipalId {
+    let principal = PrincipalId::new_user_test_id(index);
+    store.add_account(principal);
+    store.create_sub_account(principal, format!("sub_{index}"), None);
+    store.register_hardware_wallet(
+        principal,
+        RegisterHardwareWalletRequest {
+            name: format!("hw_{index}"),
+            principal: PrincipalId::new_user_test_id(1000 + index),
+            expected_version: None,
+        },
+    );
+    principal
+}
+
+/// Runs a verification to completion, one account at a time.
+fn verify(store: &mut AccountsStore, repair: bool) -> VerificationReport {
+    assert_eq!(store.start_verification(repair), VerifyStateResponse::Ok);
+    verify_to_completion(store)
+}
+
+/// Runs the verification in progress to completion, one account at a time.
+fn verify_to_completion(store: &mut AccountsStore) -> VerificationReport {
+    while store.verification_in_progress() {
+        store.step_verification(1);
+    }
+    store
+        .verification_report()
+        .expect("There should be a report of the verification")
+}
+
+#[test]
+fn a_consistent_store_should_pass_verification() {
+    let mut store = AccountsStore::default();
+    for index in 0..5 {
+        add_account_with_links(&mut store, index);
+    }
+    assert_eq!(store.verification_report(), None);
+
+    assert_eq!(
+        verify(&mut store, false),
+        VerificationReport {
+            repair: false,
+            status: VerificationStatus::Completed,
+            accounts_verified: 5,
+            links_verified: 10,
+            discrepancies_count: 0,
+            discrepancies: vec![],
+        }
+    );
+}
+
+#[test]
+fn only_one_verification_should_run_at_a_time() {
+    let mut store = AccountsStore::default();
+    add_account_with_links(&mut store, 0);
+    assert_eq!(store.start_verification(false), VerifyStateResponse::Ok);
+    assert_eq!(
+        store.start_verification(true),
+        VerifyStateResponse::VerificationInProgress
+    );
+    assert!(!store.verification_report().unwrap().repair);
+    while store.verification_in_progress() {
+        store.step_verification(AccountsStore::VERIFICATION_STEP_SIZE);
+    }
+    // Once complete, another verification may be started.
+    assert_eq!(store.start_verification(true), VerifyStateResponse::Ok);
+}
+
+#[test]
+fn wrong_stats_should_be_reported_and_repaired_only_if_requested() {
+    let mut store = AccountsStore::default();
+    for index in 0..3 {
+        add_account_with_links(&mut store, index);
+    }
+    store.accounts_db_stats.sub_accounts_count = 7;
+    store.accounts_db_stats.hardware_wallet_accounts_count = 0;
+    let expected_discrepancies = vec![
+        Discrepancy::SubAccountsCount {
+            recorded: 7,
+            counted: 3,
+        },
+        Discrepancy::HardwareWalletAccountsCount {
+            recorded: 0,
+            counted: 3,
+        },
+    ];
+
+    assert_eq!(verify(&mut store, false).discrepancies, expected_discrepancies);
+    assert_eq!(store.accounts_db_stats.sub_accounts_count, 7);
+
+    assert_eq!(verify(&mut store, true).discrepancies, expected_discrepancies);
+    assert_eq!(store.accounts_db_stats.sub_accounts_count, 3);
+    assert_eq!(store.accounts_db_stats.hardware_wallet_accounts_count, 3);
+
+    assert_eq!(verify(&mut store, false).discrepancies, vec![]);
+}
+
+#[test]
+fn unindexed_links_should_be_reported_and_repaired_if_requested() {
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    let mut store = AccountsStore::from(AccountsDb::UnboundedStableBTreeMap(
+        AccountsDbAsUnboundedStableBTreeMap::new(
+            partitions.get(PartitionType::Accounts.memory_id()),
+            partitions.get(PartitionType::AccountLinks.memory_id()),
+        ),
+    ));
+    let principal = add_account_with_links(&mut store, 0);
+    let CreateSubAccountResponse::Ok(SubAccountDetails {
+        account_identifier: sub_account,
+        ..
+    }) = store.create_sub_account(principal, "another".to_string(), None)
+    else {
+        panic!("Failed to create a sub-account");
+    };
+    // Wipe the index, as if its memory had been overwritten.
+    let _wiped = AccountsDbAsUnboundedStableBTreeMap::new(
+        partitions.get(PartitionType::CompactAccounts.memory_id()),
+        partitions.get(PartitionType::AccountLinks.memory_id()),
+    );
+    let _original_db = store.replace_accounts_db(AccountsDb::UnboundedStableBTreeMap(
+        AccountsDbAsUnboundedStableBTreeMap::load(
+            partitions.get(PartitionType::Accounts.memory_id()),
+            partitions.get(PartitionType::AccountLinks.memory_id()),
+        ),
+    ));
+    assert!(!store.store_has_account(sub_account));
+    let expected_discrepancies = vec![Discrepancy::UnindexedLinks {
+        account_identifier: AccountIdentifier::from(principal),
+    }];
+
+    assert_eq!(verify(&mut store, false).discrepancies, expected_discrepancies);
+    assert!(!store.store_has_account(sub_account));
+
+    assert_eq!(verify(&mut store, true).discrepancies, expected_discrepancies);
+    assert!(store.store_has_account(sub_account));
+    assert_eq!(
+        store.main_account_identifiers(&sub_account),
+        vec![AccountIdentifier::from(principal)]
+    );
+
+    assert_eq!(verify(&mut store, false).discrepancies, vec![]);
+}
+
+#[test]
+fn accounts_changed_during_verification_should_not_cause_discrepancies() {
+    let mut store = AccountsStore::default();
+    let principals: Vec<_> = (0..4).map(|index| add_account_with_links(&mut store, index)).collect();
+    assert_eq!(store.start_verification(false), VerifyStateResponse::Ok);
+    store.step_verification(2);
+    assert_eq!(store.verification_report().unwrap().accounts_verified, 2);
+
+    // Change accounts on both sides of the verification cursor.
+    for principal in &principals {
+        store.create_sub_account(*principal, "during".to_string(), None);
+    }
+    add_account_with_links(&mut store, 10);
+    store.step_verification(1);
+    store.create_sub_account(principals[0], "later".to_string(), None);
+
+    while store.verification_in_progress() {
+        store.step_verification(1);
+    }
+    let report = store.verification_report().unwrap();
+    assert_eq!(report.status, VerificationStatus::Completed);
+    assert_eq!(report.discrepancies, vec![]);
+}
+
+#[test]
+fn orphaned_links_should_be_reported_and_repaired_if_requested() {
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    let mut store = AccountsStore::from(AccountsDb::UnboundedStableBTreeMap(
+        AccountsDbAsUnboundedStableBTreeMap::new(
+            partitions.get(PartitionType::Accounts.memory_id()),
+            partitions.get(PartitionType::AccountLinks.memory_id()),
+        ),
+    ));
+    let principal = add_account_with_links(&mut store, 0);
+    let account_key = AccountIdentifier::from(principal).to_vec();
+    let account = store.db_get_account(&account_key).unwrap();
+    // Lose the accounts but keep the index, as if the accounts memory had been overwritten.
+    let _original_db = store.replace_accounts_db(AccountsDb::UnboundedStableBTreeMap(
+        AccountsDbAsUnboundedStableBTreeMap::load(
+            partitions.get(PartitionType::CompactAccounts.memory_id()),
+            partitions.get(PartitionType::AccountLinks.memory_id()),
+        ),
+    ));
+    store.accounts_db_stats = AccountsDbStats::default();
+    let expected_discrepancies: Vec<_> = account_links::entries(&account_key, &account)
+        .into_iter()
+        .map(|entry| Discrepancy::OrphanedLink { entry })
+        .collect();
+    assert_eq!(expected_discrepancies.len(), 2);
+
+    assert_eq!(verify(&mut store, false).discrepancies, expected_discrepancies);
+    assert_eq!(store.db_link_entries(Vec::new()).count(), 2);
+
+    assert_eq!(verify(&mut store, true).discrepancies, expected_discrepancies);
+    assert_eq!(store.db_link_entries(Vec::new()).count(), 0);
+
+    assert_eq!(verify(&mut store, false).discrepancies, vec![]);
+}
+
+#[test]
+fn discrepancies_beyond_the_maximum_should_be_counted_but_not_kept() {
+    let mut store = AccountsStore::default();
+    assert_eq!(store.start_verification(false), VerifyStateResponse::Ok);
+    for index in 0..MAX_RECORDED_DISCREPANCIES + 5 {
+        store.record_discrepancy(Discrepancy::SubAccountsCount {
+            recorded: index as u64,
+            counted: 0,
+        });
+    }
+    let report = store.verification_report().unwrap();
+    assert_eq!(report.discrepancies_count, MAX_RECORDED_DISCREPANCIES as u64 + 5);
+    assert_eq!(report.discrepancies.len(), MAX_RECORDED_DISCREPANCIES);
+}
+
+#[test]
+fn verification_report_should_be_paginated() {
+    let mut store = AccountsStore::default();
+    assert_eq!(
+        store.verification_report_page(GetStateVerificationRequest {
+            discrepancies_offset: 0
+        }),
+        None
+    );
+    assert_eq!(store.start_verification(false), VerifyStateResponse::Ok);
+    for index in 0..250 {
+        store.record_discrepancy(Discrepancy::SubAccountsCount {
+            recorded: index,
+            counted: 0,
+        });
+    }
+    let report = store.verification_report().unwrap();
+
+    let mut discrepancies = Vec::new();
+    let mut offsets = Vec::new();
+    let mut discrepancies_offset = 0;
+    loop {
+        let page = store
+            .verification_report_page(GetStateVerificationRequest { discrepancies_offset })
+            .unwrap();
+        assert_eq!(page.report.discrepancies_count, 250);
+        discrepancies.extend(page.report.discrepancies);
+        let Some(next_discrepancies_offset) = page.next_discrepancies_offset else {
+            break;
+        };
+        offsets.push(next_discrepancies_offset);
+        discrepancies_offset = next_discrepancies_offset;
+    }
+    assert_eq!(offsets, vec![100, 200]);
+    assert_eq!(discrepancies, report.discrepancies);
+}
+
+#[test]
+fn saved_verification_should_be_restored() {
+    let mut store = AccountsStore::default();
+    for index in 0..3 {
+        add_account_with_links(&mut store, index);
+    }
+    assert_eq!(store.start_verification(false), VerifyStateResponse::Ok);
+    store.step_verification(2);
+    let saved = SavedVerification::from_bytes(store.saved_verification().to_bytes());
+    assert_eq!(saved, store.saved_verification());
+
+    store.verification.0 = None;
+    store.restore_verification(saved);
+    assert!(store.verification_in_progress());
+    assert_eq!(store.verification_report().unwrap().accounts_verified, 2);
+    assert_eq!(verify_to_completion(&mut store).discrepancies, vec![]);
+}
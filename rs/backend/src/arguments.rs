@@ -6,17 +6,61 @@
 #![deny(clippy::expect_used)]
 #![deny(clippy::unwrap_used)]
 
+use crate::accounts_store::schema::SchemaLabel;
 use candid::{CandidType, Deserialize};
 use core::cell::RefCell;
 use regex::{Captures, Regex};
 use serde::Serialize;
 use std::collections::HashMap;
+use std::time::Duration;
+
+/// The default number of seconds between ledger syncs.
+pub const DEFAULT_LEDGER_SYNC_INTERVAL_SECONDS: u64 = 5;
+/// The default number of seconds between attempts to process a multi-part transaction.
+pub const DEFAULT_MULTI_PART_TRANSACTIONS_INTERVAL_SECONDS: u64 = 2;
+/// The default number of seconds between migration steps, while a migration is in progress.
+pub const DEFAULT_MIGRATION_STEP_INTERVAL_SECONDS: u64 = 1;
 
 /// `init` and `post_upgrade` arguments
 #[derive(Debug, Default, Eq, PartialEq, CandidType, Serialize, Deserialize)]
 pub struct CanisterArguments {
     /// Values that are to be set in the web front end, by injecting them into JavaScript.
     pub args: Vec<(String, String)>,
+    /// How to read blocks from the ICP ledger.  Defaults to `LedgerBlockSource::Protobuf`.
+    pub ledger_block_source: Option<LedgerBlockSource>,
+    /// Seconds between ledger syncs.  Defaults to `DEFAULT_LEDGER_SYNC_INTERVAL_SECONDS`.
+    pub ledger_sync_interval_seconds: Option<u64>,
+    /// Seconds between attempts to process a multi-part transaction.  Defaults to `DEFAULT_MULTI_PART_TRANSACTIONS_INTERVAL_SECONDS`.
+    pub multi_part_transactions_interval_seconds: Option<u64>,
+    /// Seconds between migration steps.  Defaults to `DEFAULT_MIGRATION_STEP_INTERVAL_SECONDS`.
+    pub migration_step_interval_seconds: Option<u64>,
+    /// The schema in which accounts should be stored.  If the accounts are in a different schema
+    /// and a migration to this one is registered, the migration is started.  If not set, the
+    /// accounts stay where they are.
+    pub accounts_schema: Option<SchemaLabel>,
+}
+
+/// How often each background task runs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PeriodicTaskIntervals {
+    /// Time between ledger syncs.
+    pub ledger_sync: Duration,
+    /// Time between attempts to process a multi-part transaction.
+    pub multi_part_transactions: Duration,
+    /// Time between migration steps.
+    pub migration_step: Duration,
+}
+
+/// The ledger endpoints used to sync blocks.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, CandidType, Serialize, Deserialize)]
+pub enum LedgerBlockSource {
+    /// The legacy protobuf endpoints: `tip_of_chain_pb`, `get_archive_index_pb` and `get_blocks_pb`.
+    #[default]
+    Protobuf,
+    /// The candid `query_encoded_blocks` endpoint, and the archive methods it refers to.
+    Candid,
+    /// The ICRC-3 `icrc3_get_blocks` endpoint, and the archive methods it refers to.
+    Icrc3,
 }
 
 thread_local! {
@@ -76,6 +120,35 @@ impl CanisterArguments {
         self
     }
 
+    /// The intervals at which background tasks run, using the default for any interval not set.
+    ///
+    /// Intervals are at least one second.
+    ///
+    /// ```
+    /// use nns_dapp::arguments::CanisterArguments;
+    /// use std::time::Duration;
+    /// let canister_arguments = CanisterArguments{ledger_sync_interval_seconds: Some(30), migration_step_interval_seconds: Some(0), ..CanisterArguments::default()};
+    /// let intervals = canister_arguments.periodic_task_intervals();
+    /// assert_eq!(intervals.ledger_sync, Duration::from_secs(30));
+    /// assert_eq!(intervals.multi_part_transactions, Duration::from_secs(2));
+    /// assert_eq!(intervals.migration_step, Duration::from_secs(1));
+    /// ```
+    #[must_use]
+    pub fn periodic_task_intervals(&self) -> PeriodicTaskIntervals {
+        let interval = |seconds: Option<u64>, default: u64| Duration::from_secs(seconds.unwrap_or(default).max(1));
+        PeriodicTaskIntervals {
+            ledger_sync: interval(self.ledger_sync_interval_seconds, DEFAULT_LEDGER_SYNC_INTERVAL_SECONDS),
+            multi_part_transactions: interval(
+                self.multi_part_transactions_interval_seconds,
+                DEFAULT_MULTI_PART_TRANSACTIONS_INTERVAL_SECONDS,
+            ),
+            migration_step: interval(
+                self.migration_step_interval_seconds,
+                DEFAULT_MIGRATION_STEP_INTERVAL_SECONDS,
+            ),
+        }
+    }
+
     /// Utility to convert static strings to an `args` field.
     ///
     /// ```
@@ -125,6 +198,24 @@ pub fn set_canister_arguments(canister_arguments: Option<CanisterArguments>) {
     });
 }
 
+/// The ledger endpoints to use for syncing blocks, as set in the canister arguments.
+#[must_use]
+pub fn ledger_block_source() -> LedgerBlockSource {
+    CANISTER_ARGUMENTS.with(|args| args.borrow().ledger_block_source.unwrap_or_default())
+}
+
+/// The schema in which accounts should be stored, as set in the canister arguments.
+#[must_use]
+pub fn accounts_schema() -> Option<SchemaLabel> {
+    CANISTER_ARGUMENTS.with(|args| args.borrow().accounts_schema)
+}
+
+/// The intervals at which background tasks run, as set in the canister arguments.
+#[must_use]
+pub fn periodic_task_intervals() -> PeriodicTaskIntervals {
+    CANISTER_ARGUMENTS.with(|args| args.borrow().periodic_task_intervals())
+}
+
 /// Replaces arguments in a template
 pub struct TemplateEngine {
     /// Values to replace
@@ -1,3 +1,4 @@
+use crate::accounts_store::heap_or_stable_map::HeapOrStableMap;
 use crate::arguments::{TemplateEngine, CANISTER_ARGUMENTS};
 use crate::metrics_encoder::MetricsEncoder;
 use crate::state::{with_state, with_state_mut, State};
@@ -11,9 +12,11 @@ use flate2::write::GzEncoder;
 use flate2::Compression;
 use ic_cdk::println;
 use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash, RbTree};
+use ic_stable_structures::{storable::Bound, Storable};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::io::prelude::*;
 use std::io::Read;
@@ -114,9 +117,36 @@ impl Asset {
     }
 }
 
+impl Storable for Asset {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        candid::encode_one(self).expect("Failed to serialize asset").into()
+    }
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to parse asset from store.")
+    }
+}
+
 #[derive(Default, CandidType, Deserialize, PartialEq, Eq, Debug)]
 pub struct Assets(HashMap<String, Asset>);
 
+/// Loads the assets that are persisted across upgrades, keyed by path.
+impl From<&HeapOrStableMap<Asset>> for Assets {
+    fn from(stable_assets: &HeapOrStableMap<Asset>) -> Self {
+        Assets(
+            stable_assets
+                .entries()
+                .into_iter()
+                .map(|(path, asset)| {
+                    let path = String::from_utf8(path)
+                        .unwrap_or_else(|err| unreachable!("Stored asset paths are strings: {err}"));
+                    (path, asset)
+                })
+                .collect(),
+        )
+    }
+}
+
 impl Assets {
     /// List of content encodings supported by the assets database.
     const CONTENT_ENCODINGS: [ContentEncoding; 2] = [ContentEncoding::GZip, ContentEncoding::Identity];
@@ -174,6 +204,16 @@ impl Assets {
         self.0.get(&path_with_suffix)
     }
 
+    /// The assets that are persisted across upgrades, keyed by path.
+    #[must_use]
+    pub fn stable_assets(&self) -> HeapOrStableMap<Asset> {
+        let mut stable_assets = HeapOrStableMap::default();
+        for (path, asset) in self.0.iter().filter(|(_, asset)| asset.stable) {
+            stable_assets.insert(path.as_bytes(), asset.clone());
+        }
+        stable_assets
+    }
+
     /// Returns the paths for which a given asset may be returned.
     /// Note:  All these paths must be certified.
     #[must_use]
@@ -355,6 +395,8 @@ pub fn insert_asset<S: Into<String> + Clone>(path: S, asset: Asset) {
 }
 /// Insert an asset into the given state.
 ///
+/// Stable assets are also saved to their stable memory partition.
+///
 /// Note:  This does NOT update the certificates.  To insert multiple assets, call
 ///        this repeatedly and then update the root hash.
 pub fn insert_asset_into_state<S: Into<String> + Clone>(state: &mut State, path: S, asset: Asset) {
@@ -365,6 +407,12 @@ pub fn insert_asset_into_state<S: Into<String> + Clone>(state: &mut State, path:
     for alternate_path in Assets::alternate_paths(&path) {
         asset_hashes.0.insert(alternate_path.as_bytes().to_vec(), hash);
     }
+    if asset.stable {
+        state.stable_assets.insert(path.as_bytes(), asset.clone());
+    } else {
+        // A stable asset replaced by one that is not stable must not be restored on upgrade.
+        state.stable_assets.remove(path.as_bytes());
+    }
     assets.insert(path, asset);
 }
 
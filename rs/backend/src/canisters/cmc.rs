@@ -1,4 +1,4 @@
-use cycles_minting_canister::{NotifyCreateCanister, NotifyError};
+use cycles_minting_canister::{NotifyCreateCanister, NotifyError, NotifyTopUp, NotifyTopUpResult};
 use dfn_candid::candid;
 use dfn_core::CanisterId;
 use ic_nns_constants::CYCLES_MINTING_CANISTER_ID;
@@ -8,3 +8,9 @@ pub async fn notify_create_canister(request: NotifyCreateCanister) -> Result<Res
         .await
         .map_err(|e| e.1)
 }
+
+pub async fn notify_top_up(request: NotifyTopUp) -> Result<NotifyTopUpResult, String> {
+    dfn_core::call(CYCLES_MINTING_CANISTER_ID, "notify_top_up", candid, (request,))
+        .await
+        .map_err(|e| e.1)
+}
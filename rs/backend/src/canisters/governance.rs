@@ -1,17 +1,22 @@
-pub use ic_nns_governance::pb::v1::{governance::GovernanceCachedMetrics, GovernanceError};
+pub use ic_nns_governance::pb::v1::{
+    claim_or_refresh_neuron_from_account_response, governance::GovernanceCachedMetrics,
+    governance_error::ErrorType as GovernanceErrorType, ClaimOrRefreshNeuronFromAccount,
+    ClaimOrRefreshNeuronFromAccountResponse, GovernanceError,
+};
 
 #[cfg(not(test))]
-pub use prod::get_metrics;
+pub use prod::{claim_or_refresh_neuron_from_account, get_metrics};
 
 #[cfg(test)]
-pub use testing::get_metrics;
+pub use testing::{claim_or_refresh_neuron_from_account, get_metrics};
 
 type GetMetricsCallResult = Result<Result<GovernanceCachedMetrics, GovernanceError>, String>;
+type ClaimOrRefreshNeuronCallResult = Result<ClaimOrRefreshNeuronFromAccountResponse, String>;
 
 #[cfg(not(test))]
 mod prod {
-    use super::GetMetricsCallResult;
-    use dfn_candid::candid;
+    use super::{ClaimOrRefreshNeuronCallResult, ClaimOrRefreshNeuronFromAccount, GetMetricsCallResult};
+    use dfn_candid::{candid, candid_one};
     use ic_nns_constants::GOVERNANCE_CANISTER_ID;
 
     pub async fn get_metrics() -> GetMetricsCallResult {
@@ -19,6 +24,19 @@ mod prod {
             .await
             .map_err(|e| e.1)
     }
+
+    pub async fn claim_or_refresh_neuron_from_account(
+        request: ClaimOrRefreshNeuronFromAccount,
+    ) -> ClaimOrRefreshNeuronCallResult {
+        dfn_core::call(
+            GOVERNANCE_CANISTER_ID,
+            "claim_or_refresh_neuron_from_account",
+            candid_one,
+            request,
+        )
+        .await
+        .map_err(|e| e.1)
+    }
 }
 
 #[cfg(test)]
@@ -29,6 +47,8 @@ pub mod testing {
     thread_local! {
         pub static RESPONSES:
         RefCell<VecDeque<GetMetricsCallResult>> = RefCell::default();
+        pub static CLAIM_OR_REFRESH_RESPONSES:
+        RefCell<VecDeque<ClaimOrRefreshNeuronCallResult>> = RefCell::default();
     }
 
     pub async fn get_metrics() -> GetMetricsCallResult {
@@ -52,4 +72,19 @@ pub mod testing {
         }));
         add_metrics_response(response);
     }
+
+    pub async fn claim_or_refresh_neuron_from_account(
+        _request: ClaimOrRefreshNeuronFromAccount,
+    ) -> ClaimOrRefreshNeuronCallResult {
+        CLAIM_OR_REFRESH_RESPONSES.with(|responses| {
+            responses
+                .borrow_mut()
+                .pop_front()
+                .expect("The test must provide a response before each call to claim_or_refresh_neuron_from_account.")
+        })
+    }
+
+    pub fn add_claim_or_refresh_response(response: ClaimOrRefreshNeuronCallResult) {
+        CLAIM_OR_REFRESH_RESPONSES.with(|responses| responses.borrow_mut().push_back(response));
+    }
 }
@@ -1,47 +1,420 @@
 use dfn_core::CanisterId;
-use dfn_protobuf::protobuf;
 use ic_ledger_core::block::EncodedBlock;
 use ic_nns_constants::LEDGER_CANISTER_ID;
-use icp_ledger::protobuf::get_blocks_response::GetBlocksContent;
-use icp_ledger::protobuf::{
-    ArchiveIndexResponse as ArchiveIndexResponsePb, GetBlocksResponse as GetBlocksResponsePb,
-    TipOfChainRequest as TipOfChainRequestPb, TipOfChainResponse as TipOfChainResponsePb,
-};
-use icp_ledger::{BlockIndex, GetBlocksArgs};
-
-pub async fn tip_of_chain() -> Result<BlockIndex, String> {
-    let response: TipOfChainResponsePb =
-        dfn_core::call(LEDGER_CANISTER_ID, "tip_of_chain_pb", protobuf, TipOfChainRequestPb {})
-            .await
-            .map_err(|e| e.1)?;
+use icp_ledger::protobuf::ArchiveIndexResponse as ArchiveIndexResponsePb;
+use icp_ledger::BlockIndex;
+
+pub mod icrc3;
+
+#[cfg(not(test))]
+pub use prod::{get_archive_index, get_archived_blocks, get_blocks, icrc3_get_blocks, query_blocks, tip_of_chain};
 
-    Ok(response.chain_length.map(|c| c.height).unwrap_or_default())
+#[cfg(test)]
+pub use testing::{get_archive_index, get_archived_blocks, get_blocks, icrc3_get_blocks, query_blocks, tip_of_chain};
+
+/// The response to a candid `query_encoded_blocks` call to the ledger.
+#[derive(Debug, Default)]
+pub struct QueriedBlocks {
+    /// The number of blocks in the chain.
+    pub chain_length: u64,
+    /// The height of the first block in `blocks`.
+    pub first_block_index: BlockIndex,
+    /// Requested blocks that are still held by the ledger.
+    pub blocks: Vec<EncodedBlock>,
+    /// Requested blocks that have been moved to archives, in order.
+    pub archived_blocks: Vec<ArchivedBlocks>,
 }
 
-pub async fn get_archive_index() -> Result<ArchiveIndexResponsePb, String> {
-    dfn_core::call(LEDGER_CANISTER_ID, "get_archive_index_pb", protobuf, ())
+/// A range of blocks held by an archive, and how to fetch them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ArchivedBlocks {
+    pub canister_id: CanisterId,
+    /// The candid method that returns the blocks.
+    pub method: String,
+    pub start: BlockIndex,
+    pub length: u64,
+}
+
+/// Gets the number of blocks in the chain with the ICRC-3 `icrc3_get_blocks` endpoint.
+pub async fn icrc3_chain_length() -> Result<u64, String> {
+    let response = icrc3_get_blocks(LEDGER_CANISTER_ID, "icrc3_get_blocks", 0, 0).await?;
+    icrc3::nat_to_u64(&response.log_length)
+}
+
+/// Gets blocks with the ICRC-3 `icrc3_get_blocks` endpoint, in the same form as `query_blocks`.
+pub async fn icrc3_query_blocks(from: BlockIndex, length: u64) -> Result<QueriedBlocks, String> {
+    let response = icrc3_get_blocks(LEDGER_CANISTER_ID, "icrc3_get_blocks", from, length).await?;
+    let blocks = icrc3_blocks(response.blocks)?;
+    let mut archived_blocks = Vec::new();
+    for archived in response.archived_blocks {
+        for args in archived.args {
+            archived_blocks.push(ArchivedBlocks {
+                canister_id: CanisterId::unchecked_from_principal(archived.callback.principal.into()),
+                method: archived.callback.method.clone(),
+                start: icrc3::nat_to_u64(&args.start)?,
+                length: icrc3::nat_to_u64(&args.length)?,
+            });
+        }
+    }
+    Ok(QueriedBlocks {
+        chain_length: icrc3::nat_to_u64(&response.log_length)?,
+        first_block_index: blocks.first().map_or(from, |(block_height, _)| *block_height),
+        blocks: blocks.into_iter().map(|(_, block)| block).collect(),
+        archived_blocks,
+    })
+}
+
+/// Gets blocks from an archive that `icrc3_query_blocks` referred to.
+pub async fn icrc3_get_archived_blocks(
+    archive: &ArchivedBlocks,
+    from: BlockIndex,
+    length: u64,
+) -> Result<Vec<EncodedBlock>, String> {
+    let response = icrc3_get_blocks(archive.canister_id, &archive.method, from, length).await?;
+    Ok(icrc3_blocks(response.blocks)?
+        .into_iter()
+        .map(|(_, block)| block)
+        .collect())
+}
+
+/// Converts ICRC-3 blocks to encoded ICP ledger blocks, checking that they are consecutive.
+fn icrc3_blocks(blocks: Vec<icrc3::BlockWithId>) -> Result<Vec<(BlockIndex, EncodedBlock)>, String> {
+    let mut converted: Vec<(BlockIndex, EncodedBlock)> = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        let block_height = icrc3::nat_to_u64(&block.id)?;
+        if let Some((previous, _)) = converted.last() {
+            if block_height != previous + 1 {
+                return Err(format!("Expected block {}, got block {block_height}", previous + 1));
+            }
+        }
+        converted.push((
+            block_height,
+            icrc3::encoded_block_from_value(block_height, &block.block),
+        ));
+    }
+    Ok(converted)
+}
+
+#[cfg(not(test))]
+mod prod {
+    use super::icrc3::{self, GetBlocksResult};
+    use super::{ArchiveIndexResponsePb, ArchivedBlocks, BlockIndex, CanisterId, EncodedBlock, QueriedBlocks};
+    use dfn_candid::candid_one;
+    use dfn_protobuf::protobuf;
+    use ic_nns_constants::LEDGER_CANISTER_ID;
+    use icp_ledger::protobuf::get_blocks_response::GetBlocksContent;
+    use icp_ledger::protobuf::{
+        GetBlocksResponse as GetBlocksResponsePb, TipOfChainRequest as TipOfChainRequestPb,
+        TipOfChainResponse as TipOfChainResponsePb,
+    };
+    use icp_ledger::{GetBlocksArgs, GetEncodedBlocksResult, QueryEncodedBlocksResponse};
+
+    pub async fn tip_of_chain() -> Result<BlockIndex, String> {
+        let response: TipOfChainResponsePb =
+            dfn_core::call(LEDGER_CANISTER_ID, "tip_of_chain_pb", protobuf, TipOfChainRequestPb {})
+                .await
+                .map_err(|e| e.1)?;
+
+        Ok(response.chain_length.map(|c| c.height).unwrap_or_default())
+    }
+
+    pub async fn get_archive_index() -> Result<ArchiveIndexResponsePb, String> {
+        dfn_core::call(LEDGER_CANISTER_ID, "get_archive_index_pb", protobuf, ())
+            .await
+            .map_err(|e| e.1)
+    }
+
+    pub async fn get_blocks(
+        canister_id: CanisterId,
+        from: BlockIndex,
+        length: u32,
+    ) -> Result<Vec<EncodedBlock>, String> {
+        let response: GetBlocksResponsePb = dfn_core::call(
+            canister_id,
+            "get_blocks_pb",
+            protobuf,
+            GetBlocksArgs {
+                start: from,
+                length: length as usize,
+            },
+        )
+        .await
+        .map_err(|e| e.1)?;
+
+        match response.get_blocks_content {
+            Some(GetBlocksContent::Blocks(blocks)) => {
+                Ok(blocks.blocks.into_iter().map(|b| EncodedBlock::from(b.block)).collect())
+            }
+            Some(GetBlocksContent::Error(error)) => Err(error),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub async fn query_blocks(from: BlockIndex, length: u64) -> Result<QueriedBlocks, String> {
+        let response: QueryEncodedBlocksResponse = dfn_core::call(
+            LEDGER_CANISTER_ID,
+            "query_encoded_blocks",
+            candid_one,
+            GetBlocksArgs {
+                start: from,
+                length: usize::try_from(length).unwrap_or(usize::MAX),
+            },
+        )
+        .await
+        .map_err(|e| e.1)?;
+
+        Ok(QueriedBlocks {
+            chain_length: response.chain_length,
+            first_block_index: response.first_block_index,
+            blocks: response.blocks,
+            archived_blocks: response
+                .archived_blocks
+                .into_iter()
+                .map(|range| ArchivedBlocks {
+                    canister_id: range.callback.canister_id,
+                    method: range.callback.method,
+                    start: range.start,
+                    length: range.length,
+                })
+                .collect(),
+        })
+    }
+
+    pub async fn get_archived_blocks(
+        archive: &ArchivedBlocks,
+        from: BlockIndex,
+        length: u64,
+    ) -> Result<Vec<EncodedBlock>, String> {
+        let response: GetEncodedBlocksResult = dfn_core::call(
+            archive.canister_id,
+            &archive.method,
+            candid_one,
+            GetBlocksArgs {
+                start: from,
+                length: usize::try_from(length).unwrap_or(usize::MAX),
+            },
+        )
+        .await
+        .map_err(|e| e.1)?;
+
+        response.map_err(|error| format!("{error:?}"))
+    }
+
+    pub async fn icrc3_get_blocks(
+        canister_id: CanisterId,
+        method: &str,
+        from: BlockIndex,
+        length: u64,
+    ) -> Result<GetBlocksResult, String> {
+        dfn_core::call(
+            canister_id,
+            method,
+            candid_one,
+            vec![icrc3::GetBlocksArgs::new(from, length)],
+        )
         .await
         .map_err(|e| e.1)
+    }
 }
 
-pub async fn get_blocks(canister_id: CanisterId, from: BlockIndex, length: u32) -> Result<Vec<EncodedBlock>, String> {
-    let response: GetBlocksResponsePb = dfn_core::call(
-        canister_id,
-        "get_blocks_pb",
-        protobuf,
-        GetBlocksArgs {
-            start: from,
-            length: length as usize,
-        },
-    )
-    .await
-    .map_err(|e| e.1)?;
-
-    match response.get_blocks_content {
-        Some(GetBlocksContent::Blocks(blocks)) => {
-            Ok(blocks.blocks.into_iter().map(|b| EncodedBlock::from(b.block)).collect())
+/// A mock ledger that holds a chain of blocks, the oldest of which have been moved to archives.
+///
+/// Both the protobuf and the candid methods are served from the same chain.
+#[cfg(test)]
+pub mod testing {
+    use super::icrc3::{self, BlockWithId, GetBlocksArgs, GetBlocksResult};
+    use super::{ArchiveIndexResponsePb, ArchivedBlocks, BlockIndex, CanisterId, EncodedBlock, QueriedBlocks};
+    use candid::{Func, Nat};
+    use ic_ledger_core::block::BlockType;
+    use ic_nns_constants::LEDGER_CANISTER_ID;
+    use icp_ledger::protobuf::ArchiveIndexEntry;
+    use icp_ledger::Block;
+    use std::cell::{Cell, RefCell};
+    use std::ops::Range;
+
+    #[derive(Default)]
+    pub struct MockLedger {
+        pub blocks: Vec<EncodedBlock>,
+        /// The archives and the blocks they hold.  Blocks after the last archive are held by the ledger.
+        pub archives: Vec<(CanisterId, Range<BlockIndex>)>,
+        /// The number of calls to archives that fail before archives respond again.
+        pub archive_failures: Cell<u32>,
+    }
+
+    thread_local! {
+        pub static MOCK_LEDGER: RefCell<MockLedger> = RefCell::default();
+    }
+
+    /// Replaces the chain served by the mock ledger.
+    pub fn set_mock_ledger(ledger: MockLedger) {
+        MOCK_LEDGER.with(|mock| *mock.borrow_mut() = ledger);
+    }
+
+    fn with_mock_ledger<R>(f: impl FnOnce(&MockLedger) -> R) -> R {
+        MOCK_LEDGER.with(|mock| f(&mock.borrow()))
+    }
+
+    impl MockLedger {
+        /// The height of the first block held by the ledger rather than an archive.
+        fn first_ledger_block(&self) -> BlockIndex {
+            self.archives.last().map_or(0, |(_, range)| range.end)
+        }
+
+        fn held_by(&self, canister_id: CanisterId) -> Option<Range<BlockIndex>> {
+            if canister_id == LEDGER_CANISTER_ID {
+                return Some(self.first_ledger_block()..self.blocks.len() as u64);
+            }
+            self.archives
+                .iter()
+                .find(|(archive, _)| *archive == canister_id)
+                .map(|(_, range)| range.clone())
         }
-        Some(GetBlocksContent::Error(error)) => Err(error),
-        None => Ok(Vec::new()),
+
+        /// Fails if archives are to be unavailable for more calls.
+        fn check_archive_available(&self) -> Result<(), String> {
+            let failures = self.archive_failures.get();
+            if failures == 0 {
+                return Ok(());
+            }
+            self.archive_failures.set(failures - 1);
+            Err("Archive unavailable".to_string())
+        }
+
+        fn blocks_in(&self, held: &Range<BlockIndex>, from: BlockIndex, length: u64) -> Vec<EncodedBlock> {
+            let start = from.max(held.start);
+            let end = from.saturating_add(length).min(held.end);
+            (start..end)
+                .map(|height| self.blocks[height as usize].clone())
+                .collect()
+        }
+    }
+
+    pub async fn tip_of_chain() -> Result<BlockIndex, String> {
+        with_mock_ledger(|ledger| Ok((ledger.blocks.len() as u64).saturating_sub(1)))
+    }
+
+    pub async fn get_archive_index() -> Result<ArchiveIndexResponsePb, String> {
+        with_mock_ledger(|ledger| {
+            Ok(ArchiveIndexResponsePb {
+                entries: ledger
+                    .archives
+                    .iter()
+                    .map(|(canister_id, range)| ArchiveIndexEntry {
+                        height_from: range.start,
+                        height_to: range.end - 1,
+                        canister_id: Some(canister_id.get()),
+                    })
+                    .collect(),
+            })
+        })
+    }
+
+    pub async fn get_blocks(
+        canister_id: CanisterId,
+        from: BlockIndex,
+        length: u32,
+    ) -> Result<Vec<EncodedBlock>, String> {
+        with_mock_ledger(|ledger| {
+            let held = ledger
+                .held_by(canister_id)
+                .ok_or_else(|| format!("Unknown canister {canister_id}"))?;
+            if canister_id != LEDGER_CANISTER_ID {
+                ledger.check_archive_available()?;
+            }
+            Ok(ledger.blocks_in(&held, from, u64::from(length)))
+        })
+    }
+
+    pub async fn query_blocks(from: BlockIndex, length: u64) -> Result<QueriedBlocks, String> {
+        with_mock_ledger(|ledger| {
+            let end = from.saturating_add(length).min(ledger.blocks.len() as u64);
+            let first_ledger_block = ledger.first_ledger_block();
+            let first_block_index = from.max(first_ledger_block);
+            let archived_blocks = ledger
+                .archives
+                .iter()
+                .filter_map(|(canister_id, range)| {
+                    let start = from.max(range.start);
+                    let archived_end = end.min(range.end);
+                    (start < archived_end).then(|| ArchivedBlocks {
+                        canister_id: *canister_id,
+                        method: "get_encoded_blocks".to_string(),
+                        start,
+                        length: archived_end - start,
+                    })
+                })
+                .collect();
+            Ok(QueriedBlocks {
+                chain_length: ledger.blocks.len() as u64,
+                first_block_index,
+                blocks: ledger.blocks_in(
+                    &(first_ledger_block..ledger.blocks.len() as u64),
+                    first_block_index,
+                    end.saturating_sub(first_block_index),
+                ),
+                archived_blocks,
+            })
+        })
+    }
+
+    pub async fn get_archived_blocks(
+        archive: &ArchivedBlocks,
+        from: BlockIndex,
+        length: u64,
+    ) -> Result<Vec<EncodedBlock>, String> {
+        with_mock_ledger(|ledger| {
+            let held = ledger
+                .held_by(archive.canister_id)
+                .ok_or_else(|| format!("Unknown archive {}", archive.canister_id))?;
+            ledger.check_archive_available()?;
+            Ok(ledger.blocks_in(&held, from, length))
+        })
+    }
+
+    pub async fn icrc3_get_blocks(
+        canister_id: CanisterId,
+        method: &str,
+        from: BlockIndex,
+        length: u64,
+    ) -> Result<GetBlocksResult, String> {
+        assert_eq!(method, "icrc3_get_blocks");
+        with_mock_ledger(|ledger| {
+            let held = ledger
+                .held_by(canister_id)
+                .ok_or_else(|| format!("Unknown canister {canister_id}"))?;
+            let archived_blocks = if canister_id == LEDGER_CANISTER_ID {
+                let end = from.saturating_add(length);
+                ledger
+                    .archives
+                    .iter()
+                    .filter_map(|(archive, range)| {
+                        let start = from.max(range.start);
+                        let archived_end = end.min(range.end);
+                        (start < archived_end).then(|| icrc3::ArchivedBlocks {
+                            args: vec![GetBlocksArgs::new(start, archived_end - start)],
+                            callback: Func {
+                                principal: archive.get().0,
+                                method: "icrc3_get_blocks".to_string(),
+                            },
+                        })
+                    })
+                    .collect()
+            } else {
+                ledger.check_archive_available()?;
+                Vec::new()
+            };
+            let blocks = (from.max(held.start)..)
+                .zip(ledger.blocks_in(&held, from, length))
+                .map(|(block_height, block)| BlockWithId {
+                    id: Nat::from(block_height),
+                    block: icrc3::value_from_block(&Block::decode(block).expect("Mock blocks are valid")),
+                })
+                .collect();
+            Ok(GetBlocksResult {
+                log_length: Nat::from(ledger.blocks.len() as u64),
+                blocks,
+                archived_blocks,
+            })
+        })
     }
 }
@@ -0,0 +1,296 @@
+//! The ICRC-3 `icrc3_get_blocks` interface, and the conversion of the blocks it returns to ICP
+//! ledger blocks.
+//!
+//! ICRC-3 blocks are generic values.  Both the ICRC-3 block schemas (`btype` of `1xfer`, `2approve`
+//! and so on, with accounts as a principal and optional sub-account) and the ICP ledger's own
+//! schema (`op` of `xfer`, `approve` and so on, with accounts as account identifiers) are understood.
+use candid::{CandidType, Deserialize, Func, Int, Nat, Principal};
+use ic_base_types::PrincipalId;
+use ic_cdk::println;
+use ic_ledger_core::block::{BlockType, EncodedBlock};
+use ic_ledger_core::Tokens;
+use icp_ledger::{AccountIdentifier, Block, BlockIndex, Memo, Operation, Subaccount, TimeStamp, Transaction};
+use serde_bytes::ByteBuf;
+
+#[cfg(test)]
+mod tests;
+
+/// A generic ICRC-3 value.
+#[derive(CandidType, Clone, Debug, Deserialize, Eq, PartialEq)]
+pub enum Value {
+    Blob(ByteBuf),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+impl GetBlocksArgs {
+    #[must_use]
+    pub fn new(start: BlockIndex, length: u64) -> Self {
+        GetBlocksArgs {
+            start: Nat::from(start),
+            length: Nat::from(length),
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+/// Blocks held by an archive, and the method of the archive that returns them.
+#[derive(CandidType, Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: Func,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+/// Converts a natural number to a `u64`.
+///
+/// # Errors
+/// - If the number does not fit in 64 bits.
+pub fn nat_to_u64(nat: &Nat) -> Result<u64, String> {
+    u64::try_from(&nat.0).map_err(|_| format!("{nat} does not fit in 64 bits"))
+}
+
+/// Converts an ICRC-3 block to an encoded ICP ledger block, so that it is processed like blocks
+/// from the other sources.
+///
+/// A block that cannot be converted is returned as an empty encoding.  That fails to decode, so the
+/// block is replaced and scheduled for replay like any other block that cannot be decoded.
+#[must_use]
+pub fn encoded_block_from_value(block_height: BlockIndex, value: &Value) -> EncodedBlock {
+    match block_from_value(value) {
+        Ok(block) => block.encode(),
+        Err(err) => {
+            println!("Failed to convert ICRC-3 block {block_height}: {err}");
+            EncodedBlock::from(Vec::new())
+        }
+    }
+}
+
+/// Converts an ICRC-3 block to an ICP ledger block.
+///
+/// The hash of the parent block is not kept: it is not needed to process the block.
+///
+/// # Errors
+/// - If the block does not follow one of the supported schemas.
+pub fn block_from_value(value: &Value) -> Result<Block, String> {
+    let block = as_map(value)?;
+    let tx = as_map(field(block, "tx")?)?;
+    let operation_type = match optional_field(block, "btype") {
+        Some(btype) => as_text(btype)?,
+        None => as_text(field(tx, "op")?)?,
+    };
+    let amount = tokens(field(tx, "amt")?)?;
+    let fee = match optional_field(tx, "fee").or_else(|| optional_field(block, "fee")) {
+        Some(fee) => tokens(fee)?,
+        None => Tokens::ZERO,
+    };
+    let spender = optional_field(tx, "spender").map(account).transpose()?;
+    let operation = match operation_type {
+        "1mint" | "mint" => Operation::Mint {
+            to: account(field(tx, "to")?)?,
+            amount,
+        },
+        "1burn" | "burn" => Operation::Burn {
+            from: account(field(tx, "from")?)?,
+            amount,
+            spender,
+        },
+        "1xfer" | "2xfer" | "xfer" => Operation::Transfer {
+            from: account(field(tx, "from")?)?,
+            to: account(field(tx, "to")?)?,
+            spender,
+            amount,
+            fee,
+        },
+        "2approve" | "approve" => Operation::Approve {
+            from: account(field(tx, "from")?)?,
+            spender: spender.ok_or("An approval has no spender")?,
+            allowance: amount,
+            expected_allowance: optional_field(tx, "expected_allowance").map(tokens).transpose()?,
+            expires_at: optional_field(tx, "expires_at").map(timestamp).transpose()?,
+            fee,
+        },
+        other => return Err(format!("Unsupported block type {other}")),
+    };
+    let (memo, icrc1_memo) = match optional_field(tx, "memo") {
+        None => (Memo(0), None),
+        Some(Value::Nat(memo)) => (Memo(nat_to_u64(memo)?), None),
+        Some(Value::Blob(memo)) => (Memo(0), Some(memo.clone())),
+        Some(other) => return Err(format!("Unsupported memo {other:?}")),
+    };
+    Ok(Block {
+        parent_hash: None,
+        timestamp: timestamp(field(block, "ts")?)?,
+        transaction: Transaction {
+            operation,
+            memo,
+            icrc1_memo,
+            created_at_time: optional_field(tx, "ts").map(timestamp).transpose()?,
+        },
+    })
+}
+
+fn as_map(value: &Value) -> Result<&[(String, Value)], String> {
+    match value {
+        Value::Map(entries) => Ok(entries),
+        other => Err(format!("Expected a map, got {other:?}")),
+    }
+}
+
+fn as_text(value: &Value) -> Result<&str, String> {
+    match value {
+        Value::Text(text) => Ok(text),
+        other => Err(format!("Expected text, got {other:?}")),
+    }
+}
+
+fn as_u64(value: &Value) -> Result<u64, String> {
+    match value {
+        Value::Nat(nat) => nat_to_u64(nat),
+        other => Err(format!("Expected a natural number, got {other:?}")),
+    }
+}
+
+fn optional_field<'a>(map: &'a [(String, Value)], name: &str) -> Option<&'a Value> {
+    map.iter().find(|(key, _)| key == name).map(|(_, value)| value)
+}
+
+fn field<'a>(map: &'a [(String, Value)], name: &str) -> Result<&'a Value, String> {
+    optional_field(map, name).ok_or_else(|| format!("Missing field {name}"))
+}
+
+fn tokens(value: &Value) -> Result<Tokens, String> {
+    as_u64(value).map(Tokens::from_e8s)
+}
+
+fn timestamp(value: &Value) -> Result<TimeStamp, String> {
+    as_u64(value).map(TimeStamp::from_nanos_since_unix_epoch)
+}
+
+/// Converts an account, given either as an ICP account identifier or as an ICRC-1 account.
+fn account(value: &Value) -> Result<AccountIdentifier, String> {
+    match value {
+        Value::Blob(account_identifier) => AccountIdentifier::from_slice(account_identifier)
+            .map_err(|err| format!("Invalid account identifier: {err:?}")),
+        Value::Array(parts) => {
+            let (owner, subaccount) = match parts.as_slice() {
+                [Value::Blob(owner)] => (owner, None),
+                [Value::Blob(owner), Value::Blob(subaccount)] => (owner, Some(subaccount)),
+                _ => return Err(format!("Invalid account {parts:?}")),
+            };
+            let owner = Principal::try_from_slice(owner).map_err(|err| format!("Invalid owner: {err}"))?;
+            let subaccount = subaccount
+                .map(|subaccount| {
+                    <[u8; 32]>::try_from(subaccount.as_slice())
+                        .map(Subaccount)
+                        .map_err(|_| format!("Invalid subaccount {subaccount:?}"))
+                })
+                .transpose()?;
+            Ok(AccountIdentifier::new(PrincipalId::from(owner), subaccount))
+        }
+        other => Err(format!("Expected an account, got {other:?}")),
+    }
+}
+
+/// Converts an ICP ledger block to an ICRC-3 block in the ICP ledger's schema, as served by the mock ledger.
+#[cfg(test)]
+#[must_use]
+pub fn value_from_block(block: &Block) -> Value {
+    let account = |account_identifier: &AccountIdentifier| Value::Blob(ByteBuf::from(account_identifier.to_vec()));
+    let nat = |value: u64| Value::Nat(Nat::from(value));
+    let text = |value: &str| Value::Text(value.to_string());
+    let transaction = &block.transaction;
+    let mut tx = match &transaction.operation {
+        Operation::Mint { to, amount } => vec![
+            ("op", text("mint")),
+            ("to", account(to)),
+            ("amt", nat(amount.get_e8s())),
+        ],
+        Operation::Burn { from, amount, spender } => {
+            let mut tx = vec![
+                ("op", text("burn")),
+                ("from", account(from)),
+                ("amt", nat(amount.get_e8s())),
+            ];
+            tx.extend(spender.as_ref().map(|spender| ("spender", account(spender))));
+            tx
+        }
+        Operation::Transfer {
+            from,
+            to,
+            spender,
+            amount,
+            fee,
+        } => {
+            let mut tx = vec![
+                ("op", text("xfer")),
+                ("from", account(from)),
+                ("to", account(to)),
+                ("amt", nat(amount.get_e8s())),
+                ("fee", nat(fee.get_e8s())),
+            ];
+            tx.extend(spender.as_ref().map(|spender| ("spender", account(spender))));
+            tx
+        }
+        Operation::Approve {
+            from,
+            spender,
+            allowance,
+            expected_allowance,
+            expires_at,
+            fee,
+        } => {
+            let mut tx = vec![
+                ("op", text("approve")),
+                ("from", account(from)),
+                ("spender", account(spender)),
+                ("amt", nat(allowance.get_e8s())),
+                ("fee", nat(fee.get_e8s())),
+            ];
+            tx.extend(expected_allowance.map(|allowance| ("expected_allowance", nat(allowance.get_e8s()))));
+            tx.extend(expires_at.map(|expires_at| ("expires_at", nat(expires_at.as_nanos_since_unix_epoch()))));
+            tx
+        }
+    };
+    match &transaction.icrc1_memo {
+        Some(memo) => tx.push(("memo", Value::Blob(memo.clone()))),
+        None => tx.push(("memo", nat(transaction.memo.0))),
+    }
+    tx.extend(
+        transaction
+            .created_at_time
+            .map(|created_at_time| ("ts", nat(created_at_time.as_nanos_since_unix_epoch()))),
+    );
+    let map = |entries: Vec<(&str, Value)>| {
+        Value::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    };
+    map(vec![
+        ("tx", map(tx)),
+        ("ts", nat(block.timestamp.as_nanos_since_unix_epoch())),
+    ])
+}
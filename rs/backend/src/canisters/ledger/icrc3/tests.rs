@@ -0,0 +1,129 @@
+use super::*;
+use pretty_assertions::assert_eq;
+
+fn test_account(id: u64) -> AccountIdentifier {
+    AccountIdentifier::new(PrincipalId::new_user_test_id(id), None)
+}
+
+fn test_block(operation: Operation) -> Block {
+    Block {
+        parent_hash: None,
+        timestamp: TimeStamp::from_nanos_since_unix_epoch(1_000),
+        transaction: Transaction {
+            operation,
+            memo: Memo(42),
+            icrc1_memo: None,
+            created_at_time: Some(TimeStamp::from_nanos_since_unix_epoch(900)),
+        },
+    }
+}
+
+fn text(value: &str) -> Value {
+    Value::Text(value.to_string())
+}
+
+fn nat(value: u64) -> Value {
+    Value::Nat(Nat::from(value))
+}
+
+fn blob(bytes: &[u8]) -> Value {
+    Value::Blob(ByteBuf::from(bytes.to_vec()))
+}
+
+fn map(entries: Vec<(&str, Value)>) -> Value {
+    Value::Map(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+#[test]
+fn blocks_in_the_icp_ledger_schema_are_converted() {
+    let operations = [
+        Operation::Mint {
+            to: test_account(1),
+            amount: Tokens::from_e8s(100),
+        },
+        Operation::Burn {
+            from: test_account(1),
+            amount: Tokens::from_e8s(100),
+            spender: Some(test_account(2)),
+        },
+        Operation::Transfer {
+            from: test_account(1),
+            to: test_account(2),
+            spender: None,
+            amount: Tokens::from_e8s(100),
+            fee: Tokens::from_e8s(10_000),
+        },
+        Operation::Approve {
+            from: test_account(1),
+            spender: test_account(2),
+            allowance: Tokens::from_e8s(100),
+            expected_allowance: Some(Tokens::from_e8s(50)),
+            expires_at: Some(TimeStamp::from_nanos_since_unix_epoch(2_000)),
+            fee: Tokens::from_e8s(10_000),
+        },
+    ];
+    for operation in operations {
+        let block = test_block(operation);
+        assert_eq!(block_from_value(&value_from_block(&block)), Ok(block));
+    }
+}
+
+#[test]
+fn blocks_in_the_icrc3_schema_are_converted() {
+    let owner = PrincipalId::new_user_test_id(1);
+    let subaccount = [7u8; 32];
+    let to = PrincipalId::new_user_test_id(2);
+    let value = map(vec![
+        ("btype", text("1xfer")),
+        ("fee", nat(10_000)),
+        ("ts", nat(1_000)),
+        (
+            "tx",
+            map(vec![
+                ("from", Value::Array(vec![blob(owner.as_slice()), blob(&subaccount)])),
+                ("to", Value::Array(vec![blob(to.as_slice())])),
+                ("amt", nat(100)),
+                ("memo", blob(b"note")),
+            ]),
+        ),
+    ]);
+    assert_eq!(
+        block_from_value(&value),
+        Ok(Block {
+            parent_hash: None,
+            timestamp: TimeStamp::from_nanos_since_unix_epoch(1_000),
+            transaction: Transaction {
+                operation: Operation::Transfer {
+                    from: AccountIdentifier::new(owner, Some(Subaccount(subaccount))),
+                    to: AccountIdentifier::new(to, None),
+                    spender: None,
+                    amount: Tokens::from_e8s(100),
+                    fee: Tokens::from_e8s(10_000),
+                },
+                memo: Memo(0),
+                icrc1_memo: Some(ByteBuf::from(b"note".to_vec())),
+                created_at_time: None,
+            },
+        })
+    );
+}
+
+#[test]
+fn blocks_that_cannot_be_converted_fail_to_decode() {
+    let value = map(vec![
+        ("btype", text("3unknown")),
+        ("ts", nat(1_000)),
+        ("tx", map(vec![("amt", nat(100))])),
+    ]);
+    assert_eq!(
+        block_from_value(&value),
+        Err("Unsupported block type 3unknown".to_string())
+    );
+    assert!(Block::decode(encoded_block_from_value(5, &value)).is_err());
+    assert!(block_from_value(&map(vec![("ts", nat(1_000))])).is_err());
+}
@@ -1,36 +1,90 @@
-use crate::canisters::ledger;
+use crate::arguments::{ledger_block_source, LedgerBlockSource};
+use crate::canisters::ledger::{self, ArchivedBlocks};
 use crate::state::{with_state, with_state_mut};
 use candid::Principal;
 use dfn_core::CanisterId;
+use futures::future::join_all;
 use ic_cdk::println;
-use ic_ledger_core::block::BlockType;
+use ic_ledger_core::block::{BlockType, EncodedBlock};
 use ic_ledger_core::Tokens;
 use ic_nns_constants::LEDGER_CANISTER_ID;
 use icp_ledger::protobuf::ArchiveIndexEntry;
 use icp_ledger::{AccountIdentifier, Block, BlockIndex, Memo, Operation, TimeStamp, Transaction};
 use lazy_static::lazy_static;
 use std::cmp::{max, min};
+use std::future::Future;
 use std::ops::RangeInclusive;
 use std::sync::Mutex;
 
+#[cfg(test)]
+mod tests;
+
+/// The maximum number of blocks requested in one call.
+const MAX_BLOCK_PER_ITERATION: u32 = 1000;
+/// The maximum number of calls for blocks made at the same time.
+const MAX_CONCURRENT_FETCHES: usize = 4;
+/// The maximum number of attempts at each call for blocks in one sync.
+const MAX_FETCH_ATTEMPTS: u32 = 3;
+
 lazy_static! {
     static ref LOCK: Mutex<()> = Mutex::new(());
 }
 
+/// Processes new ledger blocks, using the ledger endpoints selected in the canister arguments.
 pub async fn sync_transactions() -> Option<Result<u32, String>> {
     // Ensure this process only runs once at a time
     if LOCK.try_lock().is_ok() {
-        Some(sync_transactions_within_lock().await)
+        let source = ledger_block_source();
+        let result = sync_transactions_within_lock(source).await;
+        if let Err(err) = replay_next_block(source).await {
+            println!("Failed to replay block: {err}");
+        }
+        Some(result)
     } else {
         None
     }
 }
 
-async fn sync_transactions_within_lock() -> Result<u32, String> {
-    let block_height_synced_up_to = get_block_height_synced_up_to();
-    let tip_of_chain = ledger::tip_of_chain().await?;
+/// Fetches and processes again one of the blocks that could not be decoded when first synced.
+///
+/// If the block still cannot be decoded, or cannot be fetched, it is scheduled to be tried again later.
+async fn replay_next_block(source: LedgerBlockSource) -> Result<(), String> {
+    let Some(block_height) = with_state_mut(|s| s.accounts_store.take_block_to_replay()) else {
+        return Ok(());
+    };
+    let decoded = get_block(source, block_height).await;
+    with_state_mut(|s| match decoded {
+        Ok(block) => {
+            let transaction = block.transaction().into_owned();
+            s.accounts_store
+                .replay_transaction(&transaction.operation, transaction.memo, block_height);
+            Ok(())
+        }
+        Err(err) => {
+            s.accounts_store.schedule_block_replay(block_height);
+            Err(err)
+        }
+    })
+}
+
+/// Fetches and decodes a single block.
+async fn get_block(source: LedgerBlockSource, block_height: BlockIndex) -> Result<Block, String> {
+    match get_encoded_blocks(source, block_height, block_height)
+        .await?
+        .into_iter()
+        .next()
+    {
+        Some((_, block)) => Block::decode(block),
+        None => Err(format!("No block found at height {block_height}")),
+    }
+}
+
+async fn sync_transactions_within_lock(source: LedgerBlockSource) -> Result<u32, String> {
+    let next_block_height_required = with_state(|s| s.accounts_store.next_block_height_to_sync());
+    let tip_of_chain = get_tip_of_chain(source).await?;
+    with_state_mut(|s| s.accounts_store.set_tip_of_chain(tip_of_chain));
 
-    if block_height_synced_up_to.is_none() {
+    let Some(next_block_height_required) = next_block_height_required else {
         // We only reach here on service initialization and we don't care about previous blocks, so
         // we mark that we are synced with the latest tip_of_chain and return so that subsequent
         // syncs will continue from there
@@ -40,15 +94,14 @@ async fn sync_transactions_within_lock() -> Result<u32, String> {
             store.mark_ledger_sync_complete();
         });
         return Ok(0);
-    }
+    };
 
-    let next_block_height_required = block_height_synced_up_to.unwrap() + 1;
     if tip_of_chain < next_block_height_required {
         // There are no new blocks since our last sync, so mark sync complete and return
         with_state_mut(|s| s.accounts_store.mark_ledger_sync_complete());
         Ok(0)
     } else {
-        let blocks = get_blocks(next_block_height_required, tip_of_chain).await?;
+        let blocks = get_blocks(source, next_block_height_required, tip_of_chain).await?;
         with_state_mut(|s| {
             let store = &mut s.accounts_store;
             let blocks_count = u32::try_from(blocks.len())
@@ -57,6 +110,7 @@ async fn sync_transactions_within_lock() -> Result<u32, String> {
                 let transaction = block.transaction().into_owned();
                 store.maybe_process_transaction(&transaction.operation, transaction.memo, block_height)?;
             }
+            store.prune_processed_blocks();
             store.mark_ledger_sync_complete();
 
             Ok(blocks_count)
@@ -64,31 +118,18 @@ async fn sync_transactions_within_lock() -> Result<u32, String> {
     }
 }
 
-fn get_block_height_synced_up_to() -> Option<BlockIndex> {
-    with_state(|s| s.accounts_store.get_block_height_synced_up_to())
-}
-
-async fn get_blocks(from: BlockIndex, tip_of_chain: BlockIndex) -> Result<Vec<(BlockIndex, Block)>, String> {
-    const MAX_BLOCK_PER_ITERATION: u32 = 1000;
-
-    let archive_index_entries = ledger::get_archive_index().await?.entries;
-
-    let (canister_id, range) = determine_canister_for_blocks(from, tip_of_chain, archive_index_entries);
-
-    let count = min(
-        u32::try_from(range.end() - range.start() + 1)
-            .unwrap_or_else(|_| unreachable!("It will be a very long time before we have this many blocks")),
-        MAX_BLOCK_PER_ITERATION,
-    );
-
-    let blocks = ledger::get_blocks(canister_id, *range.start(), count).await?;
+async fn get_blocks(
+    source: LedgerBlockSource,
+    from: BlockIndex,
+    tip_of_chain: BlockIndex,
+) -> Result<Vec<(BlockIndex, Block)>, String> {
+    let blocks = get_encoded_blocks(source, from, tip_of_chain).await?;
 
     let results: Vec<_> = blocks
         .into_iter()
-        .enumerate()
-        .map(|(index, block)| {
+        .map(|(block_height, block)| {
             (
-                range.start() + (index as u64),
+                block_height,
                 match Block::decode(block) {
                     Ok(block) => block,
                     Err(err) => {
@@ -108,13 +149,11 @@ async fn get_blocks(from: BlockIndex, tip_of_chain: BlockIndex) -> Result<Vec<(B
                         };
                         println!(
                             "Replacing block {} with dummy block {:?} because of error: {}",
-                            range.start() + (index as u64),
-                            &dummy,
-                            err
+                            block_height, &dummy, err
                         );
                         with_state_mut(|s| {
-                            s.performance
-                                .record_exceptional_transaction_id(range.start() + (index as u64));
+                            s.performance.record_exceptional_transaction_id(block_height);
+                            s.accounts_store.schedule_block_replay(block_height);
                         });
                         dummy
                     }
@@ -126,12 +165,194 @@ async fn get_blocks(from: BlockIndex, tip_of_chain: BlockIndex) -> Result<Vec<(B
     Ok(results)
 }
 
+/// Blocks fetched in one call: the height of the first block requested, the number of blocks
+/// requested and the response.
+type FetchedBlocks = (BlockIndex, u64, Result<Vec<EncodedBlock>, String>);
+
+/// Gets the height of the latest block.
+async fn get_tip_of_chain(source: LedgerBlockSource) -> Result<BlockIndex, String> {
+    match source {
+        LedgerBlockSource::Protobuf => ledger::tip_of_chain().await,
+        LedgerBlockSource::Candid => Ok(ledger::query_blocks(0, 0).await?.chain_length.saturating_sub(1)),
+        LedgerBlockSource::Icrc3 => Ok(ledger::icrc3_chain_length().await?.saturating_sub(1)),
+    }
+}
+
+/// Fetches blocks, from the ledger or from whichever archives hold them, without decoding them.
+///
+/// When catching up, up to `MAX_CONCURRENT_FETCHES` calls are made concurrently.  The blocks are
+/// returned in order and without gaps: if a call fails, only the blocks before it are returned.
+async fn get_encoded_blocks(
+    source: LedgerBlockSource,
+    from: BlockIndex,
+    tip_of_chain: BlockIndex,
+) -> Result<Vec<(BlockIndex, EncodedBlock)>, String> {
+    let fetched = match source {
+        LedgerBlockSource::Protobuf => fetch_blocks_with_protobuf(from, tip_of_chain).await?,
+        LedgerBlockSource::Candid | LedgerBlockSource::Icrc3 => {
+            fetch_blocks_with_candid(source, from, tip_of_chain).await?
+        }
+    };
+    join_in_order(from, fetched)
+}
+
+/// Fetches blocks with the legacy protobuf endpoints, using the archive index to find the archives.
+async fn fetch_blocks_with_protobuf(from: BlockIndex, tip_of_chain: BlockIndex) -> Result<Vec<FetchedBlocks>, String> {
+    let archive_index_entries = ledger::get_archive_index().await?.entries;
+
+    let fetches = plan_fetches(from, tip_of_chain, &archive_index_entries, MAX_CONCURRENT_FETCHES);
+
+    let responses = join_all(fetches.iter().map(|(canister_id, range)| {
+        let count = u32::try_from(range.end() - range.start() + 1)
+            .unwrap_or_else(|_| unreachable!("Fetches are limited to MAX_BLOCK_PER_ITERATION blocks"));
+        fetch_with_retries(move || ledger::get_blocks(*canister_id, *range.start(), count))
+    }))
+    .await;
+
+    Ok(fetches
+        .iter()
+        .zip(responses)
+        .map(|((_, range), response)| (*range.start(), range.end() - range.start() + 1, response))
+        .collect())
+}
+
+/// Fetches blocks with the candid `query_encoded_blocks` endpoint or, for `LedgerBlockSource::Icrc3`,
+/// the `icrc3_get_blocks` endpoint.  Blocks that the ledger has moved to archives are then fetched
+/// from the archives it refers to.
+async fn fetch_blocks_with_candid(
+    source: LedgerBlockSource,
+    from: BlockIndex,
+    tip_of_chain: BlockIndex,
+) -> Result<Vec<FetchedBlocks>, String> {
+    let max_blocks = u64::from(MAX_BLOCK_PER_ITERATION) * MAX_CONCURRENT_FETCHES as u64;
+    let length = min(tip_of_chain.saturating_sub(from) + 1, max_blocks);
+    let response = if source == LedgerBlockSource::Icrc3 {
+        ledger::icrc3_query_blocks(from, length).await?
+    } else {
+        ledger::query_blocks(from, length).await?
+    };
+
+    let archive_fetches: Vec<(&ArchivedBlocks, BlockIndex, u64)> = response
+        .archived_blocks
+        .iter()
+        .flat_map(|archive| {
+            let end = archive.start + archive.length;
+            (archive.start..end)
+                .step_by(MAX_BLOCK_PER_ITERATION as usize)
+                .map(move |start| (archive, start, min(u64::from(MAX_BLOCK_PER_ITERATION), end - start)))
+        })
+        .take(MAX_CONCURRENT_FETCHES)
+        .collect();
+
+    let archived = join_all(archive_fetches.iter().map(|(archive, start, length)| {
+        fetch_with_retries(move || async move {
+            if source == LedgerBlockSource::Icrc3 {
+                ledger::icrc3_get_archived_blocks(archive, *start, *length).await
+            } else {
+                ledger::get_archived_blocks(archive, *start, *length).await
+            }
+        })
+    }))
+    .await;
+
+    let mut fetched: Vec<FetchedBlocks> = archive_fetches
+        .iter()
+        .zip(archived)
+        .map(|((_, start, length), response)| (*start, *length, response))
+        .collect();
+    fetched.push((
+        response.first_block_index,
+        response.blocks.len() as u64,
+        Ok(response.blocks),
+    ));
+    fetched.sort_by_key(|(start, _, _)| *start);
+    Ok(fetched)
+}
+
+/// Makes a call for blocks, making it again if it fails, up to `MAX_FETCH_ATTEMPTS` times.
+///
+/// Archives may be briefly unavailable, for example while they are being upgraded.  Blocks that
+/// still cannot be fetched are left for the next sync, which resumes from the first missing block.
+async fn fetch_with_retries<F, R>(fetch: F) -> Result<Vec<EncodedBlock>, String>
+where
+    F: Fn() -> R,
+    R: Future<Output = Result<Vec<EncodedBlock>, String>>,
+{
+    let mut response = fetch().await;
+    for _ in 1..MAX_FETCH_ATTEMPTS {
+        let Err(err) = &response else {
+            break;
+        };
+        println!("Retrying a call for blocks that failed: {err}");
+        response = fetch().await;
+    }
+    response
+}
+
+/// Joins blocks fetched in several calls into one sequence starting at `from`, stopping at the
+/// first gap or failed call so that blocks are never processed out of order.
+///
+/// Fails only if not even the first blocks could be fetched.
+fn join_in_order(from: BlockIndex, fetched: Vec<FetchedBlocks>) -> Result<Vec<(BlockIndex, EncodedBlock)>, String> {
+    let mut blocks = Vec::new();
+    let mut next = from;
+    for (start, length, response) in fetched {
+        if length == 0 {
+            continue;
+        }
+        if start != next {
+            if blocks.is_empty() {
+                return Err(format!(
+                    "Expected block {next}, but the blocks fetched start at {start}"
+                ));
+            }
+            break;
+        }
+        match response {
+            Ok(response_blocks) => {
+                let count = response_blocks.len() as u64;
+                blocks.extend((start..).zip(response_blocks));
+                next = start + count;
+                if count < length {
+                    // Later blocks would leave a gap.
+                    break;
+                }
+            }
+            Err(err) if blocks.is_empty() => return Err(err),
+            Err(err) => {
+                println!("Failed to fetch {length} blocks from height {start}: {err}");
+                break;
+            }
+        }
+    }
+    Ok(blocks)
+}
+
+/// Splits the blocks from `from` up to `tip_of_chain` into calls to whichever canisters hold them,
+/// in order, each for at most `MAX_BLOCK_PER_ITERATION` blocks.  At most `max_fetches` calls are planned.
+fn plan_fetches(
+    from: BlockIndex,
+    tip_of_chain: BlockIndex,
+    archive_index_entries: &[ArchiveIndexEntry],
+    max_fetches: usize,
+) -> Vec<(CanisterId, RangeInclusive<BlockIndex>)> {
+    let mut fetches = Vec::new();
+    let mut next = from;
+    while next <= tip_of_chain && fetches.len() < max_fetches {
+        let (canister_id, range) = determine_canister_for_blocks(next, tip_of_chain, archive_index_entries);
+        let range_end = min(*range.end(), range.start() + u64::from(MAX_BLOCK_PER_ITERATION) - 1);
+        fetches.push((canister_id, *range.start()..=range_end));
+        next = range_end + 1;
+    }
+    fetches
+}
+
 fn determine_canister_for_blocks(
     from: BlockIndex,
     tip_of_chain: BlockIndex,
-    archive_index_entries: Vec<ArchiveIndexEntry>,
+    archive_index_entries: &[ArchiveIndexEntry],
 ) -> (CanisterId, RangeInclusive<BlockIndex>) {
-    for archive_index_entry in archive_index_entries.into_iter().rev() {
+    for archive_index_entry in archive_index_entries.iter().rev() {
         if archive_index_entry.height_to < from {
             break;
         } else if archive_index_entry.height_from > from {
@@ -0,0 +1,229 @@
+use super::*;
+use crate::canisters::ledger::testing::{set_mock_ledger, MockLedger};
+use crate::state::init_state;
+use ic_base_types::PrincipalId;
+use pretty_assertions::assert_eq;
+use std::cell::Cell;
+use std::ops::Range;
+
+fn archive(id: u64, height_from: BlockIndex, height_to: BlockIndex) -> ArchiveIndexEntry {
+    ArchiveIndexEntry {
+        height_from,
+        height_to,
+        canister_id: Some(PrincipalId::new_user_test_id(id)),
+    }
+}
+
+fn get_block_height_synced_up_to() -> Option<BlockIndex> {
+    with_state(|s| s.accounts_store.get_block_height_synced_up_to())
+}
+
+fn archive_canister_id(id: u64) -> CanisterId {
+    CanisterId::unchecked_from_principal(PrincipalId::new_user_test_id(id))
+}
+
+#[test]
+fn recent_blocks_are_fetched_from_the_ledger() {
+    let archives = [archive(1, 0, 999)];
+    assert_eq!(
+        plan_fetches(1_200, 1_500, &archives, MAX_CONCURRENT_FETCHES),
+        vec![(LEDGER_CANISTER_ID, 1_200..=1_500)]
+    );
+}
+
+#[test]
+fn catching_up_fetches_from_archives_and_the_ledger_in_order() {
+    let archives = [archive(1, 0, 1_499), archive(2, 1_500, 1_999)];
+    assert_eq!(
+        plan_fetches(100, 2_200, &archives, MAX_CONCURRENT_FETCHES),
+        vec![
+            (archive_canister_id(1), 100..=1_099),
+            (archive_canister_id(1), 1_100..=1_499),
+            (archive_canister_id(2), 1_500..=1_999),
+            (LEDGER_CANISTER_ID, 2_000..=2_200),
+        ]
+    );
+}
+
+#[test]
+fn number_of_fetches_is_bounded() {
+    let fetches = plan_fetches(0, 1_000_000, &[], 3);
+    assert_eq!(
+        fetches,
+        vec![
+            (LEDGER_CANISTER_ID, 0..=999),
+            (LEDGER_CANISTER_ID, 1_000..=1_999),
+            (LEDGER_CANISTER_ID, 2_000..=2_999),
+        ]
+    );
+}
+
+#[test]
+fn nothing_is_fetched_when_synced() {
+    assert_eq!(plan_fetches(11, 10, &[], MAX_CONCURRENT_FETCHES), vec![]);
+}
+
+fn block(height: BlockIndex) -> EncodedBlock {
+    Block {
+        parent_hash: None,
+        timestamp: TimeStamp::new(0, 0),
+        transaction: Transaction {
+            memo: Memo(height),
+            created_at_time: None,
+            icrc1_memo: None,
+            operation: Operation::Mint {
+                to: AccountIdentifier::new(PrincipalId::new_user_test_id(height), None),
+                amount: Tokens::from_e8s(1),
+            },
+        },
+    }
+    .encode()
+}
+
+fn mock_ledger(length: u64, archives: &[(u64, Range<BlockIndex>)]) -> MockLedger {
+    MockLedger {
+        blocks: (0..length).map(block).collect(),
+        archives: archives
+            .iter()
+            .map(|(id, range)| (archive_canister_id(*id), range.clone()))
+            .collect(),
+    }
+}
+
+/// Checks that blocks start at the given height, are in order, and have the expected content.
+fn assert_blocks_in_order(blocks: &[(BlockIndex, EncodedBlock)], from: BlockIndex, to: BlockIndex) {
+    let heights: Vec<BlockIndex> = blocks.iter().map(|(height, _)| *height).collect();
+    assert_eq!(heights, (from..=to).collect::<Vec<_>>());
+    for (height, encoded) in blocks {
+        assert_eq!(*encoded, block(*height));
+    }
+}
+
+#[test]
+fn blocks_are_joined_in_order_up_to_the_first_gap() {
+    let fetched = vec![
+        (10, 2, Ok(vec![block(10), block(11)])),
+        (12, 2, Ok(vec![block(12)])),
+        (14, 2, Ok(vec![block(14), block(15)])),
+    ];
+    assert_blocks_in_order(&join_in_order(10, fetched).unwrap(), 10, 12);
+}
+
+#[test]
+fn blocks_are_joined_in_order_up_to_the_first_failure() {
+    let fetched = vec![
+        (10, 2, Ok(vec![block(10), block(11)])),
+        (12, 2, Err("Archive unavailable".to_string())),
+        (14, 2, Ok(vec![block(14), block(15)])),
+    ];
+    assert_blocks_in_order(&join_in_order(10, fetched).unwrap(), 10, 11);
+}
+
+#[test]
+fn joining_fails_if_the_first_blocks_cannot_be_fetched() {
+    let fetched = vec![
+        (10, 2, Err("Archive unavailable".to_string())),
+        (12, 2, Ok(vec![block(12), block(13)])),
+    ];
+    assert_eq!(join_in_order(10, fetched), Err("Archive unavailable".to_string()));
+    assert!(join_in_order(10, vec![(11, 1, Ok(vec![block(11)]))]).is_err());
+}
+
+#[tokio::test]
+async fn all_block_sources_agree_on_the_tip_of_chain() {
+    set_mock_ledger(mock_ledger(1_234, &[(1, 0..1_000)]));
+    assert_eq!(get_tip_of_chain(LedgerBlockSource::Protobuf).await, Ok(1_233));
+    assert_eq!(get_tip_of_chain(LedgerBlockSource::Candid).await, Ok(1_233));
+    assert_eq!(get_tip_of_chain(LedgerBlockSource::Icrc3).await, Ok(1_233));
+}
+
+#[tokio::test]
+async fn all_block_sources_fetch_archived_and_recent_blocks_in_order() {
+    set_mock_ledger(mock_ledger(5_000, &[(1, 0..2_000), (2, 2_000..4_500)]));
+
+    // The protobuf source makes up to four calls of up to 1000 blocks each.
+    let blocks = get_encoded_blocks(LedgerBlockSource::Protobuf, 1_500, 4_999)
+        .await
+        .unwrap();
+    assert_blocks_in_order(&blocks, 1_500, 4_499);
+
+    // The candid source gets the recent blocks from the ledger in the same call that tells it where
+    // the archived blocks are.
+    let blocks = get_encoded_blocks(LedgerBlockSource::Candid, 1_500, 4_999)
+        .await
+        .unwrap();
+    assert_blocks_in_order(&blocks, 1_500, 4_999);
+
+    // As does the ICRC-3 source, whose blocks are converted from generic values.
+    let blocks = get_encoded_blocks(LedgerBlockSource::Icrc3, 1_500, 4_999)
+        .await
+        .unwrap();
+    assert_blocks_in_order(&blocks, 1_500, 4_999);
+}
+
+#[tokio::test]
+async fn sync_with_candid_block_source_processes_new_blocks() {
+    init_state();
+    set_mock_ledger(mock_ledger(10, &[]));
+    assert_eq!(sync_transactions_within_lock(LedgerBlockSource::Candid).await, Ok(0));
+    assert_eq!(get_block_height_synced_up_to(), Some(9));
+
+    set_mock_ledger(mock_ledger(2_500, &[(1, 0..2_000)]));
+    assert_eq!(
+        sync_transactions_within_lock(LedgerBlockSource::Candid).await,
+        Ok(2_490)
+    );
+    assert_eq!(get_block_height_synced_up_to(), Some(2_499));
+}
+
+#[tokio::test]
+async fn sync_with_icrc3_block_source_processes_new_blocks() {
+    init_state();
+    set_mock_ledger(mock_ledger(10, &[]));
+    assert_eq!(sync_transactions_within_lock(LedgerBlockSource::Icrc3).await, Ok(0));
+    assert_eq!(get_block_height_synced_up_to(), Some(9));
+
+    set_mock_ledger(mock_ledger(2_500, &[(1, 0..2_000)]));
+    assert_eq!(sync_transactions_within_lock(LedgerBlockSource::Icrc3).await, Ok(2_490));
+    assert_eq!(get_block_height_synced_up_to(), Some(2_499));
+}
+
+#[tokio::test]
+async fn failed_calls_to_archives_are_made_again() {
+    for source in [
+        LedgerBlockSource::Protobuf,
+        LedgerBlockSource::Candid,
+        LedgerBlockSource::Icrc3,
+    ] {
+        set_mock_ledger(MockLedger {
+            archive_failures: Cell::new(MAX_FETCH_ATTEMPTS - 1),
+            ..mock_ledger(1_500, &[(1, 0..1_000)])
+        });
+        let blocks = get_encoded_blocks(source, 500, 1_499).await.unwrap();
+        assert_blocks_in_order(&blocks, 500, 1_499);
+    }
+}
+
+#[tokio::test]
+async fn blocks_that_could_not_be_fetched_are_fetched_in_the_next_sync() {
+    init_state();
+    set_mock_ledger(mock_ledger(10, &[]));
+    assert_eq!(sync_transactions_within_lock(LedgerBlockSource::Candid).await, Ok(0));
+
+    set_mock_ledger(MockLedger {
+        archive_failures: Cell::new(u32::MAX),
+        ..mock_ledger(2_500, &[(1, 0..2_000)])
+    });
+    assert_eq!(
+        sync_transactions_within_lock(LedgerBlockSource::Candid).await,
+        Err("Archive unavailable".to_string())
+    );
+    assert_eq!(get_block_height_synced_up_to(), Some(9));
+
+    set_mock_ledger(mock_ledger(2_500, &[(1, 0..2_000)]));
+    assert_eq!(
+        sync_transactions_within_lock(LedgerBlockSource::Candid).await,
+        Ok(2_490)
+    );
+    assert_eq!(get_block_height_synced_up_to(), Some(2_499));
+}
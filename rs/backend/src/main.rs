@@ -1,26 +1,40 @@
 use crate::accounts_store::histogram::AccountsStoreHistogram;
+use crate::accounts_store::schema::proxy::MigrationProgress;
+use crate::accounts_store::verification::{GetStateVerificationRequest, VerificationReportPage};
 use crate::accounts_store::{
-    AccountDetails, AttachCanisterRequest, AttachCanisterResponse, CreateSubAccountResponse, DetachCanisterRequest,
-    DetachCanisterResponse, GetImportedTokensResponse, ImportedTokens, NamedCanister, RegisterHardwareWalletRequest,
-    RegisterHardwareWalletResponse, RenameCanisterRequest, RenameCanisterResponse, RenameSubAccountRequest,
-    RenameSubAccountResponse, SetImportedTokensResponse,
+    AcceptAccountTransferRequest, AcceptAccountTransferResponse, AccountDetails, AccountExport,
+    AddAddressBookEntryRequest, AddAddressBookEntryResponse, AddImportedTokenResponse, AttachCanisterRequest,
+    AttachCanisterResponse, CancelAccountTransferResponse, CreateCanisterGroupRequest, CreateCanisterGroupResponse,
+    CreateSubAccountResponse, DeleteAccountRequest, DeleteAccountResponse, DeleteCanisterGroupRequest,
+    DeleteCanisterGroupResponse, DetachCanisterRequest, DetachCanisterResponse, DisableTransactionIndexResponse,
+    EnableTransactionIndexResponse, ExportAccountRequest, ExportAccountResponse, GetAddressBookResponse,
+    GetCanisterGroupsResponse, GetCanistersRequest, GetImportedTokensResponse, GetIndexedTransactionsRequest,
+    GetIndexedTransactionsResponse, GetPreferencesResponse, ImportAccountResponse, ImportedToken, ImportedTokens,
+    NamedCanister, ProposeAccountTransferRequest, ProposeAccountTransferResponse, RegisterHardwareWalletRequest,
+    RegisterHardwareWalletResponse, RemoveAddressBookEntryRequest, RemoveAddressBookEntryResponse,
+    RemoveImportedTokenRequest, RemoveImportedTokenResponse, RemoveSubAccountRequest, RemoveSubAccountResponse,
+    RenameAddressBookEntryRequest, RenameAddressBookEntryResponse, RenameCanisterGroupRequest,
+    RenameCanisterGroupResponse, RenameCanisterRequest, RenameCanisterResponse, RenameSubAccountRequest,
+    RenameSubAccountResponse, ReorderCanisterGroupsRequest, ReorderCanisterGroupsResponse, RestoreSubAccountRequest,
+    RestoreSubAccountResponse, ResyncFromResponse, RollbackMigrationResponse, SetCanisterGroupRequest,
+    SetCanisterGroupResponse, SetImportedTokensResponse, SetPreferencesRequest, SetPreferencesResponse,
+    UnregisterHardwareWalletRequest, UnregisterHardwareWalletResponse, UpdateCanisterMetadataRequest,
+    UpdateCanisterMetadataResponse, VerifyStateRequest, VerifyStateResponse,
 };
-use crate::arguments::{set_canister_arguments, CanisterArguments};
+use crate::arguments::{accounts_schema, periodic_task_intervals, set_canister_arguments, CanisterArguments};
 use crate::assets::{hash_bytes, insert_asset, Asset};
+use crate::multi_part_transactions_processor::MultiPartOperation;
 use crate::perf::PerformanceCount;
-use crate::periodic_tasks_runner::run_periodic_tasks;
 use crate::state::{init_state, restore_state, save_state, with_state, with_state_mut, StableState};
 use crate::tvl::TvlResponse;
 use candid::candid_method;
 
-use accounts_store::schema::proxy::AccountsDbAsProxy;
 pub use candid::{CandidType, Deserialize};
 use dfn_candid::{candid, candid_one};
 use dfn_core::{over, over_async};
-use ic_cdk::api::call::RejectionCode;
-use ic_cdk::{eprintln, println};
+use ic_cdk::println;
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade};
-use icp_ledger::AccountIdentifier;
+use icp_ledger::{AccountIdentifier, BlockIndex};
 pub use serde::Serialize;
 
 #[cfg(any(test, feature = "toy_data_gen"))]
@@ -53,9 +67,11 @@ fn init(args: Option<CanisterArguments>) {
     perf::save_instruction_count(counter_before);
     set_canister_arguments(args);
     perf::record_instruction_count("init after set_canister_arguments");
+    start_accounts_migration_if_requested();
     // Legacy:
     assets::init_assets();
     tvl::init_timers();
+    periodic_tasks_runner::init_timers(periodic_task_intervals());
     perf::record_instruction_count("init stop");
     println!("END   init with args");
 }
@@ -63,6 +79,20 @@ fn init(args: Option<CanisterArguments>) {
 /// Redundant function, never called but required as this is `main.rs`.
 fn main() {}
 
+/// Starts migrating accounts to the schema given in the canister arguments, if any.
+fn start_accounts_migration_if_requested() {
+    if let Some(schema) = accounts_schema() {
+        with_state_mut(|s| s.start_accounts_migration(schema));
+    }
+}
+
+/// Continues the state verification that was in progress before the upgrade, if any.
+fn resume_state_verification_if_in_progress() {
+    if with_state(|s| s.accounts_store.verification_in_progress()) {
+        periodic_tasks_runner::schedule_state_verification_step();
+    }
+}
+
 #[pre_upgrade]
 fn pre_upgrade() {
     println!(
@@ -91,8 +121,11 @@ fn post_upgrade(args_maybe: Option<CanisterArguments>) {
     perf::record_instruction_count("post_upgrade after state_recovery");
     set_canister_arguments(args_maybe);
     perf::record_instruction_count("post_upgrade after set_canister_arguments");
+    start_accounts_migration_if_requested();
     assets::init_assets();
     tvl::init_timers();
+    periodic_tasks_runner::init_timers(periodic_task_intervals());
+    resume_state_verification_if_in_progress();
     perf::record_instruction_count("post_upgrade stop");
     println!("END   post-upgrade");
 }
@@ -149,13 +182,18 @@ fn add_account_impl() -> AccountIdentifier {
 /// ledger accounts is not derivable externally).
 #[export_name = "canister_update create_sub_account"]
 pub fn create_sub_account() {
-    over(candid_one, create_sub_account_impl);
+    over(candid, |(sub_account_name, expected_version)| {
+        create_sub_account_impl(sub_account_name, expected_version)
+    });
 }
 
 #[candid_method(update, rename = "create_sub_account")]
-fn create_sub_account_impl(sub_account_name: String) -> CreateSubAccountResponse {
+fn create_sub_account_impl(sub_account_name: String, expected_version: Option<u64>) -> CreateSubAccountResponse {
     let principal = dfn_core::api::caller();
-    with_state_mut(|s| s.accounts_store.create_sub_account(principal, sub_account_name))
+    with_state_mut(|s| {
+        s.accounts_store
+            .create_sub_account(principal, sub_account_name, expected_version)
+    })
 }
 
 /// Changes the alias given to the chosen sub account.
@@ -172,6 +210,36 @@ fn rename_sub_account_impl(request: RenameSubAccountRequest) -> RenameSubAccount
     with_state_mut(|s| s.accounts_store.rename_sub_account(principal, request))
 }
 
+/// Removes a sub account from the user's account.
+///
+/// The sub account is archived rather than deleted so that its subaccount index is never reused
+/// for a differently named sub account.  Any funds in the sub account are not affected.
+#[export_name = "canister_update remove_sub_account"]
+pub fn remove_sub_account() {
+    over(candid_one, remove_sub_account_impl);
+}
+
+#[candid_method(update, rename = "remove_sub_account")]
+fn remove_sub_account_impl(request: RemoveSubAccountRequest) -> RemoveSubAccountResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.remove_sub_account(principal, request))
+}
+
+/// Restores a sub account that the user has removed, under the name it had when it was removed.
+///
+/// Removed sub accounts keep their subaccount indices, so once every index has been used, this is
+/// the only way for the user to get a sub account back.
+#[export_name = "canister_update restore_sub_account"]
+pub fn restore_sub_account() {
+    over(candid_one, restore_sub_account_impl);
+}
+
+#[candid_method(update, rename = "restore_sub_account")]
+fn restore_sub_account_impl(request: RestoreSubAccountRequest) -> RestoreSubAccountResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.restore_sub_account(principal, request))
+}
+
 /// Links a hardware wallet to the user's account.
 ///
 /// A single hardware wallet can be linked to multiple user accounts, but in order to make calls to
@@ -188,16 +256,117 @@ fn register_hardware_wallet_impl(request: RegisterHardwareWalletRequest) -> Regi
     with_state_mut(|s| s.accounts_store.register_hardware_wallet(principal, request))
 }
 
+/// Unlinks a hardware wallet from the user's account.
+#[export_name = "canister_update unregister_hardware_wallet"]
+pub fn unregister_hardware_wallet() {
+    over(candid_one, unregister_hardware_wallet_impl);
+}
+
+#[candid_method(update, rename = "unregister_hardware_wallet")]
+fn unregister_hardware_wallet_impl(request: UnregisterHardwareWalletRequest) -> UnregisterHardwareWalletResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.unregister_hardware_wallet(principal, request))
+}
+
 /// Returns the list of canisters which the user has attached to their account.
+///
+/// If a tag is given, only the canisters with that tag are returned.
 #[export_name = "canister_query get_canisters"]
 pub fn get_canisters() {
-    over(candid, |()| get_canisters_impl());
+    over(candid_one, get_canisters_impl);
 }
 
 #[candid_method(query, rename = "get_canisters")]
-fn get_canisters_impl() -> Vec<NamedCanister> {
+fn get_canisters_impl(request: Option<GetCanistersRequest>) -> Vec<NamedCanister> {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| match request {
+        Some(request) => s.accounts_store.get_filtered_canisters(principal, request),
+        None => s.accounts_store.get_canisters(principal),
+    })
+}
+
+/// Returns the names of the user's canister groups, in order.
+#[export_name = "canister_query get_canister_groups"]
+pub fn get_canister_groups() {
+    over(candid, |()| get_canister_groups_impl());
+}
+
+#[candid_method(query, rename = "get_canister_groups")]
+fn get_canister_groups_impl() -> GetCanisterGroupsResponse {
+    let principal = dfn_core::api::caller();
+    with_state(|s| s.accounts_store.get_canister_groups(principal))
+}
+
+/// Adds a canister group to the end of the user's list of groups.
+#[export_name = "canister_update create_canister_group"]
+pub fn create_canister_group() {
+    over(candid_one, create_canister_group_impl);
+}
+
+#[candid_method(update, rename = "create_canister_group")]
+fn create_canister_group_impl(request: CreateCanisterGroupRequest) -> CreateCanisterGroupResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.create_canister_group(principal, request))
+}
+
+/// Renames one of the user's canister groups.
+#[export_name = "canister_update rename_canister_group"]
+pub fn rename_canister_group() {
+    over(candid_one, rename_canister_group_impl);
+}
+
+#[candid_method(update, rename = "rename_canister_group")]
+fn rename_canister_group_impl(request: RenameCanisterGroupRequest) -> RenameCanisterGroupResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.rename_canister_group(principal, request))
+}
+
+/// Deletes one of the user's canister groups, leaving its canisters ungrouped.
+#[export_name = "canister_update delete_canister_group"]
+pub fn delete_canister_group() {
+    over(candid_one, delete_canister_group_impl);
+}
+
+#[candid_method(update, rename = "delete_canister_group")]
+fn delete_canister_group_impl(request: DeleteCanisterGroupRequest) -> DeleteCanisterGroupResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.delete_canister_group(principal, request))
+}
+
+/// Sets the order of the user's canister groups.
+#[export_name = "canister_update reorder_canister_groups"]
+pub fn reorder_canister_groups() {
+    over(candid_one, reorder_canister_groups_impl);
+}
+
+#[candid_method(update, rename = "reorder_canister_groups")]
+fn reorder_canister_groups_impl(request: ReorderCanisterGroupsRequest) -> ReorderCanisterGroupsResponse {
     let principal = dfn_core::api::caller();
-    with_state_mut(|s| s.accounts_store.get_canisters(principal))
+    with_state_mut(|s| s.accounts_store.reorder_canister_groups(principal, request))
+}
+
+/// Moves a canister into one of the user's canister groups, or out of any group.
+#[export_name = "canister_update set_canister_group"]
+pub fn set_canister_group() {
+    over(candid_one, set_canister_group_impl);
+}
+
+#[candid_method(update, rename = "set_canister_group")]
+fn set_canister_group_impl(request: SetCanisterGroupRequest) -> SetCanisterGroupResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.set_canister_group(principal, request))
+}
+
+/// Replaces the metadata that the user keeps about one of their canisters.
+#[export_name = "canister_update update_canister_metadata"]
+pub fn update_canister_metadata() {
+    over(candid_one, update_canister_metadata_impl);
+}
+
+#[candid_method(update, rename = "update_canister_metadata")]
+fn update_canister_metadata_impl(request: UpdateCanisterMetadataRequest) -> UpdateCanisterMetadataResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.update_canister_metadata(principal, request))
 }
 
 /// Attaches a canister to the user's account.
@@ -238,13 +407,18 @@ fn detach_canister_impl(request: DetachCanisterRequest) -> DetachCanisterRespons
 
 #[export_name = "canister_update set_imported_tokens"]
 pub fn set_imported_tokens() {
-    over(candid_one, set_imported_tokens_impl);
+    over(candid, |(settings, expected_version)| {
+        set_imported_tokens_impl(settings, expected_version)
+    });
 }
 
 #[candid_method(update, rename = "set_imported_tokens")]
-fn set_imported_tokens_impl(settings: ImportedTokens) -> SetImportedTokensResponse {
+fn set_imported_tokens_impl(settings: ImportedTokens, expected_version: Option<u64>) -> SetImportedTokensResponse {
     let principal = dfn_core::api::caller();
-    with_state_mut(|s| s.accounts_store.set_imported_tokens(principal, settings))
+    with_state_mut(|s| {
+        s.accounts_store
+            .set_imported_tokens(principal, settings, expected_version)
+    })
 }
 
 #[export_name = "canister_query get_imported_tokens"]
@@ -258,6 +432,251 @@ fn get_imported_tokens_impl() -> GetImportedTokensResponse {
     with_state_mut(|s| s.accounts_store.get_imported_tokens(principal))
 }
 
+/// Adds one imported token, or replaces the imported token with the same ledger canister ID.
+#[export_name = "canister_update add_imported_token"]
+pub fn add_imported_token() {
+    over(candid, |(imported_token, expected_version)| {
+        add_imported_token_impl(imported_token, expected_version)
+    });
+}
+
+#[candid_method(update, rename = "add_imported_token")]
+fn add_imported_token_impl(imported_token: ImportedToken, expected_version: Option<u64>) -> AddImportedTokenResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| {
+        s.accounts_store
+            .add_imported_token(principal, imported_token, expected_version)
+    })
+}
+
+/// Removes one imported token.
+#[export_name = "canister_update remove_imported_token"]
+pub fn remove_imported_token() {
+    over(candid_one, remove_imported_token_impl);
+}
+
+#[candid_method(update, rename = "remove_imported_token")]
+fn remove_imported_token_impl(request: RemoveImportedTokenRequest) -> RemoveImportedTokenResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.remove_imported_token(principal, request))
+}
+
+/// Saves a named destination address in the user's address book.
+///
+/// The address may be either an ICP `AccountIdentifier` or an ICRC-1 account.
+#[export_name = "canister_update add_address_book_entry"]
+pub fn add_address_book_entry() {
+    over(candid_one, add_address_book_entry_impl);
+}
+
+#[candid_method(update, rename = "add_address_book_entry")]
+fn add_address_book_entry_impl(request: AddAddressBookEntryRequest) -> AddAddressBookEntryResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.add_address_book_entry(principal, request))
+}
+
+/// Renames an entry in the user's address book.
+#[export_name = "canister_update rename_address_book_entry"]
+pub fn rename_address_book_entry() {
+    over(candid_one, rename_address_book_entry_impl);
+}
+
+#[candid_method(update, rename = "rename_address_book_entry")]
+fn rename_address_book_entry_impl(request: RenameAddressBookEntryRequest) -> RenameAddressBookEntryResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.rename_address_book_entry(principal, request))
+}
+
+/// Removes an entry from the user's address book.
+#[export_name = "canister_update remove_address_book_entry"]
+pub fn remove_address_book_entry() {
+    over(candid_one, remove_address_book_entry_impl);
+}
+
+#[candid_method(update, rename = "remove_address_book_entry")]
+fn remove_address_book_entry_impl(request: RemoveAddressBookEntryRequest) -> RemoveAddressBookEntryResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.remove_address_book_entry(principal, request))
+}
+
+/// Returns the user's address book, sorted by name.
+#[export_name = "canister_query get_address_book"]
+pub fn get_address_book() {
+    over(candid, |()| get_address_book_impl());
+}
+
+#[candid_method(query, rename = "get_address_book")]
+fn get_address_book_impl() -> GetAddressBookResponse {
+    let principal = dfn_core::api::caller();
+    with_state(|s| s.accounts_store.get_address_book(principal))
+}
+
+/// Saves the user's user interface preferences.
+#[export_name = "canister_update set_preferences"]
+pub fn set_preferences() {
+    over(candid_one, set_preferences_impl);
+}
+
+#[candid_method(update, rename = "set_preferences")]
+fn set_preferences_impl(request: SetPreferencesRequest) -> SetPreferencesResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.set_preferences(principal, request))
+}
+
+/// Returns the user's user interface preferences.
+#[export_name = "canister_query get_preferences"]
+pub fn get_preferences() {
+    over(candid, |()| get_preferences_impl());
+}
+
+#[candid_method(query, rename = "get_preferences")]
+fn get_preferences_impl() -> GetPreferencesResponse {
+    let principal = dfn_core::api::caller();
+    with_state(|s| s.accounts_store.get_preferences(principal))
+}
+
+/// Returns a page of the user's full account record, so that it can be backed up or imported into another account.
+///
+/// Note: This is an update call so that the response is certified and a backup can be trusted.
+#[export_name = "canister_update export_account"]
+pub fn export_account() {
+    over(candid_one, export_account_impl);
+}
+
+#[candid_method(update, rename = "export_account")]
+fn export_account_impl(request: ExportAccountRequest) -> ExportAccountResponse {
+    let principal = dfn_core::api::caller();
+    with_state(|s| s.accounts_store.export_account(principal, request))
+}
+
+/// Restores an exported account record into the caller's account, which must be empty.
+#[export_name = "canister_update import_account"]
+pub fn import_account() {
+    over(candid, |(export, expected_version)| {
+        import_account_impl(export, expected_version)
+    });
+}
+
+#[candid_method(update, rename = "import_account")]
+fn import_account_impl(export: AccountExport, expected_version: Option<u64>) -> ImportAccountResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.import_account(principal, export, expected_version))
+}
+
+/// Offers to move the caller's account to a new principal, which must accept the offer before it expires.
+#[export_name = "canister_update propose_account_transfer"]
+pub fn propose_account_transfer() {
+    over(candid_one, propose_account_transfer_impl);
+}
+
+#[candid_method(update, rename = "propose_account_transfer")]
+fn propose_account_transfer_impl(request: ProposeAccountTransferRequest) -> ProposeAccountTransferResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.propose_account_transfer(principal, request))
+}
+
+/// Withdraws the caller's pending account transfer offer.
+#[export_name = "canister_update cancel_account_transfer"]
+pub fn cancel_account_transfer() {
+    over(candid, |()| cancel_account_transfer_impl());
+}
+
+#[candid_method(update, rename = "cancel_account_transfer")]
+fn cancel_account_transfer_impl() -> CancelAccountTransferResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.cancel_account_transfer(principal))
+}
+
+/// Accepts an account transfer offered to the caller.
+#[export_name = "canister_update accept_account_transfer"]
+pub fn accept_account_transfer() {
+    over(candid_one, accept_account_transfer_impl);
+}
+
+#[candid_method(update, rename = "accept_account_transfer")]
+fn accept_account_transfer_impl(request: AcceptAccountTransferRequest) -> AcceptAccountTransferResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.accept_account_transfer(principal, request))
+}
+
+/// Deletes all the data stored for the user.  The user's main account identifier must be given as confirmation.
+#[export_name = "canister_update delete_account"]
+pub fn delete_account() {
+    over(candid_one, delete_account_impl);
+}
+
+#[candid_method(update, rename = "delete_account")]
+fn delete_account_impl(request: DeleteAccountRequest) -> DeleteAccountResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.delete_account(principal, request))
+}
+
+/// Starts indexing the ledger blocks that touch the caller's accounts.
+#[export_name = "canister_update enable_transaction_index"]
+pub fn enable_transaction_index() {
+    over(candid, |()| enable_transaction_index_impl());
+}
+
+#[candid_method(update, rename = "enable_transaction_index")]
+fn enable_transaction_index_impl() -> EnableTransactionIndexResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.enable_transaction_index(principal))
+}
+
+/// Stops indexing the ledger blocks that touch the caller's accounts.
+#[export_name = "canister_update disable_transaction_index"]
+pub fn disable_transaction_index() {
+    over(candid, |()| disable_transaction_index_impl());
+}
+
+#[candid_method(update, rename = "disable_transaction_index")]
+fn disable_transaction_index_impl() -> DisableTransactionIndexResponse {
+    let principal = dfn_core::api::caller();
+    with_state_mut(|s| s.accounts_store.disable_transaction_index(principal))
+}
+
+/// Returns a page of the ledger blocks indexed for the caller, newest first.
+#[export_name = "canister_query get_indexed_transactions"]
+pub fn get_indexed_transactions() {
+    over(candid_one, get_indexed_transactions_impl);
+}
+
+#[candid_method(query, rename = "get_indexed_transactions")]
+fn get_indexed_transactions_impl(request: GetIndexedTransactionsRequest) -> GetIndexedTransactionsResponse {
+    let principal = dfn_core::api::caller();
+    with_state(|s| s.accounts_store.get_indexed_transactions(principal, request))
+}
+
+/// Returns the caller's canister creations, canister top-ups and neuron stakes that are still being
+/// completed in the background, followed by the outcomes of those recently completed.
+#[export_name = "canister_query get_pending_operations"]
+pub fn get_pending_operations() {
+    over(candid, |()| get_pending_operations_impl());
+}
+
+#[candid_method(query, rename = "get_pending_operations")]
+fn get_pending_operations_impl() -> Vec<MultiPartOperation> {
+    let principal = dfn_core::api::caller();
+    with_state(|s| s.accounts_store.get_pending_operations(principal))
+}
+
+/// Rewinds ledger sync so that blocks from the given height onwards are processed again.
+///
+/// Only the controller may call this.  It is intended for repairing state after an incident.
+#[export_name = "canister_update resync_from"]
+pub fn resync_from() {
+    over(candid_one, resync_from_impl);
+}
+
+#[candid_method(update, rename = "resync_from")]
+fn resync_from_impl(block_height: BlockIndex) -> ResyncFromResponse {
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        dfn_core::api::trap_with("Only the controller may resync the ledger");
+    }
+    with_state_mut(|s| s.accounts_store.resync_from(block_height))
+}
+
 #[export_name = "canister_update get_proposal_payload"]
 pub fn get_proposal_payload() {
     over_async(candid_one, get_proposal_payload_impl);
@@ -307,23 +726,6 @@ pub fn get_histogram_impl() -> AccountsStoreHistogram {
     with_state(|state| state.accounts_store.get_histogram())
 }
 
-/// Executes on every block height and is used to run background processes.
-///
-/// These background processes include:
-/// - Sync transactions from the ledger
-/// - Process any queued 'multi-part' actions (e.g. staking a neuron or topping up a canister)
-/// - Prune old transactions if memory usage is too high
-#[export_name = "canister_heartbeat"]
-pub fn canister_heartbeat() {
-    let future = run_periodic_tasks();
-
-    dfn_core::api::futures::spawn(future);
-    let migration_in_progress = with_state_mut(|s| s.accounts_store.migration_in_progress());
-    if migration_in_progress {
-        dfn_core::api::futures::spawn(call_step_migration_with_retries());
-    }
-}
-
 /// Steps the migration.
 #[export_name = "canister_update step_migration"]
 pub fn step_migration() {
@@ -338,25 +740,79 @@ fn step_migration_impl(step_size: u32) {
         dfn_core::api::trap_with("Only the canister itself may call step_migration");
     }
     with_state_mut(|s| {
-        s.accounts_store.step_migration(step_size);
+        s.step_accounts_migration(step_size);
     });
 }
 
-/// Calls `step_migration()` without panicking and rolling back if anything goes wrong.
-async fn call_step_migration(step_size: u32) -> Result<(), (RejectionCode, String)> {
-    ic_cdk::api::call::call(ic_cdk::id(), "step_migration", (step_size,)).await
+/// Abandons the accounts migration in progress, if it has not been finalized yet.
+///
+/// Only the controller may call this.  The accounts stay in the current database.
+#[export_name = "canister_update rollback_migration"]
+pub fn rollback_migration() {
+    over(candid, |()| rollback_migration_impl());
 }
 
-/// Calls step migration, dropping the step size to 1 on failure.
-async fn call_step_migration_with_retries() {
-    for step_size in [AccountsDbAsProxy::MIGRATION_STEP_SIZE, 1] {
-        if let Err((code, msg)) = call_step_migration(step_size).await {
-            println!("WARNING: step_migration failed with step size {step_size}: {code:?} {msg}");
-        } else {
-            return;
-        }
+#[candid_method(update, rename = "rollback_migration")]
+fn rollback_migration_impl() -> RollbackMigrationResponse {
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        dfn_core::api::trap_with("Only the controller may roll back a migration");
+    }
+    with_state_mut(|s| s.accounts_store.rollback_migration())
+}
+
+/// Reports the progress of recent accounts migrations, oldest first.
+#[export_name = "canister_query get_migration_progress"]
+pub fn get_migration_progress() {
+    over(candid, |()| get_migration_progress_impl());
+}
+
+#[candid_method(query, rename = "get_migration_progress")]
+fn get_migration_progress_impl() -> Vec<MigrationProgress> {
+    with_state(|s| s.accounts_store.migration_progress())
+}
+
+/// Starts checking that the accounts store is internally consistent, optionally repairing any
+/// discrepancies found.
+///
+/// Only the controller may call this.  The accounts are checked in steps run on timers; the
+/// outcome is available from `get_state_verification` and summarized in the stats.
+#[export_name = "canister_update verify_state"]
+pub fn verify_state() {
+    over(candid_one, verify_state_impl);
+}
+
+#[candid_method(update, rename = "verify_state")]
+fn verify_state_impl(request: VerifyStateRequest) -> VerifyStateResponse {
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        dfn_core::api::trap_with("Only the controller may verify the state");
+    }
+    let response = with_state_mut(|s| s.start_state_verification(request.repair));
+    if response == VerifyStateResponse::Ok {
+        periodic_tasks_runner::schedule_state_verification_step();
+    }
+    response
+}
+
+/// Reports the progress and findings of the state verification in progress or, if there is none,
+/// of the latest verification.
+///
+/// The discrepancies are returned a page at a time, from the requested offset.
+///
+/// Only the controller may call this.
+#[export_name = "canister_query get_state_verification"]
+pub fn get_state_verification() {
+    over(candid_one, get_state_verification_impl);
+}
+
+#[candid_method(query, rename = "get_state_verification")]
+fn get_state_verification_impl(request: GetStateVerificationRequest) -> Option<VerificationReportPage> {
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        dfn_core::api::trap_with("Only the controller may get the state verification");
     }
-    eprintln!("ERROR: step_migration failed.");
+    with_state(|s| s.accounts_store.verification_report_page(request))
 }
 
 /// Add an asset to be served by the canister.
@@ -438,6 +894,23 @@ fn get_toy_account_impl(toy_account_index: u64) -> GetAccountResponse {
     })
 }
 
+/// Measures the cost of decoding a toy account in each of the encodings in which accounts may be stored.
+#[cfg(any(test, feature = "toy_data_gen"))]
+#[export_name = "canister_query benchmark_account_decoding"]
+pub fn benchmark_account_decoding() {
+    over(candid_one, benchmark_account_decoding_impl);
+}
+
+#[cfg(any(test, feature = "toy_data_gen"))]
+#[candid_method(query, rename = "benchmark_account_decoding")]
+fn benchmark_account_decoding_impl(toy_account_index: u64) -> Option<perf::AccountDecodingBenchmark> {
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        dfn_core::api::trap_with("Only the controller may benchmark toy accounts");
+    }
+    with_state(|s| s.accounts_store.benchmark_account_decoding(toy_account_index))
+}
+
 #[export_name = "canister_query get_exceptional_transactions"]
 pub fn get_exceptional_transactions() {
     over(candid, |()| get_exceptional_transactions_impl());
@@ -1,43 +1,400 @@
+use crate::accounts_store::heap_or_stable_map::HeapOrStableMap;
+use crate::accounts_store::schema::accounts_in_unbounded_stable_btree_map::ProductionMemoryType;
 use candid::CandidType;
+use core::fmt;
 use ic_base_types::{CanisterId, PrincipalId};
-use icp_ledger::BlockIndex;
+use ic_stable_structures::{storable::Bound, Storable};
+use icp_ledger::{BlockIndex, Memo};
 use serde::Deserialize;
+use std::borrow::Cow;
 use std::collections::VecDeque;
+use std::ops::Range;
 
-#[derive(Default, CandidType, Deserialize, Debug, Eq, PartialEq)]
+/// The number of attempts after which a transaction is moved to the dead-letter list.
+pub const MAX_ATTEMPTS: u32 = 10;
+/// How long to wait before the first retry.  The delay doubles with every further attempt.
+pub const INITIAL_RETRY_DELAY_NANOS: u64 = 10 * 1_000_000_000;
+/// The longest delay between two attempts.
+pub const MAX_RETRY_DELAY_NANOS: u64 = 60 * 60 * 1_000_000_000;
+/// The maximum number of transactions kept in the dead-letter list.  When exceeded, the oldest are dropped.
+pub const MAX_DEAD_LETTERS: usize = 1_000;
+
+/// The multi-part transactions waiting to be processed, together with those that were given up on.
+///
+/// Each transaction is an entry of its own in a map, keyed by the section it is in followed by a
+/// sequence number, so that a change writes only the entries concerned.  Transactions waiting for
+/// another attempt are keyed by the time of that attempt first, so that the earliest comes first.
+///
+/// Note: The map is kept in its own stable memory partition.
+#[derive(Default)]
 pub struct MultiPartTransactionsProcessor {
+    transactions: HeapOrStableMap<QueuedTransaction>,
+    /// The sequence number given to the next transaction added to a section.
+    next_sequence_number: u64,
+    /// The number of transactions in each section, indexed by `Section`.
+    lengths: [u64; 3],
+}
+
+/// The sections of the map of multi-part transactions.  The value is the first byte of the key.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Section {
+    /// Transactions that have not been attempted yet, in the order they were found on the ledger.
+    Queue = 0,
+    /// Transactions waiting for another attempt, in the order they are due.
+    Retries = 1,
+    /// Transactions that were given up on after `MAX_ATTEMPTS` attempts, oldest first.
+    DeadLetters = 2,
+}
+
+impl Section {
+    /// The range of keys in the section.
+    fn keys(self) -> Range<Vec<u8>> {
+        vec![self as u8]..vec![self as u8 + 1]
+    }
+}
+
+/// The multi-part transactions as serialized with the heap by releases that predate the stable
+/// memory partition.
+#[derive(Default, CandidType, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct EncodedMultiPartTransactions {
+    /// Transactions that have not been attempted yet, in the order they were found on the ledger.
     queue: VecDeque<(BlockIndex, MultiPartTransactionToBeProcessed)>,
+    /// Transactions waiting for another attempt.
+    ///
+    /// Note: Optional so that state saved before retries were introduced can still be decoded.
+    retries: Option<Vec<QueuedTransaction>>,
+    /// Transactions that were given up on after `MAX_ATTEMPTS` attempts, oldest first.
+    dead_letters: Option<VecDeque<QueuedTransaction>>,
+}
+
+impl From<EncodedMultiPartTransactions> for MultiPartTransactionsProcessor {
+    fn from(encoded: EncodedMultiPartTransactions) -> Self {
+        let mut processor = MultiPartTransactionsProcessor::default();
+        for (block_height, transaction) in encoded.queue {
+            processor.push(block_height, transaction);
+        }
+        for queued in encoded.retries.into_iter().flatten() {
+            processor.insert(Section::Retries, queued);
+        }
+        for queued in encoded.dead_letters.into_iter().flatten() {
+            processor.insert(Section::DeadLetters, queued);
+        }
+        processor
+    }
+}
+
+impl fmt::Debug for MultiPartTransactionsProcessor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "MultiPartTransactionsProcessor{{queue: {}, retries: {}, dead_letters: {}}}",
+            self.get_queue_length(),
+            self.get_retry_queue_length(),
+            self.get_dead_letter_count()
+        )
+    }
+}
+
+/// Checks whether two processors hold the same transactions, in the same order.
+#[cfg(test)]
+impl PartialEq for MultiPartTransactionsProcessor {
+    fn eq(&self, other: &Self) -> bool {
+        self.encode() == other.encode()
+    }
+}
+#[cfg(test)]
+impl Eq for MultiPartTransactionsProcessor {}
+
+/// A transaction taken from the queue, together with its retry state.
+#[derive(Clone, CandidType, Deserialize, Debug, Eq, PartialEq)]
+pub struct QueuedTransaction {
+    pub block_height: BlockIndex,
+    pub transaction: MultiPartTransactionToBeProcessed,
+    /// The number of attempts made so far.
+    pub attempts: u32,
+    /// The earliest time at which the next attempt may be made.
+    pub next_attempt_timestamp_nanos: u64,
+}
+
+impl Storable for QueuedTransaction {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        candid::encode_one(self)
+            .expect("Failed to serialize a multi-part transaction")
+            .into()
+    }
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to parse a multi-part transaction from store.")
+    }
+}
+
+/// What happened to a transaction that could not be completed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RetryOutcome {
+    /// Another attempt will be made once the given time has passed.
+    Scheduled { next_attempt_timestamp_nanos: u64 },
+    /// The transaction has been moved to the dead-letter list.
+    GaveUp,
+}
+
+/// The delay before the next attempt, given the number of attempts made so far.
+#[must_use]
+pub fn retry_delay_nanos(attempts: u32) -> u64 {
+    2_u64
+        .saturating_pow(attempts.saturating_sub(1))
+        .saturating_mul(INITIAL_RETRY_DELAY_NANOS)
+        .min(MAX_RETRY_DELAY_NANOS)
 }
 
 #[derive(Clone, CandidType, Deserialize, Debug, Eq, PartialEq)]
 pub enum MultiPartTransactionToBeProcessed {
     CreateCanisterV2(PrincipalId),
-    // TODO: Remove TopUpCanisterV2 after a version has been released that does
-    //       not add TopUpCanisterV2 to the multi-part transaction queue
-    //       anymore.
+    /// A canister attached to the given principal's account has been topped up and the CMC needs to be notified.
     TopUpCanisterV2(PrincipalId, CanisterId),
+    /// A neuron has been staked by the given controller with the given memo and needs to be claimed.
+    StakeNeuron(PrincipalId, Memo),
+    /// A neuron has been topped up and needs to be refreshed.
+    TopUpNeuron(PrincipalId, Memo),
+}
+
+impl MultiPartTransactionToBeProcessed {
+    /// The principal on whose behalf the transaction is processed.
+    #[must_use]
+    pub fn principal(&self) -> PrincipalId {
+        match self {
+            MultiPartTransactionToBeProcessed::CreateCanisterV2(principal)
+            | MultiPartTransactionToBeProcessed::TopUpCanisterV2(principal, _)
+            | MultiPartTransactionToBeProcessed::StakeNeuron(principal, _)
+            | MultiPartTransactionToBeProcessed::TopUpNeuron(principal, _) => *principal,
+        }
+    }
+
+    /// What the transaction does, as shown to the user.
+    #[must_use]
+    pub fn operation_kind(&self) -> OperationKind {
+        match self {
+            MultiPartTransactionToBeProcessed::CreateCanisterV2(_) => OperationKind::CreateCanister,
+            MultiPartTransactionToBeProcessed::TopUpCanisterV2(_, canister_id) => OperationKind::TopUpCanister {
+                canister_id: *canister_id,
+            },
+            MultiPartTransactionToBeProcessed::StakeNeuron(_, memo) => OperationKind::StakeNeuron { memo: memo.0 },
+            MultiPartTransactionToBeProcessed::TopUpNeuron(_, memo) => OperationKind::TopUpNeuron { memo: memo.0 },
+        }
+    }
+}
+
+/// The kind of a multi-part transaction, as shown to the user.
+#[derive(Clone, CandidType, Deserialize, Debug, Eq, PartialEq)]
+pub enum OperationKind {
+    CreateCanister,
+    TopUpCanister { canister_id: CanisterId },
+    StakeNeuron { memo: u64 },
+    TopUpNeuron { memo: u64 },
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, Eq, PartialEq)]
+pub enum OperationStatus {
+    /// The transaction is waiting to be processed.
+    Pending,
+    Succeeded,
+    Failed {
+        error: String,
+    },
+}
+
+/// A multi-part transaction, either waiting to be processed or with the outcome of processing it.
+#[derive(Clone, CandidType, Deserialize, Debug, Eq, PartialEq)]
+pub struct MultiPartOperation {
+    /// The height of the ledger block that started the transaction.
+    pub block_height: BlockIndex,
+    pub kind: OperationKind,
+    pub status: OperationStatus,
+    /// The ledger block in which the funds were returned to the user, if they were.
+    pub refund_block_height: Option<BlockIndex>,
+    /// When processing finished.  `None` while the transaction is pending.
+    pub completed_timestamp_nanos: Option<u64>,
 }
 
 impl MultiPartTransactionsProcessor {
+    /// Creates the transactions in the given memory, or loads those already stored there.
+    ///
+    /// If the memory is new, as it is when upgrading from a release that kept the transactions on
+    /// the heap, it is populated with the transactions decoded from the heap.
+    #[must_use]
+    pub fn init_or_migrate(memory: ProductionMemoryType, on_heap: Self) -> Self {
+        let transactions = HeapOrStableMap::init_or_migrate(memory, on_heap.transactions);
+        let mut processor = MultiPartTransactionsProcessor {
+            transactions,
+            ..Default::default()
+        };
+        for (key, _) in processor.transactions.entries() {
+            processor.lengths[usize::from(key[0])] += 1;
+            let sequence_number = sequence_number_from_key(&key);
+            processor.next_sequence_number = processor.next_sequence_number.max(sequence_number + 1);
+        }
+        processor
+    }
+
+    /// The transactions in the format serialized with the heap.
+    #[must_use]
+    pub fn encode(&self) -> EncodedMultiPartTransactions {
+        EncodedMultiPartTransactions {
+            queue: self
+                .section(Section::Queue)
+                .map(|(_, queued)| (queued.block_height, queued.transaction))
+                .collect(),
+            retries: Some(self.section(Section::Retries).map(|(_, queued)| queued).collect()),
+            dead_letters: Some(self.section(Section::DeadLetters).map(|(_, queued)| queued).collect()),
+        }
+    }
+
     pub fn push(&mut self, block_height: BlockIndex, transaction_to_be_processed: MultiPartTransactionToBeProcessed) {
-        self.queue.push_back((block_height, transaction_to_be_processed));
+        self.insert(
+            Section::Queue,
+            QueuedTransaction {
+                block_height,
+                transaction: transaction_to_be_processed,
+                attempts: 0,
+                next_attempt_timestamp_nanos: 0,
+            },
+        );
     }
 
     #[must_use]
     pub fn take_next(&mut self) -> Option<(BlockIndex, MultiPartTransactionToBeProcessed)> {
-        self.queue.pop_front()
+        let (key, queued) = self.section(Section::Queue).next()?;
+        self.remove(&key);
+        Some((queued.block_height, queued.transaction))
+    }
+
+    /// Takes the next transaction to attempt: a retry that is due, if there is one, otherwise the
+    /// next transaction that has not been attempted yet.
+    #[must_use]
+    pub fn take_next_due(&mut self, now_nanos: u64) -> Option<QueuedTransaction> {
+        let earliest_retry = self.section(Section::Retries).next();
+        if let Some((key, retry)) = earliest_retry {
+            if retry.next_attempt_timestamp_nanos <= now_nanos {
+                self.remove(&key);
+                return Some(retry);
+            }
+        }
+        self.take_next().map(|(block_height, transaction)| QueuedTransaction {
+            block_height,
+            transaction,
+            attempts: 0,
+            next_attempt_timestamp_nanos: now_nanos,
+        })
+    }
+
+    /// Records a failed attempt at a transaction and either schedules another attempt, with
+    /// exponential backoff, or moves the transaction to the dead-letter list.
+    pub fn retry(&mut self, mut queued: QueuedTransaction, now_nanos: u64) -> RetryOutcome {
+        queued.attempts = queued.attempts.saturating_add(1);
+        if queued.attempts >= MAX_ATTEMPTS {
+            self.insert(Section::DeadLetters, queued);
+            while self.lengths[Section::DeadLetters as usize] > MAX_DEAD_LETTERS as u64 {
+                let oldest = self.section(Section::DeadLetters).next();
+                if let Some((key, _)) = oldest {
+                    self.remove(&key);
+                }
+            }
+            RetryOutcome::GaveUp
+        } else {
+            let next_attempt_timestamp_nanos = now_nanos.saturating_add(retry_delay_nanos(queued.attempts));
+            queued.next_attempt_timestamp_nanos = next_attempt_timestamp_nanos;
+            self.insert(Section::Retries, queued);
+            RetryOutcome::Scheduled {
+                next_attempt_timestamp_nanos,
+            }
+        }
+    }
+
+    /// The transactions waiting to be processed on behalf of a principal: those not attempted
+    /// yet, in processing order, followed by those waiting for another attempt.
+    pub fn pending_for(
+        &self,
+        principal: PrincipalId,
+    ) -> impl Iterator<Item = (BlockIndex, MultiPartTransactionToBeProcessed)> + '_ {
+        self.section(Section::Queue)
+            .chain(self.section(Section::Retries))
+            .filter(move |(_, queued)| queued.transaction.principal() == principal)
+            .map(|(_, queued)| (queued.block_height, queued.transaction))
+    }
+
+    /// Discards the transactions processed on behalf of a principal, whether they are waiting to
+    /// be processed or have been given up on.
+    pub fn remove_for(&mut self, principal: PrincipalId) {
+        let keys: Vec<Vec<u8>> = self
+            .transactions
+            .range(Section::Queue.keys().start..Section::DeadLetters.keys().end)
+            .filter(|(_, queued)| queued.transaction.principal() == principal)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    /// The transactions that were given up on, oldest first.
+    pub fn dead_letters(&self) -> impl Iterator<Item = QueuedTransaction> + '_ {
+        self.section(Section::DeadLetters).map(|(_, queued)| queued)
     }
 
     #[must_use]
     pub fn get_queue_length(&self) -> u32 {
-        u32::try_from(self.queue.len())
+        u32::try_from(self.lengths[Section::Queue as usize])
             .unwrap_or_else(|err| unreachable!("MultiPartTransactionsProcessor queue length has length greater than u32::MAX.  Transactions are pruned by the periodic_tasks_runner if they consume more than 1Gb of data, so this should never happen. Error: {:?}", err))
     }
+
+    /// The number of transactions waiting for another attempt.
+    #[must_use]
+    pub fn get_retry_queue_length(&self) -> u32 {
+        u32::try_from(self.lengths[Section::Retries as usize]).unwrap_or(u32::MAX)
+    }
+
+    /// The number of transactions in the dead-letter list.
+    #[must_use]
+    pub fn get_dead_letter_count(&self) -> u32 {
+        u32::try_from(self.lengths[Section::DeadLetters as usize]).unwrap_or(u32::MAX)
+    }
+
+    /// The transactions in a section, in key order, with their keys.
+    fn section(&self, section: Section) -> impl Iterator<Item = (Vec<u8>, QueuedTransaction)> + '_ {
+        self.transactions.range(section.keys())
+    }
+
+    /// Adds a transaction to the end of a section or, for retries, in order of the next attempt.
+    fn insert(&mut self, section: Section, queued: QueuedTransaction) {
+        let mut key = vec![section as u8];
+        if section == Section::Retries {
+            key.extend_from_slice(&queued.next_attempt_timestamp_nanos.to_be_bytes());
+        }
+        key.extend_from_slice(&self.next_sequence_number.to_be_bytes());
+        self.next_sequence_number += 1;
+        self.transactions.insert(&key, queued);
+        self.lengths[section as usize] += 1;
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.transactions.remove(key);
+        self.lengths[usize::from(key[0])] -= 1;
+    }
+}
+
+/// The sequence number at the end of a key of the map of multi-part transactions.
+fn sequence_number_from_key(key: &[u8]) -> u64 {
+    let bytes = key
+        .last_chunk::<8>()
+        .unwrap_or_else(|| unreachable!("Multi-part transaction keys end with a sequence number."));
+    u64::from_be_bytes(*bytes)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::state::partitions::{PartitionType, Partitions};
+    use ic_stable_structures::DefaultMemoryImpl;
     use pretty_assertions::assert_eq;
     use std::str::FromStr;
 
@@ -62,4 +419,164 @@ mod tests {
 
         assert!(processor.take_next().is_none());
     }
+
+    fn create_canister(block_height: BlockIndex) -> QueuedTransaction {
+        QueuedTransaction {
+            block_height,
+            transaction: MultiPartTransactionToBeProcessed::CreateCanisterV2(
+                PrincipalId::from_str(TEST_ACCOUNT_1).unwrap(),
+            ),
+            attempts: 0,
+            next_attempt_timestamp_nanos: 0,
+        }
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_maximum() {
+        assert_eq!(retry_delay_nanos(1), INITIAL_RETRY_DELAY_NANOS);
+        assert_eq!(retry_delay_nanos(2), 2 * INITIAL_RETRY_DELAY_NANOS);
+        assert_eq!(retry_delay_nanos(3), 4 * INITIAL_RETRY_DELAY_NANOS);
+        assert_eq!(retry_delay_nanos(20), MAX_RETRY_DELAY_NANOS);
+        assert_eq!(retry_delay_nanos(u32::MAX), MAX_RETRY_DELAY_NANOS);
+    }
+
+    #[test]
+    fn retries_are_taken_only_once_due() {
+        let mut processor = MultiPartTransactionsProcessor::default();
+        let now = 1_000;
+        let outcome = processor.retry(create_canister(5), now);
+        let due = now + INITIAL_RETRY_DELAY_NANOS;
+        assert_eq!(
+            outcome,
+            RetryOutcome::Scheduled {
+                next_attempt_timestamp_nanos: due
+            }
+        );
+        processor.push(6, create_canister(6).transaction);
+
+        // The retry is not due yet, so the fresh transaction is taken first.
+        let fresh = processor.take_next_due(due - 1).unwrap();
+        assert_eq!((fresh.block_height, fresh.attempts), (6, 0));
+        assert_eq!(processor.take_next_due(due - 1), None);
+        assert_eq!(processor.get_retry_queue_length(), 1);
+
+        let retry = processor.take_next_due(due).unwrap();
+        assert_eq!((retry.block_height, retry.attempts), (5, 1));
+        assert_eq!(processor.get_retry_queue_length(), 0);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut processor = MultiPartTransactionsProcessor::default();
+        let mut now = 0;
+        processor.push(5, create_canister(5).transaction);
+        for _ in 1..MAX_ATTEMPTS {
+            let queued = processor.take_next_due(now).unwrap();
+            let RetryOutcome::Scheduled {
+                next_attempt_timestamp_nanos,
+            } = processor.retry(queued, now)
+            else {
+                panic!("Gave up too early");
+            };
+            now = next_attempt_timestamp_nanos;
+        }
+        let queued = processor.take_next_due(now).unwrap();
+        assert_eq!(queued.attempts, MAX_ATTEMPTS - 1);
+        assert_eq!(processor.retry(queued, now), RetryOutcome::GaveUp);
+
+        assert_eq!(processor.take_next_due(u64::MAX), None);
+        assert_eq!(processor.get_dead_letter_count(), 1);
+        let dead_letter = processor.dead_letters().next().unwrap();
+        assert_eq!((dead_letter.block_height, dead_letter.attempts), (5, MAX_ATTEMPTS));
+    }
+
+    #[test]
+    fn dead_letter_list_is_bounded() {
+        let mut processor = MultiPartTransactionsProcessor::default();
+        for block_height in 0..(MAX_DEAD_LETTERS as u64 + 3) {
+            let queued = QueuedTransaction {
+                attempts: MAX_ATTEMPTS - 1,
+                ..create_canister(block_height)
+            };
+            assert_eq!(processor.retry(queued, 0), RetryOutcome::GaveUp);
+        }
+        assert_eq!(processor.get_dead_letter_count(), MAX_DEAD_LETTERS as u32);
+        assert_eq!(processor.dead_letters().next().map(|dead| dead.block_height), Some(3));
+    }
+
+    #[test]
+    fn pending_includes_retries() {
+        let mut processor = MultiPartTransactionsProcessor::default();
+        let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+        let _ = processor.retry(create_canister(5), 0);
+        processor.push(6, create_canister(6).transaction);
+        let pending: Vec<BlockIndex> = processor
+            .pending_for(principal)
+            .map(|(block_height, _)| block_height)
+            .collect();
+        assert_eq!(pending, vec![6, 5]);
+    }
+
+    #[test]
+    fn remove_for_discards_only_the_principals_transactions() {
+        let mut processor = MultiPartTransactionsProcessor::default();
+        let principal = PrincipalId::from_str(TEST_ACCOUNT_1).unwrap();
+        let other_principal = PrincipalId::new_user_test_id(1);
+        processor.push(4, create_canister(4).transaction);
+        processor.push(5, MultiPartTransactionToBeProcessed::CreateCanisterV2(other_principal));
+        let _ = processor.retry(create_canister(6), 0);
+        let _ = processor.retry(
+            QueuedTransaction {
+                attempts: MAX_ATTEMPTS,
+                ..create_canister(7)
+            },
+            0,
+        );
+
+        processor.remove_for(principal);
+
+        assert_eq!(processor.pending_for(principal).count(), 0);
+        assert_eq!(processor.get_dead_letter_count(), 0);
+        assert_eq!(processor.get_queue_length(), 1);
+        assert_eq!(processor.pending_for(other_principal).count(), 1);
+    }
+
+    #[test]
+    fn retry_state_survives_encoding() {
+        let mut processor = MultiPartTransactionsProcessor::default();
+        let _ = processor.retry(create_canister(5), 0);
+        let _ = processor.retry(
+            QueuedTransaction {
+                attempts: MAX_ATTEMPTS,
+                ..create_canister(6)
+            },
+            0,
+        );
+        processor.push(7, create_canister(7).transaction);
+        let bytes = candid::encode_one(processor.encode()).unwrap();
+        let decoded: EncodedMultiPartTransactions = candid::decode_one(&bytes).unwrap();
+        assert_eq!(MultiPartTransactionsProcessor::from(decoded), processor);
+    }
+
+    #[test]
+    fn transactions_are_loaded_from_stable_memory() {
+        let partitions = Partitions::from(DefaultMemoryImpl::default());
+        let memory = || partitions.get(PartitionType::MultiPartTransactions.memory_id());
+        let mut processor = MultiPartTransactionsProcessor::default();
+        processor.push(4, create_canister(4).transaction);
+        let _ = processor.retry(create_canister(5), 0);
+        let mut processor = MultiPartTransactionsProcessor::init_or_migrate(memory(), processor);
+        processor.push(6, create_canister(6).transaction);
+
+        // The transactions in the memory are used, together with the counts derived from them.
+        let mut loaded =
+            MultiPartTransactionsProcessor::init_or_migrate(memory(), MultiPartTransactionsProcessor::default());
+        assert_eq!(loaded, processor);
+        assert_eq!((loaded.get_queue_length(), loaded.get_retry_queue_length()), (2, 1));
+        loaded.push(7, create_canister(7).transaction);
+        let queue: Vec<BlockIndex> = std::iter::from_fn(|| loaded.take_next())
+            .map(|(block_height, _)| block_height)
+            .collect();
+        assert_eq!(queue, vec![4, 6, 7]);
+    }
 }
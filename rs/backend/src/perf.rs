@@ -1,12 +1,16 @@
 //! Capture and store performance counters.
+use crate::accounts_store::schema::compact_account::CompactAccount;
+use crate::accounts_store::Account;
 use crate::state::with_state_mut;
 use crate::stats::Stats;
 use crate::StableState;
 use candid::CandidType;
 use dfn_candid::Candid;
 use ic_cdk::api::instruction_counter;
+use ic_stable_structures::{storable::Bound, Storable};
 use on_wire::{FromWire, IntoWire};
 use serde::Deserialize;
+use std::borrow::Cow;
 use std::collections::VecDeque;
 #[cfg(test)]
 mod tests;
@@ -32,6 +36,44 @@ impl PerformanceCount {
     }
 }
 
+/// The cost of decoding an account, as every `get_account` call does, in each of the encodings in
+/// which accounts may be stored.
+#[derive(CandidType, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct AccountDecodingBenchmark {
+    /// The size of the account in the Candid encoding of the `AccountsInStableMemory` schema.
+    pub candid_bytes: u64,
+    /// The instructions needed to decode the Candid encoding.
+    pub candid_instructions: u64,
+    /// The size of the account in the encoding of the `CompactAccountsInStableMemory` schema.
+    pub compact_bytes: u64,
+    /// The instructions needed to decode the compact encoding.
+    pub compact_instructions: u64,
+}
+
+impl AccountDecodingBenchmark {
+    /// Measures the cost of decoding the given account.
+    ///
+    /// Note: Instructions can be counted only in a canister.
+    #[must_use]
+    pub fn measure(account: &Account) -> Self {
+        let candid = account.to_bytes();
+        let compact = CompactAccount(account.clone()).to_bytes();
+        AccountDecodingBenchmark {
+            candid_bytes: candid.len() as u64,
+            candid_instructions: count_instructions(|| Account::from_bytes(Cow::Borrowed(&candid[..]))),
+            compact_bytes: compact.len() as u64,
+            compact_instructions: count_instructions(|| CompactAccount::from_bytes(Cow::Borrowed(&compact[..]))),
+        }
+    }
+}
+
+/// Counts the instructions executed by a function.
+fn count_instructions<R>(f: impl FnOnce() -> R) -> u64 {
+    let before = instruction_counter();
+    let _result = f();
+    instruction_counter().saturating_sub(before)
+}
+
 /// Storage for recent performance counter snapshots.
 #[derive(CandidType, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
 pub struct PerformanceCounts {
@@ -107,6 +149,19 @@ impl StableState for PerformanceCounts {
     }
 }
 
+impl Storable for PerformanceCounts {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        candid::encode_one(self)
+            .expect("Failed to serialize performance counts")
+            .into()
+    }
+    /// Parse performance counts.  On error, return a blank new structure, as these are only statistics.
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap_or_default()
+    }
+}
+
 /// Gets the value of the instruction count and saves it with the given label.
 pub fn record_instruction_count(name: &str) {
     save_instruction_count(PerformanceCount::new(name));
@@ -1,47 +1,211 @@
+use crate::accounts_store::schema::proxy::AccountsDbAsProxy;
+use crate::accounts_store::AccountsStore;
+use crate::arguments::PeriodicTaskIntervals;
 use crate::canisters::cmc;
+use crate::canisters::governance::{
+    self, claim_or_refresh_neuron_from_account_response::Result as ClaimOrRefreshResult,
+    ClaimOrRefreshNeuronFromAccount, GovernanceError, GovernanceErrorType,
+};
 use crate::ledger_sync;
-use crate::multi_part_transactions_processor::MultiPartTransactionToBeProcessed;
-use crate::state::with_state_mut;
-use cycles_minting_canister::{NotifyCreateCanister, NotifyError};
+use crate::multi_part_transactions_processor::{
+    MultiPartTransactionToBeProcessed, OperationStatus, QueuedTransaction, RetryOutcome,
+};
+use crate::spawn;
+use crate::state::{with_state, with_state_mut};
+use crate::timer::{set_timer, set_timer_interval};
+use cycles_minting_canister::{NotifyCreateCanister, NotifyError, NotifyTopUp, NotifyTopUpResult};
 use dfn_core::api::{CanisterId, PrincipalId};
-use icp_ledger::BlockIndex;
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk::{eprintln, println};
+use ic_nns_common::types::NeuronId;
+use icp_ledger::{BlockIndex, Memo};
+use std::time::Duration;
 
-pub async fn run_periodic_tasks() {
-    ledger_sync::sync_transactions().await;
+#[cfg(test)]
+mod tests;
+
+/// Starts the timers that run background processes:
+/// - Sync transactions from the ledger
+/// - Process any queued 'multi-part' actions (e.g. staking a neuron or topping up a canister)
+/// - Step the stable memory migration, or the build of the index of linked accounts, while one is in progress
+///
+/// Each runs at its own interval, as set in the canister arguments.  Every run of any of them is
+/// counted in the `periodic_tasks_count` metric, as every heartbeat was before the timers.
+pub fn init_timers(intervals: PeriodicTaskIntervals) {
+    set_timer_interval(intervals.ledger_sync, || {
+        record_periodic_task_run();
+        spawn::spawn(sync_ledger());
+    });
+    set_timer_interval(intervals.multi_part_transactions, || {
+        record_periodic_task_run();
+        spawn::spawn(process_next_multi_part_transaction());
+    });
+    set_timer_interval(intervals.migration_step, || {
+        record_periodic_task_run();
+        spawn::spawn(step_migration_if_in_progress());
+    });
+}
 
+/// Counts a run of a periodic task.
+fn record_periodic_task_run() {
     with_state_mut(|state| {
         state.performance.increment_periodic_tasks_run();
     });
+}
+
+/// Gets new blocks from the ledger and processes them.
+pub async fn sync_ledger() {
+    ledger_sync::sync_transactions().await;
+}
 
+/// Processes the next multi-part transaction that is due, if any.
+pub async fn process_next_multi_part_transaction() {
     let maybe_transaction_to_process = with_state_mut(|s| s.accounts_store.try_take_next_transaction_to_process());
-    if let Some((block_height, transaction_to_process)) = maybe_transaction_to_process {
-        match transaction_to_process {
+    if let Some(queued) = maybe_transaction_to_process {
+        match queued.transaction {
             MultiPartTransactionToBeProcessed::CreateCanisterV2(controller) => {
-                handle_create_canister_v2(block_height, controller).await;
+                handle_create_canister_v2(queued, controller).await;
+            }
+            MultiPartTransactionToBeProcessed::TopUpCanisterV2(_, canister_id) => {
+                handle_top_up_canister_v2(queued, canister_id).await;
+            }
+            MultiPartTransactionToBeProcessed::StakeNeuron(controller, memo) => {
+                handle_stake_neuron(queued, controller, memo).await;
+            }
+            MultiPartTransactionToBeProcessed::TopUpNeuron(controller, memo) => {
+                handle_top_up_neuron(queued, controller, memo).await;
             }
-            // TODO: Remove TopUpCanisterV2 after a version has been released
-            //       that does not add TopUpCanisterV2 to the multi-part
-            //       transaction queue anymore.
-            MultiPartTransactionToBeProcessed::TopUpCanisterV2(_principal, _canister_id) => {}
         }
     }
 }
 
-async fn handle_create_canister_v2(block_height: BlockIndex, controller: PrincipalId) {
+/// Steps the migration, or the build of the index of linked accounts, if one is in progress.
+async fn step_migration_if_in_progress() {
+    if with_state(|s| s.accounts_store.migration_in_progress() || s.accounts_store.links_index_in_progress()) {
+        call_step_migration_with_retries().await;
+    }
+}
+
+/// Schedules the next step of the state verification in progress.
+///
+/// Each step checks a batch of accounts in its own message, so that a verification of many
+/// accounts stays within the instruction limit.  Steps are scheduled until the verification completes.
+pub fn schedule_state_verification_step() {
+    set_timer(Duration::ZERO, || {
+        let in_progress = with_state_mut(|s| {
+            s.step_state_verification(AccountsStore::VERIFICATION_STEP_SIZE);
+            s.accounts_store.verification_in_progress()
+        });
+        if in_progress {
+            schedule_state_verification_step();
+        }
+    });
+}
+
+/// Calls `step_migration()` without panicking and rolling back if anything goes wrong.
+async fn call_step_migration(step_size: u32) -> Result<(), (RejectionCode, String)> {
+    ic_cdk::api::call::call(ic_cdk::id(), "step_migration", (step_size,)).await
+}
+
+/// Calls step migration, dropping the step size to 1 on failure.
+async fn call_step_migration_with_retries() {
+    for step_size in [AccountsDbAsProxy::MIGRATION_STEP_SIZE, 1] {
+        if let Err((code, msg)) = call_step_migration(step_size).await {
+            println!("WARNING: step_migration failed with step size {step_size}: {code:?} {msg}");
+        } else {
+            return;
+        }
+    }
+    eprintln!("ERROR: step_migration failed.");
+}
+
+/// Records the outcome of a multi-part transaction so that the user can see it.
+fn record_outcome(
+    block_height: BlockIndex,
+    transaction: &MultiPartTransactionToBeProcessed,
+    status: OperationStatus,
+    refund_block_height: Option<BlockIndex>,
+) {
+    with_state_mut(|s| {
+        s.accounts_store
+            .record_operation_outcome(block_height, transaction, status, refund_block_height);
+    });
+}
+
+/// Why a call to another canister did not succeed.
+#[derive(Debug, Eq, PartialEq)]
+enum CallFailure {
+    /// The call may succeed if it is made again later, for example because the canister called was
+    /// stopped or busy.
+    Transient(String),
+    /// The call would fail again, however often it was made.
+    Permanent(String),
+}
+
+/// Determines whether governance may succeed if asked again later.
+///
+/// Governance is unavailable while it is being upgraded, and a neuron is locked while a ledger
+/// call on its behalf is in flight.
+fn is_transient_governance_error(error: &GovernanceError) -> bool {
+    [
+        GovernanceErrorType::Unavailable,
+        GovernanceErrorType::ResourceExhausted,
+        GovernanceErrorType::External,
+        GovernanceErrorType::LedgerUpdateOngoing,
+    ]
+    .iter()
+    .any(|error_type| error.error_type == *error_type as i32)
+}
+
+/// Schedules another attempt, with exponential backoff, at a transaction that failed for a reason
+/// that may pass, such as the CMC still processing it or the canister called being unavailable.
+/// If there have been too many attempts already, the transaction is given up on and recorded as
+/// failed with the last error.
+fn retry_later(queued: QueuedTransaction, error: &str) {
+    let block_height = queued.block_height;
+    let transaction = queued.transaction.clone();
+    let attempts = queued.attempts.saturating_add(1);
+    println!("Will retry the multi-part transaction at block {block_height}: {error}");
+    let outcome = with_state_mut(|s| s.accounts_store.retry_multi_part_transaction(queued));
+    if outcome == RetryOutcome::GaveUp {
+        let error = format!("Gave up after {attempts} attempts.  Last error: {error}");
+        record_outcome(block_height, &transaction, OperationStatus::Failed { error }, None);
+    }
+}
+
+/// Records the outcome of a failed call to the CMC, including the refund if there was one.
+fn record_notify_failure(
+    block_height: BlockIndex,
+    transaction: &MultiPartTransactionToBeProcessed,
+    error: NotifyError,
+) {
+    let (error, refund_block_height) = match error {
+        NotifyError::Refunded { reason, block_index } => (reason, block_index),
+        NotifyError::InvalidTransaction(error) => (error, None),
+        NotifyError::Other { error_message, .. } => (error_message, None),
+        error => (format!("{error:?}"), None),
+    };
+    record_outcome(
+        block_height,
+        transaction,
+        OperationStatus::Failed { error },
+        refund_block_height,
+    );
+}
+
+async fn handle_create_canister_v2(queued: QueuedTransaction, controller: PrincipalId) {
+    let block_height = queued.block_height;
+    let transaction = queued.transaction.clone();
     match create_canister_v2(block_height, controller).await {
-        Ok(Ok(canister_id)) => with_state_mut(|s| {
-            s.accounts_store.attach_newly_created_canister(controller, canister_id);
-        }),
-        Ok(Err(NotifyError::Processing)) => {
+        Ok(Ok(canister_id)) => {
             with_state_mut(|s| {
-                s.accounts_store.enqueue_multi_part_transaction(
-                    block_height,
-                    MultiPartTransactionToBeProcessed::CreateCanisterV2(controller),
-                );
+                s.accounts_store.attach_newly_created_canister(controller, canister_id);
             });
+            record_outcome(block_height, &transaction, OperationStatus::Succeeded, None);
         }
-        Ok(Err(_error)) => (),
-        Err(_error) => (),
+        Ok(Err(NotifyError::Processing)) => retry_later(queued, "Still being processed."),
+        Ok(Err(error)) => record_notify_failure(block_height, &transaction, error),
+        Err(error) => retry_later(queued, &error),
     }
 }
 
@@ -60,3 +224,77 @@ async fn create_canister_v2(
 
     cmc::notify_create_canister(notify_request).await
 }
+
+async fn handle_top_up_canister_v2(queued: QueuedTransaction, canister_id: CanisterId) {
+    let block_height = queued.block_height;
+    let transaction = queued.transaction.clone();
+    match top_up_canister_v2(block_height, canister_id).await {
+        Ok(Ok(_cycles)) => record_outcome(block_height, &transaction, OperationStatus::Succeeded, None),
+        Ok(Err(NotifyError::Processing)) => retry_later(queued, "Still being processed."),
+        Ok(Err(error)) => record_notify_failure(block_height, &transaction, error),
+        Err(error) => retry_later(queued, &error),
+    }
+}
+
+async fn top_up_canister_v2(block_index: BlockIndex, canister_id: CanisterId) -> Result<NotifyTopUpResult, String> {
+    let notify_request = NotifyTopUp {
+        block_index,
+        canister_id,
+    };
+
+    cmc::notify_top_up(notify_request).await
+}
+
+async fn handle_stake_neuron(queued: QueuedTransaction, controller: PrincipalId, memo: Memo) {
+    let block_height = queued.block_height;
+    let transaction = queued.transaction.clone();
+    match claim_or_refresh_neuron(controller, memo).await {
+        Ok(neuron_id) => {
+            with_state_mut(|s| s.accounts_store.mark_neuron_created(controller, memo, neuron_id));
+            record_outcome(block_height, &transaction, OperationStatus::Succeeded, None);
+        }
+        Err(CallFailure::Transient(error)) => retry_later(queued, &error),
+        Err(CallFailure::Permanent(error)) => {
+            record_outcome(block_height, &transaction, OperationStatus::Failed { error }, None);
+        }
+    }
+}
+
+async fn handle_top_up_neuron(queued: QueuedTransaction, controller: PrincipalId, memo: Memo) {
+    let block_height = queued.block_height;
+    let transaction = queued.transaction.clone();
+    match claim_or_refresh_neuron(controller, memo).await {
+        Ok(_neuron_id) => {
+            with_state_mut(|s| s.accounts_store.mark_neuron_topped_up());
+            record_outcome(block_height, &transaction, OperationStatus::Succeeded, None);
+        }
+        Err(CallFailure::Transient(error)) => retry_later(queued, &error),
+        Err(CallFailure::Permanent(error)) => {
+            record_outcome(block_height, &transaction, OperationStatus::Failed { error }, None);
+        }
+    }
+}
+
+/// Asks governance to claim a newly staked neuron, or to refresh the stake of an existing one.
+///
+/// Claiming or refreshing a neuron more than once has no further effect, so failed calls may
+/// safely be made again.
+async fn claim_or_refresh_neuron(controller: PrincipalId, memo: Memo) -> Result<NeuronId, CallFailure> {
+    let request = ClaimOrRefreshNeuronFromAccount {
+        controller: Some(controller),
+        memo: memo.0,
+    };
+    let response = governance::claim_or_refresh_neuron_from_account(request)
+        .await
+        .map_err(CallFailure::Transient)?;
+    match response.result {
+        Some(ClaimOrRefreshResult::NeuronId(neuron_id)) => Ok(NeuronId(neuron_id.id)),
+        Some(ClaimOrRefreshResult::Error(error)) if is_transient_governance_error(&error) => {
+            Err(CallFailure::Transient(error.error_message))
+        }
+        Some(ClaimOrRefreshResult::Error(error)) => Err(CallFailure::Permanent(error.error_message)),
+        None => Err(CallFailure::Permanent(
+            "Governance returned neither a neuron ID nor an error.".to_string(),
+        )),
+    }
+}
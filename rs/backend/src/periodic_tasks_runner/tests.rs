@@ -0,0 +1,183 @@
+use super::*;
+use crate::canisters::governance::testing::add_claim_or_refresh_response;
+use crate::canisters::governance::ClaimOrRefreshNeuronFromAccountResponse;
+use crate::multi_part_transactions_processor::MAX_ATTEMPTS;
+use crate::state::{init_state, with_state};
+use crate::stats::get_stats;
+use crate::timer;
+use pretty_assertions::assert_eq;
+use std::time::Duration;
+
+fn neurons_topped_up_count() -> u64 {
+    with_state(|s| get_stats(s).neurons_topped_up_count)
+}
+
+/// A top-up of neuron 42 of test user 1, as taken from the queue after the given number of attempts.
+fn queued_top_up(block_height: BlockIndex, attempts: u32) -> QueuedTransaction {
+    QueuedTransaction {
+        block_height,
+        transaction: MultiPartTransactionToBeProcessed::TopUpNeuron(PrincipalId::new_user_test_id(1), Memo(42)),
+        attempts,
+        next_attempt_timestamp_nanos: 0,
+    }
+}
+
+fn operation_statuses(principal: PrincipalId) -> Vec<OperationStatus> {
+    with_state(|s| s.accounts_store.get_pending_operations(principal))
+        .into_iter()
+        .map(|operation| operation.status)
+        .collect()
+}
+
+#[tokio::test]
+async fn top_up_neuron_is_counted_once_refreshed() {
+    init_state();
+    add_claim_or_refresh_response(Ok(ClaimOrRefreshNeuronFromAccountResponse {
+        result: Some(ClaimOrRefreshResult::NeuronId(ic_nns_common::pb::v1::NeuronId {
+            id: 7,
+        })),
+    }));
+
+    handle_top_up_neuron(queued_top_up(1, 0), PrincipalId::new_user_test_id(1), Memo(42)).await;
+
+    assert_eq!(neurons_topped_up_count(), 1);
+    assert_eq!(
+        operation_statuses(PrincipalId::new_user_test_id(1)),
+        vec![OperationStatus::Succeeded]
+    );
+}
+
+#[tokio::test]
+async fn top_up_neuron_is_not_counted_if_governance_returns_an_error() {
+    init_state();
+    add_claim_or_refresh_response(Ok(ClaimOrRefreshNeuronFromAccountResponse {
+        result: Some(ClaimOrRefreshResult::Error(GovernanceError {
+            error_type: 0,
+            error_message: "Account does not have enough funds to stake a neuron.".to_string(),
+        })),
+    }));
+
+    handle_top_up_neuron(queued_top_up(1, 0), PrincipalId::new_user_test_id(1), Memo(42)).await;
+
+    assert_eq!(neurons_topped_up_count(), 0);
+    assert_eq!(
+        operation_statuses(PrincipalId::new_user_test_id(1)),
+        vec![OperationStatus::Failed {
+            error: "Account does not have enough funds to stake a neuron.".to_string()
+        }]
+    );
+}
+
+#[tokio::test]
+async fn neuron_operations_are_retried_if_the_failure_may_pass() {
+    init_state();
+    add_claim_or_refresh_response(Err("Canister is stopped".to_string()));
+    add_claim_or_refresh_response(Ok(ClaimOrRefreshNeuronFromAccountResponse {
+        result: Some(ClaimOrRefreshResult::Error(GovernanceError {
+            error_type: GovernanceErrorType::Unavailable as i32,
+            error_message: "Governance is being upgraded.".to_string(),
+        })),
+    }));
+    add_claim_or_refresh_response(Err("Canister is stopped".to_string()));
+
+    handle_top_up_neuron(queued_top_up(1, 0), PrincipalId::new_user_test_id(1), Memo(42)).await;
+    handle_top_up_neuron(queued_top_up(2, 0), PrincipalId::new_user_test_id(1), Memo(42)).await;
+    assert_eq!(
+        operation_statuses(PrincipalId::new_user_test_id(1)),
+        vec![OperationStatus::Pending, OperationStatus::Pending]
+    );
+
+    // A transaction that has failed too often is given up on.
+    handle_top_up_neuron(
+        queued_top_up(3, MAX_ATTEMPTS - 1),
+        PrincipalId::new_user_test_id(1),
+        Memo(42),
+    )
+    .await;
+    assert_eq!(neurons_topped_up_count(), 0);
+    assert_eq!(
+        operation_statuses(PrincipalId::new_user_test_id(1)),
+        vec![
+            OperationStatus::Pending,
+            OperationStatus::Pending,
+            OperationStatus::Failed {
+                error: format!("Gave up after {MAX_ATTEMPTS} attempts.  Last error: Canister is stopped")
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn timers_run_each_task_at_its_own_interval() {
+    init_state();
+    with_state_mut(|s| {
+        s.accounts_store.enqueue_multi_part_transaction(
+            1,
+            MultiPartTransactionToBeProcessed::TopUpNeuron(PrincipalId::new_user_test_id(1), Memo(42)),
+        );
+    });
+    add_claim_or_refresh_response(Ok(ClaimOrRefreshNeuronFromAccountResponse {
+        result: Some(ClaimOrRefreshResult::NeuronId(ic_nns_common::pb::v1::NeuronId {
+            id: 7,
+        })),
+    }));
+
+    init_timers(PeriodicTaskIntervals {
+        ledger_sync: Duration::from_secs(10),
+        multi_part_transactions: Duration::from_secs(3),
+        migration_step: Duration::from_secs(1),
+    });
+
+    let mut timers = timer::testing::drain_timer_intervals();
+    let intervals: Vec<Duration> = timers.iter().map(|timer| timer.interval).collect();
+    assert_eq!(
+        intervals,
+        vec![Duration::from_secs(10), Duration::from_secs(3), Duration::from_secs(1)]
+    );
+
+    // The multi-part transactions timer processes the queued top-up.
+    (timers[1].func)();
+    let mut spawned_futures = spawn::testing::drain_spawned_futures();
+    assert_eq!(spawned_futures.len(), 1);
+    spawned_futures.pop().unwrap().await;
+    assert_eq!(neurons_topped_up_count(), 1);
+
+    // Every run of every task is counted.
+    (timers[2].func)();
+    (timers[1].func)();
+    drop(spawn::testing::drain_spawned_futures());
+    assert_eq!(with_state(|s| get_stats(s).periodic_tasks_count), Some(3));
+}
+
+#[test]
+fn state_verification_steps_are_scheduled_until_it_completes() {
+    init_state();
+    with_state_mut(|s| {
+        for i in 0..3 {
+            s.accounts_store.add_account(PrincipalId::new_user_test_id(i));
+        }
+        s.start_state_verification(false);
+    });
+
+    schedule_state_verification_step();
+    let mut steps = 0;
+    loop {
+        let timers = timer::testing::drain_timers();
+        if timers.is_empty() {
+            break;
+        }
+        for timer in timers {
+            assert_eq!(timer.delay, Duration::ZERO);
+            (timer.func)();
+            steps += 1;
+        }
+    }
+
+    // One step checks all the accounts, the next checks the index of links and the last completes
+    // the verification.
+    assert_eq!(steps, 3);
+    let stats = with_state(get_stats);
+    assert_eq!(stats.state_verification_in_progress, Some(false));
+    assert_eq!(stats.state_verification_accounts_verified, Some(3));
+    assert_eq!(stats.state_verification_discrepancies_count, Some(0));
+}
@@ -4,11 +4,19 @@ pub mod tests;
 mod with_accounts_in_stable_memory;
 
 use self::partitions::{PartitionType, Partitions, PartitionsMaybe};
+use crate::accounts_store::heap_or_stable_cell::HeapOrStableCell;
+use crate::accounts_store::heap_or_stable_map::HeapOrStableMap;
+use crate::accounts_store::operation_log::OperationLog;
 use crate::accounts_store::schema::accounts_in_unbounded_stable_btree_map::AccountsDbAsUnboundedStableBTreeMap;
-use crate::accounts_store::schema::proxy::AccountsDb;
-use crate::accounts_store::AccountsStore;
+use crate::accounts_store::schema::map::AccountsDbAsMap;
+use crate::accounts_store::schema::proxy::registry::RegisteredMigration;
+use crate::accounts_store::schema::proxy::{AccountsDb, SavedMigrations};
+use crate::accounts_store::schema::SchemaLabel;
+use crate::accounts_store::transaction_index::TransactionIndex;
+use crate::accounts_store::verification::{Discrepancy, SavedVerification};
+use crate::accounts_store::{AccountsStore, VerifyStateResponse};
 use crate::assets::AssetHashes;
-use crate::assets::Assets;
+use crate::assets::{Asset, Assets};
 use crate::perf::PerformanceCounts;
 use crate::tvl::state::TvlState;
 
@@ -24,9 +32,13 @@ pub struct State {
     pub accounts_store: AccountsStore,
     pub assets: Assets,
     pub asset_hashes: AssetHashes,
+    /// The assets that are persisted across upgrades, kept in their own stable memory partition.
+    pub stable_assets: HeapOrStableMap<Asset>,
+    /// Counts that change on almost every call, so they are kept on the heap and saved to their
+    /// stable memory partition only when the canister is upgraded.
     pub performance: PerformanceCounts,
     pub partitions_maybe: PartitionsMaybe,
-    pub tvl_state: TvlState,
+    pub tvl_state: HeapOrStableCell<TvlState>,
 }
 
 #[cfg(test)]
@@ -36,7 +48,9 @@ impl PartialEq for State {
         (self.accounts_store == other.accounts_store)
             && (self.assets == other.assets)
             && (self.asset_hashes == other.asset_hashes)
+            && (self.stable_assets == other.stable_assets)
             && (self.performance == other.performance)
+            && (self.tvl_state == other.tvl_state)
     }
 }
 #[cfg(test)]
@@ -49,6 +63,7 @@ impl core::fmt::Debug for State {
             accounts_store,
             assets: _,
             asset_hashes: _,
+            stable_assets,
             performance: _,
             partitions_maybe,
             tvl_state,
@@ -57,6 +72,7 @@ impl core::fmt::Debug for State {
         writeln!(f, "  accounts: {accounts_store:?}")?;
         writeln!(f, "  assets: <html etc> (elided)")?;
         writeln!(f, "  asset_hashes: <hashes of the assets> (elided)")?;
+        writeln!(f, "  stable_assets: {stable_assets:?}")?;
         writeln!(f, "  performance: <stats for the metrics endpoint> (elided)")?;
         writeln!(f, "  partitions_maybe: {partitions_maybe:?}")?;
         writeln!(f, "  tvl_state: {tvl_state:?}")?;
@@ -113,16 +129,23 @@ impl State {
     pub fn new(memory: DefaultMemoryImpl) -> Self {
         let partitions = Partitions::from(memory);
         let accounts_store = AccountsStore::from(AccountsDb::UnboundedStableBTreeMap(
-            AccountsDbAsUnboundedStableBTreeMap::new(partitions.get(PartitionType::Accounts.memory_id())),
+            AccountsDbAsUnboundedStableBTreeMap::new(
+                partitions.get(PartitionType::Accounts.memory_id()),
+                partitions.get(PartitionType::AccountLinks.memory_id()),
+            ),
         ));
-        State {
+        let mut state = State {
             accounts_store,
             assets: Assets::default(),
             asset_hashes: AssetHashes::default(),
+            stable_assets: HeapOrStableMap::default(),
             performance: PerformanceCounts::default(),
-            partitions_maybe: PartitionsMaybe::Partitions(partitions),
-            tvl_state: TvlState::default(),
-        }
+            partitions_maybe: PartitionsMaybe::None(DefaultMemoryImpl::default()),
+            tvl_state: HeapOrStableCell::default(),
+        };
+        state.move_to_partitions(&partitions);
+        state.partitions_maybe = PartitionsMaybe::Partitions(partitions);
+        state
     }
 
     #[must_use]
@@ -130,23 +153,222 @@ impl State {
         println!("START state::new_restored: ())");
         let partitions = Partitions::from(memory);
         let mut state = Self::recover_heap_from_managed_memory(&partitions.get(PartitionType::Heap.memory_id()));
-        let accounts_db = AccountsDb::UnboundedStableBTreeMap(AccountsDbAsUnboundedStableBTreeMap::load(
-            partitions.get(PartitionType::Accounts.memory_id()),
-        ));
+        // Releases that predate the schema label kept accounts in the `AccountsInStableMemory` schema.
+        let schema = partitions.schema_label().unwrap_or(SchemaLabel::AccountsInStableMemory);
+        let accounts_db = Self::load_accounts_db(&partitions, schema);
         // Replace the default accountsdb created by `serde` with the one from stable memory.
         let _deserialized_accounts_db = state.accounts_store.replace_accounts_db(accounts_db);
+        state.resume_accounts_migration(&partitions);
+        state.accounts_store.restore_verification(
+            HeapOrStableCell::init(
+                partitions.get(PartitionType::Verification.memory_id()),
+                SavedVerification::default(),
+            )
+            .get()
+            .clone(),
+        );
+        state.move_to_partitions(&partitions);
         state.partitions_maybe = PartitionsMaybe::Partitions(partitions);
         println!("END   state::new_restored: ()");
         state
     }
+
+    /// Loads the accounts database of the given schema from its partition.
+    fn load_accounts_db(partitions: &Partitions, schema: SchemaLabel) -> AccountsDb {
+        match schema {
+            SchemaLabel::Map => dfn_core::api::trap_with("Accounts in the Map schema are not kept in stable memory."),
+            SchemaLabel::AccountsInStableMemory => {
+                AccountsDb::UnboundedStableBTreeMap(AccountsDbAsUnboundedStableBTreeMap::load(
+                    partitions.get(PartitionType::Accounts.memory_id()),
+                    partitions.get(PartitionType::AccountLinks.memory_id()),
+                ))
+            }
+            SchemaLabel::CompactAccountsInStableMemory => {
+                AccountsDb::CompactStableBTreeMap(AccountsDbAsUnboundedStableBTreeMap::load(
+                    partitions.get(PartitionType::CompactAccounts.memory_id()),
+                    partitions.get(PartitionType::CompactAccountLinks.memory_id()),
+                ))
+            }
+        }
+    }
+
+    /// Creates an empty accounts database of the given schema, discarding anything in its partition.
+    fn new_accounts_db(partitions: &Partitions, schema: SchemaLabel) -> AccountsDb {
+        match schema {
+            SchemaLabel::Map => AccountsDb::Map(AccountsDbAsMap::default()),
+            SchemaLabel::AccountsInStableMemory => {
+                AccountsDb::UnboundedStableBTreeMap(AccountsDbAsUnboundedStableBTreeMap::new(
+                    partitions.get(PartitionType::Accounts.memory_id()),
+                    partitions.get(PartitionType::AccountLinks.memory_id()),
+                ))
+            }
+            SchemaLabel::CompactAccountsInStableMemory => {
+                AccountsDb::CompactStableBTreeMap(AccountsDbAsUnboundedStableBTreeMap::new(
+                    partitions.get(PartitionType::CompactAccounts.memory_id()),
+                    partitions.get(PartitionType::CompactAccountLinks.memory_id()),
+                ))
+            }
+        }
+    }
+
+    /// Starts migrating the accounts to the given schema, unless they are already stored in it or
+    /// being migrated.
+    ///
+    /// Only registered migrations are started; other requests are logged and ignored.  If the
+    /// schema is several registered migrations away, these are performed one after the other, see
+    /// `step_accounts_migration`.  Each new database is created in the partition of its schema, so
+    /// a migration that was rolled back starts afresh.
+    pub fn start_accounts_migration(&mut self, schema: SchemaLabel) {
+        let current = self.accounts_store.schema_label();
+        if current == schema || self.accounts_store.migration_in_progress() {
+            return;
+        }
+        if RegisteredMigration::path(current, schema).is_none() {
+            println!("WARNING: There is no registered migration from {current:?} to {schema:?}.");
+            return;
+        }
+        self.accounts_store.set_migration_target(Some(schema));
+        self.start_next_accounts_migration();
+    }
+
+    /// Starts the next registered migration towards the migration target, if it has not been reached.
+    fn start_next_accounts_migration(&mut self) {
+        let Some(target) = self.accounts_store.migration_target() else {
+            return;
+        };
+        let current = self.accounts_store.schema_label();
+        let Some(next) = RegisteredMigration::path(current, target).and_then(|path| path.first().copied()) else {
+            self.accounts_store.set_migration_target(None);
+            return;
+        };
+        let PartitionsMaybe::Partitions(partitions) = &self.partitions_maybe else {
+            println!(
+                "WARNING: Cannot migrate accounts to {:?} without stable memory partitions.",
+                next.to
+            );
+            self.accounts_store.set_migration_target(None);
+            return;
+        };
+        let db = Self::new_accounts_db(partitions, next.to);
+        if let Err(err) = self.accounts_store.start_migration(db) {
+            println!("WARNING: Failed to start migrating accounts to {:?}: {err:?}", next.to);
+            self.accounts_store.set_migration_target(None);
+        }
+    }
+
+    /// Advances the accounts migration in progress by one step.
+    ///
+    /// When a migration completes short of the migration target, the next registered migration
+    /// towards the target is started.
+    pub fn step_accounts_migration(&mut self, step_size: u32) {
+        self.accounts_store.step_migration(step_size);
+        if !self.accounts_store.migration_in_progress() {
+            self.start_next_accounts_migration();
+        }
+    }
+
+    /// Restores the state of the accounts migrations saved before an upgrade.
+    ///
+    /// A migration that was in progress continues with the database in the partition of its
+    /// target schema, which holds the accounts copied so far.
+    fn resume_accounts_migration(&mut self, partitions: &Partitions) {
+        let saved = HeapOrStableCell::init(
+            partitions.get(PartitionType::Migrations.memory_id()),
+            SavedMigrations::default(),
+        )
+        .get()
+        .clone();
+        let db = saved
+            .in_progress
+            .as_ref()
+            .map(|migration| Self::load_accounts_db(partitions, migration.to));
+        self.accounts_store.restore_migrations(saved, db);
+    }
+
+    /// Starts verifying the state, unless a verification is already in progress.
+    ///
+    /// The accounts are checked in steps, see `step_state_verification`.  The schema label
+    /// recorded in stable memory is checked straight away: it should name the schema that the
+    /// accounts were loaded from.  Otherwise the label has been overwritten.
+    pub fn start_state_verification(&mut self, repair: bool) -> VerifyStateResponse {
+        let response = self.accounts_store.start_verification(repair);
+        if response != VerifyStateResponse::Ok {
+            return response;
+        }
+        if let PartitionsMaybe::Partitions(partitions) = &self.partitions_maybe {
+            let actual = self.accounts_store.loaded_schema_label();
+            // Releases that predate the schema label did not record one, so there is nothing to check.
+            if let Some(recorded) = partitions.schema_label() {
+                if recorded != actual {
+                    self.accounts_store
+                        .record_discrepancy(Discrepancy::SchemaLabel { recorded, actual });
+                    if repair {
+                        partitions.set_schema_label(actual);
+                    }
+                }
+            }
+        }
+        response
+    }
+
+    /// Checks the next accounts of the state verification in progress, if any.
+    pub fn step_state_verification(&mut self, step_size: u32) {
+        self.accounts_store.step_verification(step_size);
+    }
+
+    /// Moves the state that has its own partition, other than the accounts, into stable memory.
+    ///
+    /// Partitions are empty when the canister is created or upgraded from a release that predates
+    /// them.  Those that held data on the heap in earlier releases are then populated with the
+    /// data decoded from the heap.  Otherwise the data already in the partitions is loaded, and
+    /// the empty values decoded from the heap are discarded.
+    fn move_to_partitions(&mut self, partitions: &Partitions) {
+        let _deserialized_transaction_index = self.accounts_store.replace_transaction_index(TransactionIndex::init(
+            partitions.get(PartitionType::TransactionIndex.memory_id()),
+        ));
+        let _deserialized_operation_log = self.accounts_store.replace_operation_log(OperationLog::init(
+            partitions.get(PartitionType::OperationLog.memory_id()),
+        ));
+        self.accounts_store
+            .load_legacy_links(partitions.get(PartitionType::HardwareWalletsAndSubAccounts.memory_id()));
+        self.accounts_store
+            .load_multi_part_transactions(partitions.get(PartitionType::MultiPartTransactions.memory_id()));
+        self.accounts_store
+            .load_neuron_accounts(partitions.get(PartitionType::NeuronAccounts.memory_id()));
+        self.accounts_store
+            .load_processed_blocks(partitions.get(PartitionType::ProcessedBlocks.memory_id()));
+        self.accounts_store
+            .load_account_transfer_offers(partitions.get(PartitionType::AccountTransferOffers.memory_id()));
+        self.accounts_store
+            .load_blocks_to_replay(partitions.get(PartitionType::BlocksToReplay.memory_id()));
+        self.stable_assets = HeapOrStableMap::init_or_migrate(
+            partitions.get(PartitionType::StableAssets.memory_id()),
+            self.assets.stable_assets(),
+        );
+        self.assets = Assets::from(&self.stable_assets);
+        self.asset_hashes = AssetHashes::from(&self.assets);
+        self.performance = HeapOrStableCell::init(
+            partitions.get(PartitionType::Performance.memory_id()),
+            self.performance.clone(),
+        )
+        .get()
+        .clone();
+        self.tvl_state = HeapOrStableCell::init(
+            partitions.get(PartitionType::Tvl.memory_id()),
+            self.tvl_state.get().clone(),
+        );
+    }
 }
 
 impl StableState for State {
     fn encode(&self) -> Vec<u8> {
+        // Stable assets and the TVL state are in their own stable memory partitions, so empty
+        // values are encoded in their place.  Downgrading to a release that predates the
+        // partitions requires a one-off release that copies them back to the heap.
         Candid((
             self.accounts_store.encode(),
-            self.assets.encode(),
-            self.tvl_state.encode(),
+            Assets::default().encode(),
+            TvlState::default().encode(),
         ))
         .into_bytes()
         .unwrap()
@@ -155,18 +377,21 @@ impl StableState for State {
     fn decode(bytes: Vec<u8>) -> Result<Self, String> {
         let (account_store_bytes, assets_bytes, tvl_state_bytes) = Candid::from_bytes(bytes).map(|c| c.0)?;
 
+        // Releases that predate the partitions for stable assets and the TVL state store them
+        // here.  They are moved to stable memory in `State::new_restored` if their partitions
+        // are new, and are otherwise discarded.
         let assets = Assets::decode(assets_bytes)?;
         let asset_hashes = AssetHashes::from(&assets);
-        let performance = PerformanceCounts::default();
         let tvl_state = TvlState::decode(tvl_state_bytes)?;
 
         Ok(State {
             accounts_store: AccountsStore::decode(account_store_bytes)?,
             assets,
             asset_hashes,
-            performance,
+            stable_assets: HeapOrStableMap::default(),
+            performance: PerformanceCounts::default(),
             partitions_maybe: PartitionsMaybe::None(DefaultMemoryImpl::default()),
-            tvl_state,
+            tvl_state: HeapOrStableCell::Heap(tvl_state),
         })
     }
 }
@@ -175,6 +400,24 @@ impl StableState for State {
 impl State {
     /// Saves any unsaved state to stable memory.
     pub fn save(&self) {
+        if let PartitionsMaybe::Partitions(partitions) = &self.partitions_maybe {
+            partitions.set_schema_label(self.accounts_store.schema_label());
+            HeapOrStableCell::init(
+                partitions.get(PartitionType::Performance.memory_id()),
+                PerformanceCounts::default(),
+            )
+            .set(self.performance.clone());
+            HeapOrStableCell::init(
+                partitions.get(PartitionType::Migrations.memory_id()),
+                SavedMigrations::default(),
+            )
+            .set(self.accounts_store.saved_migrations());
+            HeapOrStableCell::init(
+                partitions.get(PartitionType::Verification.memory_id()),
+                SavedVerification::default(),
+            )
+            .set(self.accounts_store.saved_verification());
+        }
         self.save_heap_to_managed_memory();
     }
 }
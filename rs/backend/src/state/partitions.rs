@@ -4,6 +4,7 @@
 //! This code is here to protect the memory!
 //!
 //! This code also stores virtual memory IDs and other memory functions.
+use crate::accounts_store::schema::{SchemaLabel, SchemaLabelBytes};
 use core::borrow::Borrow;
 use ic_cdk::api::stable::WASM_PAGE_SIZE_IN_BYTES;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
@@ -85,6 +86,74 @@ pub enum PartitionType {
     ///
     /// Note: This ID is guaranteed to be stable across deployments.
     Accounts = 2,
+    /// The virtual memory containing the opt-in per-account transaction index.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    TransactionIndex = 3,
+    /// The virtual memory containing the outcomes of multi-part transactions.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    OperationLog = 4,
+    /// The virtual memory that held the links from sub-accounts and hardware wallets to their
+    /// accounts, keyed by the sub-account or hardware wallet, before they were indexed in `AccountLinks`.
+    ///
+    /// The links found here are consulted while that index is being built, then discarded.  The ID
+    /// is reserved so that data of another type is never read from, or written over, these links.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    HardwareWalletsAndSubAccounts = 5,
+    /// The virtual memory containing the multi-part transactions waiting to be processed.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    MultiPartTransactions = 6,
+    /// The virtual memory containing the assets that are persisted across upgrades.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    StableAssets = 7,
+    /// The virtual memory containing performance counters.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    Performance = 8,
+    /// The virtual memory containing the total value locked.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    Tvl = 9,
+    /// The virtual memory containing accounts in the `CompactAccountsInStableMemory` schema.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    CompactAccounts = 10,
+    /// The virtual memory containing the index from sub-accounts and hardware wallets to their accounts in the `CompactAccounts` partition.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    CompactAccountLinks = 11,
+    /// The virtual memory containing the index from sub-accounts and hardware wallets to their accounts in the `Accounts` partition.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    AccountLinks = 12,
+    /// The virtual memory containing the governance accounts of neurons staked with the dapp.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    NeuronAccounts = 13,
+    /// The virtual memory containing the heights of the blocks that have been queued as multi-part transactions.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    ProcessedBlocks = 14,
+    /// The virtual memory containing the pending offers to move an account to a new principal.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    AccountTransferOffers = 15,
+    /// The virtual memory containing the blocks that could not be decoded and are to be replayed.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    BlocksToReplay = 16,
+    /// The virtual memory containing the state of the accounts migrations, saved on upgrade.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    Migrations = 17,
+    /// The virtual memory containing the state verification, saved on upgrade.
+    ///
+    /// Note: This ID is guaranteed to be stable across deployments.
+    Verification = 18,
 }
 impl PartitionType {
     /// The memory ID.
@@ -116,6 +185,26 @@ impl Partitions {
         }
         memory.write(offset, bytes);
     }
+
+    /// Gets the schema of the accounts database, as recorded in the metadata partition.
+    ///
+    /// Returns `None` if no valid label has been recorded, as is the case for releases that
+    /// predate the label.
+    #[must_use]
+    pub fn schema_label(&self) -> Option<SchemaLabel> {
+        let memory = self.get(PartitionType::Metadata.memory_id());
+        if memory.size() == 0 {
+            return None;
+        }
+        let mut label_bytes: SchemaLabelBytes = [0u8; SchemaLabel::MAX_BYTES];
+        memory.read(0, &mut label_bytes);
+        SchemaLabel::try_from(&label_bytes).ok()
+    }
+
+    /// Records the schema of the accounts database in the metadata partition.
+    pub fn set_schema_label(&self, schema: SchemaLabel) {
+        self.growing_write(PartitionType::Metadata.memory_id(), 0, &SchemaLabelBytes::from(schema));
+    }
 }
 
 /// Gets an existing memory manager, if there is one.  If not, returns the unmodified memory.
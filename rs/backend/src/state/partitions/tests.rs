@@ -217,6 +217,18 @@ fn debug_should_portray_partitions_accurately() {
     partitions.get(PartitionType::Accounts.memory_id()).grow(2);
     assert_eq!(
         format!("{:?}", partitions),
-        "Partitions {\n  Metadata partition: 5 pages\n  Heap partition: 0 pages\n  Accounts partition: 2 pages\n}\n"
+        "Partitions {\n  Metadata partition: 5 pages\n  Heap partition: 0 pages\n  Accounts partition: 2 pages\n  TransactionIndex partition: 0 pages\n  OperationLog partition: 0 pages\n  HardwareWalletsAndSubAccounts partition: 0 pages\n  MultiPartTransactions partition: 0 pages\n  StableAssets partition: 0 pages\n  Performance partition: 0 pages\n  Tvl partition: 0 pages\n  CompactAccounts partition: 0 pages\n  CompactAccountLinks partition: 0 pages\n  AccountLinks partition: 0 pages\n  NeuronAccounts partition: 0 pages\n  ProcessedBlocks partition: 0 pages\n  AccountTransferOffers partition: 0 pages\n  BlocksToReplay partition: 0 pages\n  Migrations partition: 0 pages\n  Verification partition: 0 pages\n}\n"
+    );
+}
+
+#[test]
+fn schema_label_should_be_recorded_in_the_metadata_partition() {
+    let toy_memory = DefaultMemoryImpl::default();
+    let partitions = Partitions::from(Rc::clone(&toy_memory));
+    assert_eq!(partitions.schema_label(), None);
+    partitions.set_schema_label(SchemaLabel::CompactAccountsInStableMemory);
+    assert_eq!(
+        Partitions::from(Rc::clone(&toy_memory)).schema_label(),
+        Some(SchemaLabel::CompactAccountsInStableMemory)
     );
 }
@@ -1,9 +1,23 @@
 use crate::{
-    accounts_store::schema::{map::AccountsDbAsMap, proxy::AccountsDb, AccountsDbTrait},
-    state::{partitions::PartitionsMaybe, AssetHashes, Assets, PerformanceCounts, StableState, State},
+    accounts_store::heap_or_stable_cell::HeapOrStableCell,
+    accounts_store::heap_or_stable_map::HeapOrStableMap,
+    accounts_store::schema::{
+        map::AccountsDbAsMap,
+        proxy::{AccountsDb, MigrationStatus},
+        AccountsDbTrait, SchemaLabel,
+    },
+    accounts_store::verification::{Discrepancy, VerificationStatus},
+    accounts_store::VerifyStateResponse,
+    assets::{insert_asset_into_state, Asset},
+    state::{
+        partitions::{PartitionType, Partitions, PartitionsMaybe},
+        AssetHashes, Assets, PerformanceCounts, StableState, State,
+    },
     tvl::state::TvlState,
 };
+use dfn_candid::Candid;
 use ic_stable_structures::{DefaultMemoryImpl, VectorMemory};
+use on_wire::IntoWire;
 use pretty_assertions::assert_eq;
 use proptest::proptest;
 use std::rc::Rc;
@@ -14,17 +28,22 @@ pub fn setup_test_state() -> State {
         accounts_store: crate::accounts_store::tests::setup_test_store(),
         assets: Assets::default(),
         asset_hashes: AssetHashes::default(),
+        stable_assets: HeapOrStableMap::default(),
         performance: PerformanceCounts::test_data(),
         partitions_maybe: PartitionsMaybe::None(VectorMemory::default()),
-        tvl_state: TvlState::test_data(),
+        tvl_state: HeapOrStableCell::Heap(TvlState::test_data()),
     }
 }
 
 #[test]
 fn state_heap_contents_can_be_serialized_and_deserialized() {
     let mut toy_state = setup_test_state();
+    // As in production, state that has its own partition is kept there.
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    toy_state.move_to_partitions(&partitions);
     let bytes: Vec<u8> = toy_state.encode();
-    let parsed = State::decode(bytes).expect("Failed to parse");
+    let mut parsed = State::decode(bytes).expect("Failed to parse");
+    parsed.move_to_partitions(&partitions);
     // Drop the accounts DB from the accounts store before comparing. We use
     // stable structures to store the accounts DB, which are stored separately
     // so we don't encode/decode them as part of the accounts store.
@@ -42,6 +61,79 @@ fn state_heap_contents_can_be_serialized_and_deserialized() {
     assert_eq!(toy_state.tvl_state, parsed.tvl_state, "TVL state has changed");
 }
 
+#[test]
+fn state_heap_contents_exclude_partitioned_state() {
+    let mut toy_state = setup_test_state();
+    insert_asset_into_state(&mut toy_state, "/stable.js", Asset::new_stable(vec![1, 2, 3]));
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    toy_state.move_to_partitions(&partitions);
+
+    // Empty values are serialized with the heap in place of the partitioned state.
+    let mut parsed = State::decode(toy_state.encode()).expect("Failed to parse");
+    assert!(parsed.assets.get("/stable.js").is_none());
+    assert_eq!(*parsed.tvl_state.get(), TvlState::default());
+
+    // The partitioned state is loaded from the partitions after an upgrade.
+    parsed.move_to_partitions(&partitions);
+    assert!(parsed.assets.get("/stable.js").is_some());
+    assert_eq!(*parsed.tvl_state.get(), TvlState::test_data());
+    assert_eq!(parsed.stable_assets.len(), 1);
+}
+
+#[test]
+fn stable_asset_replaced_by_an_asset_that_is_not_stable_is_not_restored_on_upgrade() {
+    let mut toy_state = setup_test_state();
+    let partitions = Partitions::from(DefaultMemoryImpl::default());
+    toy_state.move_to_partitions(&partitions);
+    insert_asset_into_state(&mut toy_state, "/index.js", Asset::new_stable(vec![1, 2, 3]));
+    insert_asset_into_state(&mut toy_state, "/index.js", Asset::new(vec![4, 5, 6]));
+    assert!(toy_state.stable_assets.is_empty());
+
+    let mut parsed = State::decode(toy_state.encode()).expect("Failed to parse");
+    parsed.move_to_partitions(&partitions);
+    assert!(parsed.assets.get("/index.js").is_none());
+}
+
+#[test]
+fn performance_counts_are_saved_on_upgrade() {
+    let memory = DefaultMemoryImpl::default();
+    let mut state = State::new(Rc::clone(&memory));
+    state.performance = PerformanceCounts::test_data();
+    state.save();
+
+    let restored = State::new_restored(memory);
+
+    assert_eq!(restored.performance, PerformanceCounts::test_data());
+}
+
+#[test]
+fn state_saved_by_an_earlier_release_is_moved_to_partitions() {
+    // Earlier releases serialized the TVL state and stable assets with the heap.
+    let mut legacy_state = setup_test_state();
+    insert_asset_into_state(&mut legacy_state, "/stable.js", Asset::new_stable(vec![1, 2, 3]));
+    let legacy_heap = Candid((
+        legacy_state.accounts_store.encode(),
+        legacy_state.assets.encode(),
+        legacy_state.tvl_state.get().encode(),
+    ))
+    .into_bytes()
+    .unwrap();
+    let memory = DefaultMemoryImpl::default();
+    let partitions = Partitions::from(Rc::clone(&memory));
+    partitions.growing_write(
+        PartitionType::Heap.memory_id(),
+        0,
+        &(legacy_heap.len() as u64).to_be_bytes(),
+    );
+    partitions.growing_write(PartitionType::Heap.memory_id(), 8, &legacy_heap);
+
+    let restored = State::new_restored(memory);
+
+    assert_eq!(*restored.tvl_state.get(), TvlState::test_data());
+    assert!(restored.assets.get("/stable.js").is_some());
+    assert_eq!(restored.stable_assets.len(), 1);
+}
+
 #[test]
 fn state_can_be_created() {
     // State is backed by stable memory:
@@ -88,3 +180,183 @@ proptest! {
         state_can_be_saved_and_recovered_from_stable_memory(u64::from(num_accounts))
     }
 }
+
+#[test]
+fn accounts_migrated_to_the_compact_schema_are_restored_from_its_partition() {
+    let memory = DefaultMemoryImpl::default();
+    let memory_after_upgrade = Rc::clone(&memory);
+    let mut state = State::new(memory);
+    for toy_account_index in 0..30u64 {
+        state.accounts_store.db_insert_account(
+            &toy_account_index.to_be_bytes()[..],
+            crate::accounts_store::schema::tests::toy_account(toy_account_index, 2),
+        );
+    }
+
+    state.start_accounts_migration(SchemaLabel::CompactAccountsInStableMemory);
+    assert!(state.accounts_store.migration_in_progress());
+    while state.accounts_store.migration_in_progress() {
+        state.accounts_store.step_migration(7);
+    }
+    assert_eq!(
+        state.accounts_store.schema_label(),
+        SchemaLabel::CompactAccountsInStableMemory
+    );
+
+    state.save();
+    let new_state = State::new_restored(memory_after_upgrade);
+    assert_eq!(
+        new_state.accounts_store.schema_label(),
+        SchemaLabel::CompactAccountsInStableMemory
+    );
+    assert_eq!(state, new_state);
+}
+
+#[test]
+fn accounts_are_migrated_along_several_registered_migrations() {
+    let mut state = State::new(DefaultMemoryImpl::default());
+    let _stable_db = state
+        .accounts_store
+        .replace_accounts_db(AccountsDb::Map(AccountsDbAsMap::default()));
+    for toy_account_index in 0..30u64 {
+        state.accounts_store.db_insert_account(
+            &toy_account_index.to_be_bytes()[..],
+            crate::accounts_store::schema::tests::toy_account(toy_account_index, 2),
+        );
+    }
+
+    state.start_accounts_migration(SchemaLabel::CompactAccountsInStableMemory);
+    while state.accounts_store.migration_in_progress() {
+        state.step_accounts_migration(7);
+    }
+
+    assert_eq!(
+        state.accounts_store.schema_label(),
+        SchemaLabel::CompactAccountsInStableMemory
+    );
+    assert_eq!(
+        state
+            .accounts_store
+            .migration_progress()
+            .into_iter()
+            .map(|progress| (progress.from, progress.to, progress.status, progress.accounts_migrated))
+            .collect::<Vec<_>>(),
+        vec![
+            (
+                SchemaLabel::Map,
+                SchemaLabel::AccountsInStableMemory,
+                MigrationStatus::Completed,
+                30
+            ),
+            (
+                SchemaLabel::AccountsInStableMemory,
+                SchemaLabel::CompactAccountsInStableMemory,
+                MigrationStatus::Completed,
+                30
+            ),
+        ]
+    );
+    assert_eq!(state.accounts_store.migration_target(), None);
+}
+
+#[test]
+fn accounts_migration_is_resumed_after_an_upgrade() {
+    let memory = DefaultMemoryImpl::default();
+    let mut state = State::new(Rc::clone(&memory));
+    for toy_account_index in 0..30u64 {
+        state.accounts_store.db_insert_account(
+            &toy_account_index.to_be_bytes()[..],
+            crate::accounts_store::schema::tests::toy_account(toy_account_index, 2),
+        );
+    }
+    state.start_accounts_migration(SchemaLabel::CompactAccountsInStableMemory);
+    state.step_accounts_migration(7);
+    state.save();
+
+    // The migration continues where it left off.
+    let mut restored = State::new_restored(Rc::clone(&memory));
+    assert!(restored.accounts_store.migration_in_progress());
+    assert_eq!(
+        restored.accounts_store.migration_progress(),
+        state.accounts_store.migration_progress()
+    );
+    while restored.accounts_store.migration_in_progress() {
+        restored.step_accounts_migration(7);
+    }
+    assert_eq!(
+        restored.accounts_store.schema_label(),
+        SchemaLabel::CompactAccountsInStableMemory
+    );
+    assert_eq!(restored.accounts_store.migration_progress()[0].accounts_migrated, 30);
+
+    // Finished migrations are still reported after the next upgrade.
+    restored.save();
+    let upgraded = State::new_restored(memory);
+    assert_eq!(
+        upgraded.accounts_store.migration_progress(),
+        restored.accounts_store.migration_progress()
+    );
+    assert_eq!(upgraded.accounts_store.db_accounts_len(), 30);
+}
+
+#[test]
+fn accounts_are_migrated_only_along_registered_migrations() {
+    let mut state = State::new(DefaultMemoryImpl::default());
+    state.start_accounts_migration(SchemaLabel::Map);
+    assert!(!state.accounts_store.migration_in_progress());
+    state.start_accounts_migration(SchemaLabel::AccountsInStableMemory);
+    assert!(!state.accounts_store.migration_in_progress());
+    assert_eq!(state.accounts_store.schema_label(), SchemaLabel::AccountsInStableMemory);
+}
+
+#[test]
+fn state_verification_is_resumed_after_an_upgrade() {
+    let memory = DefaultMemoryImpl::default();
+    let mut state = State::new(Rc::clone(&memory));
+    for toy_account_index in 0..30u64 {
+        state.accounts_store.db_insert_account(
+            &toy_account_index.to_be_bytes()[..],
+            crate::accounts_store::schema::tests::toy_account(toy_account_index, 2),
+        );
+    }
+    assert_eq!(state.start_state_verification(false), VerifyStateResponse::Ok);
+    state.step_state_verification(7);
+    state.save();
+
+    // The verification continues where it left off.
+    let mut restored = State::new_restored(memory);
+    assert!(restored.accounts_store.verification_in_progress());
+    assert_eq!(
+        restored.accounts_store.verification_report(),
+        state.accounts_store.verification_report()
+    );
+    while restored.accounts_store.verification_in_progress() {
+        restored.step_state_verification(7);
+    }
+    let report = restored.accounts_store.verification_report().unwrap();
+    assert_eq!(report.status, VerificationStatus::Completed);
+    assert_eq!(report.accounts_verified, 30);
+}
+
+#[test]
+fn state_verification_reports_and_repairs_an_overwritten_schema_label() {
+    let mut state = State::new(DefaultMemoryImpl::default());
+    let PartitionsMaybe::Partitions(partitions) = &state.partitions_maybe else {
+        panic!("The state should have partitions");
+    };
+    partitions.set_schema_label(SchemaLabel::CompactAccountsInStableMemory);
+
+    assert_eq!(state.start_state_verification(true), VerifyStateResponse::Ok);
+    let report = state.accounts_store.verification_report().unwrap();
+    assert_eq!(
+        report.discrepancies,
+        vec![Discrepancy::SchemaLabel {
+            recorded: SchemaLabel::CompactAccountsInStableMemory,
+            actual: SchemaLabel::AccountsInStableMemory,
+        }]
+    );
+    let PartitionsMaybe::Partitions(partitions) = &state.partitions_maybe else {
+        panic!("The state should have partitions");
+    };
+    assert_eq!(partitions.schema_label(), Some(SchemaLabel::AccountsInStableMemory));
+}
@@ -48,6 +48,20 @@ pub struct Stats {
     pub periodic_tasks_count: Option<u32>,
     /// Whether account stats were recomputed on upgrade.
     pub accounts_db_stats_recomputed_on_upgrade: Option<bool>,
+    /// The number of multi-part transactions waiting for another attempt.
+    pub transactions_to_retry_queue_length: Option<u32>,
+    /// The number of multi-part transactions that were given up on after too many attempts.
+    pub dead_letter_transactions_count: Option<u32>,
+    /// How many blocks ledger sync was behind the tip of the ledger when last checked.  Large while catching up.
+    pub blocks_behind_tip: Option<u64>,
+    /// The number of blocks that could not be decoded and are waiting to be fetched again.
+    pub blocks_to_replay_count: Option<u32>,
+    /// Whether a state verification is in progress.  `None` if there has been no verification.
+    pub state_verification_in_progress: Option<bool>,
+    /// The number of accounts checked by the current or latest state verification.
+    pub state_verification_accounts_verified: Option<u64>,
+    /// The number of discrepancies found by the current or latest state verification, including any not kept in its report.
+    pub state_verification_discrepancies_count: Option<u32>,
 }
 
 /// Encodes the metrics into the format scraped by the monitoring system.
@@ -101,10 +115,35 @@ pub fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
         f64::from(stats.exceptional_transactions_count.unwrap_or(0)),
         "The number of exceptional transactions in the canister log.",
     )?;
+    w.encode_gauge(
+        "transactions_to_retry_queue_length",
+        f64::from(stats.transactions_to_retry_queue_length.unwrap_or(0)),
+        "The number of multi-part transactions waiting for another attempt.",
+    )?;
+    w.encode_gauge(
+        "dead_letter_transactions_count",
+        f64::from(stats.dead_letter_transactions_count.unwrap_or(0)),
+        "The number of multi-part transactions given up on after too many attempts.",
+    )?;
+    w.encode_gauge(
+        "blocks_behind_tip",
+        stats.blocks_behind_tip.unwrap_or(0) as f64,
+        "How many ledger blocks ledger sync was behind the tip of the chain when last checked.",
+    )?;
+    w.encode_gauge(
+        "blocks_to_replay_count",
+        f64::from(stats.blocks_to_replay_count.unwrap_or(0)),
+        "The number of ledger blocks that could not be decoded and are waiting to be fetched again.",
+    )?;
+    w.encode_gauge(
+        "state_verification_discrepancies_count",
+        f64::from(stats.state_verification_discrepancies_count.unwrap_or(0)),
+        "The number of discrepancies found by the current or latest state verification.",
+    )?;
     w.encode_gauge(
         "periodic_tasks_count",
         f64::from(stats.periodic_tasks_count.unwrap_or(0)),
-        "The number of times the periodic tasks runner has run successfully (ignoring async tasks).",
+        "The number of times a periodic task has been started by its timer (ignoring async tasks).",
         // Note: The counter is always incremented, however on Wasm trap (e.g. `ic_cdk::trap` or Rust `panic!`) the increment is lost.
     )?;
     Ok(())
@@ -1,16 +1,28 @@
 use crate::state::StableState;
 use candid::CandidType;
 use dfn_candid::Candid;
+use ic_stable_structures::{storable::Bound, Storable};
 use on_wire::{FromWire, IntoWire};
 use serde::Deserialize;
+use std::borrow::Cow;
 
-#[derive(CandidType, Default, Debug, Deserialize, PartialEq)]
+#[derive(CandidType, Clone, Default, Debug, Deserialize, PartialEq, Eq)]
 pub struct TvlState {
     pub total_locked_icp_e8s: u64,
     pub usd_e8s_per_icp: u64,
     pub exchange_rate_timestamp_seconds: u64,
 }
 
+impl Storable for TvlState {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        candid::encode_one(self).expect("Failed to serialize TVL state").into()
+    }
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to parse TVL state from store.")
+    }
+}
+
 impl StableState for TvlState {
     fn encode(&self) -> Vec<u8> {
         Candid((self,)).into_bytes().unwrap_or_default()
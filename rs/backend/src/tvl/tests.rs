@@ -22,19 +22,22 @@ lazy_static! {
 }
 
 fn get_usd_e8s_per_icp() -> u64 {
-    with_state(|s| s.tvl_state.usd_e8s_per_icp)
+    with_state(|s| s.tvl_state.get().usd_e8s_per_icp)
 }
 
 fn set_usd_e8s_per_icp(new_value: u64) {
-    with_state_mut(|s| s.tvl_state.usd_e8s_per_icp = new_value);
+    with_state_mut(|s| s.tvl_state.update(|tvl_state| tvl_state.usd_e8s_per_icp = new_value));
 }
 
 fn get_exchange_rate_timestamp_seconds() -> u64 {
-    with_state(|s| s.tvl_state.exchange_rate_timestamp_seconds)
+    with_state(|s| s.tvl_state.get().exchange_rate_timestamp_seconds)
 }
 
 fn set_exchange_rate_timestamp_seconds(new_value: u64) {
-    with_state_mut(|s| s.tvl_state.exchange_rate_timestamp_seconds = new_value);
+    with_state_mut(|s| {
+        s.tvl_state
+            .update(|tvl_state| tvl_state.exchange_rate_timestamp_seconds = new_value)
+    });
 }
 
 fn get_only_xrc_request() -> exchange_rate_canister::GetExchangeRateRequest {
@@ -44,11 +47,14 @@ fn get_only_xrc_request() -> exchange_rate_canister::GetExchangeRateRequest {
 }
 
 fn get_total_locked_icp_e8s() -> u64 {
-    with_state(|s| s.tvl_state.total_locked_icp_e8s)
+    with_state(|s| s.tvl_state.get().total_locked_icp_e8s)
 }
 
 fn set_total_locked_icp_e8s(new_value: u64) {
-    with_state_mut(|s| s.tvl_state.total_locked_icp_e8s = new_value);
+    with_state_mut(|s| {
+        s.tvl_state
+            .update(|tvl_state| tvl_state.total_locked_icp_e8s = new_value)
+    });
 }
 
 #[tokio::test]